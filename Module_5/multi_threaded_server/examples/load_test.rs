@@ -0,0 +1,166 @@
+//! Compare the thread-per-connection and reactor server cores under load
+//!
+//! Connects many idle clients, then broadcasts messages to all of them and
+//! measures how long the fan-out takes and how many threads the server used.
+//!
+//!     cargo run --release --example load_test -- [clients] [messages] [workers]
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use multi_threaded_server::common::protocol::{FramedMessage, Message};
+use multi_threaded_server::server::listener::{IoModel, Server, ServerConfig};
+
+/// Threads used to connect clients
+const JOINERS: usize = 16;
+
+/// What one run measured
+struct Report {
+    join_time: Duration,
+    fan_out_time: Duration,
+    deliveries: usize,
+    server_threads: Option<usize>,
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let args: Vec<usize> = std::env::args()
+        .skip(1)
+        .map(|arg| arg.parse())
+        .collect::<Result<_, _>>()?;
+    let clients = args.first().copied().unwrap_or(500);
+    let messages = args.get(1).copied().unwrap_or(100);
+    let workers = args.get(2).copied().unwrap_or(4);
+    
+    println!("{} clients, {} broadcasts each\n", clients, messages);
+    println!("{:<24} {:>10} {:>12} {:>14} {:>8}", "model", "join (s)", "fan-out (s)", "deliveries/s", "threads");
+    
+    let models = [
+        ("thread-per-connection".to_string(), IoModel::ThreadPerConnection),
+        (format!("reactor ({} workers)", workers), IoModel::Reactor { workers }),
+    ];
+    for (name, io_model) in models {
+        let report = run(io_model, clients, messages)?;
+        let rate = report.deliveries as f64 / report.fan_out_time.as_secs_f64();
+        let threads = report.server_threads.map_or("-".to_string(), |n| n.to_string());
+        println!(
+            "{:<24} {:>10.3} {:>12.3} {:>14.0} {:>8}",
+            name,
+            report.join_time.as_secs_f64(),
+            report.fan_out_time.as_secs_f64(),
+            rate,
+            threads
+        );
+    }
+    Ok(())
+}
+
+/// Connect `clients` clients to a server using `io_model` and time a burst of broadcasts
+fn run(io_model: IoModel, clients: usize, messages: usize) -> Result<Report, anyhow::Error> {
+    let threads_before = thread_count();
+    let server = Server::bind(ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(),
+        io_model,
+        max_connections: clients + 1,
+        // Clients sit idle while the others join
+        connection_timeout: Duration::from_secs(300),
+        // Room for every join notice and broadcast, so nothing is dropped
+        outbound_queue_size: clients + messages + 16,
+        ..ServerConfig::default()
+    })?;
+    let addr = server.local_addr()?;
+    let shutdown = server.shutdown_handle();
+    let server_handle = server.spawn();
+    
+    // Join from several threads at once, as independent clients would
+    let started = Instant::now();
+    let joiners: Vec<_> = (0..JOINERS)
+        .map(|joiner| thread::spawn(move || {
+            (joiner..clients)
+                .step_by(JOINERS)
+                .map(|n| join(addr, &format!("load{}", n)))
+                .collect::<Result<Vec<_>, _>>()
+        }))
+        .collect();
+    let mut streams = Vec::with_capacity(clients);
+    for joiner in joiners {
+        streams.extend(joiner.join().expect("joiner thread panicked")?);
+    }
+    let join_time = started.elapsed();
+    // The load generator has only this thread so far, so the rest are the server's
+    let server_threads = thread_count().zip(threads_before).map(|(after, before)| after.saturating_sub(before));
+    
+    let readers: Vec<_> = streams.into_iter()
+        .map(|(stream, buffer)| thread::spawn(move || count_broadcasts(stream, buffer, messages)))
+        .collect();
+    
+    let (mut sender, _) = join(addr, "sender")?;
+    let started = Instant::now();
+    for n in 0..messages {
+        let message = Message::chat("sender".to_string(), format!("message {}", n));
+        sender.write_all(&FramedMessage::encode(&message)?)?;
+    }
+    let mut deliveries = 0;
+    for reader in readers {
+        deliveries += reader.join().expect("reader thread panicked");
+    }
+    let fan_out_time = started.elapsed();
+    
+    shutdown.shutdown("load test finished");
+    server_handle.join().expect("server thread panicked")?;
+    
+    Ok(Report {
+        join_time,
+        fan_out_time,
+        deliveries,
+        server_threads,
+    })
+}
+
+/// Connect and join, returning the stream and any bytes read past the Welcome
+fn join(addr: SocketAddr, username: &str) -> Result<(TcpStream, Vec<u8>), anyhow::Error> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    stream.write_all(&FramedMessage::encode(&Message::join(username.to_string(), None))?)?;
+    
+    let mut buffer = Vec::new();
+    match next_message(&mut stream, &mut buffer) {
+        Some(Message::Welcome { .. }) => Ok((stream, buffer)),
+        other => anyhow::bail!("{} expected Welcome, got {:?}", username, other),
+    }
+}
+
+/// Read until `expected` broadcasts have arrived or the stream stalls
+fn count_broadcasts(mut stream: TcpStream, mut buffer: Vec<u8>, expected: usize) -> usize {
+    let mut received = 0;
+    while received < expected {
+        match next_message(&mut stream, &mut buffer) {
+            Some(Message::Broadcast { .. }) => received += 1,
+            Some(_) => {}
+            None => break,
+        }
+    }
+    received
+}
+
+fn next_message(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> Option<Message> {
+    let mut read_buf = [0u8; 4096];
+    loop {
+        if let Some(message) = FramedMessage::decode(buffer).ok()? {
+            return Some(message);
+        }
+        match stream.read(&mut read_buf) {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => buffer.extend_from_slice(&read_buf[..n]),
+        }
+    }
+}
+
+/// Threads in this process, where the platform makes that easy to find
+fn thread_count() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status.lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .and_then(|count| count.trim().parse().ok())
+}
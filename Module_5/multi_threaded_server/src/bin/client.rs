@@ -0,0 +1,53 @@
+use std::io::IsTerminal;
+use clap::Parser;
+use log::info;
+use env_logger::Env;
+
+use multi_threaded_server::client::client::{Client, Interface};
+use multi_threaded_server::client::script::{self, exit_codes};
+use multi_threaded_server::client::settings::ClientSettings;
+
+fn main() -> Result<(), anyhow::Error> {
+    // Flags and environment first, then the config file, then defaults
+    let mut config = ClientSettings::parse().resolve()?;
+    // The full-screen interface needs a terminal on both ends
+    if config.interface == Interface::Full && !(std::io::stdin().is_terminal() && std::io::stdout().is_terminal()) {
+        config.interface = Interface::Plain;
+    }
+    let scripted = matches!(config.interface, Interface::Pipe | Interface::Script(_));
+    
+    // Initialize logging; log lines would garble the full-screen interface, so
+    // there they are off unless RUST_LOG asks for them
+    let default_filter = match config.interface {
+        Interface::Full => "off",
+        Interface::Plain => "info",
+        Interface::Pipe | Interface::Script(_) => "warn",
+    };
+    env_logger::Builder::from_env(Env::default().default_filter_or(default_filter)).init();
+    
+    if config.username.is_empty() {
+        // A pipe's or script's input is not for us to read
+        if scripted {
+            eprintln!("Error: a username is required with --pipe or --script");
+            std::process::exit(exit_codes::USAGE);
+        }
+        println!("Enter username: ");
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input)?;
+        config.username = input.trim().to_string();
+    }
+    
+    info!("Starting chat client...");
+    
+    // Connect and run; scripts and pipes report how it went in the exit code
+    match Client::connect(config).and_then(Client::run) {
+        Err(e) if scripted => {
+            eprintln!("Error: {:#}", e);
+            std::process::exit(script::exit_code(&e));
+        }
+        result => result?,
+    }
+    
+    info!("Client shutdown complete");
+    Ok(())
+}
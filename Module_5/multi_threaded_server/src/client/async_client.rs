@@ -0,0 +1,119 @@
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, warn};
+use tokio::net::TcpStream;
+use tokio::time::{interval_at, Instant, Interval, MissedTickBehavior};
+use tokio_util::codec::Framed;
+
+use crate::client::client::ClientConfig;
+use crate::common::codec::{Frame, MessageCodec};
+use crate::common::protocol::Message;
+
+/// Tokio counterpart to `ChatSession`, for async applications talking to the chat server
+///
+/// Callers send and receive raw `Message`s, and nothing is done on their
+/// behalf beyond heartbeats. TLS is not supported.
+pub struct AsyncClient {
+    framed: Framed<TcpStream, MessageCodec>,
+    username: String,
+    /// Capabilities the server agreed to in its Hello
+    capabilities: Vec<String>,
+    /// The Welcome, held back so `recv` returns it first
+    welcome: Option<Message>,
+    heartbeat: Interval,
+}
+
+impl AsyncClient {
+    /// Connect, negotiate the protocol and join, returning once the server has welcomed us
+    pub async fn connect(config: ClientConfig) -> Result<Self, anyhow::Error> {
+        if config.tls.is_some() {
+            anyhow::bail!("TLS is not supported by the async client");
+        }
+        
+        let stream = TcpStream::connect(&config.server_addr).await?;
+        let mut framed = Framed::new(stream, MessageCodec::new(config.wire_format));
+        framed.send(Message::hello()).await?;
+        framed.send(Message::join(config.username.clone(), config.credentials.clone())).await?;
+        
+        let mut capabilities = Vec::new();
+        let welcome = loop {
+            match next_message(&mut framed).await? {
+                Some(Message::Hello { version, capabilities: agreed }) => {
+                    debug!("Server speaks protocol {} with {:?}", version, agreed);
+                    capabilities = agreed;
+                }
+                Some(welcome @ Message::Welcome { .. }) => break welcome,
+                Some(Message::Error { code, message }) => anyhow::bail!("Join rejected ({}): {}", code, message),
+                Some(other) => debug!("Ignoring {} before joining", other.kind()),
+                None => anyhow::bail!("Server closed the connection before we joined"),
+            }
+        };
+        
+        let mut heartbeat = interval_at(Instant::now() + config.heartbeat_interval, config.heartbeat_interval);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Ok(AsyncClient {
+            framed,
+            username: config.username,
+            capabilities,
+            welcome: Some(welcome),
+            heartbeat,
+        })
+    }
+    
+    /// The name we joined with
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+    
+    /// Capabilities the server agreed to in its Hello
+    pub fn capabilities(&self) -> &[String] {
+        &self.capabilities
+    }
+    
+    /// Send a message to the server
+    pub async fn send(&mut self, message: Message) -> Result<(), anyhow::Error> {
+        self.framed.send(message).await
+    }
+    
+    /// Wait for the next message, sending heartbeats while the connection is quiet
+    ///
+    /// Pongs are consumed here. Returns None once the server closes the
+    /// connection. Dropping the future loses no messages, so it can be raced
+    /// against a shutdown future with `tokio::select!`.
+    pub async fn recv(&mut self) -> Result<Option<Message>, anyhow::Error> {
+        if let Some(welcome) = self.welcome.take() {
+            return Ok(Some(welcome));
+        }
+        
+        loop {
+            tokio::select! {
+                frame = self.framed.next() => match frame.transpose()? {
+                    Some(Frame::Message(Message::Pong, _)) => debug!("Heartbeat acknowledged"),
+                    Some(Frame::Message(message, _)) => return Ok(Some(message)),
+                    Some(Frame::Malformed(e)) => warn!("Skipping malformed message: {}", e),
+                    None => return Ok(None),
+                },
+                _ = self.heartbeat.tick() => self.framed.send(Message::Ping).await?,
+            }
+        }
+    }
+    
+    /// Tell the server we are leaving and close the connection
+    pub async fn leave(mut self) -> Result<(), anyhow::Error> {
+        let leave = Message::Leave {
+            username: self.username.clone(),
+        };
+        self.framed.send(leave).await?;
+        self.framed.close().await
+    }
+}
+
+/// The next message from the server, skipping any that cannot be decoded
+async fn next_message(framed: &mut Framed<TcpStream, MessageCodec>) -> Result<Option<Message>, anyhow::Error> {
+    loop {
+        match framed.next().await.transpose()? {
+            Some(Frame::Message(message, _)) => return Ok(Some(message)),
+            Some(Frame::Malformed(e)) => warn!("Skipping malformed message: {}", e),
+            None => return Ok(None),
+        }
+    }
+}
@@ -1,283 +1,360 @@
-use std::net::TcpStream;
-use std::io::{Read, Write, stdin, stdout};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
+use std::io::{Write, stdin, stdout};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
-use log::{info, warn, error, debug};
-use crossbeam_channel::{bounded, select, tick, Receiver};
+use log::{info, error};
 
-use crate::common::protocol::{Message, FramedMessage};
+use crate::client::reconnect::ReconnectConfig;
+use crate::client::script;
+use crate::client::session::{ChatSession, Event};
+use crate::client::tui;
+use crate::common::codec::WireFormat;
+use crate::common::protocol::{BanTarget, DeliveryStatus, Message, capabilities, current_timestamp};
+use crate::common::tls::ClientTlsConfig;
+
+/// Every /command `Client::handle_command` understands
+pub(crate) const COMMANDS: &[&str] = &[
+    "/quit", "/exit", "/help", "/users", "/join", "/create", "/part", "/rooms",
+    "/msg", "/send", "/accept", "/kick", "/ban", "/mute",
+];
 
 /// Client configuration
+#[derive(Clone)]
 pub struct ClientConfig {
     pub server_addr: String,
     pub username: String,
     pub heartbeat_interval: Duration,
+    /// Token or password sent with Join, if the server requires one
+    pub credentials: Option<String>,
+    /// How to verify an encrypted connection; None connects over plain TCP
+    pub tls: Option<ClientTlsConfig>,
+    /// Codec the server's listener speaks
+    pub wire_format: WireFormat,
+    /// Where accepted files are saved
+    pub download_dir: PathBuf,
+    /// How to retry after the connection drops; None exits instead
+    pub reconnect: Option<ReconnectConfig>,
+    /// How the client is driven: by a person at a terminal, a pipe or a script
+    pub interface: Interface,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            server_addr: "127.0.0.1:8080".to_string(),
+            username: String::new(),
+            heartbeat_interval: Duration::from_secs(30),
+            credentials: None,
+            tls: None,
+            wire_format: WireFormat::Bincode,
+            download_dir: PathBuf::from("downloads"),
+            reconnect: Some(ReconnectConfig::default()),
+            interface: Interface::Full,
+        }
+    }
+}
+
+/// How the client takes input and shows what happens
+#[derive(Debug, Clone, PartialEq)]
+pub enum Interface {
+    /// Full-screen terminal interface
+    Full,
+    /// Line by line on the terminal
+    Plain,
+    /// Lines from stdin are typed in; events go to stdout as JSON lines
+    Pipe,
+    /// Commands, waits and expectations are read from a file
+    Script(PathBuf),
 }
 
-/// Chat client
+/// Chat client driven from the terminal, a pipe or a script
+///
+/// Takes commands as a user would type them and shows the events of its
+/// `ChatSession` in the way its `Interface` calls for.
 pub struct Client {
-    config: ClientConfig,
-    stream: TcpStream,
-    running: Arc<AtomicBool>,
+    session: ChatSession,
+    interface: Interface,
 }
 
 impl Client {
-    /// Connect to server and create a new client
+    /// Connect to the server and join
     pub fn connect(config: ClientConfig) -> Result<Self, anyhow::Error> {
-        let stream = TcpStream::connect(&config.server_addr)?;
-        stream.set_nodelay(true)?;
-        
         Ok(Client {
-            config,
-            stream,
-            running: Arc::new(AtomicBool::new(true)),
+            interface: config.interface.clone(),
+            session: ChatSession::connect(config)?,
         })
     }
     
-    /// Run the client (blocking)
-    pub fn run(&mut self) -> Result<(), anyhow::Error> {
-        info!("Connected to {}", self.config.server_addr);
-        
-        // Send join message
-        let join_msg = Message::Join {
-            username: self.config.username.clone(),
-        };
-        self.send_message(&join_msg)?;
-        
-        // Create channels for coordination
-        let (shutdown_tx, shutdown_rx) = bounded(0);
-        let running = self.running.clone();
-        
-        // Spawn receiver thread
-        let mut reader_stream = self.stream.try_clone()?;
-        let reader_handle = thread::spawn(move || {
-            Self::receiver_loop(&mut reader_stream, running, shutdown_tx);
-        });
-        
-        // Spawn heartbeat thread
-        let heartbeat_interval = self.config.heartbeat_interval;
-        let heartbeat_stream = self.stream.try_clone()?;
-        let heartbeat_running = self.running.clone();
-        let heartbeat_handle = thread::spawn(move || {
-            Self::heartbeat_loop(heartbeat_stream, heartbeat_interval, heartbeat_running);
-        });
-        
-        // Main thread handles user input
-        let result = self.input_loop(&shutdown_rx);
-        
-        // Shutdown
-        self.running.store(false, Ordering::SeqCst);
-        let _ = reader_handle.join();
-        let _ = heartbeat_handle.join();
-        
-        result
+    /// The session this client drives
+    pub fn session(&self) -> &ChatSession {
+        &self.session
     }
     
-    /// Send a message to the server
-    fn send_message(&mut self, message: &Message) -> Result<(), anyhow::Error> {
-        let bytes = FramedMessage::encode(message)?;
-        self.stream.write_all(&bytes)?;
-        self.stream.flush()?;
-        Ok(())
+    /// Run the client until the user quits or the session ends (blocking)
+    pub fn run(mut self) -> Result<(), anyhow::Error> {
+        let result = match self.interface.clone() {
+            Interface::Full => tui::run(&mut self),
+            Interface::Plain => self.input_loop(),
+            Interface::Pipe => script::pipe(&mut self),
+            Interface::Script(path) => script::run(&mut self, &path),
+        };
+        self.session.close();
+        result
     }
     
-    /// Receiver thread function
-    fn receiver_loop(
-        stream: &mut TcpStream,
-        running: Arc<AtomicBool>,
-        shutdown_tx: crossbeam_channel::Sender<()>,
-    ) {
-        let mut buffer = Vec::new();
-        let mut read_buf = [0u8; 1024];
-        
-        while running.load(Ordering::SeqCst) {
-            match stream.read(&mut read_buf) {
-                Ok(0) => {
-                    info!("Server closed connection");
-                    break;
-                }
-                Ok(n) => {
-                    buffer.extend_from_slice(&read_buf[..n]);
-                    
-                    while let Ok(Some(message)) = FramedMessage::decode(&mut buffer) {
-                        Self::handle_incoming_message(message);
-                    }
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    thread::sleep(Duration::from_millis(100));
-                }
-                Err(e) => {
-                    error!("Receive error: {}", e);
-                    break;
-                }
-            }
-        }
+    /// Handle user input in line mode, printing events as they arrive
+    fn input_loop(&mut self) -> Result<(), anyhow::Error> {
+        let events = self.session.events().clone();
+        let mut input = String::new();
+        // Whether the cursor sits after a prompt, so events start on a new line
+        let mut at_prompt = false;
         
-        let _ = shutdown_tx.send(());
-    }
-    
-    /// Heartbeat thread function
-    fn heartbeat_loop(mut stream: TcpStream, interval: Duration, running: Arc<AtomicBool>) {
-        let ticker = tick(interval);
+        println!("Connected as {}. Type /help for commands.", self.session.username());
         
-        while running.load(Ordering::SeqCst) {
-            select! {
-                recv(ticker) -> _ => {
-                    let ping = Message::Ping;
-                    if let Ok(bytes) = FramedMessage::encode(&ping) {
-                        if stream.write_all(&bytes).is_err() {
-                            break;
-                        }
-                        let _ = stream.flush();
+        loop {
+            let mut shown = false;
+            for event in events.try_iter() {
+                if event == Event::Closed {
+                    info!("Shutting down...");
+                    return Ok(());
+                }
+                if let Some(text) = describe(&event) {
+                    if at_prompt && !shown {
+                        println!();
                     }
+                    println!("{}", text);
+                    shown = true;
                 }
-                default(Duration::from_millis(100)) => {}
             }
-        }
-    }
-    
-    /// Handle incoming messages
-    fn handle_incoming_message(message: Message) {
-        match message {
-            Message::Welcome { message, connected_clients } => {
-                println!("\n*** {} ***", message);
-                println!("Connected users: {}", connected_clients.join(", "));
+            if shown || !at_prompt {
+                print!("> ");
+                let _ = stdout().flush();
+                at_prompt = true;
             }
             
-            Message::Broadcast { from, content, timestamp } => {
-                println!("\n[{}] {}: {}", format_timestamp(timestamp), from, content);
+            // Check for input
+            if !wait_for_input(Duration::from_millis(50)) {
+                continue;
             }
-            
-            Message::Private { from, content, timestamp, .. } => {
-                println!("\n[PM from {}] {}: {}", from, format_timestamp(timestamp), content);
-            }
-            
-            Message::UserJoined { username } => {
-                println!("\n*** {} joined the chat ***", username);
-            }
-            
-            Message::UserLeft { username } => {
-                println!("\n*** {} left the chat ***", username);
-            }
-            
-            Message::Pong => {
-                debug!("Heartbeat response received");
-            }
-            
-            Message::Error { code, message } => {
-                println!("\n*** Error {}: {} ***", code, message);
-            }
-            
-            _ => {
-                debug!("Received: {:?}", message);
-            }
-        }
-        
-        // Print prompt
-        print!("> ");
-        let _ = stdout().flush();
-    }
-    
-    /// Handle user input
-    fn input_loop(&mut self, shutdown_rx: &Receiver<()>) -> Result<(), anyhow::Error> {
-        let mut input = String::new();
-        
-        println!("Connected as {}. Type /help for commands.", self.config.username);
-        print!("> ");
-        let _ = stdout().flush();
-        
-        loop {
-            select! {
-                recv(shutdown_rx) -> _ => {
-                    info!("Shutting down...");
-                    break;
-                }
-                default(Duration::from_millis(100)) => {
-                    // Check for input
-                    if wait_for_input(Duration::from_millis(10)) {
-                        input.clear();
-                        match stdin().read_line(&mut input) {
-                            Ok(0) => break,
-                            Ok(_) => {
-                                let cmd = input.trim();
-                                if cmd.is_empty() {
-                                    print!("> ");
-                                    let _ = stdout().flush();
-                                    continue;
-                                }
-                                
-                                if !self.handle_command(cmd)? {
-                                    break;
-                                }
-                                
-                                print!("> ");
-                                let _ = stdout().flush();
-                            }
-                            Err(e) => {
-                                error!("Input error: {}", e);
-                                break;
-                            }
-                        }
+            input.clear();
+            match stdin().read_line(&mut input) {
+                Ok(0) => break,
+                Ok(_) => {
+                    at_prompt = false;
+                    let cmd = input.trim();
+                    if cmd.is_empty() {
+                        continue;
+                    }
+                    if !self.handle_command(cmd, &mut stdout())? {
+                        break;
                     }
                 }
+                Err(e) => {
+                    error!("Input error: {}", e);
+                    break;
+                }
             }
         }
         
         Ok(())
     }
     
-    /// Handle user commands
-    fn handle_command(&mut self, input: &str) -> Result<bool, anyhow::Error> {
+    /// Carry out a line typed by the user: a /command, or chat for the current room
+    ///
+    /// Anything to tell the user is written to `out`. Returns false once the
+    /// user asks to quit.
+    pub fn handle_command(&mut self, input: &str, out: &mut impl Write) -> Result<bool, anyhow::Error> {
+        let session = &self.session;
         if input.starts_with('/') {
             let parts: Vec<&str> = input.split_whitespace().collect();
             match parts[0] {
+                "/join" | "/create" | "/part" | "/rooms" if !session.supports(capabilities::ROOMS) => {
+                    writeln!(out, "This server does not support rooms")?;
+                }
+                "/send" | "/accept" if !session.supports(capabilities::FILES) => {
+                    writeln!(out, "This server does not support file transfers")?;
+                }
                 "/quit" | "/exit" => {
-                    self.send_message(&Message::Leave {
-                        username: self.config.username.clone(),
-                    })?;
                     return Ok(false);
                 }
                 "/help" => {
-                    println!("Commands:");
-                    println!("  /quit or /exit - Disconnect");
-                    println!("  /msg <user> <message> - Send private message");
-                    println!("  /users - List connected users");
-                    println!("  /help - Show this help");
+                    writeln!(out, "Commands:")?;
+                    writeln!(out, "  /quit or /exit - Disconnect")?;
+                    writeln!(out, "  /msg <user> <message> - Send private message")?;
+                    writeln!(out, "  /join <#room> - Join a room and talk there")?;
+                    writeln!(out, "  /create <#room> - Create a new room and join it")?;
+                    writeln!(out, "  /part [#room] - Leave a room (default: the current one)")?;
+                    writeln!(out, "  /rooms - List rooms")?;
+                    writeln!(out, "  /users - List connected users")?;
+                    writeln!(out, "  /send <user> <path> - Offer a file to a user")?;
+                    writeln!(out, "  /accept [id] - Download an offered file (default: the latest offer)")?;
+                    writeln!(out, "  /kick <user> [reason] - Disconnect a user (operators only)")?;
+                    writeln!(out, "  /ban <user|ip> [duration] [reason] - Ban a user or address, e.g. 10m (operators only)")?;
+                    writeln!(out, "  /mute <user> <duration> - Stop a user chatting, e.g. 30s (operators only)")?;
+                    writeln!(out, "  /help - Show this help")?;
                 }
                 "/users" => {
-                    // Server doesn't have a direct command for this yet
-                    // Could be implemented as a special message
+                    session.send(&Message::ListUsers)?;
+                }
+                "/join" if parts.len() == 2 => {
+                    session.join(parts[1])?;
+                }
+                "/create" if parts.len() == 2 => {
+                    session.create_room(parts[1])?;
+                }
+                "/part" => {
+                    let room = match parts.get(1) {
+                        Some(room) => Some(room.to_string()),
+                        None => session.current_room(),
+                    };
+                    match room {
+                        Some(room) => session.part(&room)?,
+                        None => writeln!(out, "You are not in any room")?,
+                    }
+                }
+                "/rooms" => {
+                    session.send(&Message::ListRooms)?;
                 }
                 "/msg" if parts.len() >= 3 => {
-                    let to = parts[1];
-                    let content = parts[2..].join(" ");
-                    self.send_message(&Message::private(
-                        self.config.username.clone(),
-                        to.to_string(),
-                        content,
-                    ))?;
+                    session.send_private(parts[1], &parts[2..].join(" "))?;
+                }
+                "/send" if parts.len() >= 3 => {
+                    let path = parts[2..].join(" ");
+                    match session.offer_file(parts[1], Path::new(&path)) {
+                        Ok(()) => writeln!(out, "Offered {} to {}", path, parts[1])?,
+                        Err(e) => writeln!(out, "Cannot send {}: {}", path, e)?,
+                    }
+                }
+                "/accept" if parts.len() <= 2 => {
+                    let transfer_id = match parts.get(1).map(|id| id.parse()) {
+                        Some(Ok(id)) => Some(id),
+                        Some(Err(_)) => {
+                            writeln!(out, "Invalid transfer ID {}", parts[1])?;
+                            return Ok(true);
+                        }
+                        None => None,
+                    };
+                    if let Err(e) = session.accept_file(transfer_id) {
+                        writeln!(out, "Cannot accept: {}", e)?;
+                    }
+                }
+                "/kick" if parts.len() >= 2 => {
+                    session.send(&Message::Kick {
+                        username: parts[1].to_string(),
+                        reason: parts[2..].join(" "),
+                    })?;
                 }
+                "/ban" if parts.len() >= 2 => {
+                    let target = match parts[1].parse() {
+                        Ok(ip) => BanTarget::Ip(ip),
+                        Err(_) => BanTarget::Username(parts[1].to_string()),
+                    };
+                    // The duration is optional; without one the ban is permanent
+                    let duration_secs = parts.get(2).and_then(|d| parse_duration(d));
+                    let reason_start = if duration_secs.is_some() { 3 } else { 2 };
+                    session.send(&Message::Ban {
+                        target,
+                        duration_secs,
+                        reason: parts.get(reason_start..).unwrap_or_default().join(" "),
+                    })?;
+                }
+                "/mute" if parts.len() == 3 => match parse_duration(parts[2]) {
+                    Some(duration_secs) => session.send(&Message::Mute {
+                        username: parts[1].to_string(),
+                        duration_secs,
+                    })?,
+                    None => writeln!(out, "Invalid duration {}; use e.g. 30s, 10m, 2h or 1d", parts[2])?,
+                },
                 _ => {
-                    println!("Unknown command: {}", parts[0]);
+                    writeln!(out, "Unknown command: {}", parts[0])?;
                 }
             }
         } else if !input.is_empty() {
-            self.send_message(&Message::chat(
-                self.config.username.clone(),
-                input.to_string(),
-            ))?;
+            match session.current_room() {
+                Some(_) => {
+                    session.send_chat(input)?;
+                }
+                None => writeln!(out, "You are not in any room; use /join <#room>")?,
+            }
         }
         
         Ok(true)
     }
 }
 
+/// What to print for an event, if anything
+pub(crate) fn describe(event: &Event) -> Option<String> {
+    let text = match event {
+        Event::Welcome { message, users } => {
+            format!("*** {} ***\nConnected users: {}", message, users.join(", "))
+        }
+        Event::Chat(message) => format!(
+            "[{}] {} {}: {}",
+            format_timestamp(message.timestamp), message.room, message.from, message.content
+        ),
+        Event::History { room, messages } => {
+            let mut text = format!("--- Recent messages in {} ---\n", room);
+            for message in messages {
+                text += &format!("[{}] {}: {}\n", format_timestamp(message.timestamp), message.from, message.content);
+            }
+            text + "--- End of history ---"
+        }
+        Event::Private { from, content, timestamp } => {
+            format!("[PM from {}] {}: {}", from, format_timestamp(*timestamp), content)
+        }
+        Event::Receipt { status: DeliveryStatus::Queued, to: Some(to), .. } => {
+            format!("({} is offline and will get it when they return)", to)
+        }
+        Event::Receipt { to: Some(to), .. } => format!("(delivered to {})", to),
+        // Errors are reported on their own, and sent is the usual case
+        Event::Receipt { to: None, .. } => return None,
+        Event::UserJoined { username, room } => format!("*** {} joined {} ***", username, room),
+        Event::UserLeft { username, room } => format!("*** {} left {} ***", username, room),
+        Event::RoomJoined { room, members } => {
+            format!("*** Now talking in {} ({}) ***", room, members.join(", "))
+        }
+        Event::RoomLeft { room, current: Some(current) } => {
+            format!("*** Left {}, now talking in {} ***", room, current)
+        }
+        Event::RoomLeft { room, current: None } => {
+            format!("*** Left {}; /join a room to keep chatting ***", room)
+        }
+        Event::Users(users) => {
+            let width = users.iter().map(|u| u.username.len()).max().unwrap_or(0).max(4);
+            let mut text = format!("{:<width$}  {:>10}  {:>10}", "USER", "CONNECTED", "IDLE", width = width);
+            for user in users {
+                let connected = current_timestamp().saturating_sub(user.connected_at);
+                text += &format!(
+                    "\n{:<width$}  {:>10}  {:>10}",
+                    user.username,
+                    format_duration(connected),
+                    format_duration(user.idle_secs),
+                    width = width
+                );
+            }
+            text
+        }
+        Event::Rooms(rooms) => {
+            let mut text = "Rooms:".to_string();
+            for room in rooms {
+                text += &format!("\n  {} ({} members)", room.name, room.members);
+            }
+            text
+        }
+        Event::Transfer(note) | Event::Notice(note) => format!("*** {} ***", note),
+        Event::Error { code, message } => format!("*** Error {}: {} ***", code, message),
+        Event::Shutdown { reason } => format!("*** Server shutting down: {} ***", reason),
+        Event::Held => "(not connected; it will be sent once reconnected)".to_string(),
+        Event::Disconnected => "*** Connection lost ***".to_string(),
+        Event::Reconnecting { attempt } => format!("*** reconnecting (attempt {})… ***", attempt),
+        Event::GaveUp { attempts } => format!("*** Could not reconnect after {} attempts ***", attempts),
+        Event::Pong { .. } | Event::Closed => return None,
+    };
+    Some(text)
+}
+
 /// Helper to check if input is available
 fn wait_for_input(timeout: Duration) -> bool {
     use std::os::fd::AsRawFd;
-    use std::os::unix::io::RawFd;
     
     let stdin_fd = std::io::stdin().as_raw_fd();
     let mut fds = unsafe {
@@ -311,41 +388,27 @@ fn format_timestamp(timestamp: u64) -> String {
     format!("{}", timestamp)
 }
 
+/// Format a number of seconds as a short duration like "1h02m" or "45s"
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60),
+    }
+}
 
-use std::time::Duration;
-use log::info;
-use env_logger::Env;
-
-use multi_threaded_server::client::client::{Client, ClientConfig};
-
-fn main() -> Result<(), anyhow::Error> {
-    // Initialize logging
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
-    
-    info!("Starting chat client...");
-    
-    // Parse command line arguments
-    let args: Vec<String> = std::env::args().collect();
-    
-    let server_addr = args.get(1).map(|s| s.as_str()).unwrap_or("127.0.0.1:8080");
-    let username = args.get(2).map(|s| s.to_string()).unwrap_or_else(|| {
-        println!("Enter username: ");
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
-        input.trim().to_string()
-    });
-    
-    // Create client config
-    let config = ClientConfig {
-        server_addr: server_addr.to_string(),
-        username,
-        heartbeat_interval: Duration::from_secs(30),
+/// Parse a duration like "45", "30s", "10m", "2h" or "1d" into seconds
+pub(crate) fn parse_duration(input: &str) -> Option<u64> {
+    let (number, unit) = match input.find(|c: char| !c.is_ascii_digit()) {
+        Some(split) => input.split_at(split),
+        None => (input, "s"),
     };
-    
-    // Connect and run
-    let mut client = Client::connect(config)?;
-    client.run()?;
-    
-    info!("Client shutdown complete");
-    Ok(())
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
 }
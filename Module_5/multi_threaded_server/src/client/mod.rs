@@ -1 +1,11 @@
-pub mod client;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod client;
+pub mod transfers;
+pub mod outbox;
+pub mod reconnect;
+pub mod settings;
+pub mod session;
+pub mod tui;
+pub mod script;
+#[cfg(feature = "async")]
+pub mod async_client;
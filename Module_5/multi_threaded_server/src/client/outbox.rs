@@ -0,0 +1,79 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::common::protocol::{DeliveryStatus, Message};
+
+/// Unconfirmed messages kept for resending before the oldest are given up on
+pub const MAX_UNCONFIRMED: usize = 256;
+
+/// Chat and private messages the server has not confirmed yet
+///
+/// Each message is tagged with a client ID so the server can tell a resend
+/// from a new message. IDs start at a random point so a restarted client does
+/// not reuse ones the server still remembers.
+pub struct Outbox {
+    next_id: u64,
+    /// Messages waiting for a receipt, by client ID in the order they were sent
+    unconfirmed: BTreeMap<u64, Message>,
+    /// Recipients of private messages that have not been acknowledged
+    undelivered: HashMap<u64, String>,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Outbox {
+            // Half the range leaves plenty of room to count up without wrapping
+            next_id: (rand::random::<u64>() >> 1).max(1),
+            unconfirmed: BTreeMap::new(),
+            undelivered: HashMap::new(),
+        }
+    }
+    
+    /// Tag a Chat or Private with the next client ID and hold on to it until confirmed
+    ///
+    /// Other messages are returned unchanged.
+    pub fn track(&mut self, mut message: Message) -> Message {
+        let id = self.next_id;
+        match &mut message {
+            Message::Chat { client_id, .. } => *client_id = id,
+            Message::Private { client_id, to, .. } => {
+                *client_id = id;
+                self.undelivered.insert(id, to.clone());
+            }
+            _ => return message,
+        }
+        self.next_id += 1;
+        self.unconfirmed.insert(id, message.clone());
+        // A server that never answers must not make every reconnect resend more
+        while self.unconfirmed.len() > MAX_UNCONFIRMED {
+            if let Some((oldest, _)) = self.unconfirmed.pop_first() {
+                self.undelivered.remove(&oldest);
+            }
+        }
+        message
+    }
+    
+    /// Messages to send again after reconnecting, oldest first
+    pub fn unconfirmed(&self) -> Vec<Message> {
+        self.unconfirmed.values().cloned().collect()
+    }
+    
+    /// Apply a receipt, returning the recipient if a private message was just queued or delivered
+    pub fn confirm(&mut self, client_id: u64, status: DeliveryStatus) -> Option<String> {
+        self.unconfirmed.remove(&client_id);
+        match status {
+            DeliveryStatus::Sent => None,
+            DeliveryStatus::Queued => self.undelivered.get(&client_id).cloned(),
+            DeliveryStatus::Delivered => self.undelivered.remove(&client_id),
+            DeliveryStatus::Failed => {
+                self.undelivered.remove(&client_id);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::time::Duration;
+use rand::Rng;
+
+/// How the client retries after losing its connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectConfig {
+    /// Delay before the first attempt, doubled after each failure
+    pub initial_delay: Duration,
+    /// Longest delay between attempts
+    pub max_delay: Duration,
+    /// Attempts before giving up; None keeps trying
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+/// Exponential backoff with jitter, so clients dropped together do not all retry at once
+pub struct Backoff {
+    config: ReconnectConfig,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(config: ReconnectConfig) -> Self {
+        Backoff { config, attempt: 0 }
+    }
+    
+    /// Attempts made since the last reset
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+    
+    /// How long to wait before the next attempt, or None once attempts run out
+    ///
+    /// The wait is somewhere between half and all of the current exponential delay.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.config.max_attempts.is_some_and(|max| self.attempt >= max) {
+            return None;
+        }
+        let ceiling = self.config.initial_delay
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.config.max_delay);
+        self.attempt += 1;
+        Some(ceiling / 2 + ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=0.5)))
+    }
+    
+    /// Start over after a successful reconnect
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
@@ -0,0 +1,340 @@
+use std::fs;
+use std::io::{self, BufRead, Write, stdin, stderr, stdout};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{select, unbounded, Receiver};
+use thiserror::Error;
+
+use crate::client::client::{describe, parse_duration, Client, COMMANDS};
+use crate::client::session::Event;
+
+/// Exit codes for `--script` and `--pipe` runs
+pub mod exit_codes {
+    pub const SUCCESS: i32 = 0;
+    /// An expectation was not met in time
+    pub const UNMET: i32 = 1;
+    /// The script or command line has a mistake
+    pub const USAGE: i32 = 2;
+    /// Could not connect, or the connection ended before the run did
+    pub const DISCONNECTED: i32 = 3;
+}
+
+/// How long an expectation waits unless the script says otherwise
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a pipe waits for replies to its last lines before leaving
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why a scripted or piped run failed
+#[derive(Debug, Error)]
+pub enum ScriptError {
+    #[error("Failed to read {}: {error}", path.display())]
+    Read { path: PathBuf, error: io::Error },
+    
+    #[error("Line {line}: {message}")]
+    Parse { line: usize, message: String },
+    
+    #[error("Line {line}: timed out waiting for `{expectation}`")]
+    Unmet { line: usize, expectation: String },
+    
+    #[error("Connection closed before the run finished")]
+    Closed,
+}
+
+impl ScriptError {
+    /// Exit code for the process to report
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ScriptError::Read { .. } | ScriptError::Parse { .. } => exit_codes::USAGE,
+            ScriptError::Unmet { .. } => exit_codes::UNMET,
+            ScriptError::Closed => exit_codes::DISCONNECTED,
+        }
+    }
+}
+
+/// Exit code for a failed scripted or piped run
+///
+/// Failures other than a `ScriptError` come from connecting or sending.
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    error.downcast_ref::<ScriptError>().map_or(exit_codes::DISCONNECTED, ScriptError::exit_code)
+}
+
+/// Kinds of event an expectation can wait for
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Broadcast,
+    Private,
+    Joined,
+    Left,
+    Notice,
+    Error,
+}
+
+/// An event a script waits for, such as "broadcast from bob containing hi"
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expectation {
+    kind: Kind,
+    /// Who sent the message, or who joined or left
+    from: Option<String>,
+    room: Option<String>,
+    /// Text the message, notice or error must contain
+    containing: Option<String>,
+}
+
+impl Expectation {
+    /// Parse the part after `expect`: `<kind> [from <user>] [in <room>] [containing <text>]`
+    ///
+    /// Kinds are broadcast, private, joined, left, notice and error.
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let mut rest = input.trim();
+        let mut word = || {
+            let (word, tail) = rest.split_once(' ').unwrap_or((rest, ""));
+            rest = tail.trim_start();
+            word
+        };
+        
+        let kind = match word() {
+            "broadcast" => Kind::Broadcast,
+            "private" => Kind::Private,
+            "joined" => Kind::Joined,
+            "left" => Kind::Left,
+            "notice" => Kind::Notice,
+            "error" => Kind::Error,
+            other => return Err(format!("Unknown event {:?}; expected broadcast, private, joined, left, notice or error", other)),
+        };
+        let mut expectation = Expectation { kind, from: None, room: None, containing: None };
+        loop {
+            match word() {
+                "" => return Ok(expectation),
+                "from" => expectation.from = Some(word().to_string()),
+                "in" => expectation.room = Some(word().to_string()),
+                // The text runs to the end of the line
+                "containing" => {
+                    expectation.containing = Some(rest.to_string());
+                    return Ok(expectation);
+                }
+                other => return Err(format!("Unexpected {:?}; expected from, in or containing", other)),
+            }
+        }
+    }
+    
+    /// Check whether an event is the one expected
+    pub fn matches(&self, event: &Event) -> bool {
+        let (from, room, text) = match (self.kind, event) {
+            (Kind::Broadcast, Event::Chat(message)) => {
+                (Some(&message.from), Some(&message.room), message.content.clone())
+            }
+            (Kind::Private, Event::Private { from, content, .. }) => (Some(from), None, content.clone()),
+            (Kind::Joined, Event::UserJoined { username, room })
+            | (Kind::Left, Event::UserLeft { username, room }) => (Some(username), Some(room), String::new()),
+            (Kind::Notice, Event::Notice(message)) => (None, None, message.clone()),
+            (Kind::Error, Event::Error { code, message }) => (None, None, format!("{} {}", code, message)),
+            _ => return false,
+        };
+        self.from.as_ref().is_none_or(|expected| from.is_some_and(|from| from.eq_ignore_ascii_case(expected)))
+            && self.room.as_ref().is_none_or(|expected| room == Some(expected))
+            && self.containing.as_ref().is_none_or(|expected| text.contains(expected.as_str()))
+    }
+}
+
+/// One line of a script
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// Typed as in the interactive client: chat, or a /command
+    Input(String),
+    /// Pause, still collecting events
+    Wait(Duration),
+    /// How long the expectations after this one wait
+    Timeout(Duration),
+    /// Wait for a matching event; the text is kept for error messages
+    Expect(Expectation, String),
+}
+
+/// Parse a script into its steps, each with its line number
+///
+/// Blank lines and lines starting with `#` are skipped. `wait <duration>`,
+/// `timeout <duration>` and `expect ...` are directives, `say <text>` chats,
+/// and /commands are typed in as in the interactive client. Anything else is
+/// a mistake, so a mistyped directive is not sent as chat.
+pub fn parse(script: &str) -> Result<Vec<(usize, Step)>, ScriptError> {
+    let mut steps = Vec::new();
+    for (index, line) in script.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: String| ScriptError::Parse { line: line_number, message };
+        
+        let (directive, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let step = match directive {
+            "wait" | "timeout" => {
+                let duration = parse_wait(rest.trim())
+                    .ok_or_else(|| error(format!("Invalid duration {:?}; use e.g. 500ms, 2s or 1m", rest)))?;
+                match directive {
+                    "wait" => Step::Wait(duration),
+                    _ => Step::Timeout(duration),
+                }
+            }
+            "expect" => Step::Expect(Expectation::parse(rest).map_err(error)?, rest.trim().to_string()),
+            "say" => Step::Input(rest.trim().to_string()),
+            command if command.starts_with('/') => {
+                if !COMMANDS.contains(&command) {
+                    return Err(error(format!("Unknown command {}; see /help", command)));
+                }
+                Step::Input(line.to_string())
+            }
+            other => {
+                return Err(error(format!(
+                    "Unknown directive {:?}; expected wait, timeout, expect, say or a /command",
+                    other
+                )));
+            }
+        };
+        steps.push((line_number, step));
+    }
+    Ok(steps)
+}
+
+/// Parse a duration like "500ms", or anything `parse_duration` accepts
+fn parse_wait(input: &str) -> Option<Duration> {
+    match input.strip_suffix("ms") {
+        Some(millis) => millis.parse().ok().map(Duration::from_millis),
+        None => parse_duration(input).map(Duration::from_secs),
+    }
+}
+
+/// Run a script file, returning once every step is done or one fails
+///
+/// Events are printed as they arrive. Each expectation is met by the first
+/// matching event not already used by an earlier one, even if it arrived
+/// before the expectation was reached.
+pub fn run(client: &mut Client, path: &Path) -> Result<(), anyhow::Error> {
+    let contents = fs::read_to_string(path)
+        .map_err(|error| ScriptError::Read { path: path.to_path_buf(), error })?;
+    let steps = parse(&contents)?;
+    let events = client.session().events().clone();
+    let mut unused = Vec::new();
+    let mut timeout = DEFAULT_TIMEOUT;
+    
+    for (line, step) in steps {
+        match step {
+            Step::Input(input) => {
+                if !type_in(client, &input)? {
+                    break;
+                }
+            }
+            Step::Wait(duration) => {
+                let deadline = Instant::now() + duration;
+                while let Some(event) = next_event(&events, deadline)? {
+                    unused.push(event);
+                }
+            }
+            Step::Timeout(duration) => timeout = duration,
+            Step::Expect(expectation, text) => {
+                let deadline = Instant::now() + timeout;
+                loop {
+                    if let Some(index) = unused.iter().position(|event| expectation.matches(event)) {
+                        unused.remove(index);
+                        break;
+                    }
+                    match next_event(&events, deadline)? {
+                        Some(event) => unused.push(event),
+                        None => return Err(ScriptError::Unmet { line, expectation: text }.into()),
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The next event before the deadline, printed as it is taken
+fn next_event(events: &Receiver<Event>, deadline: Instant) -> Result<Option<Event>, ScriptError> {
+    match events.recv_deadline(deadline) {
+        Ok(Event::Closed) => Err(ScriptError::Closed),
+        Ok(event) => {
+            if let Some(text) = describe(&event) {
+                println!("{}", text);
+            }
+            Ok(Some(event))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Type in a line as the user would, sending what it prints to stderr
+///
+/// Returns false once the line asks to quit.
+fn type_in(client: &mut Client, input: &str) -> Result<bool, anyhow::Error> {
+    let mut output = Vec::new();
+    let keep_going = client.handle_command(input, &mut output)?;
+    stderr().write_all(&output)?;
+    Ok(keep_going)
+}
+
+/// Type in lines from stdin and write events to stdout as JSON, one per line
+///
+/// Once stdin ends, waits for the server to answer what was sent before
+/// leaving.
+pub fn pipe(client: &mut Client) -> Result<(), anyhow::Error> {
+    let events = client.session().events().clone();
+    let (lines_tx, lines) = unbounded();
+    thread::spawn(move || {
+        for line in stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if lines_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    
+    loop {
+        select! {
+            recv(events) -> event => match event {
+                Ok(event) => print_json(&event)?,
+                Err(_) => return Err(ScriptError::Closed.into()),
+            },
+            recv(lines) -> line => match line {
+                Ok(line) if line.trim().is_empty() => {}
+                Ok(line) => {
+                    if !type_in(client, line.trim())? {
+                        break;
+                    }
+                }
+                // End of input
+                Err(_) => break,
+            },
+        }
+    }
+    
+    // The server answers in order, so its reply to our ping comes after
+    // everything else; a heartbeat's Pong may arrive first
+    let ping = client.session().ping()?;
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    loop {
+        match events.recv_deadline(deadline) {
+            Ok(Event::Pong { ping: answered, .. }) if answered >= ping => return Ok(()),
+            Ok(event) => print_json(&event)?,
+            Err(_) => return Ok(()),
+        }
+    }
+}
+
+/// Write an event as a line of JSON, leaving out heartbeat replies
+fn print_json(event: &Event) -> Result<(), anyhow::Error> {
+    match event {
+        Event::Pong { .. } => Ok(()),
+        Event::Closed => Err(ScriptError::Closed.into()),
+        event => {
+            let mut out = stdout().lock();
+            serde_json::to_writer(&mut out, event)?;
+            writeln!(out)?;
+            out.flush()?;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,768 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use log::{info, error, debug};
+use crossbeam_channel::{select, tick, unbounded, Receiver, Sender};
+use serde::Serialize;
+
+use crate::client::client::ClientConfig;
+use crate::client::outbox::Outbox;
+use crate::client::reconnect::Backoff;
+use crate::client::transfers::FileTransfers;
+use crate::common::codec::WireFormat;
+use crate::common::protocol::{
+    DeliveryStatus, Message, RoomInfo, UserInfo, capabilities, error_codes, DEFAULT_ROOM,
+};
+use crate::common::transport::Transport;
+
+/// A message in a room, live or replayed from history
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChatMessage {
+    /// Assigned by the server, increasing with every message
+    pub id: u64,
+    pub room: String,
+    pub from: String,
+    pub content: String,
+    pub timestamp: u64,
+}
+
+/// Something that happened on a `ChatSession`
+///
+/// Events serialize the way `Message` does, for `--pipe` output.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum Event {
+    /// The server accepted us, after connecting or reconnecting
+    Welcome { message: String, users: Vec<String> },
+    /// A message in one of our rooms
+    Chat(ChatMessage),
+    /// Recent messages in a room we just joined
+    History { room: String, messages: Vec<ChatMessage> },
+    /// A private message to us
+    Private { from: String, content: String, timestamp: u64 },
+    /// What became of a message, by the client ID `send_chat` or `send_private` returned
+    ///
+    /// `to` names the recipient when a private message was queued or delivered.
+    Receipt { client_id: u64, status: DeliveryStatus, to: Option<String> },
+    UserJoined { username: String, room: String },
+    UserLeft { username: String, room: String },
+    /// We are now in a room, and chat goes there
+    RoomJoined { room: String, members: Vec<String> },
+    /// We left a room; chat now goes to `current`, if we are in any room
+    RoomLeft { room: String, current: Option<String> },
+    /// Reply to `ListUsers`
+    Users(Vec<UserInfo>),
+    /// Reply to `ListRooms`
+    Rooms(Vec<RoomInfo>),
+    /// A file transfer started, finished or failed
+    Transfer(String),
+    /// Information from the server, such as a moderation action
+    Notice(String),
+    Error { code: u16, message: String },
+    /// The server is about to close the connection
+    Shutdown { reason: String },
+    /// The server answered heartbeat number `ping`, as returned by `ChatSession::ping`
+    Pong { ping: u64, latency: Duration },
+    /// A message was kept to send once reconnected
+    Held,
+    /// The connection dropped; the session reconnects if configured to
+    Disconnected,
+    /// About to try connecting again
+    Reconnecting { attempt: u32 },
+    /// Reconnecting failed too many times
+    GaveUp { attempts: u32 },
+    /// The session is over; no more events follow
+    Closed,
+}
+
+/// Writes whole messages to the server from any thread
+///
+/// File chunks can take several writes, so every thread sends through one
+/// lock to keep frames from interleaving. The stream is None while the
+/// session is reconnecting.
+#[derive(Clone)]
+struct MessageWriter {
+    stream: Arc<Mutex<Option<Transport>>>,
+    wire_format: WireFormat,
+}
+
+impl MessageWriter {
+    fn send(&self, message: &Message) -> Result<(), anyhow::Error> {
+        let bytes = self.wire_format.codec().encode(message)?;
+        let mut stream = self.stream.lock().unwrap();
+        let stream = stream.as_mut().ok_or_else(|| anyhow::anyhow!("Not connected"))?;
+        stream.write_all(&bytes)?;
+        stream.flush()?;
+        Ok(())
+    }
+    
+    /// Send on a new connection from now on
+    fn replace(&self, stream: Transport) {
+        *self.stream.lock().unwrap() = Some(stream);
+    }
+    
+    /// Drop the connection, which also ends the receiver thread's read
+    fn disconnect(&self) {
+        if let Some(stream) = self.stream.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// How far the session has got in picking itself back up after a reconnect
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Rejoin {
+    /// Joined, or never disconnected
+    Done,
+    /// Sent a fresh Join
+    Joining,
+    /// Sent a Resume with the server's token
+    Resuming,
+}
+
+/// What the caller's thread and the receiver thread both keep track of
+struct SharedState {
+    /// Join sent again when there is no session to resume
+    join: Message,
+    /// Rooms we are in; the last one is where chat goes
+    rooms: Mutex<Vec<String>>,
+    /// Capabilities the server agreed to in its Hello
+    capabilities: Mutex<Vec<String>>,
+    /// Files being sent or received
+    transfers: Mutex<FileTransfers>,
+    /// Sent messages the server has not confirmed yet
+    outbox: Mutex<Outbox>,
+    /// Untracked messages sent while disconnected, sent once rejoined
+    held: Mutex<Vec<Message>>,
+    /// Token from the server for resuming this session
+    token: Mutex<Option<String>>,
+    /// Highest broadcast ID received, so a resume replays only what was missed
+    last_id: AtomicU64,
+    rejoin: Mutex<Rejoin>,
+    /// Unanswered Pings by number and when they were sent, oldest first
+    ///
+    /// The server answers in order, so each Pong is for the oldest.
+    pings: Mutex<VecDeque<(u64, Instant)>>,
+    /// Number of the last Ping sent
+    last_ping: AtomicU64,
+}
+
+/// A joined chat session with no user interface of its own
+///
+/// Callers send through its methods and read what happens from `events()`;
+/// background threads answer heartbeats, acknowledge private messages,
+/// relay file chunks and reconnect after the connection drops.
+pub struct ChatSession {
+    config: ClientConfig,
+    writer: MessageWriter,
+    running: Arc<AtomicBool>,
+    state: Arc<SharedState>,
+    events: Receiver<Event>,
+    /// For events raised by the caller's own sends
+    notify: Sender<Event>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ChatSession {
+    /// Connect, negotiate the protocol and join, returning once the server has welcomed us
+    ///
+    /// The Welcome is the first event.
+    pub fn connect(config: ClientConfig) -> Result<Self, anyhow::Error> {
+        let mut stream = Transport::connect(&config.server_addr, config.tls.as_ref())?;
+        info!("Connected to {}", config.server_addr);
+        
+        let writer = MessageWriter {
+            stream: Arc::new(Mutex::new(Some(stream.try_clone()?))),
+            wire_format: config.wire_format,
+        };
+        let state = Arc::new(SharedState {
+            join: Message::join(config.username.clone(), config.credentials.clone()),
+            rooms: Mutex::new(vec![DEFAULT_ROOM.to_string()]),
+            capabilities: Mutex::new(Vec::new()),
+            transfers: Mutex::new(FileTransfers::new(&config.download_dir)),
+            outbox: Mutex::new(Outbox::new()),
+            held: Mutex::new(Vec::new()),
+            token: Mutex::new(None),
+            last_id: AtomicU64::new(0),
+            rejoin: Mutex::new(Rejoin::Done),
+            pings: Mutex::new(VecDeque::new()),
+            last_ping: AtomicU64::new(0),
+        });
+        let (notify, events) = unbounded();
+        
+        writer.send(&Message::hello())?;
+        writer.send(&state.join)?;
+        let mut buffer = Vec::new();
+        Self::await_welcome(&mut stream, &mut buffer, &writer, &state, &notify)?;
+        
+        let running = Arc::new(AtomicBool::new(true));
+        let mut threads = Vec::new();
+        
+        // Spawn receiver thread
+        let reader_config = config.clone();
+        let reader_writer = writer.clone();
+        let reader_running = running.clone();
+        let reader_state = state.clone();
+        let reader_events = notify.clone();
+        threads.push(thread::spawn(move || {
+            Self::receiver_loop(
+                stream, buffer, &reader_config, reader_writer, reader_running, reader_state, reader_events,
+            );
+        }));
+        
+        // Spawn heartbeat thread
+        let heartbeat_interval = config.heartbeat_interval;
+        let heartbeat_writer = writer.clone();
+        let heartbeat_running = running.clone();
+        let heartbeat_state = state.clone();
+        threads.push(thread::spawn(move || {
+            Self::heartbeat_loop(heartbeat_writer, heartbeat_interval, heartbeat_running, heartbeat_state);
+        }));
+        
+        Ok(ChatSession {
+            config,
+            writer,
+            running,
+            state,
+            events,
+            notify,
+            threads,
+        })
+    }
+    
+    /// Handle messages until the server welcomes us, failing if it turns us away
+    fn await_welcome(
+        stream: &mut Transport,
+        buffer: &mut Vec<u8>,
+        writer: &MessageWriter,
+        state: &SharedState,
+        events: &Sender<Event>,
+    ) -> Result<(), anyhow::Error> {
+        let mut read_buf = [0u8; 1024];
+        loop {
+            while let Some(message) = writer.wire_format.codec().decode(buffer)? {
+                if let Message::Error { code, message } = message {
+                    anyhow::bail!("Join rejected ({}): {}", code, message);
+                }
+                let welcomed = matches!(message, Message::Welcome { .. });
+                if let Some(event) = Self::handle_incoming_message(message, state, writer) {
+                    let _ = events.send(event);
+                }
+                if welcomed {
+                    return Ok(());
+                }
+            }
+            
+            let n = stream.read(&mut read_buf)?;
+            if n == 0 {
+                anyhow::bail!("Server closed the connection before we joined");
+            }
+            buffer.extend_from_slice(&read_buf[..n]);
+        }
+    }
+    
+    /// Events from the server and the connection, ending with `Event::Closed`
+    pub fn events(&self) -> &Receiver<Event> {
+        &self.events
+    }
+    
+    /// The name we joined as
+    pub fn username(&self) -> &str {
+        &self.config.username
+    }
+    
+    /// The room chat messages currently go to
+    pub fn current_room(&self) -> Option<String> {
+        self.state.rooms.lock().unwrap().last().cloned()
+    }
+    
+    /// Check whether the server agreed to a capability
+    pub fn supports(&self, capability: &str) -> bool {
+        self.state.capabilities.lock().unwrap().iter().any(|c| c == capability)
+    }
+    
+    /// Send a heartbeat now rather than waiting for the next one, to measure latency
+    ///
+    /// Returns the number the answering `Event::Pong` will carry.
+    pub fn ping(&self) -> Result<u64, anyhow::Error> {
+        Self::send_ping(&self.writer, &self.state)
+    }
+    
+    fn send_ping(writer: &MessageWriter, state: &SharedState) -> Result<u64, anyhow::Error> {
+        // Held while sending, so Pings go out in the order they are numbered
+        let mut pings = state.pings.lock().unwrap();
+        let number = state.last_ping.fetch_add(1, Ordering::SeqCst) + 1;
+        pings.push_back((number, Instant::now()));
+        writer.send(&Message::Ping)?;
+        Ok(number)
+    }
+    
+    /// Say something in the current room, returning the client ID its receipt will carry
+    pub fn send_chat(&self, content: &str) -> Result<u64, anyhow::Error> {
+        let room = self.current_room().ok_or_else(|| anyhow::anyhow!("Not in any room"))?;
+        self.send_tracked(Message::room_chat(self.config.username.clone(), room, content.to_string()))
+    }
+    
+    /// Send a private message, returning the client ID its receipts will carry
+    pub fn send_private(&self, to: &str, content: &str) -> Result<u64, anyhow::Error> {
+        self.send_tracked(Message::private(self.config.username.clone(), to.to_string(), content.to_string()))
+    }
+    
+    /// Join a room, which becomes where chat goes once the server confirms it
+    pub fn join(&self, room: &str) -> Result<(), anyhow::Error> {
+        self.send(&Message::JoinRoom { room: room.to_string() })
+    }
+    
+    /// Create a room and join it
+    pub fn create_room(&self, room: &str) -> Result<(), anyhow::Error> {
+        self.send(&Message::CreateRoom { room: room.to_string() })
+    }
+    
+    /// Leave a room
+    pub fn part(&self, room: &str) -> Result<(), anyhow::Error> {
+        self.send(&Message::PartRoom { room: room.to_string() })
+    }
+    
+    /// Offer a file to a user, who has to accept it before it is sent
+    pub fn offer_file(&self, to: &str, path: &Path) -> Result<(), anyhow::Error> {
+        let offer = self.state.transfers.lock().unwrap().offer(to, path)?;
+        self.send(&offer)
+    }
+    
+    /// Download a file offered to us; None picks the latest offer
+    pub fn accept_file(&self, transfer_id: Option<u64>) -> Result<(), anyhow::Error> {
+        let accept = self.state.transfers.lock().unwrap().accept(transfer_id)?;
+        self.send(&accept)
+    }
+    
+    /// Send any message, holding it while the session reconnects
+    pub fn send(&self, message: &Message) -> Result<(), anyhow::Error> {
+        // Locked first so a rejoin cannot flush the held messages in between
+        let mut held = self.state.held.lock().unwrap();
+        match self.writer.send(message) {
+            Err(_) if self.config.reconnect.is_some() => {
+                held.push(message.clone());
+                let _ = self.notify.send(Event::Held);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+    
+    /// Send a chat or private message, keeping it until the server confirms it
+    fn send_tracked(&self, message: Message) -> Result<u64, anyhow::Error> {
+        let message = self.state.outbox.lock().unwrap().track(message);
+        let client_id = match &message {
+            Message::Chat { client_id, .. } | Message::Private { client_id, .. } => *client_id,
+            _ => 0,
+        };
+        match self.writer.send(&message) {
+            // The outbox sends it again after rejoining
+            Err(_) if self.config.reconnect.is_some() => {
+                let _ = self.notify.send(Event::Held);
+                Ok(client_id)
+            }
+            result => result.map(|()| client_id),
+        }
+    }
+    
+    /// Leave the chat and disconnect, waiting for the background threads to finish
+    pub fn close(mut self) {
+        if let Err(e) = self.writer.send(&Message::Leave { username: self.config.username.clone() }) {
+            debug!("Could not say goodbye: {}", e);
+        }
+        self.stop();
+    }
+    
+    fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        self.writer.disconnect();
+        for handle in self.threads.drain(..) {
+            let _ = handle.join();
+        }
+    }
+    
+    /// Receiver thread function, which also reconnects when the connection drops
+    fn receiver_loop(
+        mut stream: Transport,
+        mut buffer: Vec<u8>,
+        config: &ClientConfig,
+        writer: MessageWriter,
+        running: Arc<AtomicBool>,
+        state: Arc<SharedState>,
+        events: Sender<Event>,
+    ) {
+        let mut backoff = config.reconnect.clone().map(Backoff::new);
+        
+        loop {
+            let refused = Self::read_until_closed(&mut stream, buffer, &writer, &running, &state, &events);
+            writer.disconnect();
+            if !running.load(Ordering::SeqCst) || refused {
+                break;
+            }
+            let Some(backoff) = backoff.as_mut() else {
+                break;
+            };
+            
+            // Only a connection that got as far as rejoining counts as a success
+            if *state.rejoin.lock().unwrap() == Rejoin::Done {
+                backoff.reset();
+            }
+            let _ = events.send(Event::Disconnected);
+            match Self::reconnect(config, backoff, &writer, &running, &state, &events) {
+                Some(reconnected) => stream = reconnected,
+                None => break,
+            }
+            buffer = Vec::new();
+        }
+        
+        let _ = events.send(Event::Closed);
+    }
+    
+    /// Handle messages until the connection closes
+    ///
+    /// Returns true if the server turned us away (a kick or ban), in which
+    /// case there is no point reconnecting. A message that cannot be decoded
+    /// leaves the stream out of step, so it ends the connection too.
+    fn read_until_closed(
+        stream: &mut Transport,
+        mut buffer: Vec<u8>,
+        writer: &MessageWriter,
+        running: &AtomicBool,
+        state: &SharedState,
+        events: &Sender<Event>,
+    ) -> bool {
+        let mut read_buf = [0u8; 1024];
+        let mut refused = false;
+        
+        while running.load(Ordering::SeqCst) {
+            loop {
+                let message = match writer.wire_format.codec().decode(&mut buffer) {
+                    Ok(Some(message)) => message,
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Malformed message from server: {}", e);
+                        let _ = events.send(Event::Error {
+                            code: error_codes::BAD_REQUEST,
+                            message: format!("Malformed message from server: {}", e),
+                        });
+                        return refused;
+                    }
+                };
+                match message {
+                    Message::Error { code: error_codes::REMOVED, .. } => refused = true,
+                    Message::Welcome { .. } => refused = false,
+                    _ => {}
+                }
+                if let Some(event) = Self::handle_incoming_message(message, state, writer) {
+                    let _ = events.send(event);
+                }
+            }
+            
+            match stream.read(&mut read_buf) {
+                Ok(0) => {
+                    info!("Server closed connection");
+                    break;
+                }
+                Ok(n) => buffer.extend_from_slice(&read_buf[..n]),
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Err(e) => {
+                    // Expected when the session is closed
+                    if running.load(Ordering::SeqCst) {
+                        error!("Receive error: {}", e);
+                    }
+                    break;
+                }
+            }
+        }
+        
+        refused
+    }
+    
+    /// Connect again after a backoff delay, returning the stream to read from
+    ///
+    /// Gives up if the attempts run out or the session is closed while waiting.
+    fn reconnect(
+        config: &ClientConfig,
+        backoff: &mut Backoff,
+        writer: &MessageWriter,
+        running: &AtomicBool,
+        state: &SharedState,
+        events: &Sender<Event>,
+    ) -> Option<Transport> {
+        while running.load(Ordering::SeqCst) {
+            let Some(delay) = backoff.next_delay() else {
+                let _ = events.send(Event::GaveUp { attempts: backoff.attempt() });
+                return None;
+            };
+            let _ = events.send(Event::Reconnecting { attempt: backoff.attempt() });
+            
+            // Sleep in short steps so closing is not kept waiting
+            let deadline = Instant::now() + delay;
+            while Instant::now() < deadline {
+                if !running.load(Ordering::SeqCst) {
+                    return None;
+                }
+                thread::sleep(Duration::from_millis(50).min(deadline - Instant::now()));
+            }
+            
+            let stream = match Transport::connect(&config.server_addr, config.tls.as_ref()) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("Reconnect failed: {}", e);
+                    continue;
+                }
+            };
+            let reader = match stream.try_clone() {
+                Ok(reader) => reader,
+                Err(e) => {
+                    error!("Failed to clone stream: {}", e);
+                    continue;
+                }
+            };
+            writer.replace(stream);
+            // Closed while connecting; `stop` may have missed the new stream
+            if !running.load(Ordering::SeqCst) {
+                writer.disconnect();
+                return None;
+            }
+            if let Err(e) = Self::rejoin(writer, state) {
+                debug!("Rejoin failed: {}", e);
+                writer.disconnect();
+                continue;
+            }
+            return Some(reader);
+        }
+        None
+    }
+    
+    /// Say Hello on a new connection, then resume the session or join afresh
+    fn rejoin(writer: &MessageWriter, state: &SharedState) -> Result<(), anyhow::Error> {
+        // Pings sent on the lost connection will never be answered
+        state.pings.lock().unwrap().clear();
+        writer.send(&Message::hello())?;
+        let token = state.token.lock().unwrap().clone();
+        let mut rejoin = state.rejoin.lock().unwrap();
+        match token {
+            Some(token) => {
+                *rejoin = Rejoin::Resuming;
+                writer.send(&Message::Resume {
+                    token,
+                    last_id: state.last_id.load(Ordering::SeqCst),
+                })
+            }
+            None => {
+                *rejoin = Rejoin::Joining;
+                writer.send(&state.join)
+            }
+        }
+    }
+    
+    /// Heartbeat thread function
+    fn heartbeat_loop(
+        writer: MessageWriter,
+        interval: Duration,
+        running: Arc<AtomicBool>,
+        state: Arc<SharedState>,
+    ) {
+        let ticker = tick(interval);
+        
+        while running.load(Ordering::SeqCst) {
+            select! {
+                recv(ticker) -> _ => {
+                    // Failures are noticed and handled by the receiver thread
+                    let _ = Self::send_ping(&writer, &state);
+                }
+                default(Duration::from_millis(100)) => {}
+            }
+        }
+    }
+    
+    /// Handle an incoming message, returning what the caller should hear about it
+    fn handle_incoming_message(
+        message: Message,
+        state: &SharedState,
+        writer: &MessageWriter,
+    ) -> Option<Event> {
+        match message {
+            Message::Hello { version, capabilities: agreed } => {
+                debug!("Server speaks protocol {} with {:?}", version, agreed);
+                *state.capabilities.lock().unwrap() = agreed;
+                None
+            }
+            
+            Message::Welcome { message, connected_clients } => {
+                let rejoin = std::mem::replace(&mut *state.rejoin.lock().unwrap(), Rejoin::Done);
+                if let Err(e) = Self::restore_rooms(rejoin, state, writer) {
+                    error!("Failed to rejoin rooms: {}", e);
+                }
+                
+                // Anything unconfirmed from before a reconnect goes out again;
+                // the server recognises and drops repeats
+                for message in state.outbox.lock().unwrap().unconfirmed() {
+                    if let Err(e) = writer.send(&message) {
+                        error!("Failed to resend {}: {}", message.kind(), e);
+                        break;
+                    }
+                }
+                for message in state.held.lock().unwrap().drain(..) {
+                    if let Err(e) = writer.send(&message) {
+                        error!("Failed to send {}: {}", message.kind(), e);
+                        break;
+                    }
+                }
+                Some(Event::Welcome { message, users: connected_clients })
+            }
+            
+            Message::SessionToken { token } => {
+                *state.token.lock().unwrap() = Some(token);
+                None
+            }
+            
+            Message::Error { code, message } if *state.rejoin.lock().unwrap() != Rejoin::Done => {
+                match code {
+                    // The session can no longer be resumed, so start a new one
+                    error_codes::UNAUTHORIZED if state.token.lock().unwrap().take().is_some() => {
+                        *state.rejoin.lock().unwrap() = Rejoin::Joining;
+                        if let Err(e) = writer.send(&state.join) {
+                            error!("Failed to join: {}", e);
+                        }
+                        None
+                    }
+                    // The server has not noticed the old connection drop yet; try again shortly
+                    error_codes::USERNAME_TAKEN => {
+                        writer.disconnect();
+                        None
+                    }
+                    _ => Some(Event::Error { code, message }),
+                }
+            }
+            
+            Message::Broadcast { from, room, content, timestamp, id } => {
+                state.last_id.fetch_max(id, Ordering::SeqCst);
+                Some(Event::Chat(ChatMessage { id, room, from, content, timestamp }))
+            }
+            
+            Message::History { room, messages } => {
+                let messages = messages
+                    .into_iter()
+                    .filter_map(|message| match message {
+                        Message::Broadcast { from, room, content, timestamp, id } => {
+                            state.last_id.fetch_max(id, Ordering::SeqCst);
+                            Some(ChatMessage { id, room, from, content, timestamp })
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                Some(Event::History { room, messages })
+            }
+            
+            Message::Private { from, content, timestamp, id, .. } => {
+                if id != 0 {
+                    if let Err(e) = writer.send(&Message::Ack { id }) {
+                        error!("Failed to acknowledge message {}: {}", id, e);
+                    }
+                }
+                Some(Event::Private { from, content, timestamp })
+            }
+            
+            Message::Receipt { client_id, status, .. } => {
+                let to = state.outbox.lock().unwrap().confirm(client_id, status);
+                Some(Event::Receipt { client_id, status, to })
+            }
+            
+            Message::UserJoined { username, room } => Some(Event::UserJoined { username, room }),
+            
+            Message::UserLeft { username, room } => Some(Event::UserLeft { username, room }),
+            
+            Message::RoomJoined { room, members } => {
+                let mut rooms = state.rooms.lock().unwrap();
+                rooms.retain(|r| *r != room);
+                rooms.push(room.clone());
+                Some(Event::RoomJoined { room, members })
+            }
+            
+            Message::RoomLeft { room } => {
+                let mut rooms = state.rooms.lock().unwrap();
+                rooms.retain(|r| *r != room);
+                Some(Event::RoomLeft { room, current: rooms.last().cloned() })
+            }
+            
+            Message::UserList { users } => Some(Event::Users(users)),
+            
+            Message::RoomList { rooms } => Some(Event::Rooms(rooms)),
+            
+            Message::Pong => {
+                debug!("Heartbeat response received");
+                let (ping, sent) = state.pings.lock().unwrap().pop_front()?;
+                Some(Event::Pong { ping, latency: sent.elapsed() })
+            }
+            
+            Message::Error { code, message } => Some(Event::Error { code, message }),
+            
+            Message::Shutdown { reason } => Some(Event::Shutdown { reason }),
+            
+            Message::Notice { message } => Some(Event::Notice(message)),
+            
+            message @ (Message::FileOffer { .. }
+            | Message::FileAccept { .. }
+            | Message::FileChunk { .. }
+            | Message::FileComplete { .. }
+            | Message::FileAbort { .. }) => {
+                let update = state.transfers.lock().unwrap().handle(message);
+                for reply in &update.replies {
+                    if let Err(e) = writer.send(reply) {
+                        error!("Failed to send {}: {}", reply.kind(), e);
+                        break;
+                    }
+                }
+                // Chunks and acknowledgements are not worth mentioning
+                update.note.map(Event::Transfer)
+            }
+            
+            _ => {
+                debug!("Received: {:?}", message);
+                None
+            }
+        }
+    }
+    
+    /// Get back into the rooms we were in before reconnecting
+    ///
+    /// A fresh session starts in the default room, so the others are joined
+    /// again; a resumed one is already back in all of them, and only the room
+    /// chat went to needs to be made current again.
+    fn restore_rooms(rejoin: Rejoin, state: &SharedState, writer: &MessageWriter) -> Result<(), anyhow::Error> {
+        if !state.capabilities.lock().unwrap().iter().any(|c| c == capabilities::ROOMS) {
+            return Ok(());
+        }
+        let rooms = {
+            let mut rooms = state.rooms.lock().unwrap();
+            match rejoin {
+                Rejoin::Done => return Ok(()),
+                Rejoin::Joining => std::mem::replace(&mut *rooms, vec![DEFAULT_ROOM.to_string()])
+                    .into_iter()
+                    .filter(|room| room != DEFAULT_ROOM)
+                    .collect(),
+                Rejoin::Resuming => rooms.last().cloned().into_iter().collect::<Vec<_>>(),
+            }
+        };
+        for room in rooms {
+            writer.send(&Message::JoinRoom { room })?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ChatSession {
+    /// Disconnect without leaving, as if the connection had dropped
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::client::client::{ClientConfig, Interface};
+use crate::common::codec::WireFormat;
+use crate::common::tls::ClientTlsConfig;
+
+/// Client settings gathered from flags, the environment and a config file
+///
+/// Precedence is the same as the server's: flags, then `CHAT_*` environment
+/// variables, then the TOML file named by `--config`, then
+/// `ClientConfig::default()`.
+#[derive(Debug, Clone, Default, Parser, Deserialize)]
+#[command(name = "client", version, about = "Chat client", long_about = None)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSettings {
+    /// TOML file to read settings from
+    #[arg(short, long, env = "CHAT_CLIENT_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+    
+    /// Server to connect to [default: 127.0.0.1:8080]
+    #[arg(env = "CHAT_SERVER")]
+    pub server: Option<String>,
+    /// Name to join as; asked for when not given
+    #[arg(env = "CHAT_USERNAME")]
+    pub username: Option<String>,
+    /// Token or password sent with Join (prefer the environment variable)
+    #[arg(long, env = "CHAT_CREDENTIALS", hide_env_values = true)]
+    pub credentials: Option<String>,
+    /// Speak JSON lines, for servers started with --json-bind
+    #[arg(long, env = "CHAT_JSON", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub json: Option<bool>,
+    /// Seconds between heartbeats
+    #[arg(long, env = "CHAT_HEARTBEAT_SECS")]
+    pub heartbeat_secs: Option<u64>,
+    /// Where accepted files are saved
+    #[arg(long, env = "CHAT_DOWNLOAD_DIR")]
+    pub download_dir: Option<PathBuf>,
+    /// Whether to reconnect after losing the connection [default: true]
+    #[arg(long, env = "CHAT_RECONNECT", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub reconnect: Option<bool>,
+    /// Print line by line instead of using the full-screen interface
+    #[arg(long, env = "CHAT_PLAIN", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub plain: Option<bool>,
+    /// Type in lines from stdin and write received events to stdout as JSON
+    #[arg(long, env = "CHAT_PIPE", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub pipe: Option<bool>,
+    /// Run the commands, waits and expectations in a file, then exit
+    #[arg(long, env = "CHAT_SCRIPT", conflicts_with = "pipe")]
+    pub script: Option<PathBuf>,
+    
+    /// CA (PEM) to verify the server with; setting it switches to TLS
+    #[arg(long, env = "CHAT_TLS_CA")]
+    pub tls_ca: Option<PathBuf>,
+    /// Name to verify the server certificate against, if not the host
+    #[arg(long, env = "CHAT_TLS_SERVER_NAME")]
+    pub tls_server_name: Option<String>,
+    /// Client certificate (PEM) for servers that require one
+    #[arg(long, env = "CHAT_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    /// Private key (PEM) for the client certificate
+    #[arg(long, env = "CHAT_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+}
+
+impl ClientSettings {
+    /// Read settings from a TOML file
+    pub fn from_file(path: &Path) -> Result<Self, anyhow::Error> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        toml::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e))
+    }
+    
+    /// Fill in settings left unset here from `lower`
+    pub fn or(self, lower: ClientSettings) -> ClientSettings {
+        macro_rules! overlay {
+            ($($field:ident),* $(,)?) => {
+                ClientSettings { $($field: self.$field.or(lower.$field)),* }
+            };
+        }
+        overlay!(
+            config, server, username, credentials, json, heartbeat_secs, download_dir, reconnect,
+            plain, pipe, script, tls_ca, tls_server_name, tls_cert, tls_key,
+        )
+    }
+    
+    /// Build the client configuration, reading the config file if one was given
+    pub fn resolve(&self) -> Result<ClientConfig, anyhow::Error> {
+        let settings = match &self.config {
+            Some(path) => self.clone().or(Self::from_file(path)?),
+            None => self.clone(),
+        };
+        Ok(settings.into_config())
+    }
+    
+    /// Turn these settings into a configuration, using defaults for anything unset
+    pub fn into_config(self) -> ClientConfig {
+        let defaults = ClientConfig::default();
+        ClientConfig {
+            server_addr: self.server.unwrap_or(defaults.server_addr),
+            username: self.username.unwrap_or(defaults.username),
+            heartbeat_interval: self.heartbeat_secs.map_or(defaults.heartbeat_interval, Duration::from_secs),
+            credentials: self.credentials.or(defaults.credentials),
+            tls: match self.tls_ca {
+                Some(ca_path) => Some(ClientTlsConfig {
+                    ca_path,
+                    server_name: self.tls_server_name,
+                    cert_path: self.tls_cert,
+                    key_path: self.tls_key,
+                }),
+                None => defaults.tls,
+            },
+            wire_format: match self.json {
+                Some(true) => WireFormat::JsonLines,
+                _ => defaults.wire_format,
+            },
+            download_dir: self.download_dir.unwrap_or(defaults.download_dir),
+            reconnect: match self.reconnect {
+                Some(false) => None,
+                _ => defaults.reconnect,
+            },
+            interface: match (self.script, self.pipe, self.plain) {
+                (Some(path), _, _) => Interface::Script(path),
+                (None, Some(true), _) => Interface::Pipe,
+                (None, _, Some(true)) => Interface::Plain,
+                _ => defaults.interface,
+            },
+        }
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use log::debug;
+use sha2::{Digest, Sha256};
+
+use crate::common::protocol::{Message, FILE_CHUNK_SIZE};
+
+/// Chunks the sender keeps in flight before waiting for the recipient to catch up
+const WINDOW: u64 = 8;
+
+/// What to send and what to tell the user after a transfer message
+#[derive(Debug, Default)]
+pub struct Update {
+    pub replies: Vec<Message>,
+    pub note: Option<String>,
+}
+
+impl Update {
+    fn note(note: String) -> Self {
+        Update {
+            replies: Vec::new(),
+            note: Some(note),
+        }
+    }
+}
+
+/// A file we offered to someone
+struct Outgoing {
+    file: File,
+    to: String,
+    name: String,
+    size: u64,
+    /// Offset of the next chunk to send; None until the recipient accepts
+    next: Option<u64>,
+}
+
+/// A file someone offered to us
+struct Offer {
+    transfer_id: u64,
+    from: String,
+    name: String,
+    size: u64,
+    sha256: String,
+}
+
+/// A file being downloaded into a partial file
+struct Incoming {
+    offer: Offer,
+    file: File,
+    partial: PathBuf,
+    received: u64,
+}
+
+/// The client's side of file transfers, both sending and receiving
+///
+/// Downloads go to `.<sha256>.part` in the download directory until they are
+/// verified, so offering the same file again resumes where it stopped.
+pub struct FileTransfers {
+    download_dir: PathBuf,
+    outgoing: HashMap<u64, Outgoing>,
+    /// Offers not yet accepted, oldest first
+    offers: Vec<Offer>,
+    incoming: HashMap<u64, Incoming>,
+}
+
+impl FileTransfers {
+    /// Save accepted files in `download_dir`, creating it when needed
+    pub fn new(download_dir: impl Into<PathBuf>) -> Self {
+        FileTransfers {
+            download_dir: download_dir.into(),
+            outgoing: HashMap::new(),
+            offers: Vec::new(),
+            incoming: HashMap::new(),
+        }
+    }
+    
+    /// Offer a file to a user, returning the message to send
+    pub fn offer(&mut self, to: &str, path: &Path) -> io::Result<Message> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut hasher = Sha256::new();
+        io::copy(&mut file, &mut hasher)?;
+        let name = path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Not a file"))?;
+        
+        let transfer_id = rand::random();
+        self.outgoing.insert(transfer_id, Outgoing {
+            file,
+            to: to.to_string(),
+            name: name.clone(),
+            size,
+            next: None,
+        });
+        Ok(Message::FileOffer {
+            transfer_id,
+            from: String::new(),
+            to: to.to_string(),
+            name,
+            size,
+            sha256: hex::encode(hasher.finalize()),
+        })
+    }
+    
+    /// Accept an offer, or the latest one if no ID is given, returning the message to send
+    pub fn accept(&mut self, transfer_id: Option<u64>) -> io::Result<Message> {
+        let index = match transfer_id {
+            Some(id) => self.offers.iter().position(|offer| offer.transfer_id == id),
+            None => self.offers.len().checked_sub(1),
+        };
+        let Some(index) = index else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "No such file offer"));
+        };
+        
+        fs::create_dir_all(&self.download_dir)?;
+        let offer = &self.offers[index];
+        let partial = self.download_dir.join(format!(".{}.part", offer.sha256));
+        let file = OpenOptions::new().create(true).append(true).open(&partial)?;
+        let mut received = file.metadata()?.len();
+        if received > offer.size {
+            file.set_len(0)?;
+            received = 0;
+        }
+        
+        let offer = self.offers.remove(index);
+        let transfer_id = offer.transfer_id;
+        self.incoming.insert(transfer_id, Incoming { offer, file, partial, received });
+        Ok(Message::FileAccept { transfer_id, offset: received })
+    }
+    
+    /// Act on a file transfer message from the server
+    pub fn handle(&mut self, message: Message) -> Update {
+        match message {
+            Message::FileOffer { transfer_id, from, name, size, sha256, .. } => {
+                // The checksum names the partial file, so it must be plain hex
+                if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return Update {
+                        replies: vec![Message::FileAbort {
+                            transfer_id,
+                            reason: "Invalid checksum".to_string(),
+                        }],
+                        note: Some(format!("Refused {} from {}: invalid checksum", name, from)),
+                    };
+                }
+                let note = format!(
+                    "{} offers {} ({} bytes); type /accept {} to download it",
+                    from, name, size, transfer_id
+                );
+                self.offers.push(Offer { transfer_id, from, name, size, sha256: sha256.to_lowercase() });
+                Update::note(note)
+            }
+            
+            Message::FileAccept { transfer_id, offset } => self.send_chunks(transfer_id, offset),
+            
+            Message::FileChunk { transfer_id, offset, data } => self.receive_chunk(transfer_id, offset, &data),
+            
+            Message::FileComplete { transfer_id } => match self.incoming.remove(&transfer_id) {
+                Some(incoming) => Update::note(finish(incoming, &self.download_dir)),
+                None => Update::default(),
+            },
+            
+            Message::FileAbort { transfer_id, reason } => {
+                let name = if let Some(outgoing) = self.outgoing.remove(&transfer_id) {
+                    outgoing.name
+                } else if let Some(incoming) = self.incoming.remove(&transfer_id) {
+                    incoming.offer.name
+                } else if let Some(index) = self.offers.iter().position(|o| o.transfer_id == transfer_id) {
+                    self.offers.remove(index).name
+                } else {
+                    return Update::default();
+                };
+                Update::note(format!("Transfer of {} aborted: {}", name, reason))
+            }
+            
+            other => {
+                debug!("Not a file transfer message: {}", other.kind());
+                Update::default()
+            }
+        }
+    }
+    
+    /// Send chunks until the window is full, or finish once the recipient has everything
+    fn send_chunks(&mut self, transfer_id: u64, acked: u64) -> Update {
+        let Some(outgoing) = self.outgoing.get_mut(&transfer_id) else {
+            return Update::default();
+        };
+        
+        let mut update = Update::default();
+        let mut next = match outgoing.next {
+            Some(next) => next,
+            // The first accept says where to start
+            None => {
+                update.note = Some(match acked {
+                    0 => format!("{} accepted {}", outgoing.to, outgoing.name),
+                    _ => format!("{} accepted {}, resuming at byte {}", outgoing.to, outgoing.name, acked),
+                });
+                acked
+            }
+        };
+        
+        if acked == outgoing.size {
+            let outgoing = self.outgoing.remove(&transfer_id).unwrap();
+            update.replies.push(Message::FileComplete { transfer_id });
+            update.note = Some(format!("Sent {} to {}", outgoing.name, outgoing.to));
+            return update;
+        }
+        
+        let window_end = outgoing.size.min(acked + WINDOW * FILE_CHUNK_SIZE as u64);
+        while next < window_end {
+            match read_chunk(&mut outgoing.file, next) {
+                Ok(data) if !data.is_empty() => {
+                    let offset = next;
+                    next += data.len() as u64;
+                    update.replies.push(Message::FileChunk { transfer_id, offset, data });
+                }
+                result => {
+                    let reason = match result {
+                        Err(e) => format!("Could not read the file: {}", e),
+                        Ok(_) => "The file got shorter while it was being sent".to_string(),
+                    };
+                    let outgoing = self.outgoing.remove(&transfer_id).unwrap();
+                    update.replies.push(Message::FileAbort { transfer_id, reason: reason.clone() });
+                    update.note = Some(format!("Sending {} failed: {}", outgoing.name, reason));
+                    return update;
+                }
+            }
+        }
+        outgoing.next = Some(next);
+        update
+    }
+    
+    /// Append a chunk to its partial file and acknowledge it
+    fn receive_chunk(&mut self, transfer_id: u64, offset: u64, data: &[u8]) -> Update {
+        let Some(incoming) = self.incoming.get_mut(&transfer_id) else {
+            return Update::default();
+        };
+        
+        let result = if offset != incoming.received {
+            Err(format!("Expected data at byte {}, got byte {}", incoming.received, offset))
+        } else {
+            incoming.file.write_all(data).map_err(|e| format!("Could not save the file: {}", e))
+        };
+        match result {
+            Ok(()) => {
+                incoming.received += data.len() as u64;
+                Update {
+                    replies: vec![Message::FileAccept { transfer_id, offset: incoming.received }],
+                    note: None,
+                }
+            }
+            Err(reason) => {
+                let incoming = self.incoming.remove(&transfer_id).unwrap();
+                Update {
+                    replies: vec![Message::FileAbort { transfer_id, reason: reason.clone() }],
+                    note: Some(format!(
+                        "Download of {} stopped: {}; accept it again to resume",
+                        incoming.offer.name, reason
+                    )),
+                }
+            }
+        }
+    }
+}
+
+/// Read up to one chunk of a file from `offset`
+fn read_chunk(file: &mut File, offset: u64) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::with_capacity(FILE_CHUNK_SIZE);
+    file.take(FILE_CHUNK_SIZE as u64).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Verify a finished download and move it to its final name, describing the outcome
+fn finish(incoming: Incoming, download_dir: &Path) -> String {
+    let Incoming { offer, file, partial, received } = incoming;
+    drop(file);
+    
+    let verified = received == offer.size && checksum(&partial).is_ok_and(|sum| sum == offer.sha256);
+    if !verified {
+        let _ = fs::remove_file(&partial);
+        return format!("Download of {} from {} failed its checksum and was discarded", offer.name, offer.from);
+    }
+    
+    let path = unique_path(download_dir, &offer.name);
+    match fs::rename(&partial, &path) {
+        Ok(()) => format!("Saved {} from {} to {}", offer.name, offer.from, path.display()),
+        Err(e) => format!("Could not save {} to {}: {}", offer.name, path.display(), e),
+    }
+}
+
+/// Hex-encoded SHA-256 of a file
+fn checksum(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// A path in `dir` for the offered name that does not overwrite anything
+///
+/// Only the final component of the name is used, so senders cannot write
+/// outside the download directory.
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let name = Path::new(name).file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .filter(|name| !name.starts_with('.'))
+        .unwrap_or_else(|| "download".to_string());
+    
+    let path = dir.join(&name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name.as_str(), String::new()),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
@@ -0,0 +1,440 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::time::Duration;
+use crossbeam_channel::Receiver;
+use crossterm::event::{self, Event as TermEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::DefaultTerminal;
+use ratatui::Frame;
+use ratatui::layout::{Constraint, Layout, Position};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph};
+
+use crate::client::client::{describe, Client};
+use crate::client::session::{ChatMessage, Event};
+use crate::common::protocol::{current_timestamp, DEFAULT_ROOM};
+
+/// Lines kept in the message pane; older ones scroll away for good
+const MAX_LINES: usize = 1000;
+
+/// Width of the user list
+const SIDEBAR_WIDTH: u16 = 20;
+
+/// The line being typed, with a cursor and a history of earlier lines
+#[derive(Debug, Default)]
+pub struct InputLine {
+    chars: Vec<char>,
+    /// Position of the cursor, in characters
+    cursor: usize,
+    /// Lines entered so far, oldest first
+    history: Vec<String>,
+    /// Which history entry is showing while recalling earlier lines
+    recalled: Option<usize>,
+    /// What was being typed before recalling started
+    draft: Vec<char>,
+}
+
+impl InputLine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    pub fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+    
+    /// Position of the cursor, in characters
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+    
+    pub fn insert(&mut self, c: char) {
+        self.chars.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+    
+    /// Delete the character before the cursor
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.chars.remove(self.cursor);
+        }
+    }
+    
+    /// Delete the character under the cursor
+    pub fn delete(&mut self) {
+        if self.cursor < self.chars.len() {
+            self.chars.remove(self.cursor);
+        }
+    }
+    
+    /// Delete everything before the cursor
+    pub fn clear_to_start(&mut self) {
+        self.chars.drain(..self.cursor);
+        self.cursor = 0;
+    }
+    
+    pub fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+    
+    pub fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.chars.len());
+    }
+    
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+    
+    pub fn end(&mut self) {
+        self.cursor = self.chars.len();
+    }
+    
+    /// Show the line entered before the one showing
+    pub fn previous(&mut self) {
+        let index = match self.recalled {
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = std::mem::take(&mut self.chars);
+                self.history.len() - 1
+            }
+            Some(0) => return,
+            Some(index) => index - 1,
+        };
+        self.recall(Some(index));
+    }
+    
+    /// Show the line entered after the one showing, or go back to the draft
+    pub fn next(&mut self) {
+        match self.recalled {
+            None => {}
+            Some(index) if index + 1 < self.history.len() => self.recall(Some(index + 1)),
+            Some(_) => self.recall(None),
+        }
+    }
+    
+    fn recall(&mut self, index: Option<usize>) {
+        self.chars = match index {
+            Some(index) => self.history[index].chars().collect(),
+            None => std::mem::take(&mut self.draft),
+        };
+        self.recalled = index;
+        self.cursor = self.chars.len();
+    }
+    
+    /// Take the line for sending, remembering it unless it repeats the last one
+    pub fn submit(&mut self) -> String {
+        let text = self.text();
+        if !text.trim().is_empty() && self.history.last() != Some(&text) {
+            self.history.push(text.clone());
+        }
+        self.chars.clear();
+        self.draft.clear();
+        self.recalled = None;
+        self.cursor = 0;
+        text
+    }
+}
+
+/// Who is in each of our rooms, kept up to date from presence events
+#[derive(Debug, Default)]
+pub struct Roster {
+    rooms: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Roster {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Update the members from an event; other events are ignored
+    pub fn apply(&mut self, event: &Event) {
+        match event {
+            // A new session starts out in the default room with everyone
+            Event::Welcome { users, .. } => {
+                self.rooms.clear();
+                self.rooms.insert(DEFAULT_ROOM.to_string(), users.iter().cloned().collect());
+            }
+            Event::RoomJoined { room, members } => {
+                self.rooms.insert(room.clone(), members.iter().cloned().collect());
+            }
+            Event::RoomLeft { room, .. } => {
+                self.rooms.remove(room);
+            }
+            Event::UserJoined { username, room } => {
+                self.rooms.entry(room.clone()).or_default().insert(username.clone());
+            }
+            Event::UserLeft { username, room } => {
+                if let Some(members) = self.rooms.get_mut(room) {
+                    members.remove(username);
+                }
+            }
+            _ => {}
+        }
+    }
+    
+    /// Members of a room, in name order
+    pub fn members(&self, room: &str) -> Vec<&str> {
+        self.rooms.get(room).into_iter().flatten().map(String::as_str).collect()
+    }
+}
+
+/// Connection state shown in the status bar
+enum Connection {
+    Connected,
+    Disconnected,
+    Reconnecting(u32),
+    Closed,
+}
+
+/// The full-screen interface: messages, the user list, the input line and a status bar
+struct Tui<'a> {
+    client: &'a mut Client,
+    events: Receiver<Event>,
+    /// Message pane contents, oldest first
+    lines: VecDeque<(String, Style)>,
+    /// How many rows the message pane is scrolled back from the latest
+    scroll: usize,
+    /// Inner size of the message pane at the last draw, for wrapping and paging
+    width: usize,
+    height: usize,
+    roster: Roster,
+    input: InputLine,
+    connection: Connection,
+    latency: Option<Duration>,
+}
+
+/// Run the full-screen interface until the user quits
+pub fn run(client: &mut Client) -> Result<(), anyhow::Error> {
+    let mut terminal = ratatui::try_init()?;
+    let result = Tui::new(client).run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl<'a> Tui<'a> {
+    fn new(client: &'a mut Client) -> Self {
+        Tui {
+            events: client.session().events().clone(),
+            client,
+            lines: VecDeque::new(),
+            scroll: 0,
+            width: 80,
+            height: 20,
+            roster: Roster::new(),
+            input: InputLine::new(),
+            connection: Connection::Connected,
+            latency: None,
+        }
+    }
+    
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<(), anyhow::Error> {
+        // Measure latency now rather than at the first heartbeat
+        let _ = self.client.session().ping();
+        
+        loop {
+            for event in self.events.try_iter().collect::<Vec<_>>() {
+                self.on_event(event);
+            }
+            terminal.draw(|frame| self.draw(frame))?;
+            
+            if !event::poll(Duration::from_millis(50))? {
+                continue;
+            }
+            if let TermEvent::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !self.on_key(key)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+    
+    fn on_event(&mut self, event: Event) {
+        self.roster.apply(&event);
+        match event {
+            Event::Welcome { .. } => self.connection = Connection::Connected,
+            Event::Disconnected => self.connection = Connection::Disconnected,
+            Event::Reconnecting { attempt } => self.connection = Connection::Reconnecting(attempt),
+            Event::GaveUp { .. } | Event::Closed => self.connection = Connection::Closed,
+            Event::Pong { latency, .. } => self.latency = Some(latency),
+            _ => {}
+        }
+        
+        let style = match event {
+            Event::Chat(_) | Event::History { .. } => Style::default(),
+            Event::Private { .. } => Style::default().fg(Color::Magenta),
+            Event::Error { .. } | Event::Shutdown { .. } | Event::GaveUp { .. } => Style::default().fg(Color::Red),
+            _ => Style::default().fg(Color::Yellow),
+        };
+        if let Some(text) = describe(&event) {
+            self.show(&text, style);
+        }
+    }
+    
+    /// Returns false once the user quits
+    fn on_key(&mut self, key: KeyEvent) -> Result<bool, anyhow::Error> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') if ctrl => return Ok(false),
+            KeyCode::Char('d') if ctrl && self.input.text().is_empty() => return Ok(false),
+            KeyCode::Char('u') if ctrl => self.input.clear_to_start(),
+            KeyCode::Char('a') if ctrl => self.input.home(),
+            KeyCode::Char('e') if ctrl => self.input.end(),
+            KeyCode::Char(c) if !ctrl => self.input.insert(c),
+            KeyCode::Backspace => self.input.backspace(),
+            KeyCode::Delete => self.input.delete(),
+            KeyCode::Left => self.input.left(),
+            KeyCode::Right => self.input.right(),
+            KeyCode::Home => self.input.home(),
+            KeyCode::End => self.input.end(),
+            KeyCode::Up => self.input.previous(),
+            KeyCode::Down => self.input.next(),
+            KeyCode::PageUp => self.scroll += self.height.saturating_sub(1).max(1),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(self.height.saturating_sub(1).max(1)),
+            KeyCode::Enter => return self.submit(),
+            _ => {}
+        }
+        Ok(true)
+    }
+    
+    /// Carry out the typed line, showing what was sent and any command output
+    fn submit(&mut self) -> Result<bool, anyhow::Error> {
+        let line = self.input.submit();
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(true);
+        }
+        // Jump back to the latest messages
+        self.scroll = 0;
+        
+        // The server does not echo our own chat back, so show it here
+        let session = self.client.session();
+        match session.current_room() {
+            Some(room) if !line.starts_with('/') => {
+                let echo = Event::Chat(ChatMessage {
+                    id: 0,
+                    room,
+                    from: session.username().to_string(),
+                    content: line.to_string(),
+                    timestamp: current_timestamp(),
+                });
+                if let Some(text) = describe(&echo) {
+                    self.show(&text, Style::default().add_modifier(Modifier::BOLD));
+                }
+            }
+            _ => self.show(&format!("> {}", line), Style::default().fg(Color::DarkGray)),
+        }
+        
+        let mut output = Vec::new();
+        let result = self.client.handle_command(line, &mut output);
+        self.show(String::from_utf8_lossy(&output).trim_end(), Style::default());
+        match result {
+            Ok(keep_going) => Ok(keep_going),
+            // Usually a send after the session ended; say so and let the user quit
+            Err(e) => {
+                self.show(&format!("*** {} ***", e), Style::default().fg(Color::Red));
+                Ok(true)
+            }
+        }
+    }
+    
+    /// Add text to the message pane, one entry per line
+    fn show(&mut self, text: &str, style: Style) {
+        for line in text.lines() {
+            // Keep the view still while the user is reading back
+            if self.scroll > 0 {
+                self.scroll += wrap(line, self.width).len();
+            }
+            self.lines.push_back((line.to_string(), style));
+        }
+        while self.lines.len() > MAX_LINES {
+            self.lines.pop_front();
+        }
+    }
+    
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, input_area, status_area] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(3),
+            Constraint::Length(1),
+        ]).areas(frame.area());
+        let [messages_area, users_area] = Layout::horizontal([
+            Constraint::Min(10),
+            Constraint::Length(SIDEBAR_WIDTH),
+        ]).areas(main);
+        let room = self.client.session().current_room();
+        
+        // Messages, wrapped to the pane and scrolled back from the latest
+        self.width = messages_area.width.saturating_sub(2).max(1) as usize;
+        self.height = messages_area.height.saturating_sub(2) as usize;
+        let wrapped: Vec<Line> = self.lines
+            .iter()
+            .flat_map(|(text, style)| {
+                wrap(text, self.width).into_iter().map(move |piece| Line::styled(piece, *style))
+            })
+            .collect();
+        self.scroll = self.scroll.min(wrapped.len().saturating_sub(self.height));
+        let end = wrapped.len() - self.scroll;
+        let start = end.saturating_sub(self.height);
+        let title = match (&room, self.scroll) {
+            (Some(room), 0) => format!(" {} ", room),
+            (Some(room), scroll) => format!(" {} (scrolled back {} lines) ", room, scroll),
+            (None, _) => " no room ".to_string(),
+        };
+        frame.render_widget(
+            Paragraph::new(wrapped[start..end].to_vec()).block(Block::bordered().title(title)),
+            messages_area,
+        );
+        
+        // Members of the current room
+        let members: Vec<Line> = room
+            .as_deref()
+            .map(|room| self.roster.members(room))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|name| Line::from(name.to_string()))
+            .collect();
+        frame.render_widget(
+            Paragraph::new(members).block(Block::bordered().title(" Users ")),
+            users_area,
+        );
+        
+        // The input line, scrolled sideways to keep the cursor in view
+        let inner_width = input_area.width.saturating_sub(2).max(1) as usize;
+        let offset = (self.input.cursor() + 1).saturating_sub(inner_width);
+        let visible: String = self.input.text().chars().skip(offset).take(inner_width).collect();
+        frame.render_widget(Paragraph::new(visible).block(Block::bordered()), input_area);
+        frame.set_cursor_position(Position::new(
+            input_area.x + 1 + (self.input.cursor() - offset) as u16,
+            input_area.y + 1,
+        ));
+        
+        // Status bar
+        let (state, color) = match self.connection {
+            Connection::Connected => ("connected".to_string(), Color::Green),
+            Connection::Disconnected => ("disconnected".to_string(), Color::Red),
+            Connection::Reconnecting(attempt) => (format!("reconnecting (attempt {})", attempt), Color::Yellow),
+            Connection::Closed => ("closed; /quit to exit".to_string(), Color::Red),
+        };
+        let latency = match self.latency {
+            Some(latency) => format!("{:.1} ms", latency.as_secs_f64() * 1000.0),
+            None => "-".to_string(),
+        };
+        let status = format!(
+            " {} | {} | latency {} | PgUp/PgDn scroll, /help, Ctrl-C quits",
+            state, self.client.session().username(), latency,
+        );
+        frame.render_widget(
+            Paragraph::new(status).style(Style::default().bg(color).fg(Color::Black)),
+            status_area,
+        );
+    }
+}
+
+/// Break a line into pieces of at most `width` characters
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let chars: Vec<char> = line.chars().collect();
+    if chars.is_empty() {
+        return vec![String::new()];
+    }
+    chars.chunks(width.max(1)).map(|chunk| chunk.iter().collect()).collect()
+}
@@ -0,0 +1,192 @@
+use crate::common::protocol::Message;
+
+/// Largest encoded message either codec accepts (1MB)
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Turns messages into bytes on the wire and back
+pub trait Codec: Send + Sync {
+    /// Encode a message as one complete frame
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, anyhow::Error>;
+    
+    /// Encode a message for a peer that negotiated protocol `version`
+    fn encode_for(&self, message: &Message, _version: u16) -> Result<Vec<u8>, anyhow::Error> {
+        self.encode(message)
+    }
+    
+    /// Decode the next message from received bytes, removing it from the buffer
+    ///
+    /// Returns `Ok(None)` until a complete frame has arrived.
+    fn decode(&self, buffer: &mut Vec<u8>) -> Result<Option<Message>, anyhow::Error>;
+}
+
+/// Which codec a connection speaks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum WireFormat {
+    /// u32 big-endian length followed by a bincode-encoded message
+    #[default]
+    Bincode,
+    /// One JSON-encoded message per line, readable with netcat
+    JsonLines,
+}
+
+impl WireFormat {
+    /// The codec implementing this format
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            WireFormat::Bincode => &BincodeCodec,
+            WireFormat::JsonLines => &JsonLinesCodec,
+        }
+    }
+}
+
+/// Length-prefixed bincode frames
+pub struct BincodeCodec;
+
+impl BincodeCodec {
+    /// Put the length prefix in front of an encoded message
+    fn frame(data: Vec<u8>) -> Result<Vec<u8>, anyhow::Error> {
+        if data.len() > MAX_MESSAGE_SIZE {
+            anyhow::bail!("Message too large: {} bytes", data.len());
+        }
+        
+        let len = data.len() as u32;
+        let mut frame = len.to_be_bytes().to_vec();
+        frame.extend(data);
+        Ok(frame)
+    }
+}
+
+impl Codec for BincodeCodec {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, anyhow::Error> {
+        Self::frame(message.to_bytes()?)
+    }
+    
+    /// Leaves out fields added after `version`, which bincode readers cannot skip
+    fn encode_for(&self, message: &Message, version: u16) -> Result<Vec<u8>, anyhow::Error> {
+        Self::frame(message.to_bytes_for(version)?)
+    }
+    
+    fn decode(&self, buffer: &mut Vec<u8>) -> Result<Option<Message>, anyhow::Error> {
+        if buffer.len() < 4 {
+            return Ok(None); // Not enough data for length prefix
+        }
+        
+        // Read length prefix
+        let len_bytes: [u8; 4] = buffer[0..4].try_into()?;
+        let msg_len = u32::from_be_bytes(len_bytes) as usize;
+        
+        if msg_len > MAX_MESSAGE_SIZE {
+            anyhow::bail!("Invalid message length: {}", msg_len);
+        }
+        
+        if buffer.len() < 4 + msg_len {
+            return Ok(None); // Not enough data for complete message
+        }
+        
+        // Extract message
+        let msg_data = buffer[4..4 + msg_len].to_vec();
+        buffer.drain(0..4 + msg_len);
+        
+        let message = Message::from_bytes(&msg_data)?;
+        Ok(Some(message))
+    }
+}
+
+/// Newline-delimited JSON, e.g. `{"Join":{"username":"qa"}}`
+///
+/// Blank lines and trailing carriage returns are ignored so the format can be
+/// typed by hand.
+pub struct JsonLinesCodec;
+
+impl Codec for JsonLinesCodec {
+    fn encode(&self, message: &Message) -> Result<Vec<u8>, anyhow::Error> {
+        let mut line = serde_json::to_vec(message)?;
+        if line.len() > MAX_MESSAGE_SIZE {
+            anyhow::bail!("Message too large: {} bytes", line.len());
+        }
+        line.push(b'\n');
+        Ok(line)
+    }
+    
+    fn decode(&self, buffer: &mut Vec<u8>) -> Result<Option<Message>, anyhow::Error> {
+        loop {
+            let Some(end) = buffer.iter().position(|&b| b == b'\n') else {
+                if buffer.len() > MAX_MESSAGE_SIZE {
+                    anyhow::bail!("Line longer than {} bytes", MAX_MESSAGE_SIZE);
+                }
+                return Ok(None);
+            };
+            
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            let line = line.trim_ascii();
+            if line.is_empty() {
+                continue;
+            }
+            return Ok(Some(serde_json::from_slice(line)?));
+        }
+    }
+}
+
+/// Tokio adapter that frames a byte stream with one of the wire formats
+#[cfg(feature = "async")]
+pub struct MessageCodec {
+    format: WireFormat,
+    /// Bytes taken from tokio's buffer that do not make up a whole frame yet
+    pending: Vec<u8>,
+}
+
+/// One frame read by `MessageCodec`
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub enum Frame {
+    /// A message and the number of bytes it took on the wire
+    Message(Message, usize),
+    /// A frame that could not be decoded; it was skipped, so reading can go on
+    Malformed(anyhow::Error),
+}
+
+#[cfg(feature = "async")]
+impl MessageCodec {
+    pub fn new(format: WireFormat) -> Self {
+        MessageCodec {
+            format,
+            pending: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl tokio_util::codec::Decoder for MessageCodec {
+    type Item = Frame;
+    type Error = anyhow::Error;
+    
+    /// Errors only when the framing itself is lost and the stream cannot go on
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Frame>, anyhow::Error> {
+        // The codecs work on a Vec, so move new bytes over once and decode from there
+        self.pending.extend_from_slice(&src.split());
+        let buffered = self.pending.len();
+        match self.format.codec().decode(&mut self.pending) {
+            Ok(message) => Ok(message.map(|message| Frame::Message(message, buffered - self.pending.len()))),
+            Err(e) if self.pending.len() < buffered => Ok(Some(Frame::Malformed(e))),
+            Err(e) => Err(e),
+        }
+    }
+    
+    fn decode_eof(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Frame>, anyhow::Error> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if self.pending.is_empty() => Ok(None),
+            None => anyhow::bail!("Connection closed in the middle of a message"),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl tokio_util::codec::Encoder<Message> for MessageCodec {
+    type Error = anyhow::Error;
+    
+    fn encode(&mut self, message: Message, dst: &mut bytes::BytesMut) -> Result<(), anyhow::Error> {
+        dst.extend_from_slice(&self.format.codec().encode(&message)?);
+        Ok(())
+    }
+}
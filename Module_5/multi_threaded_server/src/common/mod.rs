@@ -1,2 +1,6 @@
+pub mod codec;
 pub mod errors;
-pub mod protocol;
\ No newline at end of file
+pub mod protocol;
+pub mod tls;
+pub mod transport;
+pub mod websocket;
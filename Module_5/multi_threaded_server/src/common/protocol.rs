@@ -1,19 +1,99 @@
-use serde::{Serialize, Deserialize};
+use bincode::Options;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::cell::Cell;
+use std::net::IpAddr;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::common::codec::{BincodeCodec, Codec, MAX_MESSAGE_SIZE};
+
+/// Room every client is placed in when it joins
+pub const DEFAULT_ROOM: &str = "#general";
+
+/// Protocol version spoken by this build
+///
+/// Version 2 added message IDs to `Chat`, `Broadcast` and `Private`; they are
+/// left out of bincode frames for peers that negotiated version 1.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Oldest protocol version this build still accepts, and the one assumed for
+/// clients that skip the Hello
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+thread_local! {
+    /// Protocol version whose bincode layout is being written or read on this thread
+    static LAYOUT: Cell<u16> = const { Cell::new(PROTOCOL_VERSION) };
+}
+
+/// Largest `data` carried by a single `FileChunk` (64KB)
+pub const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Summary of a room returned by `ListRooms`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RoomInfo {
+    pub name: String,
+    pub members: usize,
+}
+
+/// Details about a connected user returned by `ListUsers`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UserInfo {
+    pub username: String,
+    /// Unix timestamp (seconds) of when the user joined
+    pub connected_at: u64,
+    /// Seconds since the user last sent anything other than a heartbeat
+    pub idle_secs: u64,
+}
+
+/// What became of a message, reported to its sender in a `Receipt`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// The server accepted the message and passed it on
+    Sent,
+    /// The recipient is offline; the message waits until they next join
+    Queued,
+    /// The recipient acknowledged the private message
+    Delivered,
+    /// Nobody could be given the message
+    Failed,
+}
+
+/// Who a ban applies to
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum BanTarget {
+    /// A username, compared case-insensitively
+    Username(String),
+    /// Every connection from an address
+    Ip(IpAddr),
+}
+
+impl std::fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BanTarget::Username(name) => f.write_str(name),
+            BanTarget::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
 /// Message types exchanged between client and server
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum Message {
-    /// Client sends a chat message
+    /// Client sends a chat message to a room
     Chat {
         sender: String,
+        room: String,
         content: String,
         timestamp: u64,
+        /// Chosen by the client so a resent message is not delivered twice; 0 if untracked
+        #[serde(default, skip_serializing_if = "added_in_v2", deserialize_with = "added_field")]
+        client_id: u64,
     },
     
     /// Client announces presence
     Join {
         username: String,
+        /// Token or password, depending on how the server authenticates
+        credentials: Option<String>,
     },
     
     /// Client leaves
@@ -21,19 +101,29 @@ pub enum Message {
         username: String,
     },
     
-    /// Server broadcasts to all clients
+    /// Server broadcasts to the members of a room
     Broadcast {
         from: String,
+        room: String,
         content: String,
         timestamp: u64,
+        /// Assigned by the server, increasing with every message
+        #[serde(default, skip_serializing_if = "added_in_v2", deserialize_with = "added_field")]
+        id: u64,
     },
     
-    /// Server sends private message
+    /// Client sends, and server relays, a private message
     Private {
         from: String,
         to: String,
         content: String,
         timestamp: u64,
+        /// Assigned by the server when relaying; 0 from the client
+        #[serde(default, skip_serializing_if = "added_in_v2", deserialize_with = "added_field")]
+        id: u64,
+        /// Chosen by the client as for `Chat`; 0 when relayed
+        #[serde(default, skip_serializing_if = "added_in_v2", deserialize_with = "added_field")]
+        client_id: u64,
     },
     
     /// Server acknowledges connection
@@ -42,14 +132,16 @@ pub enum Message {
         connected_clients: Vec<String>,
     },
     
-    /// Server notifies of new client
+    /// Server notifies room members of a new member
     UserJoined {
         username: String,
+        room: String,
     },
     
-    /// Server notifies of leaving client
+    /// Server notifies room members of a leaving member
     UserLeft {
         username: String,
+        room: String,
     },
     
     /// Heartbeat to keep connection alive
@@ -63,24 +155,204 @@ pub enum Message {
         code: u16,
         message: String,
     },
+    
+    /// Server is shutting down and will close the connection
+    Shutdown {
+        reason: String,
+    },
+    
+    /// Client creates a new room and joins it
+    CreateRoom {
+        room: String,
+    },
+    
+    /// Client joins an existing room
+    JoinRoom {
+        room: String,
+    },
+    
+    /// Client leaves a room
+    PartRoom {
+        room: String,
+    },
+    
+    /// Client asks for the list of rooms
+    ListRooms,
+    
+    /// Server confirms a room join, listing the current members
+    RoomJoined {
+        room: String,
+        members: Vec<String>,
+    },
+    
+    /// Server confirms a room part
+    RoomLeft {
+        room: String,
+    },
+    
+    /// Server replies to `ListRooms`
+    RoomList {
+        rooms: Vec<RoomInfo>,
+    },
+    
+    /// Client asks for the list of connected users
+    ListUsers,
+    
+    /// Server replies to `ListUsers`
+    UserList {
+        users: Vec<UserInfo>,
+    },
+    
+    /// Server replays recent messages of a room to a client that just joined it
+    History {
+        room: String,
+        messages: Vec<Message>,
+    },
+    
+    /// Opens a connection: the client advertises what it speaks and the server
+    /// answers with the capabilities both sides share
+    ///
+    /// Older peers can only decode variants they know about, so new variants
+    /// must always be appended and existing ones never reordered. Fields added
+    /// to an existing variant go at its end, are read with `added_field`, and
+    /// need a new protocol version.
+    Hello {
+        version: u16,
+        capabilities: Vec<String>,
+    },
+    
+    /// Operator disconnects a user
+    Kick {
+        username: String,
+        reason: String,
+    },
+    
+    /// Operator bans a user or address; no duration bans for good
+    Ban {
+        target: BanTarget,
+        duration_secs: Option<u64>,
+        reason: String,
+    },
+    
+    /// Operator stops a user from chatting for a while
+    Mute {
+        username: String,
+        duration_secs: u64,
+    },
+    
+    /// Server announcement shown to every client, such as a moderation action
+    Notice {
+        message: String,
+    },
+    
+    /// Sender proposes a file to one user; the server fills in `from`
+    FileOffer {
+        transfer_id: u64,
+        from: String,
+        to: String,
+        name: String,
+        size: u64,
+        /// Hex-encoded SHA-256 of the whole file
+        sha256: String,
+    },
+    
+    /// Recipient wants the file from `offset` on
+    ///
+    /// Sent again as chunks arrive, so the sender keeps only a few in flight.
+    /// A nonzero first offset resumes an earlier, interrupted transfer.
+    FileAccept {
+        transfer_id: u64,
+        offset: u64,
+    },
+    
+    /// Part of the file, at most `FILE_CHUNK_SIZE` bytes
+    FileChunk {
+        transfer_id: u64,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    
+    /// Sender has sent every chunk
+    FileComplete {
+        transfer_id: u64,
+    },
+    
+    /// The sender, the recipient or the server gave up on the transfer
+    FileAbort {
+        transfer_id: u64,
+        reason: String,
+    },
+    
+    /// Recipient confirms it received the private message with this ID
+    Ack {
+        id: u64,
+    },
+    
+    /// Server tells the sender what became of a message it tagged with a client ID
+    Receipt {
+        client_id: u64,
+        /// The server's ID for the message; 0 if it never got one
+        id: u64,
+        status: DeliveryStatus,
+    },
+    
+    /// Server hands out a token the client can use to resume this session
+    SessionToken {
+        token: String,
+    },
+    
+    /// Client rejoins with a token from `SessionToken` instead of a `Join`
+    ///
+    /// The server restores the rooms the client was in and replays the
+    /// messages after `last_id` that it still remembers.
+    Resume {
+        token: String,
+        /// ID of the last broadcast the client received
+        last_id: u64,
+    },
 }
 
 impl Message {
-    /// Create a new chat message
+    /// Create a join message
+    pub fn join(username: String, credentials: Option<String>) -> Self {
+        Message::Join {
+            username,
+            credentials,
+        }
+    }
+    
+    /// Create a Hello advertising this build's version and capabilities
+    pub fn hello() -> Self {
+        Message::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: capabilities::SUPPORTED.iter().map(|cap| cap.to_string()).collect(),
+        }
+    }
+    
+    /// Create a new chat message for the default room
     pub fn chat(sender: String, content: String) -> Self {
+        Self::room_chat(sender, DEFAULT_ROOM.to_string(), content)
+    }
+    
+    /// Create a new chat message for a given room
+    pub fn room_chat(sender: String, room: String, content: String) -> Self {
         Message::Chat {
             sender,
+            room,
             content,
             timestamp: current_timestamp(),
+            client_id: 0,
         }
     }
     
-    /// Create a new broadcast message
-    pub fn broadcast(from: String, content: String) -> Self {
+    /// Create a new broadcast message with a server-assigned ID
+    pub fn broadcast(id: u64, from: String, room: String, content: String) -> Self {
         Message::Broadcast {
             from,
+            room,
             content,
             timestamp: current_timestamp(),
+            id,
         }
     }
     
@@ -91,6 +363,58 @@ impl Message {
             to,
             content,
             timestamp: current_timestamp(),
+            id: 0,
+            client_id: 0,
+        }
+    }
+    
+    /// Short name of the message type, used as a metrics label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Chat { .. } => "chat",
+            Message::Join { .. } => "join",
+            Message::Leave { .. } => "leave",
+            Message::Broadcast { .. } => "broadcast",
+            Message::Private { .. } => "private",
+            Message::Welcome { .. } => "welcome",
+            Message::UserJoined { .. } => "user_joined",
+            Message::UserLeft { .. } => "user_left",
+            Message::Ping => "ping",
+            Message::Pong => "pong",
+            Message::Error { .. } => "error",
+            Message::Shutdown { .. } => "shutdown",
+            Message::CreateRoom { .. } => "create_room",
+            Message::JoinRoom { .. } => "join_room",
+            Message::PartRoom { .. } => "part_room",
+            Message::ListRooms => "list_rooms",
+            Message::RoomJoined { .. } => "room_joined",
+            Message::RoomLeft { .. } => "room_left",
+            Message::RoomList { .. } => "room_list",
+            Message::ListUsers => "list_users",
+            Message::UserList { .. } => "user_list",
+            Message::History { .. } => "history",
+            Message::Hello { .. } => "hello",
+            Message::Kick { .. } => "kick",
+            Message::Ban { .. } => "ban",
+            Message::Mute { .. } => "mute",
+            Message::Notice { .. } => "notice",
+            Message::FileOffer { .. } => "file_offer",
+            Message::FileAccept { .. } => "file_accept",
+            Message::FileChunk { .. } => "file_chunk",
+            Message::FileComplete { .. } => "file_complete",
+            Message::FileAbort { .. } => "file_abort",
+            Message::Ack { .. } => "ack",
+            Message::Receipt { .. } => "receipt",
+            Message::SessionToken { .. } => "session_token",
+            Message::Resume { .. } => "resume",
+        }
+    }
+    
+    /// The client ID of a tracked Chat or Private; 0 for anything else
+    pub fn client_id(&self) -> u64 {
+        match self {
+            Message::Chat { client_id, .. } | Message::Private { client_id, .. } => *client_id,
+            _ => 0,
         }
     }
     
@@ -99,63 +423,141 @@ impl Message {
         bincode::serialize(self)
     }
     
+    /// Serialize message to bytes in the layout of protocol `version`
+    pub fn to_bytes_for(&self, version: u16) -> Result<Vec<u8>, bincode::Error> {
+        in_layout(version, || bincode::serialize(self))
+    }
+    
     /// Deserialize message from bytes
+    ///
+    /// A frame that ends exactly where the version 1 layout does, such as a
+    /// history log entry written by an older build or a message from a client
+    /// that skipped the Hello, is read with its added fields as 0.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, bincode::Error> {
-        bincode::deserialize(bytes)
+        bincode::deserialize(bytes).or_else(|e| {
+            let legacy = bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .reject_trailing_bytes();
+            in_layout(MIN_PROTOCOL_VERSION, || legacy.deserialize(bytes)).map_err(|_| e)
+        })
+    }
+}
+
+/// Check that a room name is `#` followed by 1-31 letters, digits, `-` or `_`
+pub fn is_valid_room_name(name: &str) -> bool {
+    match name.strip_prefix('#') {
+        Some(rest) => {
+            !rest.is_empty()
+                && rest.len() < 32
+                && rest.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        }
+        None => false,
+    }
+}
+
+/// Optional features negotiated in the Hello exchange
+pub mod capabilities {
+    /// Named rooms besides the default one
+    pub const ROOMS: &str = "rooms";
+    /// Replay of recent room messages on join
+    pub const HISTORY: &str = "history";
+    /// Relayed file transfers between users
+    pub const FILES: &str = "files";
+    /// Session tokens for picking up where a dropped connection left off
+    pub const RESUME: &str = "resume";
+    
+    /// Capabilities this build implements
+    pub const SUPPORTED: &[&str] = &[ROOMS, HISTORY, FILES, RESUME];
+    
+    /// Capabilities assumed for clients that join without a Hello
+    pub const LEGACY: &[&str] = &[ROOMS, HISTORY];
+    
+    /// The capabilities in `ours` that the peer also advertised
+    pub fn negotiate(ours: &[&str], theirs: &[String]) -> Vec<String> {
+        ours.iter()
+            .filter(|cap| theirs.iter().any(|t| t == *cap))
+            .map(|cap| cap.to_string())
+            .collect()
+    }
+}
+
+/// Error codes carried by `Message::Error`
+pub mod error_codes {
+    /// Malformed or unexpected message
+    pub const BAD_REQUEST: u16 = 400;
+    /// Missing or wrong credentials on Join
+    pub const UNAUTHORIZED: u16 = 401;
+    /// Username is on the reserved list
+    pub const RESERVED_USERNAME: u16 = 403;
+    /// Client sent to or parted a room it is not a member of
+    pub const NOT_IN_ROOM: u16 = 403;
+    /// Client is muted or lacks operator rights
+    pub const FORBIDDEN: u16 = 403;
+    /// Target user or resource does not exist
+    pub const NOT_FOUND: u16 = 404;
+    /// Username is already used by another client
+    pub const USERNAME_TAKEN: u16 = 409;
+    /// A room with that name already exists
+    pub const ROOM_EXISTS: u16 = 409;
+    /// A file transfer with that ID is already in progress
+    pub const TRANSFER_EXISTS: u16 = 409;
+    /// Client was kicked or is banned, so reconnecting will not help
+    pub const REMOVED: u16 = 410;
+    /// Offered file is larger than the server relays
+    pub const FILE_TOO_LARGE: u16 = 413;
+    /// Username breaks the length or character rules
+    pub const INVALID_USERNAME: u16 = 422;
+    /// Client speaks a protocol version the server does not support
+    pub const UPGRADE_REQUIRED: u16 = 426;
+    /// Client is sending faster than the server's rate limit allows
+    pub const RATE_LIMITED: u16 = 429;
+    /// Recipient is offline and cannot be sent any more messages until they return
+    pub const MAILBOX_FULL: u16 = 507;
+}
+
+/// Run `f` with bincode reading and writing the layout of protocol `version`
+fn in_layout<T>(version: u16, f: impl FnOnce() -> T) -> T {
+    let previous = LAYOUT.replace(version);
+    let result = f();
+    LAYOUT.set(previous);
+    result
+}
+
+/// Whether a field added in version 2 is left out of the layout being written
+fn added_in_v2(_: &u64) -> bool {
+    LAYOUT.get() < 2
+}
+
+/// Read a field appended to a variant, as 0 in a layout that predates it
+fn added_field<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    if LAYOUT.get() < 2 {
+        return Ok(0);
     }
+    u64::deserialize(deserializer)
 }
 
 /// Get current timestamp in seconds
-fn current_timestamp() -> u64 {
+pub fn current_timestamp() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap_or_default()
         .as_secs()
 }
 
-/// Length-prefixed framing for TCP streams
+/// Length-prefixed bincode framing for TCP streams and the history log
 pub struct FramedMessage;
 
 impl FramedMessage {
     /// Maximum message size (1MB)
-    pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+    pub const MAX_MESSAGE_SIZE: usize = MAX_MESSAGE_SIZE;
     
     /// Encode a message with length prefix
     pub fn encode(message: &Message) -> Result<Vec<u8>, anyhow::Error> {
-        let data = message.to_bytes()?;
-        if data.len() > Self::MAX_MESSAGE_SIZE {
-            anyhow::bail!("Message too large: {} bytes", data.len());
-        }
-        
-        let len = data.len() as u32;
-        let mut frame = len.to_be_bytes().to_vec();
-        frame.extend(data);
-        Ok(frame)
+        BincodeCodec.encode(message)
     }
     
     /// Decode a message from a stream (call this repeatedly with incoming data)
     pub fn decode(buffer: &mut Vec<u8>) -> Result<Option<Message>, anyhow::Error> {
-        if buffer.len() < 4 {
-            return Ok(None); // Not enough data for length prefix
-        }
-        
-        // Read length prefix
-        let len_bytes: [u8; 4] = buffer[0..4].try_into()?;
-        let msg_len = u32::from_be_bytes(len_bytes) as usize;
-        
-        if msg_len > Self::MAX_MESSAGE_SIZE {
-            anyhow::bail!("Invalid message length: {}", msg_len);
-        }
-        
-        if buffer.len() < 4 + msg_len {
-            return Ok(None); // Not enough data for complete message
-        }
-        
-        // Extract message
-        let msg_data = buffer[4..4 + msg_len].to_vec();
-        buffer.drain(0..4 + msg_len);
-        
-        let message = Message::from_bytes(&msg_data)?;
-        Ok(Some(message))
+        BincodeCodec.decode(buffer)
     }
 }
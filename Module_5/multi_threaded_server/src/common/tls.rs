@@ -0,0 +1,113 @@
+use std::path::PathBuf;
+
+#[cfg(feature = "tls")]
+use std::fs::File;
+#[cfg(feature = "tls")]
+use std::io::BufReader;
+#[cfg(feature = "tls")]
+use std::path::Path;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+#[cfg(feature = "tls")]
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+/// Certificate and key a server presents to TLS clients
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerTlsConfig {
+    /// PEM file with the server certificate chain
+    pub cert_path: PathBuf,
+    /// PEM file with the private key for the certificate
+    pub key_path: PathBuf,
+    /// PEM file with CA certificates; when set, clients must present a certificate signed by one
+    pub client_ca_path: Option<PathBuf>,
+}
+
+/// How a client verifies the server and, optionally, identifies itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientTlsConfig {
+    /// PEM file with the CA certificates trusted to sign the server certificate
+    pub ca_path: PathBuf,
+    /// Name checked against the server certificate; defaults to the host in `server_addr`
+    pub server_name: Option<String>,
+    /// PEM file with a client certificate, for servers that require one
+    pub cert_path: Option<PathBuf>,
+    /// PEM file with the private key for `cert_path`
+    pub key_path: Option<PathBuf>,
+}
+
+#[cfg(feature = "tls")]
+impl ServerTlsConfig {
+    /// Load the certificates and keys into a rustls server configuration
+    pub fn build(&self) -> Result<Arc<rustls::ServerConfig>, anyhow::Error> {
+        let builder = rustls::ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_ca_path {
+            Some(path) => {
+                let roots = Arc::new(load_roots(path)?);
+                let verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(roots, provider())
+                    .build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_single_cert(load_certs(&self.cert_path)?, load_key(&self.key_path)?)?;
+        Ok(Arc::new(config))
+    }
+}
+
+#[cfg(feature = "tls")]
+impl ClientTlsConfig {
+    /// Load the trusted CAs and client identity into a rustls client configuration
+    pub fn build(&self) -> Result<Arc<rustls::ClientConfig>, anyhow::Error> {
+        let builder = rustls::ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(load_roots(&self.ca_path)?);
+        let config = match (&self.cert_path, &self.key_path) {
+            (Some(cert), Some(key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => anyhow::bail!("A client certificate needs both cert_path and key_path"),
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+/// Cryptography backend shared by every TLS configuration
+#[cfg(feature = "tls")]
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Open a PEM file for reading
+#[cfg(feature = "tls")]
+fn open_pem(path: &Path) -> Result<BufReader<File>, anyhow::Error> {
+    let file = File::open(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+    Ok(BufReader::new(file))
+}
+
+/// Read every certificate in a PEM file
+#[cfg(feature = "tls")]
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, anyhow::Error> {
+    let certs = rustls_pemfile::certs(&mut open_pem(path)?).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+/// Read the first private key in a PEM file
+#[cfg(feature = "tls")]
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, anyhow::Error> {
+    rustls_pemfile::private_key(&mut open_pem(path)?)?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", path.display()))
+}
+
+/// Read a PEM file of CA certificates into a trust store
+#[cfg(feature = "tls")]
+fn load_roots(path: &Path) -> Result<rustls::RootCertStore, anyhow::Error> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
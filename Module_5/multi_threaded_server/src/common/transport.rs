@@ -0,0 +1,239 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use crate::common::tls::{ClientTlsConfig, ServerTlsConfig};
+use crate::common::websocket::{self, WebSocketSession};
+
+/// A connection to a peer: plain TCP, TLS over TCP with the `tls` feature, or
+/// a WebSocket carrying JSON lines
+///
+/// Like `TcpStream`, a transport can be cloned so one thread reads while
+/// another writes; clones share the socket and the TLS or WebSocket session.
+pub struct Transport {
+    tcp: TcpStream,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<Mutex<rustls::Connection>>>,
+    websocket: Option<Arc<Mutex<WebSocketSession>>>,
+}
+
+impl Transport {
+    /// Wrap a TCP stream without encryption
+    pub fn plain(tcp: TcpStream) -> Self {
+        Transport {
+            tcp,
+            #[cfg(feature = "tls")]
+            tls: None,
+            websocket: None,
+        }
+    }
+    
+    /// Complete the WebSocket upgrade on an accepted stream
+    fn upgrade(tcp: TcpStream) -> io::Result<Self> {
+        let session = WebSocketSession::accept(&tcp)?;
+        Ok(Transport {
+            websocket: Some(Arc::new(Mutex::new(session))),
+            ..Transport::plain(tcp)
+        })
+    }
+    
+    /// Connect to a server, using TLS if a configuration is given
+    pub fn connect(addr: &str, tls: Option<&ClientTlsConfig>) -> Result<Self, anyhow::Error> {
+        let tcp = TcpStream::connect(addr)?;
+        tcp.set_nodelay(true)?;
+        match tls {
+            None => Ok(Transport::plain(tcp)),
+            #[cfg(feature = "tls")]
+            Some(config) => {
+                let server_name = match &config.server_name {
+                    Some(name) => name.clone(),
+                    None => host_of(addr).to_string(),
+                };
+                let server_name = rustls::pki_types::ServerName::try_from(server_name)?;
+                let conn = rustls::ClientConnection::new(config.build()?, server_name)?;
+                Ok(Transport::handshake(tcp, conn.into())?)
+            }
+            #[cfg(not(feature = "tls"))]
+            Some(_) => anyhow::bail!("TLS requested, but this build lacks the `tls` feature"),
+        }
+    }
+    
+    /// Complete the TLS handshake before any application data is exchanged
+    #[cfg(feature = "tls")]
+    fn handshake(tcp: TcpStream, mut conn: rustls::Connection) -> io::Result<Self> {
+        while conn.is_handshaking() {
+            conn.complete_io(&mut &tcp)?;
+        }
+        while conn.wants_write() {
+            conn.write_tls(&mut &tcp)?;
+        }
+        Ok(Transport {
+            tcp,
+            tls: Some(Arc::new(Mutex::new(conn))),
+            websocket: None,
+        })
+    }
+    
+    /// Create another handle to the same connection
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Transport {
+            tcp: self.tcp.try_clone()?,
+            #[cfg(feature = "tls")]
+            tls: self.tls.clone(),
+            websocket: self.websocket.clone(),
+        })
+    }
+    
+    /// The underlying socket, for timeouts and addresses
+    pub fn tcp(&self) -> &TcpStream {
+        &self.tcp
+    }
+    
+    /// Shut down the socket immediately, as `TcpStream::shutdown` does
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.tcp.shutdown(how)
+    }
+    
+    /// Tell the peer we are done (a TLS close_notify or WebSocket close frame),
+    /// then shut down the socket
+    pub fn close(&self) -> io::Result<()> {
+        if let Some(session) = &self.websocket {
+            session.lock().unwrap().close();
+        }
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            let mut conn = tls.lock().unwrap();
+            conn.send_close_notify();
+            while conn.wants_write() {
+                if conn.write_tls(&mut &self.tcp).is_err() {
+                    break;
+                }
+            }
+        }
+        self.tcp.shutdown(Shutdown::Both)
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(session) = &self.websocket {
+            return websocket::read(&self.tcp, session, buf);
+        }
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            return read_tls(&self.tcp, tls, buf);
+        }
+        (&self.tcp).read(buf)
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(session) = &self.websocket {
+            return websocket::write(session, buf);
+        }
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            let mut conn = tls.lock().unwrap();
+            // Accept what fits in the TLS buffer and push it out before the next chunk
+            let n = conn.writer().write(buf)?;
+            while conn.wants_write() {
+                conn.write_tls(&mut &self.tcp)?;
+            }
+            return Ok(n);
+        }
+        (&self.tcp).write(buf)
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        (&self.tcp).flush()
+    }
+}
+
+/// Read decrypted bytes, pulling more records off the socket as needed
+///
+/// The session lock is not held while waiting on the socket, so a writer
+/// sharing the session is never stuck behind a blocked reader.
+#[cfg(feature = "tls")]
+fn read_tls(tcp: &TcpStream, tls: &Mutex<rustls::Connection>, buf: &mut [u8]) -> io::Result<usize> {
+    let mut socket = tcp;
+    let mut raw = [0u8; 4096];
+    loop {
+        match tls.lock().unwrap().reader().read(buf) {
+            Ok(n) => return Ok(n),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        
+        let n = socket.read(&mut raw)?;
+        if n == 0 {
+            return Ok(0);
+        }
+        
+        let mut conn = tls.lock().unwrap();
+        let mut incoming = &raw[..n];
+        while !incoming.is_empty() {
+            conn.read_tls(&mut incoming)?;
+            conn.process_new_packets()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        while conn.wants_write() {
+            conn.write_tls(&mut socket)?;
+        }
+    }
+}
+
+/// The host part of a `host:port` address, without IPv6 brackets
+#[cfg(feature = "tls")]
+fn host_of(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Turns accepted TCP streams into transports, adding TLS when configured
+#[derive(Clone)]
+pub struct Acceptor {
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+    /// Expect an HTTP upgrade to WebSocket instead of raw frames
+    websocket: bool,
+}
+
+impl Acceptor {
+    /// Create an acceptor, loading certificates if TLS is configured
+    pub fn new(tls: Option<&ServerTlsConfig>) -> Result<Self, anyhow::Error> {
+        #[cfg(feature = "tls")]
+        return Ok(Acceptor {
+            tls: tls.map(|config| config.build()).transpose()?,
+            websocket: false,
+        });
+        #[cfg(not(feature = "tls"))]
+        match tls {
+            Some(_) => anyhow::bail!("TLS requested, but this build lacks the `tls` feature"),
+            None => Ok(Acceptor { websocket: false }),
+        }
+    }
+    
+    /// Create an acceptor for plain-TCP WebSocket connections
+    pub fn websocket() -> Self {
+        Acceptor {
+            #[cfg(feature = "tls")]
+            tls: None,
+            websocket: true,
+        }
+    }
+    
+    /// Wrap an accepted stream, performing the TLS or WebSocket handshake if needed
+    pub fn accept(&self, tcp: TcpStream) -> io::Result<Transport> {
+        if self.websocket {
+            return Transport::upgrade(tcp);
+        }
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls {
+            let conn = rustls::ServerConnection::new(config.clone())
+                .map_err(io::Error::other)?;
+            return Transport::handshake(tcp, conn.into());
+        }
+        Ok(Transport::plain(tcp))
+    }
+}
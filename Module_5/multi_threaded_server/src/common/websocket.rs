@@ -0,0 +1,145 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use tungstenite::handshake::HandshakeError;
+use tungstenite::{Message as Frame, WebSocket};
+
+/// The server side of a WebSocket connection, shared by a transport's clones
+///
+/// Each text frame carries one message as JSON. Reads turn frames into JSON
+/// lines and writes turn lines back into frames, so the rest of the server
+/// treats the connection like any other JSON-lines client.
+pub struct WebSocketSession {
+    socket: WebSocket<Received>,
+    /// The rest of the last frame read, newline-terminated
+    unread: Vec<u8>,
+    /// Written bytes that do not make up a whole line yet
+    unsent: Vec<u8>,
+}
+
+/// What the WebSocket reads from and writes to
+///
+/// Reads come from bytes the reader already took off the socket, so the
+/// session lock is never held while waiting for the peer.
+struct Received {
+    tcp: TcpStream,
+    bytes: Vec<u8>,
+}
+
+impl Read for Received {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.bytes.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(self.bytes.len());
+        buf[..n].copy_from_slice(&self.bytes[..n]);
+        self.bytes.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for Received {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&self.tcp).write(buf)
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        (&self.tcp).flush()
+    }
+}
+
+impl WebSocketSession {
+    /// Answer the HTTP upgrade request that opens a WebSocket connection
+    pub fn accept(tcp: &TcpStream) -> io::Result<Self> {
+        let mut attempt = tungstenite::accept(Received {
+            tcp: tcp.try_clone()?,
+            bytes: Vec::new(),
+        });
+        loop {
+            match attempt {
+                Ok(socket) => {
+                    return Ok(WebSocketSession {
+                        socket,
+                        unread: Vec::new(),
+                        unsent: Vec::new(),
+                    });
+                }
+                Err(HandshakeError::Interrupted(mut handshake)) => {
+                    if !receive(tcp, &mut handshake.get_mut().get_mut().bytes)? {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    attempt = handshake.handshake();
+                }
+                Err(HandshakeError::Failure(e)) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        }
+    }
+    
+    /// Queue a close frame and send it
+    pub fn close(&mut self) {
+        let _ = self.socket.close(None);
+        let _ = self.socket.flush();
+    }
+}
+
+/// Read the next message as a JSON line, waiting on `tcp` for more frames as needed
+pub(crate) fn read(tcp: &TcpStream, session: &Mutex<WebSocketSession>, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        {
+            let mut session = session.lock().unwrap();
+            if !session.unread.is_empty() {
+                let n = buf.len().min(session.unread.len());
+                buf[..n].copy_from_slice(&session.unread[..n]);
+                session.unread.drain(..n);
+                return Ok(n);
+            }
+            
+            match session.socket.read() {
+                Ok(frame @ (Frame::Text(_) | Frame::Binary(_))) => {
+                    let mut line = frame.into_data().to_vec();
+                    line.push(b'\n');
+                    session.unread = line;
+                    continue;
+                }
+                // Pings are answered by tungstenite; the rest carry no messages
+                Ok(_) => continue,
+                Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => return Ok(0),
+                Err(tungstenite::Error::Io(e)) => return Err(e),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        }
+        
+        let mut received = Vec::new();
+        if !receive(tcp, &mut received)? {
+            return Ok(0);
+        }
+        session.lock().unwrap().socket.get_mut().bytes.extend_from_slice(&received);
+    }
+}
+
+/// Send every complete JSON line in `buf` as a text frame
+pub(crate) fn write(session: &Mutex<WebSocketSession>, buf: &[u8]) -> io::Result<usize> {
+    let mut session = session.lock().unwrap();
+    session.unsent.extend_from_slice(buf);
+    while let Some(end) = session.unsent.iter().position(|&b| b == b'\n') {
+        let line: Vec<u8> = session.unsent.drain(..=end).collect();
+        let text = String::from_utf8(line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        session.socket.send(Frame::text(text.trim_end()))
+            .map_err(|e| match e {
+                tungstenite::Error::Io(e) => e,
+                e => io::Error::new(io::ErrorKind::BrokenPipe, e),
+            })?;
+    }
+    Ok(buf.len())
+}
+
+/// Append what the socket has to `bytes`, returning false at end of stream
+fn receive(tcp: &TcpStream, bytes: &mut Vec<u8>) -> io::Result<bool> {
+    let mut socket = tcp;
+    let mut raw = [0u8; 4096];
+    let n = socket.read(&mut raw)?;
+    bytes.extend_from_slice(&raw[..n]);
+    Ok(n > 0)
+}
@@ -1,43 +1,47 @@
-mod server;
-mod common;
-
-use std::sync::Arc;
-use std::time::Duration;
+use std::thread;
+use clap::Parser;
 use log::{info, error};
 use env_logger::Env;
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
 
-use server::listener::{Server, ServerConfig};
+use multi_threaded_server::server::listener::Server;
+use multi_threaded_server::server::settings::ServerSettings;
 
 fn main() -> Result<(), anyhow::Error> {
     // Initialize logging
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     
-    info!("Starting multi-threaded server...");
+    // Flags and environment first, then the config file, then defaults
+    let settings = ServerSettings::parse();
+    let config = settings.resolve()?;
     
-    // Parse command line arguments
-    let args: Vec<String> = std::env::args().collect();
-    let bind_addr = args.get(1).map(|s| s.as_str()).unwrap_or("127.0.0.1:8080");
+    info!("Starting multi-threaded server...");
     
-    // Create server config
-    let config = ServerConfig {
-        bind_addr: bind_addr.to_string(),
-        max_connections: 100,
-        connection_timeout: Duration::from_secs(30),
-    };
+    let server = Server::bind(config)?;
+    info!("Bound to {}", server.local_addr()?);
     
     // Setup Ctrl+C handler
-    let running = Arc::new(std::sync::atomic::AtomicBool::new(true));
-    let r = running.clone();
-    
+    let shutdown = server.shutdown_handle();
     ctrlc::set_handler(move || {
         info!("Received shutdown signal, cleaning up...");
-        r.store(false, std::sync::atomic::Ordering::SeqCst);
+        shutdown.shutdown("Server is shutting down");
     })?;
     
-    // Run server
-    let server = Server::new(config);
-    
-    if let Err(e) = server.run() {
+    // SIGHUP re-reads the config file and ban list
+    let reload = server.reload_handle();
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            info!("Received SIGHUP, reloading configuration...");
+            if let Err(e) = settings.resolve().and_then(|config| reload.reload(config)) {
+                error!("Failed to reload configuration: {}", e);
+            }
+        }
+    });
+    
+    // Run server until shutdown completes
+    if let Err(e) = server.serve() {
         error!("Server error: {}", e);
     }
     
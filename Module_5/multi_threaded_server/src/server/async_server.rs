@@ -0,0 +1,416 @@
+use std::future::Future;
+use std::net::{Shutdown, SocketAddr};
+use std::os::fd::AsFd;
+use std::sync::Arc;
+use log::{info, warn, error, debug};
+use futures_util::StreamExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::time::timeout;
+use tokio_util::codec::FramedRead;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use crate::common::codec::{Frame, MessageCodec, WireFormat};
+use crate::common::protocol::Message;
+use crate::common::transport::Transport;
+use crate::server::auth::Authenticator;
+use crate::server::connection_manager::{Notify, Outbound, OutboundQueue};
+use crate::server::handler::{
+    end_session, on_join_message, process_received, report_malformed, start_session, HandlerContext,
+    JoinState, JoinStep, Session,
+};
+use crate::server::listener::{bind_metrics, IoModel, ReloadHandle, Server, ServerConfig, ShutdownHandle};
+
+/// Reason sent to clients when the shutdown future completes before the handle is used
+const DEFAULT_SHUTDOWN_REASON: &str = "Server is shutting down";
+
+/// Tokio counterpart to `Server`, serving every client as tasks on the current runtime
+///
+/// Behaves like the blocking server, except that `io_model` is ignored and TLS
+/// and WebSocket clients are not supported. Clients are read through
+/// `MessageCodec`, and their messages are handled on tokio's blocking pool.
+pub struct AsyncServer {
+    server: Server,
+    /// The main listener first, then the extra listeners in config order
+    listeners: Vec<(TcpListener, WireFormat)>,
+    metrics_listener: Option<std::net::TcpListener>,
+}
+
+impl AsyncServer {
+    /// Bind the listening sockets without accepting connections yet
+    pub async fn bind(config: ServerConfig) -> Result<Self, anyhow::Error> {
+        if config.tls.is_some() {
+            anyhow::bail!("TLS is not supported by the async server");
+        }
+        if config.websocket_addr.is_some() {
+            anyhow::bail!("WebSocket clients are not supported by the async server");
+        }
+        // Tokio schedules the connections, so there is no I/O model to pick
+        if config.io_model != IoModel::ThreadPerConnection {
+            anyhow::bail!("The async server does not use the {:?} I/O model", config.io_model);
+        }
+        
+        let mut listeners = vec![(TcpListener::bind(&config.bind_addr).await?, config.wire_format)];
+        for extra in &config.extra_listeners {
+            listeners.push((TcpListener::bind(&extra.bind_addr).await?, extra.wire_format));
+        }
+        let metrics_listener = bind_metrics(&config)?;
+        Ok(AsyncServer {
+            server: Server::new(config),
+            listeners,
+            metrics_listener,
+        })
+    }
+    
+    /// The address the main listener actually bound to (useful with port 0)
+    pub fn local_addr(&self) -> Result<SocketAddr, anyhow::Error> {
+        Ok(self.listeners[0].0.local_addr()?)
+    }
+    
+    /// The addresses of every listener, main listener first
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>, anyhow::Error> {
+        let mut addrs = Vec::new();
+        for (listener, _) in &self.listeners {
+            addrs.push(listener.local_addr()?);
+        }
+        Ok(addrs)
+    }
+    
+    /// The address of the metrics endpoint, if one is configured
+    pub fn metrics_addr(&self) -> Result<Option<SocketAddr>, anyhow::Error> {
+        match &self.metrics_listener {
+            Some(listener) => Ok(Some(listener.local_addr()?)),
+            None => Ok(None),
+        }
+    }
+    
+    /// Get a handle that can stop the server from another thread or task
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.server.shutdown_handle()
+    }
+    
+    /// Get a handle that can change the server's settings from another thread or task
+    pub fn reload_handle(&self) -> ReloadHandle {
+        self.server.reload_handle()
+    }
+    
+    /// Use a custom authenticator instead of the one described by `config.auth`
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.server = self.server.with_authenticator(authenticator);
+        self
+    }
+    
+    /// Accept connections until a shutdown has been requested through the handle
+    pub async fn serve(self) -> Result<(), anyhow::Error> {
+        self.serve_with_shutdown(std::future::pending()).await
+    }
+    
+    /// Accept connections until `signal` completes or the shutdown handle is used
+    ///
+    /// Clients are sent the reason given to the handle, or a generic one when
+    /// `signal` completed first.
+    pub async fn serve_with_shutdown(self, signal: impl Future<Output = ()>) -> Result<(), anyhow::Error> {
+        let contexts = self.server.contexts(self.listeners.iter().map(|(_, wire_format)| *wire_format))?;
+        let manager = contexts[0].manager.clone();
+        let shutdown_timeout = contexts[0].config.get().shutdown_timeout;
+        let shutdown = self.server.shutdown_handle();
+        let metrics_handle = self.server.serve_metrics(self.metrics_listener)?;
+        
+        let server = Arc::new(self.server);
+        let stopping = CancellationToken::new();
+        let connections = TaskTracker::new();
+        let mut accepting = Vec::new();
+        for ((listener, wire_format), ctx) in self.listeners.into_iter().zip(contexts) {
+            info!("Server listening on {} ({:?})", listener.local_addr()?, wire_format);
+            accepting.push(tokio::spawn(accept_loop(
+                listener,
+                ctx,
+                server.clone(),
+                connections.clone(),
+                stopping.clone(),
+            )));
+        }
+        
+        tokio::select! {
+            _ = signal => {}
+            _ = wait_for_handle(&shutdown) => {}
+        }
+        if !shutdown.is_shutdown() {
+            // Also stops the metrics endpoint, which watches the handle
+            shutdown.shutdown(DEFAULT_SHUTDOWN_REASON);
+        }
+        
+        stopping.cancel();
+        for task in accepting {
+            let _ = task.await;
+        }
+        let reason = shutdown.reason();
+        info!("Shutting down ({} clients connected): {}", manager.client_count(), reason);
+        
+        // Joined clients get the goodbye and are closed by their writer tasks
+        blocking(move || manager.close_all(&Message::Shutdown { reason })).await;
+        connections.close();
+        if timeout(shutdown_timeout, connections.wait()).await.is_err() {
+            warn!("{} connections did not close before the shutdown deadline", connections.len());
+        }
+        
+        if let Some(handle) = metrics_handle {
+            let _ = tokio::task::spawn_blocking(move || handle.join()).await;
+        }
+        Ok(())
+    }
+}
+
+/// Resolve once a shutdown has been requested through `handle`
+async fn wait_for_handle(handle: &ShutdownHandle) {
+    // The handle is a plain flag, so check it as often as the blocking server does
+    let mut ticks = tokio::time::interval(std::time::Duration::from_millis(100));
+    while !handle.is_shutdown() {
+        ticks.tick().await;
+    }
+}
+
+/// Spawn a task for every connection accepted on `listener` until `stopping` is cancelled
+async fn accept_loop(
+    listener: TcpListener,
+    ctx: HandlerContext,
+    server: Arc<Server>,
+    connections: TaskTracker,
+    stopping: CancellationToken,
+) {
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            },
+            _ = stopping.cancelled() => break,
+        };
+        if !server.admit(&addr, &ctx) {
+            continue;
+        }
+        
+        connections.spawn(serve_client(stream, addr, ctx.clone(), stopping.clone()));
+        ctx.manager.metrics().connection_accepted();
+    }
+}
+
+/// Serve one client from its Join until it leaves or the server shuts down
+async fn serve_client(stream: TcpStream, addr: SocketAddr, ctx: HandlerContext, stopping: CancellationToken) {
+    info!("New connection from: {}", addr);
+    
+    let wakeup = Arc::new(tokio::sync::Notify::new());
+    let notify: Notify = {
+        let wakeup = wakeup.clone();
+        Arc::new(move || wakeup.notify_one())
+    };
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = FramedRead::new(reader, MessageCodec::new(ctx.wire_format));
+    
+    // Clients that never joined get no goodbye, so drop them as soon as we stop
+    let joined = tokio::select! {
+        joined = wait_for_join(&mut reader, &mut writer, &addr, &ctx, &notify) => joined,
+        _ = stopping.cancelled() => None,
+    };
+    let Some((session, queue, control)) = joined else {
+        return;
+    };
+    let session = Arc::new(session);
+    
+    {
+        let (ctx, session) = (ctx.clone(), session.clone());
+        blocking(move || start_session(&addr, &ctx, &session)).await;
+    }
+    let writing = tokio::spawn(write_queued(writer, control, addr, queue.clone(), wakeup, ctx.clone()));
+    
+    // Anything sent right after the Join is already waiting in the reader
+    let mut limiter = None;
+    loop {
+        let frame = match timeout(ctx.config.get().connection_timeout, reader.next()).await {
+            Err(_) => {
+                debug!("Connection from {} timed out", addr);
+                break;
+            }
+            Ok(None) => {
+                debug!("Connection closed by client: {}", addr);
+                break;
+            }
+            Ok(Some(Err(e))) => {
+                debug!("Error reading from {}: {}", addr, e);
+                break;
+            }
+            Ok(Some(Ok(frame))) => frame,
+        };
+        
+        let (ctx, session) = (ctx.clone(), session.clone());
+        let handled = blocking(move || {
+            let mut limiter = limiter;
+            let open = match frame {
+                Frame::Message(msg, size) => {
+                    ctx.manager.metrics().bytes_in(size);
+                    process_received(msg, size, &addr, &ctx, &session, &mut limiter)
+                }
+                Frame::Malformed(e) => {
+                    report_malformed(&addr, &ctx, &e);
+                    true
+                }
+            };
+            (open, limiter)
+        }).await;
+        match handled {
+            Some((true, kept)) => limiter = kept,
+            _ => break,
+        }
+    }
+    
+    // Unregister, then let the writer deliver what is already queued
+    {
+        let ctx = ctx.clone();
+        blocking(move || end_session(&addr, &ctx)).await;
+    }
+    queue.close();
+    let _ = writing.await;
+}
+
+/// Run handler code on the blocking pool
+///
+/// Handlers read and write files, hash passwords, take locks and, under the
+/// Block policy, wait for room in slow clients' queues. Returns None if the
+/// code panicked.
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> Option<T> {
+    tokio::task::spawn_blocking(work).await.ok()
+}
+
+/// Read until the client has joined, answering it directly in the meantime
+///
+/// Returns the session, its outbound queue and a handle for closing the socket,
+/// or None if the connection should be closed.
+async fn wait_for_join(
+    reader: &mut FramedRead<OwnedReadHalf, MessageCodec>,
+    writer: &mut OwnedWriteHalf,
+    addr: &SocketAddr,
+    ctx: &HandlerContext,
+    notify: &Notify,
+) -> Option<(Session, OutboundQueue, std::net::TcpStream)> {
+    // A blocking handle on the same socket lets the manager close it
+    let control = match writer.as_ref().as_fd().try_clone_to_owned() {
+        Ok(fd) => std::net::TcpStream::from(fd),
+        Err(e) => {
+            error!("Failed to register {}: {}", addr, e);
+            return None;
+        }
+    };
+    let mut state = JoinState::default();
+    
+    loop {
+        let msg = match timeout(ctx.config.get().connection_timeout, reader.next()).await {
+            Ok(Some(Ok(Frame::Message(msg, size)))) => {
+                ctx.manager.metrics().bytes_in(size);
+                msg
+            }
+            Ok(Some(Ok(Frame::Malformed(e)))) => {
+                ctx.manager.metrics().decode_error();
+                error!("Failed to get join message from {}: {}", addr, e);
+                return None;
+            }
+            Ok(Some(Err(e))) => {
+                error!("Failed to get join message from {}: {}", addr, e);
+                return None;
+            }
+            Ok(None) => {
+                debug!("Connection closed by client: {}", addr);
+                return None;
+            }
+            Err(_) => {
+                error!("Failed to get join message from {}: timed out", addr);
+                return None;
+            }
+        };
+        
+        let (step, returned, registered) = {
+            let (ctx, addr, notify) = (ctx.clone(), *addr, notify.clone());
+            let control = control.try_clone().ok()?;
+            blocking(move || {
+                let mut state = state;
+                let mut registered = None;
+                let register = |username: &str, version| {
+                    let queue = ctx.manager.add_polled_client(
+                        addr,
+                        username.to_string(),
+                        Transport::plain(control.try_clone()?),
+                        ctx.wire_format,
+                        version,
+                        notify,
+                    )?;
+                    let added = queue.is_some();
+                    registered = queue.map(|queue| (queue, control));
+                    Ok(added)
+                };
+                let step = on_join_message(msg, &addr, &ctx, &mut state, register);
+                (step, state, registered)
+            }).await?
+        };
+        state = returned;
+        
+        match step {
+            Ok(JoinStep::Reply(reply)) => write_direct(writer, ctx, &reply).await?,
+            Ok(JoinStep::Joined(session)) => {
+                return registered.map(|(queue, control)| (session, queue, control));
+            }
+            Ok(JoinStep::Reject(reply, e)) => {
+                error!("Failed to get join message from {}: {}", addr, e);
+                let _ = write_direct(writer, ctx, &reply).await;
+                return None;
+            }
+            Err(e) => {
+                error!("Failed to register {}: {}", addr, e);
+                return None;
+            }
+        }
+    }
+}
+
+/// Send a reply to a client that has no outbound queue yet
+async fn write_direct(writer: &mut OwnedWriteHalf, ctx: &HandlerContext, message: &Message) -> Option<()> {
+    let bytes = ctx.wire_format.codec().encode(message).ok()?;
+    writer.write_all(&bytes).await.ok()?;
+    ctx.manager.metrics().message_out(message, 1);
+    ctx.manager.metrics().bytes_out(bytes.len());
+    Some(())
+}
+
+/// Write queued frames until the queue hands over its Close marker, then close the socket
+async fn write_queued(
+    mut writer: OwnedWriteHalf,
+    control: std::net::TcpStream,
+    addr: SocketAddr,
+    queue: OutboundQueue,
+    wakeup: Arc<tokio::sync::Notify>,
+    ctx: HandlerContext,
+) {
+    'writing: loop {
+        while let Some(item) = queue.pop() {
+            match item {
+                Outbound::Frame(frame) => {
+                    if let Err(e) = writer.write_all(&frame).await {
+                        warn!("Failed to send to {}: {}", addr, e);
+                        break 'writing;
+                    }
+                    ctx.manager.metrics().bytes_out(frame.len());
+                }
+                Outbound::Close => break 'writing,
+            }
+        }
+        wakeup.notified().await;
+    }
+    
+    // Also ends the read side, so the reader notices a kick or shutdown
+    let _ = writer.flush().await;
+    let _ = control.shutdown(Shutdown::Both);
+    debug!("Writer task for {} finished", addr);
+}
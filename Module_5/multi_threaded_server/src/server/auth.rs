@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// Number of SHA-256 rounds used to stretch a salted password
+const HASH_ROUNDS: u32 = 10_000;
+
+/// Reasons a Join can fail authentication
+#[derive(Error, Debug, PartialEq)]
+pub enum AuthError {
+    #[error("Credentials required")]
+    MissingCredentials,
+    
+    #[error("Invalid credentials")]
+    InvalidCredentials,
+}
+
+/// Decides whether a client may join under a given username
+pub trait Authenticator: Send + Sync {
+    /// Check the credentials sent with a Join message
+    fn authenticate(&self, username: &str, credentials: Option<&str>) -> Result<(), AuthError>;
+}
+
+/// Which built-in authenticator a server uses
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthConfig {
+    /// Anyone may join (the default, and handy for tests)
+    AllowAll,
+    /// Every client must present the same shared token
+    Token(String),
+    /// Users and salted password hashes are read from a file
+    CredentialsFile(PathBuf),
+}
+
+impl AuthConfig {
+    /// Create the authenticator this configuration describes
+    pub fn build(&self) -> Result<Arc<dyn Authenticator>, anyhow::Error> {
+        Ok(match self {
+            AuthConfig::AllowAll => Arc::new(AllowAll),
+            AuthConfig::Token(token) => Arc::new(StaticToken::new(token.clone())),
+            AuthConfig::CredentialsFile(path) => Arc::new(CredentialsFile::load(path)?),
+        })
+    }
+}
+
+/// Accepts every client
+pub struct AllowAll;
+
+impl Authenticator for AllowAll {
+    fn authenticate(&self, _username: &str, _credentials: Option<&str>) -> Result<(), AuthError> {
+        Ok(())
+    }
+}
+
+/// Accepts clients that present a fixed shared token
+pub struct StaticToken {
+    token: String,
+}
+
+impl StaticToken {
+    pub fn new(token: String) -> Self {
+        StaticToken { token }
+    }
+}
+
+impl Authenticator for StaticToken {
+    fn authenticate(&self, _username: &str, credentials: Option<&str>) -> Result<(), AuthError> {
+        let presented = credentials.ok_or(AuthError::MissingCredentials)?;
+        if constant_time_eq(presented.as_bytes(), self.token.as_bytes()) {
+            Ok(())
+        } else {
+            Err(AuthError::InvalidCredentials)
+        }
+    }
+}
+
+/// Salted password hash for one user
+struct PasswordEntry {
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+/// Accepts users listed in a credentials file with the right password
+///
+/// Each non-empty line that does not start with `#` has the form
+/// `username:salt_hex:hash_hex`; see [`CredentialsFile::entry`].
+pub struct CredentialsFile {
+    users: HashMap<String, PasswordEntry>,
+}
+
+impl CredentialsFile {
+    /// Read users from a credentials file
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        Self::parse(&contents)
+    }
+    
+    /// Parse the contents of a credentials file
+    pub fn parse(contents: &str) -> Result<Self, anyhow::Error> {
+        let mut users = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            
+            let fields: Vec<&str> = line.split(':').collect();
+            let [username, salt, hash] = fields[..] else {
+                anyhow::bail!("Line {}: expected username:salt:hash", number + 1);
+            };
+            users.insert(username.to_string(), PasswordEntry {
+                salt: hex::decode(salt)?,
+                hash: hex::decode(hash)?,
+            });
+        }
+        Ok(CredentialsFile { users })
+    }
+    
+    /// Build a credentials file line for a user with a fresh random salt
+    pub fn entry(username: &str, password: &str) -> String {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let hash = hash_password(&salt, password);
+        format!("{}:{}:{}", username, hex::encode(salt), hex::encode(hash))
+    }
+}
+
+impl Authenticator for CredentialsFile {
+    fn authenticate(&self, username: &str, credentials: Option<&str>) -> Result<(), AuthError> {
+        let password = credentials.ok_or(AuthError::MissingCredentials)?;
+        // Unknown users get the same answer as a wrong password
+        let entry = self.users.get(username).ok_or(AuthError::InvalidCredentials)?;
+        if constant_time_eq(&hash_password(&entry.salt, password), &entry.hash) {
+            Ok(())
+        } else {
+            Err(AuthError::InvalidCredentials)
+        }
+    }
+}
+
+/// Hash a password with its salt, repeated to slow down guessing
+fn hash_password(salt: &[u8], password: &str) -> Vec<u8> {
+    let mut digest = Sha256::new()
+        .chain_update(salt)
+        .chain_update(password.as_bytes())
+        .finalize();
+    for _ in 1..HASH_ROUNDS {
+        digest = Sha256::new().chain_update(digest).chain_update(salt).finalize();
+    }
+    digest.to_vec()
+}
+
+/// Compare two byte strings without leaking where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,46 +1,340 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr, Shutdown};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError, SendTimeoutError};
 use log::{info, warn, error, debug};
 
-use crate::common::protocol::{Message, FramedMessage};
+use crate::common::codec::WireFormat;
+use crate::common::protocol::{Message, RoomInfo, UserInfo, DEFAULT_ROOM, current_timestamp};
+use crate::common::transport::Transport;
+use crate::server::metrics::Metrics;
+
+/// What to do when a client's outbound queue is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowConsumerPolicy {
+    /// Discard the oldest queued frame to make room for the new one
+    DropOldest,
+    /// Disconnect the client
+    Disconnect,
+    /// Wait up to the given time for room, then discard the new frame
+    ///
+    /// Only for thread-per-connection servers, where the wait holds up just
+    /// the sending client's handler.
+    Block(Duration),
+}
+
+/// Item waiting in a client's outbound queue
+pub(crate) enum Outbound {
+    Frame(Arc<Vec<u8>>),
+    /// Flush everything queued so far, then close the connection
+    Close,
+}
+
+/// Called whenever something is queued for a client drained by an event loop
+pub(crate) type Notify = Arc<dyn Fn() + Send + Sync>;
+
+/// Bounded queue of frames drained by a client's writer thread or event loop
+#[derive(Clone)]
+pub struct OutboundQueue {
+    tx: Sender<Outbound>,
+    // Kept so the oldest frame can be discarded under `DropOldest`
+    rx: Receiver<Outbound>,
+    policy: SlowConsumerPolicy,
+    dropped: Arc<AtomicU64>,
+    stream: Arc<Transport>,
+    /// Wakes the event loop draining the queue; None when a writer thread does
+    notify: Option<Notify>,
+}
+
+impl OutboundQueue {
+    /// Create a queue for `stream` and spawn the writer thread that drains it
+    fn spawn(
+        stream: Transport,
+        addr: SocketAddr,
+        capacity: usize,
+        policy: SlowConsumerPolicy,
+        metrics: Arc<Metrics>,
+    ) -> std::io::Result<Self> {
+        let (tx, rx) = bounded(capacity.max(1));
+        let writer_rx: Receiver<Outbound> = rx.clone();
+        let mut writer = stream.try_clone()?;
+        
+        thread::spawn(move || {
+            // Ends once the connection has been removed and every sender dropped
+            for item in writer_rx.iter() {
+                match item {
+                    Outbound::Frame(bytes) => {
+                        if let Err(e) = writer.write_all(&bytes) {
+                            warn!("Failed to send to {}: {}", addr, e);
+                            let _ = writer.shutdown(Shutdown::Both);
+                            break;
+                        }
+                        metrics.bytes_out(bytes.len());
+                    }
+                    Outbound::Close => {
+                        let _ = writer.flush();
+                        let _ = writer.close();
+                        break;
+                    }
+                }
+            }
+            debug!("Writer thread for {} finished", addr);
+        });
+        
+        Ok(OutboundQueue {
+            tx,
+            rx,
+            policy,
+            dropped: Arc::new(AtomicU64::new(0)),
+            stream: Arc::new(stream),
+            notify: None,
+        })
+    }
+    
+    /// Create a queue for `stream` that an event loop drains with `pop`
+    fn polled(stream: Transport, capacity: usize, policy: SlowConsumerPolicy, notify: Notify) -> Self {
+        let (tx, rx) = bounded(capacity.max(1));
+        OutboundQueue {
+            tx,
+            rx,
+            policy,
+            dropped: Arc::new(AtomicU64::new(0)),
+            stream: Arc::new(stream),
+            notify: Some(notify),
+        }
+    }
+    
+    /// Take the next queued item without waiting
+    pub(crate) fn pop(&self) -> Option<Outbound> {
+        self.rx.try_recv().ok()
+    }
+    
+    /// Queue a frame according to the slow-consumer policy
+    ///
+    /// Returns false if the frame could not be queued.
+    fn push(&self, frame: Arc<Vec<u8>>) -> bool {
+        let queued = self.enqueue(Outbound::Frame(frame));
+        if queued {
+            self.wake();
+        }
+        queued
+    }
+    
+    /// Put an item in the queue without waking whoever drains it
+    fn enqueue(&self, item: Outbound) -> bool {
+        match self.policy {
+            SlowConsumerPolicy::DropOldest => {
+                let mut item = item;
+                loop {
+                    match self.tx.try_send(item) {
+                        Ok(()) => return true,
+                        Err(TrySendError::Full(rejected)) => {
+                            match self.rx.try_recv() {
+                                // The connection is closing, so the new frame would never be written
+                                Ok(Outbound::Close) => {
+                                    self.queue_close();
+                                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                                    return false;
+                                }
+                                Ok(Outbound::Frame(_)) => {
+                                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                                }
+                                Err(_) => {}
+                            }
+                            item = rejected;
+                        }
+                        Err(TrySendError::Disconnected(_)) => return false,
+                    }
+                }
+            }
+            SlowConsumerPolicy::Disconnect => match self.tx.try_send(item) {
+                Ok(()) => true,
+                Err(_) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    warn!("Outbound queue full, disconnecting slow client");
+                    let _ = self.stream.shutdown(Shutdown::Both);
+                    false
+                }
+            },
+            SlowConsumerPolicy::Block(timeout) => match self.tx.send_timeout(item, timeout) {
+                Ok(()) => true,
+                Err(SendTimeoutError::Timeout(_)) | Err(SendTimeoutError::Disconnected(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    false
+                }
+            },
+        }
+    }
+    
+    /// Ask the writer to flush what is queued and then close the connection
+    pub(crate) fn close(&self) {
+        self.queue_close();
+        self.wake();
+    }
+    
+    /// Close the connection at once, without flushing what is queued
+    fn abort(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+        self.close();
+    }
+    
+    /// Queue the close marker, discarding the oldest frames if there is no room
+    fn queue_close(&self) {
+        let mut item = Outbound::Close;
+        // The close marker always gets through, even if that costs a queued frame
+        while let Err(TrySendError::Full(rejected)) = self.tx.try_send(item) {
+            // Taking out an earlier marker is fine: it goes straight back in
+            if let Ok(Outbound::Frame(_)) = self.rx.try_recv() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            item = rejected;
+        }
+    }
+    
+    /// Tell the event loop draining this queue that there is work
+    fn wake(&self) {
+        if let Some(notify) = &self.notify {
+            notify();
+        }
+    }
+    
+    /// Number of frames discarded because the client could not keep up
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// A client's address, queue, wire format and protocol version, snapshotted for sending
+type Recipient = (SocketAddr, OutboundQueue, WireFormat, u16);
 
 /// Represents a connected client
 pub struct ClientConnection {
     pub username: String,
     pub addr: SocketAddr,
-    pub writer: Arc<Mutex<std::net::TcpStream>>,
+    pub outbound: OutboundQueue,
+    /// Codec used for everything sent to this client
+    pub wire_format: WireFormat,
+    /// Protocol version negotiated in the Hello, which decides the frame layout
+    pub version: u16,
+    /// Unix timestamp of when the client joined
+    pub connected_at: u64,
+    /// When the client last sent something other than a heartbeat
+    pub last_active: Instant,
+    /// Capabilities negotiated when the client joined
+    pub capabilities: Vec<String>,
+    /// Whether private messages are relayed to the client yet; set once its mail is handed over
+    pub reachable: bool,
 }
 
 /// Manages all active client connections
 #[derive(Clone)]
 pub struct ConnectionManager {
     clients: Arc<Mutex<HashMap<SocketAddr, ClientConnection>>>,
+    /// Room name to member addresses; never locked together with `clients`
+    rooms: Arc<Mutex<HashMap<String, HashSet<SocketAddr>>>>,
+    queue_capacity: usize,
+    policy: SlowConsumerPolicy,
+    metrics: Arc<Metrics>,
+}
+
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ConnectionManager {
     pub fn new() -> Self {
+        Self::with_outbound(256, SlowConsumerPolicy::DropOldest)
+    }
+    
+    /// Create a manager whose clients get outbound queues of the given size
+    pub fn with_outbound(queue_capacity: usize, policy: SlowConsumerPolicy) -> Self {
+        let mut rooms = HashMap::new();
+        rooms.insert(DEFAULT_ROOM.to_string(), HashSet::new());
+        
         ConnectionManager {
             clients: Arc::new(Mutex::new(HashMap::new())),
+            rooms: Arc::new(Mutex::new(rooms)),
+            queue_capacity,
+            policy,
+            metrics: Arc::new(Metrics::new()),
         }
     }
     
+    /// Counters shared by everything that serves this manager's clients
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+    
     /// Add a new client connection
+    ///
+    /// Returns `Ok(false)` if another client already uses the username
+    /// (compared case-insensitively).
     pub fn add_client(
         &self,
         addr: SocketAddr,
         username: String,
-        stream: std::net::TcpStream,
-    ) {
+        stream: Transport,
+        wire_format: WireFormat,
+        version: u16,
+    ) -> std::io::Result<bool> {
+        let registered = self.insert_client(addr, username, wire_format, version, || {
+            OutboundQueue::spawn(stream, addr, self.queue_capacity, self.policy, self.metrics.clone())
+        })?;
+        Ok(registered.is_some())
+    }
+    
+    /// Add a client whose outbound queue is drained by an event loop
+    ///
+    /// Returns the queue to drain, or `Ok(None)` if the username is taken.
+    pub(crate) fn add_polled_client(
+        &self,
+        addr: SocketAddr,
+        username: String,
+        stream: Transport,
+        wire_format: WireFormat,
+        version: u16,
+        notify: Notify,
+    ) -> std::io::Result<Option<OutboundQueue>> {
+        self.insert_client(addr, username, wire_format, version, || {
+            Ok(OutboundQueue::polled(stream, self.queue_capacity, self.policy, notify))
+        })
+    }
+    
+    fn insert_client(
+        &self,
+        addr: SocketAddr,
+        username: String,
+        wire_format: WireFormat,
+        version: u16,
+        outbound: impl FnOnce() -> std::io::Result<OutboundQueue>,
+    ) -> std::io::Result<Option<OutboundQueue>> {
         let mut clients = self.clients.lock().unwrap();
+        if clients.values().any(|c| c.username.eq_ignore_ascii_case(&username)) {
+            return Ok(None);
+        }
+        
+        let outbound = outbound()?;
+        info!("Client added: {} at {}", username, addr);
         let client = ClientConnection {
             username,
             addr,
-            writer: Arc::new(Mutex::new(stream.try_clone().unwrap())),
+            outbound: outbound.clone(),
+            wire_format,
+            version,
+            connected_at: current_timestamp(),
+            last_active: Instant::now(),
+            capabilities: Vec::new(),
+            reachable: false,
         };
         clients.insert(addr, client);
-        info!("Client added: {} at {}", username, addr);
+        self.metrics.client_joined();
+        Ok(Some(outbound))
     }
     
     /// Remove a client connection
@@ -48,7 +342,13 @@ impl ConnectionManager {
         let mut clients = self.clients.lock().unwrap();
         let client = clients.remove(addr);
         if let Some(ref c) = client {
-            info!("Client removed: {} at {}", c.username, addr);
+            self.metrics.client_left();
+            info!(
+                "Client removed: {} at {} ({} frames dropped)",
+                c.username,
+                addr,
+                c.outbound.dropped()
+            );
         }
         client
     }
@@ -59,61 +359,286 @@ impl ConnectionManager {
         clients.get(addr).map(|c| c.username.clone())
     }
     
+    /// Find the address of the client using a given username
+    pub fn find_by_username(&self, username: &str) -> Option<SocketAddr> {
+        let clients = self.clients.lock().unwrap();
+        clients.iter()
+            .find(|(_, c)| c.username.eq_ignore_ascii_case(username))
+            .map(|(addr, _)| *addr)
+    }
+    
+    /// Find the address of a client private messages can be relayed to
+    pub fn find_reachable(&self, username: &str) -> Option<SocketAddr> {
+        let clients = self.clients.lock().unwrap();
+        clients.iter()
+            .find(|(_, c)| c.reachable && c.username.eq_ignore_ascii_case(username))
+            .map(|(addr, _)| *addr)
+    }
+    
+    /// Start relaying private messages to a client
+    pub fn set_reachable(&self, addr: &SocketAddr) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get_mut(addr) {
+            client.reachable = true;
+        }
+    }
+    
+    /// Find the addresses of every client connected from an IP address
+    pub fn find_by_ip(&self, ip: IpAddr) -> Vec<SocketAddr> {
+        let clients = self.clients.lock().unwrap();
+        clients.keys().filter(|addr| addr.ip() == ip).copied().collect()
+    }
+    
     /// Get all connected usernames
     pub fn get_all_usernames(&self) -> Vec<String> {
         let clients = self.clients.lock().unwrap();
         clients.values().map(|c| c.username.clone()).collect()
     }
     
+    /// Get every connected user with connection and idle times, sorted by name
+    pub fn get_user_info(&self) -> Vec<UserInfo> {
+        let clients = self.clients.lock().unwrap();
+        let mut users: Vec<UserInfo> = clients.values()
+            .map(|c| UserInfo {
+                username: c.username.clone(),
+                connected_at: c.connected_at,
+                idle_secs: c.last_active.elapsed().as_secs(),
+            })
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+    
+    /// Record activity from a client, resetting its idle time
+    pub fn touch(&self, addr: &SocketAddr) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get_mut(addr) {
+            client.last_active = Instant::now();
+        }
+    }
+    
+    /// Record the capabilities a client negotiated
+    pub fn set_capabilities(&self, addr: &SocketAddr, capabilities: Vec<String>) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get_mut(addr) {
+            client.capabilities = capabilities;
+        }
+    }
+    
+    /// Check whether a connected client negotiated a capability
+    pub fn supports(&self, addr: &SocketAddr, capability: &str) -> bool {
+        let clients = self.clients.lock().unwrap();
+        clients.get(addr).is_some_and(|c| c.capabilities.iter().any(|cap| cap == capability))
+    }
+    
     /// Get count of connected clients
     pub fn client_count(&self) -> usize {
         let clients = self.clients.lock().unwrap();
         clients.len()
     }
     
+    /// Number of frames dropped for a client because it could not keep up
+    pub fn dropped_frames(&self, addr: &SocketAddr) -> Option<u64> {
+        let clients = self.clients.lock().unwrap();
+        clients.get(addr).map(|c| c.outbound.dropped())
+    }
+    
+    /// Create an empty room; returns false if it already exists
+    pub fn create_room(&self, room: &str) -> bool {
+        let mut rooms = self.rooms.lock().unwrap();
+        if rooms.contains_key(room) {
+            return false;
+        }
+        rooms.insert(room.to_string(), HashSet::new());
+        info!("Room created: {}", room);
+        true
+    }
+    
+    /// Add a client to a room, returning the member usernames afterwards
+    ///
+    /// Returns None if the room does not exist.
+    pub fn join_room(&self, room: &str, addr: &SocketAddr) -> Option<Vec<String>> {
+        let members: Vec<SocketAddr> = {
+            let mut rooms = self.rooms.lock().unwrap();
+            let members = rooms.get_mut(room)?;
+            members.insert(*addr);
+            members.iter().copied().collect()
+        };
+        
+        let clients = self.clients.lock().unwrap();
+        Some(members.iter()
+            .filter_map(|a| clients.get(a).map(|c| c.username.clone()))
+            .collect())
+    }
+    
+    /// Remove a client from a room; returns false if it was not a member
+    ///
+    /// Rooms other than the default room are deleted once empty.
+    pub fn part_room(&self, room: &str, addr: &SocketAddr) -> bool {
+        let mut rooms = self.rooms.lock().unwrap();
+        let removed = match rooms.get_mut(room) {
+            Some(members) => members.remove(addr),
+            None => return false,
+        };
+        if room != DEFAULT_ROOM && rooms.get(room).is_some_and(|m| m.is_empty()) {
+            rooms.remove(room);
+            info!("Room removed: {}", room);
+        }
+        removed
+    }
+    
+    /// Remove a client from every room, returning the rooms it was in
+    pub fn part_all_rooms(&self, addr: &SocketAddr) -> Vec<String> {
+        let joined: Vec<String> = {
+            let rooms = self.rooms.lock().unwrap();
+            rooms.iter()
+                .filter(|(_, members)| members.contains(addr))
+                .map(|(name, _)| name.clone())
+                .collect()
+        };
+        for room in &joined {
+            self.part_room(room, addr);
+        }
+        joined
+    }
+    
+    /// Check whether a client is a member of a room
+    pub fn is_in_room(&self, room: &str, addr: &SocketAddr) -> bool {
+        let rooms = self.rooms.lock().unwrap();
+        rooms.get(room).is_some_and(|members| members.contains(addr))
+    }
+    
+    /// List every room with its member count
+    pub fn list_rooms(&self) -> Vec<RoomInfo> {
+        let rooms = self.rooms.lock().unwrap();
+        let mut list: Vec<RoomInfo> = rooms.iter()
+            .map(|(name, members)| RoomInfo {
+                name: name.clone(),
+                members: members.len(),
+            })
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+    
     /// Broadcast message to all connected clients
     pub fn broadcast(&self, message: &Message, exclude_addr: Option<&SocketAddr>) {
-        let clients = self.clients.lock().unwrap();
-        let message_bytes = match FramedMessage::encode(message) {
-            Ok(bytes) => bytes,
-            Err(e) => {
-                error!("Failed to encode broadcast message: {}", e);
-                return;
+        let started = Instant::now();
+        
+        // Snapshot the queues so the lock is not held while queueing
+        let queues: Vec<Recipient> = {
+            let clients = self.clients.lock().unwrap();
+            clients.iter()
+                .filter(|(addr, _)| Some(*addr) != exclude_addr)
+                .map(|(addr, client)| (*addr, client.outbound.clone(), client.wire_format, client.version))
+                .collect()
+        };
+        self.push_all(message, queues);
+        self.metrics.observe_broadcast(started.elapsed());
+    }
+    
+    /// Broadcast message to the members of a room
+    pub fn broadcast_to_room(&self, room: &str, message: &Message, exclude_addr: Option<&SocketAddr>) {
+        let started = Instant::now();
+        let members: Vec<SocketAddr> = {
+            let rooms = self.rooms.lock().unwrap();
+            match rooms.get(room) {
+                Some(members) => members.iter()
+                    .filter(|addr| Some(*addr) != exclude_addr)
+                    .copied()
+                    .collect(),
+                None => return,
             }
         };
         
-        for (addr, client) in clients.iter() {
-            if let Some(exclude) = exclude_addr {
-                if addr == exclude {
-                    continue;
-                }
-            }
-            
-            let mut writer = client.writer.lock().unwrap();
-            if let Err(e) = writer.write_all(&message_bytes) {
-                warn!("Failed to send to {}: {}", addr, e);
+        let queues: Vec<Recipient> = {
+            let clients = self.clients.lock().unwrap();
+            members.iter()
+                .filter_map(|addr| clients.get(addr).map(|c| (*addr, c.outbound.clone(), c.wire_format, c.version)))
+                .collect()
+        };
+        self.push_all(message, queues);
+        self.metrics.observe_broadcast(started.elapsed());
+    }
+    
+    /// Encode a message once per wire format and version and queue it for each of the given clients
+    fn push_all(&self, message: &Message, queues: Vec<Recipient>) {
+        let mut frames: HashMap<(WireFormat, u16), Arc<Vec<u8>>> = HashMap::new();
+        let mut queued = 0;
+        
+        for (addr, queue, format, version) in queues {
+            let frame = match frames.get(&(format, version)) {
+                Some(frame) => frame.clone(),
+                None => match format.codec().encode_for(message, version) {
+                    Ok(bytes) => frames.entry((format, version)).or_insert(Arc::new(bytes)).clone(),
+                    Err(e) => {
+                        error!("Failed to encode broadcast message: {}", e);
+                        return;
+                    }
+                },
+            };
+            if queue.push(frame) {
+                queued += 1;
+            } else {
+                warn!("Dropped broadcast frame for {}", addr);
             }
         }
+        self.metrics.message_out(message, queued);
     }
     
     /// Send message to specific client
     pub fn send_to(&self, addr: &SocketAddr, message: &Message) -> bool {
-        let clients = self.clients.lock().unwrap();
-        if let Some(client) = clients.get(addr) {
-            let mut writer = client.writer.lock().unwrap();
-            match FramedMessage::encode(message).and_then(|bytes| {
-                writer.write_all(&bytes)?;
-                writer.flush()?;
-                Ok(())
-            }) {
-                Ok(_) => true,
-                Err(e) => {
-                    warn!("Failed to send to {}: {}", addr, e);
-                    false
+        let (queue, format, version) = {
+            let clients = self.clients.lock().unwrap();
+            match clients.get(addr) {
+                Some(client) => (client.outbound.clone(), client.wire_format, client.version),
+                None => return false,
+            }
+        };
+        
+        match format.codec().encode_for(message, version) {
+            Ok(bytes) => {
+                let queued = queue.push(Arc::new(bytes));
+                if queued {
+                    self.metrics.message_out(message, 1);
                 }
+                queued
             }
+            Err(e) => {
+                warn!("Failed to send to {}: {}", addr, e);
+                false
+            }
+        }
+    }
+    
+    /// Send a final message to one client and close its connection
+    ///
+    /// A client too far behind to take the message is cut off without it.
+    /// Returns false if the client was not connected.
+    pub fn disconnect(&self, addr: &SocketAddr, message: &Message) -> bool {
+        let queued = self.send_to(addr, message);
+        let clients = self.clients.lock().unwrap();
+        let Some(client) = clients.get(addr) else {
+            return false;
+        };
+        if queued {
+            client.outbound.close();
         } else {
-            false
+            client.outbound.abort();
+        }
+        true
+    }
+    
+    /// Send a final message to every client and close their connections
+    ///
+    /// Returns the addresses of the clients that were closed.
+    pub fn close_all(&self, message: &Message) -> Vec<SocketAddr> {
+        self.broadcast(message, None);
+        
+        let clients = self.clients.lock().unwrap();
+        for client in clients.values() {
+            client.outbound.close();
         }
+        clients.keys().copied().collect()
     }
 }
@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::common::protocol::DeliveryStatus;
+
+/// Tracked messages remembered per sender for spotting resends
+const REMEMBERED_PER_SENDER: usize = 256;
+/// Private messages waiting for an Ack before the oldest are forgotten
+const MAX_UNACKED: usize = 4096;
+/// How long a message relayed to an online recipient waits for its Ack
+const ACK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Message IDs, plus what became of the messages clients asked to track
+///
+/// Senders are remembered by username rather than address, so a message
+/// resent after a reconnect is recognised and not delivered again.
+#[derive(Clone)]
+pub struct Deliveries {
+    inner: Arc<Mutex<DeliveriesInner>>,
+}
+
+struct DeliveriesInner {
+    next_id: u64,
+    /// How long a queued private message waits for its Ack, as long as mailboxes keep it
+    mailbox_expiry: Duration,
+    /// Recent tracked messages by lowercased sender, oldest first
+    sent: HashMap<String, VecDeque<Sent>>,
+    /// Private messages by ID, waiting for the recipient's Ack
+    unacked: BTreeMap<u64, Unacked>,
+}
+
+struct Sent {
+    client_id: u64,
+    id: u64,
+    status: DeliveryStatus,
+}
+
+struct Unacked {
+    sender: String,
+    recipient: String,
+    client_id: u64,
+    relayed_at: Instant,
+    /// How long to wait for the Ack
+    wait: Duration,
+}
+
+impl Deliveries {
+    /// Hand out IDs starting at `first_id`
+    ///
+    /// Queued private messages are forgotten once `mailbox_expiry` has passed
+    /// without an Ack, as the mailbox no longer holds them by then.
+    pub fn new(first_id: u64, mailbox_expiry: Duration) -> Self {
+        Deliveries {
+            inner: Arc::new(Mutex::new(DeliveriesInner {
+                next_id: first_id.max(1),
+                mailbox_expiry,
+                sent: HashMap::new(),
+                unacked: BTreeMap::new(),
+            })),
+        }
+    }
+    
+    /// The most recently assigned ID, or 0 if there is none
+    pub fn last_id(&self) -> u64 {
+        self.inner.lock().unwrap().next_id - 1
+    }
+    
+    /// Make sure every ID assigned from now on is above `id`
+    pub fn skip_past(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id = inner.next_id.max(id + 1);
+    }
+    
+    /// Assign the next message ID
+    pub fn next_id(&self) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        id
+    }
+    
+    /// The ID and status of a message `sender` already sent with this client ID
+    pub fn lookup(&self, sender: &str, client_id: u64) -> Option<(u64, DeliveryStatus)> {
+        if client_id == 0 {
+            return None;
+        }
+        let inner = self.inner.lock().unwrap();
+        inner.sent.get(&sender.to_lowercase())?
+            .iter()
+            .find(|sent| sent.client_id == client_id)
+            .map(|sent| (sent.id, sent.status))
+    }
+    
+    /// Remember that a tracked message was sent or queued under `id`
+    ///
+    /// Only messages that were passed on are recorded, so resending one that
+    /// failed tries again. Recording a message again updates its status.
+    pub fn record(&self, sender: &str, client_id: u64, id: u64, status: DeliveryStatus) {
+        if client_id == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let sent = inner.sent.entry(sender.to_lowercase()).or_default();
+        if let Some(earlier) = sent.iter_mut().find(|sent| sent.id == id) {
+            earlier.status = status;
+            return;
+        }
+        sent.push_back(Sent { client_id, id, status });
+        if sent.len() > REMEMBERED_PER_SENDER {
+            sent.pop_front();
+        }
+    }
+    
+    /// Wait for `recipient` to acknowledge private message `id`, recording its status
+    ///
+    /// Call this before relaying the message so a quick Ack is not missed.
+    /// Clients that never ack, such as those that skip the Hello, leave the
+    /// message waiting until it expires.
+    pub fn expect_ack(&self, id: u64, sender: &str, recipient: &str, client_id: u64, status: DeliveryStatus) {
+        self.record(sender, client_id, id, status);
+        let mut inner = self.inner.lock().unwrap();
+        let wait = match status {
+            DeliveryStatus::Queued => inner.mailbox_expiry,
+            _ => ACK_TIMEOUT,
+        };
+        inner.unacked.insert(id, Unacked {
+            sender: sender.to_string(),
+            recipient: recipient.to_lowercase(),
+            client_id,
+            relayed_at: Instant::now(),
+            wait,
+        });
+        inner.forget_expired();
+        while inner.unacked.len() > MAX_UNACKED {
+            inner.unacked.pop_first();
+        }
+    }
+    
+    /// Forget a private message that could not be relayed after all
+    pub fn cancel(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(unacked) = inner.unacked.remove(&id) {
+            if let Some(sent) = inner.sent.get_mut(&unacked.sender.to_lowercase()) {
+                sent.retain(|sent| sent.id != id);
+            }
+        }
+    }
+    
+    /// Record `from` acknowledging message `id`, returning the sender and client ID to tell
+    pub fn ack(&self, id: u64, from: &str) -> Option<(String, u64)> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.forget_expired();
+        if inner.unacked.get(&id)?.recipient != from.to_lowercase() {
+            return None;
+        }
+        let unacked = inner.unacked.remove(&id)?;
+        if let Some(sent) = inner.sent.get_mut(&unacked.sender.to_lowercase()) {
+            if let Some(sent) = sent.iter_mut().find(|sent| sent.id == id) {
+                sent.status = DeliveryStatus::Delivered;
+            }
+        }
+        Some((unacked.sender, unacked.client_id))
+    }
+}
+
+impl DeliveriesInner {
+    /// Drop private messages that have waited too long for their Ack
+    fn forget_expired(&mut self) {
+        self.unacked.retain(|_, unacked| unacked.relayed_at.elapsed() < unacked.wait);
+    }
+}
@@ -1,91 +1,168 @@
 use std::net::{TcpStream, SocketAddr};
+use std::io::{self, Read, Write};
 use std::sync::Arc;
-use std::io::{Read, Write};
 use std::thread;
+use std::time::Duration;
 use log::{info, warn, error, debug};
 
-use crate::common::protocol::{Message, FramedMessage};
+use crate::common::protocol::{
+    BanTarget, DeliveryStatus, Message, capabilities, current_timestamp, error_codes,
+    is_valid_room_name, DEFAULT_ROOM, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::common::codec::WireFormat;
+use crate::common::transport::{Acceptor, Transport};
+use crate::server::auth::Authenticator;
 use crate::server::connection_manager::ConnectionManager;
+use crate::server::deliveries::Deliveries;
+use crate::server::history::History;
+use crate::server::mailbox::Mailboxes;
+use crate::server::moderation::{Ban, Moderation};
+use crate::server::rate_limit::{RateLimitConfig, RateLimiter, Verdict};
+use crate::server::resume::ResumeTokens;
+use crate::server::listener::SharedConfig;
+use crate::server::transfers::{TransferError, Transfers};
+use crate::server::username::UsernameError;
+
+/// Shared server state handed to every connection handler
+#[derive(Clone)]
+pub struct HandlerContext {
+    pub manager: ConnectionManager,
+    /// Settings, which may be reloaded while the server runs
+    pub config: SharedConfig,
+    pub history: History,
+    /// Message IDs and the delivery status of tracked messages
+    pub deliveries: Deliveries,
+    /// Files being relayed between clients
+    pub transfers: Transfers,
+    pub authenticator: Arc<dyn Authenticator>,
+    /// Wraps accepted sockets, adding TLS when configured
+    pub acceptor: Acceptor,
+    /// Operator grants, bans and mutes
+    pub moderation: Moderation,
+    /// Private messages waiting for offline users
+    pub mailboxes: Mailboxes,
+    /// Tokens for resuming dropped sessions
+    pub resume: ResumeTokens,
+    /// Codec spoken on the listener the client connected to
+    pub wire_format: WireFormat,
+}
+
+/// What the handler knows about a client once it has joined
+pub(crate) struct Session {
+    username: String,
+    /// Capabilities both sides advertised in the Hello exchange
+    capabilities: Vec<String>,
+    /// Whether the client may kick, ban and mute others
+    operator: bool,
+    /// Set when the client picked up a dropped session with a token
+    resumed: Option<Resumed>,
+}
+
+/// Where a resumed session left off
+pub(crate) struct Resumed {
+    rooms: Vec<String>,
+    /// Last broadcast the client saw; later ones are replayed
+    last_id: u64,
+}
+
+impl Session {
+    /// Check whether a capability was negotiated
+    fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
 
 /// Handle a single client connection
 pub fn handle_client(
-    mut stream: TcpStream,
+    stream: TcpStream,
     addr: SocketAddr,
-    manager: ConnectionManager,
+    ctx: HandlerContext,
 ) -> thread::JoinHandle<()> {
     thread::spawn(move || {
         info!("New connection from: {}", addr);
         
+        let mut stream = match ctx.acceptor.accept(stream) {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("TLS handshake with {} failed: {}", addr, e);
+                return;
+            }
+        };
+        
         let mut buffer = Vec::new();
-        let mut username = String::new();
         
-        // Wait for join message
-        match wait_for_join(&mut stream, &mut buffer, &addr) {
-            Ok(name) => {
-                username = name.clone();
-                manager.add_client(addr, username.clone(), stream.try_clone().unwrap());
-                
-                // Send welcome message
-                let welcome = Message::Welcome {
-                    message: format!("Welcome, {}!", username),
-                    connected_clients: manager.get_all_usernames(),
-                };
-                let _ = manager.send_to(&addr, &welcome);
-                
-                // Notify others
-                let joined_msg = Message::UserJoined {
-                    username: username.clone(),
-                };
-                manager.broadcast(&joined_msg, Some(&addr));
+        // Wait for join message; on success the client is registered
+        match wait_for_join(&mut stream, &mut buffer, &addr, &ctx) {
+            Ok(session) => {
+                start_session(&addr, &ctx, &session);
                 
                 // Handle incoming messages
-                handle_incoming_messages(&mut stream, &mut buffer, &addr, &manager, &username);
+                handle_incoming_messages(&mut stream, &mut buffer, &addr, &ctx, &session);
             }
             Err(e) => {
                 error!("Failed to get join message from {}: {}", addr, e);
             }
         }
         
-        // Cleanup on disconnect
-        if let Some(client) = manager.remove_client(&addr) {
-            let leave_msg = Message::UserLeft {
-                username: client.username.clone(),
-            };
-            manager.broadcast(&leave_msg, Some(&addr));
-            info!("Client disconnected: {} at {}", client.username, addr);
-        }
+        end_session(&addr, &ctx);
     })
 }
 
-/// Wait for the initial join message
+/// Progress of a connection through the Hello and Join exchange
+#[derive(Default)]
+pub(crate) struct JoinState {
+    failed_attempts: u32,
+    /// Capabilities agreed in a Hello, if the client sent one
+    negotiated: Option<Vec<String>>,
+    /// Protocol version agreed in a Hello, if the client sent one
+    version: Option<u16>,
+}
+
+/// What to do after a message from a client that has not joined yet
+pub(crate) enum JoinStep {
+    /// Send the reply and keep waiting for a Join
+    Reply(Message),
+    /// The client is registered and its session can start
+    Joined(Session),
+    /// Send the reply, then drop the connection
+    Reject(Message, anyhow::Error),
+}
+
+/// Wait for an acceptable join message and register the client
+///
+/// A Hello may come first to negotiate capabilities; clients that skip it
+/// get the legacy set. Rejected names get an error reply and the client may
+/// try again; the connection is dropped after an incompatible Hello or too
+/// many failed authentication attempts.
 fn wait_for_join(
-    stream: &mut TcpStream,
+    stream: &mut Transport,
     buffer: &mut Vec<u8>,
     addr: &SocketAddr,
-) -> Result<String, anyhow::Error> {
+    ctx: &HandlerContext,
+) -> Result<Session, anyhow::Error> {
     let mut read_buf = [0u8; 1024];
+    let mut state = JoinState::default();
     
     loop {
         match stream.read(&mut read_buf) {
             Ok(0) => anyhow::bail!("Connection closed"),
             Ok(n) => {
                 buffer.extend_from_slice(&read_buf[..n]);
+                ctx.manager.metrics().bytes_in(n);
                 
-                while let Some(msg) = FramedMessage::decode(buffer)? {
-                    match msg {
-                        Message::Join { username } => {
-                            return Ok(username);
-                        }
-                        _ => {
-                            warn!("Expected Join message from {}, got {:?}", addr, msg);
-                            // Send error and continue waiting
-                            let error = Message::Error {
-                                code: 400,
-                                message: "Expected Join message".to_string(),
-                            };
-                            if let Ok(bytes) = FramedMessage::encode(&error) {
-                                let _ = stream.write_all(&bytes);
-                            }
+                while let Some(msg) = ctx.wire_format.codec()
+                    .decode(buffer)
+                    .inspect_err(|_| ctx.manager.metrics().decode_error())?
+                {
+                    let register = |username: &str, version| {
+                        ctx.manager.add_client(*addr, username.to_string(), stream.try_clone()?, ctx.wire_format, version)
+                    };
+                    match on_join_message(msg, addr, ctx, &mut state, register)? {
+                        JoinStep::Reply(reply) => write_direct(stream, ctx, &reply),
+                        JoinStep::Joined(session) => return Ok(session),
+                        JoinStep::Reject(reply, e) => {
+                            write_direct(stream, ctx, &reply);
+                            return Err(e);
                         }
                     }
                 }
@@ -95,15 +172,250 @@ fn wait_for_join(
     }
 }
 
+/// Handle one message from a client that has not joined yet
+///
+/// `register` adds the client to the connection manager once its name and
+/// credentials have been accepted, returning false if the name is taken.
+pub(crate) fn on_join_message(
+    msg: Message,
+    addr: &SocketAddr,
+    ctx: &HandlerContext,
+    state: &mut JoinState,
+    register: impl FnOnce(&str, u16) -> io::Result<bool>,
+) -> io::Result<JoinStep> {
+    ctx.manager.metrics().message_in(&msg);
+    let error = match msg {
+        Message::Hello { version, capabilities: offered } => {
+            if version < MIN_PROTOCOL_VERSION {
+                warn!("Rejected protocol version {} from {}", version, addr);
+                let error = Message::Error {
+                    code: error_codes::UPGRADE_REQUIRED,
+                    message: format!(
+                        "Protocol version {} is not supported; this server speaks {} and up",
+                        version, MIN_PROTOCOL_VERSION
+                    ),
+                };
+                return Ok(JoinStep::Reject(error, anyhow::anyhow!("Incompatible protocol version {}", version)));
+            }
+            
+            // Newer clients fall back to the version this build speaks
+            let version = version.min(PROTOCOL_VERSION);
+            let shared = capabilities::negotiate(capabilities::SUPPORTED, &offered);
+            debug!("Negotiated version {} and {:?} with {}", version, shared, addr);
+            let reply = Message::Hello {
+                version,
+                capabilities: shared.clone(),
+            };
+            state.negotiated = Some(shared);
+            state.version = Some(version);
+            reply
+        }
+        Message::Join { username, credentials } => {
+            if let Err(e) = ctx.config.get().username_rules.validate(&username) {
+                warn!("Rejected username {:?} from {}: {}", username, addr, e);
+                Message::Error {
+                    code: e.code(),
+                    message: e.to_string(),
+                }
+            } else if let Err(e) = ctx.authenticator.authenticate(&username, credentials.as_deref()) {
+                state.failed_attempts += 1;
+                warn!("Authentication failed for {} from {}: {}", username, addr, e);
+                let error = Message::Error {
+                    code: error_codes::UNAUTHORIZED,
+                    message: e.to_string(),
+                };
+                if state.failed_attempts >= ctx.config.get().max_auth_attempts {
+                    return Ok(JoinStep::Reject(error, anyhow::anyhow!("Too many failed authentication attempts")));
+                }
+                error
+            } else if let Some(ban) = ctx.moderation.find_ban(Some(&username), addr.ip()) {
+                return Ok(reject_banned(&username, addr, &ban));
+            } else if !register(&username, state.version.unwrap_or(MIN_PROTOCOL_VERSION))? {
+                let e = UsernameError::Taken(username);
+                Message::Error {
+                    code: e.code(),
+                    message: e.to_string(),
+                }
+            } else {
+                return Ok(JoinStep::Joined(new_session(username, ctx, state, None)));
+            }
+        }
+        Message::Resume { token, last_id } => match ctx.resume.lookup(&token) {
+            Err(e) => {
+                warn!("Could not resume a session for {}: {}", addr, e);
+                Message::Error {
+                    code: e.code(),
+                    message: e.to_string(),
+                }
+            }
+            Ok(suspended) => {
+                let username = suspended.username;
+                if let Some(ban) = ctx.moderation.find_ban(Some(&username), addr.ip()) {
+                    return Ok(reject_banned(&username, addr, &ban));
+                } else if !register(&username, state.version.unwrap_or(MIN_PROTOCOL_VERSION))? {
+                    let e = UsernameError::Taken(username);
+                    Message::Error {
+                        code: e.code(),
+                        message: e.to_string(),
+                    }
+                } else {
+                    info!("{} resumed a session from {}", username, addr);
+                    let resumed = Resumed { rooms: suspended.rooms, last_id };
+                    return Ok(JoinStep::Joined(new_session(username, ctx, state, Some(resumed))));
+                }
+            }
+        },
+        _ => {
+            warn!("Expected Join message from {}, got {:?}", addr, msg);
+            Message::Error {
+                code: error_codes::BAD_REQUEST,
+                message: "Expected Join message".to_string(),
+            }
+        }
+    };
+    
+    Ok(JoinStep::Reply(error))
+}
+
+/// Turn away a banned user at Join time
+fn reject_banned(username: &str, addr: &SocketAddr, ban: &Ban) -> JoinStep {
+    warn!("Rejected banned user {} from {}", username, addr);
+    let error = Message::Error {
+        code: error_codes::REMOVED,
+        message: format!("You are banned: {}", ban.reason),
+    };
+    JoinStep::Reject(error, anyhow::anyhow!("Banned user {}", username))
+}
+
+/// The session of a client whose Join or Resume was accepted
+fn new_session(username: String, ctx: &HandlerContext, state: &mut JoinState, resumed: Option<Resumed>) -> Session {
+    let capabilities = state.negotiated.take().unwrap_or_else(|| {
+        capabilities::LEGACY.iter().map(|cap| cap.to_string()).collect()
+    });
+    let config = ctx.config.get();
+    // Worked out afresh on resume too, so rights taken away by a reload stay gone
+    let operator = ctx.moderation.grant_operator(&username, &config.operators, config.first_user_is_operator);
+    if operator {
+        info!("{} joined as an operator", username);
+    }
+    Session { username, capabilities, operator, resumed }
+}
+
+/// Write a reply straight to a client that has no outbound queue yet
+fn write_direct(stream: &mut Transport, ctx: &HandlerContext, message: &Message) {
+    if let Ok(bytes) = ctx.wire_format.codec().encode(message) {
+        if stream.write_all(&bytes).is_ok() {
+            ctx.manager.metrics().message_out(message, 1);
+            ctx.manager.metrics().bytes_out(bytes.len());
+        }
+    }
+}
+
+/// Greet a client that just joined and put it in the default room
+pub(crate) fn start_session(addr: &SocketAddr, ctx: &HandlerContext, session: &Session) {
+    let manager = &ctx.manager;
+    
+    let welcome = Message::Welcome {
+        message: format!("Welcome, {}!", session.username),
+        connected_clients: manager.get_all_usernames(),
+    };
+    manager.set_capabilities(addr, session.capabilities.clone());
+    // A token lets the client pick up from here if the connection drops
+    let token = session.supports(capabilities::RESUME)
+        .then(|| ctx.resume.issue(&session.username, ctx.deliveries.last_id()));
+    
+    // Private messages that arrived while the user was away come before any
+    // sent from now on, so the user only becomes reachable once they are queued
+    ctx.mailboxes.remember(&session.username);
+    ctx.mailboxes.hand_over(&session.username, |mail| {
+        let _ = manager.send_to(addr, &welcome);
+        if let Some(token) = token {
+            let _ = manager.send_to(addr, &Message::SessionToken { token });
+        }
+        for private in mail {
+            let _ = manager.send_to(addr, &private);
+        }
+        manager.set_reachable(addr);
+    });
+    
+    // A resumed client goes back to its rooms and gets what it missed there
+    if let Some(resumed) = &session.resumed {
+        for room in &resumed.rooms {
+            rejoin_room(room, resumed.last_id, addr, ctx, session);
+        }
+        return;
+    }
+    
+    // Every client starts out in the default room
+    manager.join_room(DEFAULT_ROOM, addr);
+    
+    // Notify others
+    let joined_msg = Message::UserJoined {
+        username: session.username.clone(),
+        room: DEFAULT_ROOM.to_string(),
+    };
+    manager.broadcast_to_room(DEFAULT_ROOM, &joined_msg, Some(addr));
+    
+    // Catch the client up on what was said before it arrived
+    send_history(DEFAULT_ROOM, 0, addr, ctx, session);
+}
+
+/// Put a resumed client back in a room, recreating it if it emptied in the meantime
+fn rejoin_room(room: &str, last_id: u64, addr: &SocketAddr, ctx: &HandlerContext, session: &Session) {
+    let manager = &ctx.manager;
+    manager.create_room(room);
+    let Some(members) = manager.join_room(room, addr) else {
+        return;
+    };
+    let _ = manager.send_to(addr, &Message::RoomJoined {
+        room: room.to_string(),
+        members,
+    });
+    let notice = Message::UserJoined {
+        username: session.username.clone(),
+        room: room.to_string(),
+    };
+    manager.broadcast_to_room(room, &notice, Some(addr));
+    send_history(room, last_id, addr, ctx, session);
+}
+
+/// Unregister a client that went away and tell the rooms it was in
+pub(crate) fn end_session(addr: &SocketAddr, ctx: &HandlerContext) {
+    let manager = &ctx.manager;
+    let rooms = manager.part_all_rooms(addr);
+    // While the name is still taken, so a quick rejoin's new token is left alone
+    if let Some(username) = manager.get_username(addr) {
+        ctx.resume.suspend(&username, rooms.clone(), ctx.deliveries.last_id());
+    }
+    if let Some(client) = manager.remove_client(addr) {
+        for room in rooms {
+            let leave_msg = Message::UserLeft {
+                username: client.username.clone(),
+                room: room.clone(),
+            };
+            manager.broadcast_to_room(&room, &leave_msg, Some(addr));
+        }
+        for (transfer_id, peer) in ctx.transfers.abandon(addr) {
+            let abort = Message::FileAbort {
+                transfer_id,
+                reason: format!("{} disconnected", client.username),
+            };
+            let _ = manager.send_to(&peer, &abort);
+        }
+        info!("Client disconnected: {} at {}", client.username, addr);
+    }
+}
+
 /// Handle incoming messages from a client
 fn handle_incoming_messages(
-    stream: &mut TcpStream,
+    stream: &mut Transport,
     buffer: &mut Vec<u8>,
     addr: &SocketAddr,
-    manager: &ConnectionManager,
-    username: &str,
+    ctx: &HandlerContext,
+    session: &Session,
 ) {
     let mut read_buf = [0u8; 1024];
+    let mut limiter = None;
     
     loop {
         match stream.read(&mut read_buf) {
@@ -113,15 +425,10 @@ fn handle_incoming_messages(
             }
             Ok(n) => {
                 buffer.extend_from_slice(&read_buf[..n]);
+                ctx.manager.metrics().bytes_in(n);
                 
-                while let Ok(Some(msg)) = FramedMessage::decode(buffer) {
-                    match process_message(msg, addr, manager, username) {
-                        ProcessResult::Continue => continue,
-                        ProcessResult::Disconnect => return,
-                        ProcessResult::Error(e) => {
-                            error!("Error processing message: {}", e);
-                        }
-                    }
+                if !process_buffer(buffer, addr, ctx, session, &mut limiter) {
+                    return;
                 }
             }
             Err(e) => {
@@ -132,6 +439,115 @@ fn handle_incoming_messages(
     }
 }
 
+/// Bring a connection's rate limiter in line with the limits a reload may have changed
+fn refresh_limiter(limiter: &mut Option<RateLimiter>, config: Option<&RateLimitConfig>) {
+    match (limiter.as_mut(), config) {
+        (Some(limiter), Some(config)) => limiter.reconfigure(config),
+        (None, Some(config)) => *limiter = Some(RateLimiter::new(config.clone())),
+        (_, None) => *limiter = None,
+    }
+}
+
+/// Decode and handle every complete message received from a joined client
+///
+/// `limiter` holds the connection's rate limiter, if rate limiting is on.
+/// Returns false once the connection should be closed.
+pub(crate) fn process_buffer(
+    buffer: &mut Vec<u8>,
+    addr: &SocketAddr,
+    ctx: &HandlerContext,
+    session: &Session,
+    limiter: &mut Option<RateLimiter>,
+) -> bool {
+    loop {
+        let buffered = buffer.len();
+        let msg = match ctx.wire_format.codec().decode(buffer) {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return true,
+            Err(e) => {
+                report_malformed(addr, ctx, &e);
+                // Nothing was consumed, so the framing is lost for good
+                if buffer.len() == buffered {
+                    return false;
+                }
+                continue;
+            }
+        };
+        if !process_received(msg, buffered - buffer.len(), addr, ctx, session, limiter) {
+            return false;
+        }
+    }
+}
+
+/// Tell a client that something it sent could not be decoded
+pub(crate) fn report_malformed(addr: &SocketAddr, ctx: &HandlerContext, error: &anyhow::Error) {
+    ctx.manager.metrics().decode_error();
+    warn!("Malformed message from {}: {}", addr, error);
+    send_error(&ctx.manager, addr, error_codes::BAD_REQUEST, format!("Malformed message: {}", error));
+}
+
+/// Rate-limit and handle one message that took `size` bytes on the wire
+///
+/// Returns false once the connection should be closed.
+pub(crate) fn process_received(
+    msg: Message,
+    size: usize,
+    addr: &SocketAddr,
+    ctx: &HandlerContext,
+    session: &Session,
+    limiter: &mut Option<RateLimiter>,
+) -> bool {
+    ctx.manager.metrics().message_in(&msg);
+    refresh_limiter(limiter, ctx.config.get().rate_limit.as_ref());
+    
+    // Floods are stopped here, before the message can reach anyone else.
+    // The next chunk of an accepted transfer is paced by the recipient's
+    // accepts instead; other file traffic and acks answer the server or
+    // a peer, so they only count against the byte rate.
+    let paced = match &msg {
+        Message::FileChunk { transfer_id, offset, .. } => ctx.transfers.expects_chunk(*transfer_id, addr, *offset),
+        _ => false,
+    };
+    let replies = matches!(msg, Message::FileChunk { .. } | Message::FileAccept { .. } | Message::Ack { .. });
+    if let Some(limiter) = limiter.as_mut().filter(|_| !paced) {
+        let verdict = if replies { limiter.check_bytes(size) } else { limiter.check(size) };
+        // Whatever is not allowed is dropped, so a tracked message is failed
+        let keep_open = match verdict {
+            Verdict::Allow => None,
+            Verdict::Warn => {
+                warn!("Rate limit exceeded by {}", session.username);
+                send_error(&ctx.manager, addr, error_codes::RATE_LIMITED, "Slow down, you are sending too fast".to_string());
+                Some(true)
+            }
+            Verdict::Mute(duration) => {
+                warn!("Muting {} for {:?} for flooding", session.username, duration);
+                let message = format!("Muted for {} seconds for flooding", duration.as_secs());
+                send_error(&ctx.manager, addr, error_codes::RATE_LIMITED, message);
+                Some(true)
+            }
+            Verdict::Muted => Some(true),
+            Verdict::Disconnect => {
+                warn!("Disconnecting {} for flooding", session.username);
+                send_error(&ctx.manager, addr, error_codes::RATE_LIMITED, "Disconnected for flooding".to_string());
+                Some(false)
+            }
+        };
+        if let Some(keep_open) = keep_open {
+            send_receipt(&ctx.manager, addr, msg.client_id(), 0, DeliveryStatus::Failed);
+            return keep_open;
+        }
+    }
+    
+    match process_message(msg, addr, ctx, session) {
+        ProcessResult::Continue => true,
+        ProcessResult::Disconnect => false,
+        ProcessResult::Error(e) => {
+            error!("Error processing message: {}", e);
+            true
+        }
+    }
+}
+
 /// Result of message processing
 enum ProcessResult {
     Continue,
@@ -143,61 +559,305 @@ enum ProcessResult {
 fn process_message(
     msg: Message,
     addr: &SocketAddr,
-    manager: &ConnectionManager,
-    username: &str,
+    ctx: &HandlerContext,
+    session: &Session,
 ) -> ProcessResult {
+    let manager = &ctx.manager;
+    let username = session.username.as_str();
+    
+    // Heartbeats keep the connection alive but do not count as activity
+    if msg != Message::Ping {
+        manager.touch(addr);
+    }
+    
     match msg {
-        Message::Chat { content, .. } => {
-            debug!("Chat from {}: {}", username, content);
-            let broadcast = Message::broadcast(username.to_string(), content);
-            manager.broadcast(&broadcast, Some(addr));
+        Message::CreateRoom { .. }
+        | Message::JoinRoom { .. }
+        | Message::PartRoom { .. }
+        | Message::ListRooms if !session.supports(capabilities::ROOMS) => {
+            send_error(manager, addr, error_codes::BAD_REQUEST, "Rooms were not negotiated".to_string());
             ProcessResult::Continue
         }
         
-        Message::Private { to, content, .. } => {
+        Message::Kick { .. } | Message::Ban { .. } | Message::Mute { .. } if !session.operator => {
+            send_error(manager, addr, error_codes::FORBIDDEN, "Only operators can do that".to_string());
+            ProcessResult::Continue
+        }
+        
+        Message::FileOffer { .. }
+        | Message::FileAccept { .. }
+        | Message::FileChunk { .. }
+        | Message::FileComplete { .. }
+        | Message::FileAbort { .. } if !session.supports(capabilities::FILES) => {
+            send_error(manager, addr, error_codes::BAD_REQUEST, "File transfers were not negotiated".to_string());
+            ProcessResult::Continue
+        }
+        
+        Message::Chat { .. }
+        | Message::Private { .. }
+        | Message::FileOffer { .. } if ctx.moderation.muted_for(username).is_some() => {
+            let remaining = ctx.moderation.muted_for(username).unwrap_or_default();
+            let message = format!("You are muted for {} more seconds", remaining.as_secs().max(1));
+            send_error(manager, addr, error_codes::FORBIDDEN, message);
+            send_receipt(manager, addr, msg.client_id(), 0, DeliveryStatus::Failed);
+            ProcessResult::Continue
+        }
+        
+        Message::Chat { room, content, client_id, .. } => {
+            debug!("Chat from {} in {}: {}", username, room, content);
+            if !manager.is_in_room(&room, addr) {
+                send_error(manager, addr, error_codes::NOT_IN_ROOM, format!("You are not in {}", room));
+                send_receipt(manager, addr, client_id, 0, DeliveryStatus::Failed);
+                return ProcessResult::Continue;
+            }
+            if let Some((id, status)) = ctx.deliveries.lookup(username, client_id) {
+                debug!("Not repeating message {} resent by {}", id, username);
+                send_receipt(manager, addr, client_id, id, status);
+                return ProcessResult::Continue;
+            }
+            let id = ctx.deliveries.next_id();
+            let broadcast = Message::broadcast(id, username.to_string(), room.clone(), content);
+            ctx.history.record(&broadcast);
+            manager.broadcast_to_room(&room, &broadcast, Some(addr));
+            ctx.deliveries.record(username, client_id, id, DeliveryStatus::Sent);
+            send_receipt(manager, addr, client_id, id, DeliveryStatus::Sent);
+            ProcessResult::Continue
+        }
+        
+        Message::CreateRoom { room } => {
+            if !is_valid_room_name(&room) {
+                send_error(manager, addr, error_codes::BAD_REQUEST, format!("Invalid room name {}", room));
+            } else if !manager.create_room(&room) {
+                send_error(manager, addr, error_codes::ROOM_EXISTS, format!("Room {} already exists", room));
+            } else {
+                join_room(&room, addr, ctx, session);
+            }
+            ProcessResult::Continue
+        }
+        
+        Message::JoinRoom { room } => {
+            join_room(&room, addr, ctx, session);
+            ProcessResult::Continue
+        }
+        
+        Message::PartRoom { room } => {
+            if manager.part_room(&room, addr) {
+                let _ = manager.send_to(addr, &Message::RoomLeft { room: room.clone() });
+                let left = Message::UserLeft {
+                    username: username.to_string(),
+                    room: room.clone(),
+                };
+                manager.broadcast_to_room(&room, &left, Some(addr));
+            } else {
+                send_error(manager, addr, error_codes::NOT_IN_ROOM, format!("You are not in {}", room));
+            }
+            ProcessResult::Continue
+        }
+        
+        Message::ListUsers => {
+            let list = Message::UserList {
+                users: manager.get_user_info(),
+            };
+            let _ = manager.send_to(addr, &list);
+            ProcessResult::Continue
+        }
+        
+        Message::ListRooms => {
+            let list = Message::RoomList {
+                rooms: manager.list_rooms(),
+            };
+            let _ = manager.send_to(addr, &list);
+            ProcessResult::Continue
+        }
+        
+        Message::Private { to, content, client_id, .. } => {
             debug!("Private from {} to {}: {}", username, to, content);
+            if let Some((id, status)) = ctx.deliveries.lookup(username, client_id) {
+                debug!("Not repeating message {} resent by {}", id, username);
+                send_receipt(manager, addr, client_id, id, status);
+                return ProcessResult::Continue;
+            }
             
-            // Find recipient address
-            let clients = manager.clients.lock().unwrap();
-            let recipient_addr = clients.iter()
-                .find(|(_, c)| c.username == to)
-                .map(|(addr, _)| *addr);
+            let id = ctx.deliveries.next_id();
+            let private = Message::Private {
+                from: username.to_string(),
+                to: to.clone(),
+                content,
+                timestamp: current_timestamp(),
+                id,
+                client_id: 0,
+            };
             
-            if let Some(recipient) = recipient_addr {
-                drop(clients); // Release lock before sending
-                
-                let private = Message::private(
-                    username.to_string(),
-                    to.clone(),
-                    content,
-                );
-                
-                if !manager.send_to(&recipient, &private) {
-                    let error = Message::Error {
-                        code: 404,
-                        message: format!("User {} is offline", to),
-                    };
-                    let _ = manager.send_to(addr, &error);
+            // Users who have joined before get it when they next join, unless
+            // the mailbox finds them online, even if they are only now joining
+            ctx.deliveries.expect_ack(id, username, &to, client_id, DeliveryStatus::Queued);
+            match ctx.mailboxes.deposit(&to, &private, || manager.find_reachable(&to)) {
+                Ok(None) => send_receipt(manager, addr, client_id, id, DeliveryStatus::Queued),
+                Ok(Some(recipient)) => {
+                    ctx.deliveries.expect_ack(id, username, &to, client_id, DeliveryStatus::Sent);
+                    if manager.send_to(&recipient, &private) {
+                        send_receipt(manager, addr, client_id, id, DeliveryStatus::Sent);
+                    } else {
+                        ctx.deliveries.cancel(id);
+                        let error = Message::Error {
+                            code: error_codes::NOT_FOUND,
+                            message: format!("User {} is offline", to),
+                        };
+                        let _ = manager.send_to(addr, &error);
+                        send_receipt(manager, addr, client_id, id, DeliveryStatus::Failed);
+                    }
+                }
+                Err(e) => {
+                    ctx.deliveries.cancel(id);
+                    send_error(manager, addr, e.code(), e.to_string());
+                    send_receipt(manager, addr, client_id, id, DeliveryStatus::Failed);
+                }
+            }
+            ProcessResult::Continue
+        }
+        
+        Message::Ack { id } => {
+            if let Some((sender, client_id)) = ctx.deliveries.ack(id, username) {
+                if let Some(sender_addr) = manager.find_by_username(&sender) {
+                    send_receipt(manager, &sender_addr, client_id, id, DeliveryStatus::Delivered);
                 }
-            } else {
-                let error = Message::Error {
-                    code: 404,
-                    message: format!("User {} not found", to),
-                };
-                let _ = manager.send_to(addr, &error);
             }
             ProcessResult::Continue
         }
         
+        Message::FileOffer { transfer_id, to, name, size, sha256, .. } => {
+            let Some(recipient) = manager.find_by_username(&to) else {
+                send_error(manager, addr, error_codes::NOT_FOUND, format!("User {} not found", to));
+                return ProcessResult::Continue;
+            };
+            if !manager.supports(&recipient, capabilities::FILES) {
+                send_error(manager, addr, error_codes::BAD_REQUEST, format!("User {} cannot receive files", to));
+                return ProcessResult::Continue;
+            }
+            if let Err(e) = ctx.transfers.offer(transfer_id, *addr, recipient, size) {
+                send_error(manager, addr, e.code(), format!("Transfer {}: {}", transfer_id, e));
+                return ProcessResult::Continue;
+            }
+            
+            info!("{} offered {} ({} bytes) to {}", username, name, size, to);
+            let offer = Message::FileOffer {
+                transfer_id,
+                from: username.to_string(),
+                to,
+                name,
+                size,
+                sha256,
+            };
+            relay_transfer(ctx, addr, transfer_id, Ok(recipient), &offer);
+            ProcessResult::Continue
+        }
+        
+        Message::FileAccept { transfer_id, offset } => {
+            let sender = ctx.transfers.accept(transfer_id, addr, offset);
+            relay_transfer(ctx, addr, transfer_id, sender, &Message::FileAccept { transfer_id, offset });
+            ProcessResult::Continue
+        }
+        
+        Message::FileChunk { transfer_id, offset, data } => {
+            let recipient = ctx.transfers.chunk(transfer_id, addr, offset, data.len());
+            relay_transfer(ctx, addr, transfer_id, recipient, &Message::FileChunk { transfer_id, offset, data });
+            ProcessResult::Continue
+        }
+        
+        Message::FileComplete { transfer_id } => {
+            let recipient = ctx.transfers.complete(transfer_id, addr);
+            relay_transfer(ctx, addr, transfer_id, recipient, &Message::FileComplete { transfer_id });
+            ProcessResult::Continue
+        }
+        
+        Message::FileAbort { transfer_id, reason } => {
+            let peer = ctx.transfers.abort(transfer_id, addr);
+            relay_transfer(ctx, addr, transfer_id, peer, &Message::FileAbort { transfer_id, reason });
+            ProcessResult::Continue
+        }
+        
+        Message::Kick { username: target, reason } => {
+            let Some(target_addr) = manager.find_by_username(&target) else {
+                send_error(manager, addr, error_codes::NOT_FOUND, format!("User {} not found", target));
+                return ProcessResult::Continue;
+            };
+            info!("{} kicked {}: {}", username, target, reason);
+            let error = Message::Error {
+                code: error_codes::REMOVED,
+                message: format!("Kicked by {}: {}", username, reason),
+            };
+            if !manager.disconnect(&target_addr, &error) {
+                send_error(manager, addr, error_codes::NOT_FOUND, format!("User {} not found", target));
+                return ProcessResult::Continue;
+            }
+            let notice = format!("{} was kicked by {} ({})", target, username, reason);
+            manager.broadcast(&Message::Notice { message: notice }, Some(&target_addr));
+            ProcessResult::Continue
+        }
+        
+        Message::Ban { target, duration_secs, reason } => {
+            info!("{} banned {} for {:?}s: {}", username, target, duration_secs, reason);
+            let ban = Ban {
+                target: target.clone(),
+                expires_at: duration_secs.map(|secs| current_timestamp() + secs),
+                reason: reason.clone(),
+                by: username.to_string(),
+            };
+            // The ban still holds in memory if it cannot be saved
+            if let Err(e) = ctx.moderation.ban(ban) {
+                error!("Failed to save ban list: {}", e);
+            }
+            
+            let banned = match &target {
+                BanTarget::Username(name) => manager.find_by_username(name).into_iter().collect(),
+                BanTarget::Ip(ip) => manager.find_by_ip(*ip),
+            };
+            let error = Message::Error {
+                code: error_codes::REMOVED,
+                message: format!("Banned by {}: {}", username, reason),
+            };
+            // The ban is announced even if nobody it covers was still connected
+            for banned_addr in &banned {
+                if !manager.disconnect(banned_addr, &error) {
+                    debug!("{} left before the ban reached it", banned_addr);
+                }
+            }
+            
+            let length = match duration_secs {
+                Some(secs) => format!("for {} seconds", secs),
+                None => "permanently".to_string(),
+            };
+            let notice = format!("{} was banned {} by {} ({})", target, length, username, reason);
+            manager.broadcast(&Message::Notice { message: notice }, None);
+            ProcessResult::Continue
+        }
+        
+        Message::Mute { username: target, duration_secs } => {
+            if manager.find_by_username(&target).is_none() {
+                send_error(manager, addr, error_codes::NOT_FOUND, format!("User {} not found", target));
+                return ProcessResult::Continue;
+            }
+            info!("{} muted {} for {}s", username, target, duration_secs);
+            ctx.moderation.mute(&target, Duration::from_secs(duration_secs));
+            let notice = format!("{} was muted for {} seconds by {}", target, duration_secs, username);
+            manager.broadcast(&Message::Notice { message: notice }, None);
+            ProcessResult::Continue
+        }
+        
         Message::Leave { .. } => {
             info!("Client {} requested disconnect", username);
+            // A client that says goodbye will not be back to resume
+            ctx.resume.forget(username);
             ProcessResult::Disconnect
         }
         
         Message::Ping => {
             let pong = Message::Pong;
-            let _ = manager.send_to(addr, &pong);
-            ProcessResult::Continue
+            if manager.send_to(addr, &pong) {
+                ProcessResult::Continue
+            } else {
+                ProcessResult::Error(anyhow::anyhow!("Failed to send pong to {}", addr))
+            }
         }
         
         _ => {
@@ -206,3 +866,86 @@ fn process_message(
         }
     }
 }
+
+/// Add a client to an existing room and tell the other members
+fn join_room(room: &str, addr: &SocketAddr, ctx: &HandlerContext, session: &Session) {
+    let manager = &ctx.manager;
+    let already_member = manager.is_in_room(room, addr);
+    match manager.join_room(room, addr) {
+        Some(members) => {
+            let joined = Message::RoomJoined {
+                room: room.to_string(),
+                members,
+            };
+            let _ = manager.send_to(addr, &joined);
+            
+            if !already_member {
+                let notice = Message::UserJoined {
+                    username: session.username.clone(),
+                    room: room.to_string(),
+                };
+                manager.broadcast_to_room(room, &notice, Some(addr));
+                send_history(room, 0, addr, ctx, session);
+            }
+        }
+        None => {
+            send_error(manager, addr, error_codes::NOT_FOUND, format!("Room {} not found", room));
+        }
+    }
+}
+
+/// Replay a room's recent messages after `last_id` to a client that just joined it
+fn send_history(room: &str, last_id: u64, addr: &SocketAddr, ctx: &HandlerContext, session: &Session) {
+    if !session.supports(capabilities::HISTORY) {
+        return;
+    }
+    let messages = ctx.history.since(room, last_id);
+    if messages.is_empty() {
+        return;
+    }
+    let history = Message::History {
+        room: room.to_string(),
+        messages,
+    };
+    let _ = ctx.manager.send_to(addr, &history);
+}
+
+/// Pass a file transfer message to the other party, or tell the client why it was refused
+fn relay_transfer(
+    ctx: &HandlerContext,
+    addr: &SocketAddr,
+    transfer_id: u64,
+    peer: Result<SocketAddr, TransferError>,
+    message: &Message,
+) {
+    match peer {
+        Ok(peer) => {
+            if !ctx.manager.send_to(&peer, message) {
+                // The other party left or fell too far behind, so the transfer cannot go on
+                let _ = ctx.transfers.abort(transfer_id, addr);
+                let abort = Message::FileAbort {
+                    transfer_id,
+                    reason: "The other party could not be reached".to_string(),
+                };
+                let _ = ctx.manager.send_to(addr, &abort);
+            }
+        }
+        Err(e) => {
+            debug!("Refused {} from {}: {}", message.kind(), addr, e);
+            send_error(&ctx.manager, addr, e.code(), format!("Transfer {}: {}", transfer_id, e));
+        }
+    }
+}
+
+/// Send a delivery receipt, unless the client did not ask to track the message
+fn send_receipt(manager: &ConnectionManager, addr: &SocketAddr, client_id: u64, id: u64, status: DeliveryStatus) {
+    if client_id != 0 {
+        let _ = manager.send_to(addr, &Message::Receipt { client_id, id, status });
+    }
+}
+
+/// Send an error reply to a registered client
+fn send_error(manager: &ConnectionManager, addr: &SocketAddr, code: u16, message: String) {
+    let error = Message::Error { code, message };
+    let _ = manager.send_to(addr, &error);
+}
@@ -0,0 +1,189 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use log::{info, warn, error};
+
+use crate::common::protocol::{Message, FramedMessage};
+
+/// Name of the log file currently being appended to
+const LOG_FILE: &str = "history.log";
+/// Name the log file is renamed to when it grows too large
+const ROTATED_LOG_FILE: &str = "history.log.1";
+
+/// Recent room messages kept in memory, optionally backed by an on-disk log
+#[derive(Clone)]
+pub struct History {
+    inner: Arc<Mutex<HistoryInner>>,
+}
+
+struct HistoryInner {
+    retention: usize,
+    rooms: HashMap<String, VecDeque<Message>>,
+    log: Option<HistoryLog>,
+}
+
+/// Append-only log of messages that is rotated once it reaches a size limit
+struct HistoryLog {
+    dir: PathBuf,
+    max_file_size: u64,
+    file: File,
+    size: u64,
+}
+
+impl History {
+    /// Create an in-memory history keeping `retention` messages per room
+    pub fn in_memory(retention: usize) -> Self {
+        History {
+            inner: Arc::new(Mutex::new(HistoryInner {
+                retention,
+                rooms: HashMap::new(),
+                log: None,
+            })),
+        }
+    }
+    
+    /// Create a history backed by log files in `dir`, replaying what they hold
+    pub fn open(retention: usize, dir: &Path, max_file_size: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        
+        let mut inner = HistoryInner {
+            retention,
+            rooms: HashMap::new(),
+            log: None,
+        };
+        
+        // Oldest file first so the newest messages win the retention limit
+        let mut loaded = 0;
+        for name in [ROTATED_LOG_FILE, LOG_FILE] {
+            for message in read_log(&dir.join(name))? {
+                inner.remember(message);
+                loaded += 1;
+            }
+        }
+        info!("Loaded {} history messages from {}", loaded, dir.display());
+        
+        let path = dir.join(LOG_FILE);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        inner.log = Some(HistoryLog {
+            dir: dir.to_path_buf(),
+            max_file_size,
+            file,
+            size,
+        });
+        
+        Ok(History {
+            inner: Arc::new(Mutex::new(inner)),
+        })
+    }
+    
+    /// Record a room broadcast; other message types are ignored
+    pub fn record(&self, message: &Message) {
+        if !matches!(message, Message::Broadcast { .. }) {
+            return;
+        }
+        
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(log) = inner.log.as_mut() {
+            if let Err(e) = log.append(message) {
+                error!("Failed to write history log: {}", e);
+            }
+        }
+        inner.remember(message.clone());
+    }
+    
+    /// The most recent messages for a room, oldest first
+    pub fn recent(&self, room: &str) -> Vec<Message> {
+        let inner = self.inner.lock().unwrap();
+        inner.rooms
+            .get(room)
+            .map(|messages| messages.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+    
+    /// The messages for a room with IDs after `last_id`, oldest first
+    pub fn since(&self, room: &str, last_id: u64) -> Vec<Message> {
+        self.recent(room)
+            .into_iter()
+            .filter(|message| matches!(message, Message::Broadcast { id, .. } if *id > last_id))
+            .collect()
+    }
+    
+    /// The highest message ID remembered in any room, or 0 if there are none
+    pub fn last_id(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.rooms.values()
+            .flatten()
+            .filter_map(|message| match message {
+                Message::Broadcast { id, .. } => Some(*id),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl HistoryInner {
+    /// Add a message to its room's buffer, evicting the oldest beyond retention
+    fn remember(&mut self, message: Message) {
+        let room = match &message {
+            Message::Broadcast { room, .. } => room.clone(),
+            _ => return,
+        };
+        let messages = self.rooms.entry(room).or_default();
+        messages.push_back(message);
+        while messages.len() > self.retention {
+            messages.pop_front();
+        }
+    }
+}
+
+impl HistoryLog {
+    /// Append a framed message, rotating first if it would exceed the size limit
+    fn append(&mut self, message: &Message) -> Result<(), anyhow::Error> {
+        let frame = FramedMessage::encode(message)?;
+        if self.size > 0 && self.size + frame.len() as u64 > self.max_file_size {
+            self.rotate()?;
+        }
+        self.file.write_all(&frame)?;
+        self.size += frame.len() as u64;
+        Ok(())
+    }
+    
+    /// Move the current log aside and start a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        let current = self.dir.join(LOG_FILE);
+        fs::rename(&current, self.dir.join(ROTATED_LOG_FILE))?;
+        self.file = OpenOptions::new().create(true).append(true).open(&current)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Read every message from a log file; a missing file is treated as empty
+fn read_log(path: &Path) -> io::Result<Vec<Message>> {
+    let mut buffer = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_to_end(&mut buffer)?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    }
+    
+    let mut messages = Vec::new();
+    loop {
+        match FramedMessage::decode(&mut buffer) {
+            Ok(Some(message)) => messages.push(message),
+            Ok(None) => break,
+            Err(e) => {
+                // A truncated or outdated entry ends the usable part of the file
+                warn!("Stopped reading {} at a bad entry: {}", path.display(), e);
+                break;
+            }
+        }
+    }
+    Ok(messages)
+}
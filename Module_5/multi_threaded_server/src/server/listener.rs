@@ -1,94 +1,652 @@
-use std::net::{TcpListener, TcpStream};
-use std::sync::Arc;
+use std::net::{TcpListener, TcpStream, SocketAddr, Shutdown};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use log::{info, warn, error};
 
-use crate::server::connection_manager::ConnectionManager;
-use crate::server::handler::handle_client;
+use crate::common::codec::WireFormat;
+use crate::common::protocol::Message;
+use crate::common::tls::ServerTlsConfig;
+use crate::common::transport::Acceptor;
+use crate::server::auth::{AuthConfig, Authenticator};
+use crate::server::connection_manager::{ConnectionManager, SlowConsumerPolicy};
+use crate::server::deliveries::Deliveries;
+use crate::server::handler::{handle_client, HandlerContext};
+use crate::server::history::History;
+use crate::server::mailbox::Mailboxes;
+use crate::server::metrics;
+use crate::server::moderation::Moderation;
+use crate::server::reactor;
+use crate::server::resume::ResumeTokens;
+use crate::server::rate_limit::RateLimitConfig;
+use crate::server::transfers::Transfers;
+use crate::server::username::UsernameRules;
+
+/// An additional socket for clients that speak a different wire format
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenerConfig {
+    pub bind_addr: String,
+    pub wire_format: WireFormat,
+}
+
+/// How the server multiplexes client connections onto threads
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum IoModel {
+    /// A handler thread and a writer thread for every client
+    #[default]
+    ThreadPerConnection,
+    /// Readiness-based event loops on a fixed number of worker threads
+    Reactor { workers: usize },
+}
 
 /// Server configuration
+#[derive(Clone)]
 pub struct ServerConfig {
     pub bind_addr: String,
+    /// Codec spoken by clients connecting to `bind_addr`
+    pub wire_format: WireFormat,
+    /// More sockets to accept clients on, each with its own wire format
+    pub extra_listeners: Vec<ListenerConfig>,
     pub max_connections: usize,
     pub connection_timeout: Duration,
+    /// How long to wait for handler threads to finish during shutdown
+    pub shutdown_timeout: Duration,
+    /// Frames that may be queued for a single client before the policy applies
+    pub outbound_queue_size: usize,
+    /// What to do with a client whose outbound queue is full
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    /// Rules for usernames accepted at Join time
+    pub username_rules: UsernameRules,
+    /// Number of recent messages kept per room and replayed to new members
+    pub history_retention: usize,
+    /// Directory for the on-disk history log; None keeps history in memory only
+    pub history_dir: Option<PathBuf>,
+    /// Size in bytes at which the history log is rotated
+    pub history_max_file_size: u64,
+    /// Largest file, in bytes, relayed between clients in one transfer
+    pub max_file_size: u64,
+    /// How clients are authenticated when they join
+    pub auth: AuthConfig,
+    /// Failed authentication attempts allowed before the connection is closed
+    pub max_auth_attempts: u32,
+    /// Certificate and key for encrypted connections; None accepts plain TCP
+    pub tls: Option<ServerTlsConfig>,
+    /// Per-connection flood protection; None lets clients send as fast as they like
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Address for the Prometheus `/metrics` endpoint; None disables it
+    pub metrics_addr: Option<String>,
+    /// Address for WebSocket clients, who send JSON text frames; None disables it
+    ///
+    /// WebSocket clients connect unencrypted, so this cannot be combined with `tls`.
+    pub websocket_addr: Option<String>,
+    /// Usernames granted operator rights when they join
+    pub operators: Vec<String>,
+    /// Also make the first client to join after startup an operator
+    pub first_user_is_operator: bool,
+    /// File the ban list is kept in; None forgets bans on restart
+    pub ban_file: Option<PathBuf>,
+    /// File private messages for offline users are kept in; None forgets them on restart
+    pub mailbox_file: Option<PathBuf>,
+    /// Messages kept for each offline user before further ones are refused
+    pub mailbox_capacity: usize,
+    /// How long a message waits for an offline user before it is dropped
+    pub mailbox_expiry: Duration,
+    /// File resumable sessions are kept in; None forgets them on restart
+    pub session_file: Option<PathBuf>,
+    /// How long after a connection drops the client can resume its session
+    pub resume_window: Duration,
+    /// Threading model used to serve clients
+    pub io_model: IoModel,
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         ServerConfig {
             bind_addr: "127.0.0.1:8080".to_string(),
+            wire_format: WireFormat::Bincode,
+            extra_listeners: Vec::new(),
             max_connections: 100,
             connection_timeout: Duration::from_secs(30),
+            shutdown_timeout: Duration::from_secs(5),
+            outbound_queue_size: 256,
+            slow_consumer_policy: SlowConsumerPolicy::DropOldest,
+            username_rules: UsernameRules::default(),
+            history_retention: 100,
+            history_dir: None,
+            history_max_file_size: 1024 * 1024,
+            max_file_size: 100 * 1024 * 1024,
+            auth: AuthConfig::AllowAll,
+            max_auth_attempts: 3,
+            tls: None,
+            rate_limit: None,
+            metrics_addr: None,
+            websocket_addr: None,
+            operators: Vec::new(),
+            first_user_is_operator: false,
+            ban_file: None,
+            mailbox_file: None,
+            mailbox_capacity: 100,
+            mailbox_expiry: Duration::from_secs(7 * 24 * 60 * 60),
+            session_file: None,
+            resume_window: Duration::from_secs(15 * 60),
+            io_model: IoModel::default(),
+        }
+    }
+}
+
+/// Handle used to request a graceful shutdown of a running server
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    reason: Arc<Mutex<String>>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        ShutdownHandle {
+            requested: Arc::new(AtomicBool::new(false)),
+            reason: Arc::new(Mutex::new(String::new())),
+        }
+    }
+    
+    /// Ask the server to stop; the reason is sent to every connected client
+    pub fn shutdown(&self, reason: impl Into<String>) {
+        *self.reason.lock().unwrap() = reason.into();
+        self.requested.store(true, Ordering::SeqCst);
+    }
+    
+    /// Check whether a shutdown has been requested
+    pub fn is_shutdown(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+    
+    /// Get the reason passed to `shutdown`
+    pub fn reason(&self) -> String {
+        self.reason.lock().unwrap().clone()
+    }
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Server configuration that can be replaced while the server runs
+///
+/// Readers take a snapshot with `get`, so a reload never changes settings
+/// halfway through a decision.
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<ServerConfig>>>);
+
+impl SharedConfig {
+    pub fn new(config: ServerConfig) -> Self {
+        SharedConfig(Arc::new(RwLock::new(Arc::new(config))))
+    }
+    
+    /// The configuration currently in effect
+    pub fn get(&self) -> Arc<ServerConfig> {
+        self.0.read().unwrap().clone()
+    }
+    
+    fn set(&self, config: ServerConfig) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
+}
+
+/// Handle used to apply new settings to a running server
+#[derive(Clone)]
+pub struct ReloadHandle {
+    config: SharedConfig,
+    transfers: Transfers,
+    /// Set once the server has loaded the ban list and started serving
+    moderation: Arc<Mutex<Option<Moderation>>>,
+}
+
+impl ReloadHandle {
+    /// Apply the settings that are safe to change at runtime, then re-read the ban list
+    ///
+    /// Connection and file size limits, timeouts, rate limiting, username rules
+    /// and operators are taken from `config`; connected clients move to a new
+    /// rate limit with whatever allowance they have left. Everything else
+    /// (addresses, TLS, storage, the I/O model) only changes on restart and is
+    /// ignored here.
+    pub fn reload(&self, config: ServerConfig) -> Result<(), anyhow::Error> {
+        let mut updated = ServerConfig::clone(&self.config.get());
+        updated.max_connections = config.max_connections;
+        updated.connection_timeout = config.connection_timeout;
+        updated.shutdown_timeout = config.shutdown_timeout;
+        updated.username_rules = config.username_rules;
+        updated.max_file_size = config.max_file_size;
+        updated.max_auth_attempts = config.max_auth_attempts;
+        updated.rate_limit = config.rate_limit;
+        updated.operators = config.operators;
+        
+        self.transfers.set_max_size(updated.max_file_size);
+        self.config.set(updated);
+        info!("Reloaded configuration");
+        
+        if let Some(moderation) = &*self.moderation.lock().unwrap() {
+            moderation.reload()?;
         }
+        Ok(())
     }
 }
 
 /// Main server that listens for connections
 pub struct Server {
-    config: ServerConfig,
+    config: SharedConfig,
     manager: ConnectionManager,
+    history: History,
+    deliveries: Deliveries,
+    transfers: Transfers,
+    /// Overrides the authenticator built from `config.auth`
+    authenticator: Option<Arc<dyn Authenticator>>,
+    shutdown: ShutdownHandle,
+    reload: ReloadHandle,
 }
 
 impl Server {
     pub fn new(config: ServerConfig) -> Self {
+        let history = match &config.history_dir {
+            Some(dir) => History::open(config.history_retention, dir, config.history_max_file_size)
+                .unwrap_or_else(|e| {
+                    error!("Failed to open history log in {}: {}", dir.display(), e);
+                    History::in_memory(config.history_retention)
+                }),
+            None => History::in_memory(config.history_retention),
+        };
+        
+        // Persisted history keeps message IDs increasing across restarts, as do
+        // the mailbox and session files once they are opened
+        let deliveries = Deliveries::new(history.last_id() + 1, config.mailbox_expiry);
+        let transfers = Transfers::new(config.max_file_size);
+        let manager = ConnectionManager::with_outbound(
+            config.outbound_queue_size,
+            config.slow_consumer_policy,
+        );
+        let config = SharedConfig::new(config);
+        
         Server {
+            manager,
+            reload: ReloadHandle {
+                config: config.clone(),
+                transfers: transfers.clone(),
+                moderation: Arc::new(Mutex::new(None)),
+            },
             config,
-            manager: ConnectionManager::new(),
+            history,
+            deliveries,
+            transfers,
+            authenticator: None,
+            shutdown: ShutdownHandle::new(),
         }
     }
     
-    /// Start the server
+    /// Get a handle that can stop the server from another thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+    
+    /// Get a handle that can change the server's settings from another thread
+    pub fn reload_handle(&self) -> ReloadHandle {
+        self.reload.clone()
+    }
+    
+    /// Use a custom authenticator instead of the one described by `config.auth`
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+    
+    /// Bind the listening sockets without accepting connections yet
+    pub fn bind(config: ServerConfig) -> Result<BoundServer, anyhow::Error> {
+        let listeners = bind_listeners(&config)?;
+        let websocket_listener = bind_websocket(&config)?;
+        let metrics_listener = bind_metrics(&config)?;
+        Ok(BoundServer {
+            server: Server::new(config),
+            listeners,
+            websocket_listener,
+            metrics_listener,
+        })
+    }
+    
+    /// Start the server, returning once a shutdown has been requested and completed
     pub fn run(&self) -> Result<(), anyhow::Error> {
-        let listener = TcpListener::bind(&self.config.bind_addr)?;
-        info!("Server listening on {}", self.config.bind_addr);
+        let config = self.config.get();
+        let listeners = bind_listeners(&config)?;
+        let websocket_listener = bind_websocket(&config)?;
+        let metrics_listener = bind_metrics(&config)?;
+        self.serve_on(listeners, websocket_listener, metrics_listener)
+    }
+    
+    /// Accept connections on already bound listeners until shutdown
+    fn serve_on(
+        &self,
+        listeners: Vec<(TcpListener, WireFormat)>,
+        websocket_listener: Option<TcpListener>,
+        metrics_listener: Option<TcpListener>,
+    ) -> Result<(), anyhow::Error> {
+        let config = self.config.get();
+        if config.tls.is_some() && config.io_model != IoModel::ThreadPerConnection {
+            anyhow::bail!("TLS is only supported with the thread-per-connection I/O model");
+        }
+        if websocket_listener.is_some() && config.io_model != IoModel::ThreadPerConnection {
+            anyhow::bail!("WebSocket clients are only supported with the thread-per-connection I/O model");
+        }
+        // Waiting for room in one client's queue would stall every connection on its worker
+        if matches!(config.slow_consumer_policy, SlowConsumerPolicy::Block(_)) && config.io_model != IoModel::ThreadPerConnection {
+            anyhow::bail!("The Block slow-consumer policy is only supported with the thread-per-connection I/O model");
+        }
+        let contexts = self.contexts(listeners.iter().map(|(_, wire_format)| *wire_format))?;
         
-        // Set non-blocking mode with timeout
-        listener.set_nonblocking(true)?;
+        let mut sockets = Vec::new();
+        for ((listener, wire_format), ctx) in listeners.into_iter().zip(contexts) {
+            info!("Server listening on {} ({:?})", listener.local_addr()?, wire_format);
+            // Non-blocking so the shutdown flag is checked regularly
+            listener.set_nonblocking(true)?;
+            sockets.push((listener, ctx));
+        }
+        if let Some(listener) = websocket_listener {
+            info!("Server listening on {} (WebSocket)", listener.local_addr()?);
+            listener.set_nonblocking(true)?;
+            // Frames are turned into JSON lines by the transport
+            let ctx = HandlerContext {
+                acceptor: Acceptor::websocket(),
+                wire_format: WireFormat::JsonLines,
+                ..sockets[0].1.clone()
+            };
+            sockets.push((listener, ctx));
+        }
         
-        let mut handles = vec![];
+        let metrics_handle = self.serve_metrics(metrics_listener)?;
         
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    if self.manager.client_count() >= self.config.max_connections {
-                        warn!("Max connections reached, rejecting new client");
-                        continue;
-                    }
-                    
-                    let addr = match stream.peer_addr() {
-                        Ok(addr) => addr,
-                        Err(e) => {
-                            error!("Failed to get peer address: {}", e);
+        match config.io_model {
+            IoModel::ThreadPerConnection => self.accept_loop(sockets),
+            IoModel::Reactor { workers } => {
+                reactor::serve(sockets, workers, &self.shutdown, |addr, ctx| self.admit(addr, ctx))?;
+            }
+        }
+        
+        if let Some(handle) = metrics_handle {
+            let _ = handle.join();
+        }
+        Ok(())
+    }
+    
+    /// Spawn a handler thread for every accepted connection until shutdown
+    fn accept_loop(&self, sockets: Vec<(TcpListener, HandlerContext)>) {
+        // Keep a clone of every stream so handlers can be unblocked on shutdown
+        let mut handles: Vec<(thread::JoinHandle<()>, SocketAddr, TcpStream)> = vec![];
+        
+        while !self.shutdown.is_shutdown() {
+            let mut idle = true;
+            for (listener, ctx) in &sockets {
+                match listener.accept() {
+                    Ok((stream, addr)) => {
+                        idle = false;
+                        if !self.admit(&addr, ctx) {
                             continue;
                         }
-                    };
-                    
-                    // Configure stream
-                    if let Err(e) = stream.set_read_timeout(Some(self.config.connection_timeout)) {
-                        error!("Failed to set read timeout: {}", e);
+                        
+                        // Configure stream
+                        if let Err(e) = stream.set_nonblocking(false) {
+                            error!("Failed to set blocking mode: {}", e);
+                        }
+                        let connection_timeout = self.config.get().connection_timeout;
+                        if let Err(e) = stream.set_read_timeout(Some(connection_timeout)) {
+                            error!("Failed to set read timeout: {}", e);
+                        }
+                        if let Err(e) = stream.set_write_timeout(Some(connection_timeout)) {
+                            error!("Failed to set write timeout: {}", e);
+                        }
+                        
+                        let control = match stream.try_clone() {
+                            Ok(control) => control,
+                            Err(e) => {
+                                error!("Failed to clone stream for {}: {}", addr, e);
+                                continue;
+                            }
+                        };
+                        
+                        // Spawn handler
+                        let handle = handle_client(stream, addr, ctx.clone());
+                        handles.push((handle, addr, control));
+                        self.manager.metrics().connection_accepted();
                     }
-                    if let Err(e) = stream.set_write_timeout(Some(self.config.connection_timeout)) {
-                        error!("Failed to set write timeout: {}", e);
+                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                    Err(e) => {
+                        error!("Connection failed: {}", e);
                     }
-                    
-                    // Spawn handler
-                    let handle = handle_client(stream, addr, self.manager.clone());
-                    handles.push(handle);
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // No connection ready, yield and continue
-                    thread::sleep(Duration::from_millis(100));
-                }
-                Err(e) => {
-                    error!("Connection failed: {}", e);
                 }
             }
             
+            if idle {
+                // No connection ready, yield and continue
+                thread::sleep(Duration::from_millis(100));
+            }
+            
             // Clean up finished threads
-            handles.retain(|h| !h.is_finished());
+            handles.retain(|(h, _, _)| !h.is_finished());
         }
         
-        Ok(())
+        drop(sockets);
+        self.shutdown_clients(handles);
+    }
+    
+    /// Decide whether a newly accepted connection may be served
+    pub(crate) fn admit(&self, addr: &SocketAddr, ctx: &HandlerContext) -> bool {
+        if self.manager.client_count() >= self.config.get().max_connections {
+            warn!("Max connections reached, rejecting new client");
+            self.manager.metrics().connection_rejected();
+            return false;
+        }
+        if let Some(ban) = ctx.moderation.find_ban(None, addr.ip()) {
+            info!("Rejected banned address {}: {}", addr, ban.reason);
+            self.manager.metrics().connection_rejected();
+            return false;
+        }
+        true
+    }
+    
+    /// Handler contexts for listeners speaking `wire_formats`, in the same order
+    pub(crate) fn contexts(
+        &self,
+        wire_formats: impl IntoIterator<Item = WireFormat>,
+    ) -> Result<Vec<HandlerContext>, anyhow::Error> {
+        let config = self.config.get();
+        let authenticator = match &self.authenticator {
+            Some(authenticator) => authenticator.clone(),
+            None => config.auth.build()?,
+        };
+        let acceptor = Acceptor::new(config.tls.as_ref())?;
+        let moderation = Moderation::open(config.ban_file.as_deref())?;
+        let mailboxes = Mailboxes::open(
+            config.mailbox_file.as_deref(),
+            config.mailbox_capacity,
+            config.mailbox_expiry,
+        )?;
+        let resume = ResumeTokens::open(config.session_file.as_deref(), config.resume_window)?;
+        self.deliveries.skip_past(mailboxes.last_id().max(resume.last_id()));
+        *self.reload.moderation.lock().unwrap() = Some(moderation.clone());
+        
+        Ok(wire_formats.into_iter()
+            .map(|wire_format| {
+                self.context(authenticator.clone(), acceptor.clone(), moderation.clone(), mailboxes.clone(), resume.clone(), wire_format)
+            })
+            .collect())
+    }
+    
+    /// Serve the metrics endpoint on its own thread until shutdown, if one is bound
+    pub(crate) fn serve_metrics(
+        &self,
+        listener: Option<TcpListener>,
+    ) -> Result<Option<thread::JoinHandle<()>>, anyhow::Error> {
+        match listener {
+            Some(listener) => Ok(Some(metrics::serve_http(
+                listener,
+                self.manager.metrics().clone(),
+                self.shutdown.clone(),
+            )?)),
+            None => Ok(None),
+        }
+    }
+    
+    /// Shared state handed to the handlers of one listener's connections
+    fn context(
+        &self,
+        authenticator: Arc<dyn Authenticator>,
+        acceptor: Acceptor,
+        moderation: Moderation,
+        mailboxes: Mailboxes,
+        resume: ResumeTokens,
+        wire_format: WireFormat,
+    ) -> HandlerContext {
+        HandlerContext {
+            manager: self.manager.clone(),
+            config: self.config.clone(),
+            history: self.history.clone(),
+            deliveries: self.deliveries.clone(),
+            transfers: self.transfers.clone(),
+            authenticator,
+            acceptor,
+            moderation,
+            mailboxes,
+            resume,
+            wire_format,
+        }
+    }
+    
+    /// Say goodbye to every client and wait for the handler threads to exit
+    fn shutdown_clients(&self, handles: Vec<(thread::JoinHandle<()>, SocketAddr, TcpStream)>) {
+        let reason = self.shutdown.reason();
+        info!("Shutting down ({} clients connected): {}", self.manager.client_count(), reason);
+        
+        // Joined clients get the goodbye and are closed by their writer threads
+        let closed = self.manager.close_all(&Message::Shutdown { reason });
+        
+        // Connections that never joined have no writer, so close them here
+        for (_, addr, stream) in &handles {
+            if !closed.contains(addr) {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        
+        let deadline = Instant::now() + self.config.get().shutdown_timeout;
+        while handles.iter().any(|(h, _, _)| !h.is_finished()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        
+        let mut abandoned = 0;
+        for (handle, _, stream) in handles {
+            if handle.is_finished() {
+                let _ = handle.join();
+            } else {
+                let _ = stream.shutdown(Shutdown::Both);
+                abandoned += 1;
+            }
+        }
+        if abandoned > 0 {
+            warn!("{} handler threads did not finish before the shutdown deadline", abandoned);
+        }
+    }
+}
+
+/// Bind the main listener followed by every extra listener
+fn bind_listeners(config: &ServerConfig) -> Result<Vec<(TcpListener, WireFormat)>, anyhow::Error> {
+    let mut listeners = vec![(TcpListener::bind(&config.bind_addr)?, config.wire_format)];
+    for extra in &config.extra_listeners {
+        listeners.push((TcpListener::bind(&extra.bind_addr)?, extra.wire_format));
+    }
+    Ok(listeners)
+}
+
+/// Bind the WebSocket listener if one is configured
+fn bind_websocket(config: &ServerConfig) -> Result<Option<TcpListener>, anyhow::Error> {
+    match &config.websocket_addr {
+        // WebSocket clients would otherwise get plain text on a server meant to be encrypted
+        Some(_) if config.tls.is_some() => {
+            anyhow::bail!("WebSocket clients cannot use TLS; serve them through a TLS-terminating proxy instead")
+        }
+        Some(addr) => Ok(Some(TcpListener::bind(addr)?)),
+        None => Ok(None),
+    }
+}
+
+/// Bind the metrics endpoint if one is configured
+pub(crate) fn bind_metrics(config: &ServerConfig) -> Result<Option<TcpListener>, anyhow::Error> {
+    match &config.metrics_addr {
+        Some(addr) => Ok(Some(TcpListener::bind(addr)?)),
+        None => Ok(None),
+    }
+}
+
+/// A server whose listening sockets are bound but not yet accepting connections
+pub struct BoundServer {
+    server: Server,
+    /// The main listener first, then the extra listeners in config order
+    listeners: Vec<(TcpListener, WireFormat)>,
+    websocket_listener: Option<TcpListener>,
+    metrics_listener: Option<TcpListener>,
+}
+
+impl BoundServer {
+    /// The address the main listener actually bound to (useful with port 0)
+    pub fn local_addr(&self) -> Result<SocketAddr, anyhow::Error> {
+        Ok(self.listeners[0].0.local_addr()?)
+    }
+    
+    /// The addresses of every listener, main listener first
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>, anyhow::Error> {
+        let mut addrs = Vec::new();
+        for (listener, _) in &self.listeners {
+            addrs.push(listener.local_addr()?);
+        }
+        Ok(addrs)
+    }
+    
+    /// The address WebSocket clients connect to, if one is configured
+    pub fn websocket_addr(&self) -> Result<Option<SocketAddr>, anyhow::Error> {
+        match &self.websocket_listener {
+            Some(listener) => Ok(Some(listener.local_addr()?)),
+            None => Ok(None),
+        }
+    }
+    
+    /// The address of the metrics endpoint, if one is configured
+    pub fn metrics_addr(&self) -> Result<Option<SocketAddr>, anyhow::Error> {
+        match &self.metrics_listener {
+            Some(listener) => Ok(Some(listener.local_addr()?)),
+            None => Ok(None),
+        }
+    }
+    
+    /// Get a handle that can stop the server from another thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.server.shutdown_handle()
+    }
+    
+    /// Get a handle that can change the server's settings from another thread
+    pub fn reload_handle(&self) -> ReloadHandle {
+        self.server.reload_handle()
+    }
+    
+    /// Use a custom authenticator instead of the one described by `config.auth`
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.server = self.server.with_authenticator(authenticator);
+        self
+    }
+    
+    /// Accept connections until a shutdown has been requested and completed
+    pub fn serve(self) -> Result<(), anyhow::Error> {
+        self.server.serve_on(self.listeners, self.websocket_listener, self.metrics_listener)
+    }
+    
+    /// Run `serve` on a background thread
+    pub fn spawn(self) -> thread::JoinHandle<Result<(), anyhow::Error>> {
+        thread::spawn(move || self.serve())
     }
 }
@@ -0,0 +1,179 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::{info, error};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+
+use crate::common::protocol::{Message, current_timestamp, error_codes};
+use crate::server::storage::atomic_write;
+
+/// Why a private message could not be kept for later
+#[derive(Debug, Error, PartialEq)]
+pub enum MailboxError {
+    #[error("User {0} not found")]
+    UnknownUser(String),
+    
+    #[error("{0}'s mailbox is full")]
+    Full(String),
+}
+
+impl MailboxError {
+    /// Error code reported to the sender
+    pub fn code(&self) -> u16 {
+        match self {
+            MailboxError::UnknownUser(_) => error_codes::NOT_FOUND,
+            MailboxError::Full(_) => error_codes::MAILBOX_FULL,
+        }
+    }
+}
+
+/// A private message waiting for its recipient
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Mail {
+    message: Message,
+    /// Unix timestamp (seconds) of when the message was queued
+    queued_at: u64,
+}
+
+/// Everything saved to the mailbox file
+#[derive(Default, Serialize, Deserialize)]
+struct Store {
+    /// Lowercased names of everyone who has joined
+    known: BTreeSet<String>,
+    /// Waiting messages by lowercased recipient, oldest first
+    mailboxes: BTreeMap<String, VecDeque<Mail>>,
+}
+
+/// Private messages kept for known users while they are offline
+#[derive(Clone)]
+pub struct Mailboxes {
+    store: Arc<Mutex<Store>>,
+    /// Where the store is saved so it survives a restart
+    file: Option<PathBuf>,
+    capacity: usize,
+    expiry: Duration,
+}
+
+impl Mailboxes {
+    /// Create the mailboxes, loading them from `file` if it exists
+    ///
+    /// Each user gets room for `capacity` messages, and messages are dropped
+    /// once they have waited longer than `expiry`.
+    pub fn open(file: Option<&Path>, capacity: usize, expiry: Duration) -> Result<Self, anyhow::Error> {
+        let store = match file {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(contents) => {
+                    let store: Store = serde_json::from_str(&contents)
+                        .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e))?;
+                    let waiting: usize = store.mailboxes.values().map(VecDeque::len).sum();
+                    info!("Loaded {} queued messages from {}", waiting, path.display());
+                    store
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Store::default(),
+                Err(e) => anyhow::bail!("Failed to read {}: {}", path.display(), e),
+            },
+            None => Store::default(),
+        };
+        
+        Ok(Mailboxes {
+            store: Arc::new(Mutex::new(store)),
+            file: file.map(Path::to_path_buf),
+            capacity,
+            expiry,
+        })
+    }
+    
+    /// Note that a user has joined, so messages can be kept for them later
+    pub fn remember(&self, username: &str) {
+        let mut store = self.store.lock().unwrap();
+        if store.known.insert(username.to_lowercase()) {
+            self.save(&store);
+        }
+    }
+    
+    /// Keep a private message until its recipient next joins, unless `online` finds them
+    ///
+    /// Returns what `online` found, in which case nothing is kept. It runs
+    /// while no mail can be handed over, so a recipient who is joining at the
+    /// same moment either gets the message with their mail or is found.
+    pub fn deposit<T>(
+        &self,
+        to: &str,
+        message: &Message,
+        online: impl FnOnce() -> Option<T>,
+    ) -> Result<Option<T>, MailboxError> {
+        let mut store = self.store.lock().unwrap();
+        if let Some(found) = online() {
+            return Ok(Some(found));
+        }
+        let key = to.to_lowercase();
+        if !store.known.contains(&key) {
+            return Err(MailboxError::UnknownUser(to.to_string()));
+        }
+        
+        let now = current_timestamp();
+        let mailbox = store.mailboxes.entry(key).or_default();
+        mailbox.retain(|mail| !self.expired(mail, now));
+        if mailbox.len() >= self.capacity {
+            return Err(MailboxError::Full(to.to_string()));
+        }
+        mailbox.push_back(Mail { message: message.clone(), queued_at: now });
+        self.save(&store);
+        Ok(None)
+    }
+    
+    /// Give every unexpired message waiting for a user to `deliver`, oldest first
+    ///
+    /// Nothing can be deposited until `deliver` returns, so it can make the
+    /// user reachable without a new message slipping in ahead of the old ones
+    /// or being left behind in the mailbox.
+    pub fn hand_over(&self, username: &str, deliver: impl FnOnce(Vec<Message>)) {
+        let mut store = self.store.lock().unwrap();
+        let mail = match store.mailboxes.remove(&username.to_lowercase()) {
+            Some(mailbox) => {
+                self.save(&store);
+                let now = current_timestamp();
+                mailbox.into_iter()
+                    .filter(|mail| !self.expired(mail, now))
+                    .map(|mail| mail.message)
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        deliver(mail);
+    }
+    
+    /// The highest ID of any waiting message, or 0 if there are none
+    pub fn last_id(&self) -> u64 {
+        let store = self.store.lock().unwrap();
+        store.mailboxes.values()
+            .flatten()
+            .filter_map(|mail| match mail.message {
+                Message::Private { id, .. } => Some(id),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+    
+    fn expired(&self, mail: &Mail, now: u64) -> bool {
+        now >= mail.queued_at.saturating_add(self.expiry.as_secs())
+    }
+    
+    /// Write the store to the mailbox file; messages stay in memory if it fails
+    fn save(&self, store: &Store) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        let result = serde_json::to_string(store)
+            .map_err(io::Error::from)
+            .and_then(|contents| atomic_write(path, contents.as_bytes()));
+        if let Err(e) = result {
+            error!("Failed to save mailboxes to {}: {}", path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use log::{info, warn, debug};
+
+use crate::common::protocol::Message;
+use crate::server::listener::ShutdownHandle;
+
+/// Upper bounds (seconds) of the broadcast latency histogram buckets
+const LATENCY_BUCKETS: [f64; 9] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.25];
+
+/// Counters and gauges describing a running server
+#[derive(Default)]
+pub struct Metrics {
+    connected_clients: AtomicI64,
+    connections_accepted: AtomicU64,
+    connections_rejected: AtomicU64,
+    messages_in: Mutex<BTreeMap<&'static str, u64>>,
+    messages_out: Mutex<BTreeMap<&'static str, u64>>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    decode_errors: AtomicU64,
+    broadcast_latency: Histogram,
+}
+
+/// Latency histogram with fixed buckets
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative; the last slot is +Inf
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// A client finished joining
+    pub fn client_joined(&self) {
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// A joined client went away
+    pub fn client_left(&self) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+    
+    /// The listener handed a new connection to a handler
+    pub fn connection_accepted(&self) {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// The listener turned a connection away
+    pub fn connection_rejected(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// A message was decoded from a client
+    pub fn message_in(&self, message: &Message) {
+        *self.messages_in.lock().unwrap().entry(message.kind()).or_default() += 1;
+    }
+    
+    /// A message was queued for `recipients` clients
+    pub fn message_out(&self, message: &Message, recipients: u64) {
+        if recipients > 0 {
+            *self.messages_out.lock().unwrap().entry(message.kind()).or_default() += recipients;
+        }
+    }
+    
+    /// Bytes read from client sockets
+    pub fn bytes_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    
+    /// Bytes written to client sockets
+    pub fn bytes_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    
+    /// A client sent something that could not be decoded
+    pub fn decode_error(&self) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Time taken to queue one broadcast for all of its recipients
+    pub fn observe_broadcast(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|&le| secs <= le).unwrap_or(LATENCY_BUCKETS.len());
+        let histogram = &self.broadcast_latency;
+        histogram.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        histogram.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        histogram.count.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Render every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        
+        metric(&mut out, "chat_connected_clients", "gauge", "Clients that have joined and not yet left");
+        let _ = writeln!(out, "chat_connected_clients {}", self.connected_clients.load(Ordering::Relaxed));
+        metric(&mut out, "chat_connections_accepted_total", "counter", "Connections handed to a handler");
+        let _ = writeln!(out, "chat_connections_accepted_total {}", load(&self.connections_accepted));
+        metric(&mut out, "chat_connections_rejected_total", "counter", "Connections turned away by the listener");
+        let _ = writeln!(out, "chat_connections_rejected_total {}", load(&self.connections_rejected));
+        
+        metric(&mut out, "chat_messages_in_total", "counter", "Messages received from clients by type");
+        for (kind, count) in self.messages_in.lock().unwrap().iter() {
+            let _ = writeln!(out, "chat_messages_in_total{{type=\"{}\"}} {}", kind, count);
+        }
+        metric(&mut out, "chat_messages_out_total", "counter", "Messages queued for clients by type");
+        for (kind, count) in self.messages_out.lock().unwrap().iter() {
+            let _ = writeln!(out, "chat_messages_out_total{{type=\"{}\"}} {}", kind, count);
+        }
+        
+        metric(&mut out, "chat_bytes_in_total", "counter", "Bytes read from client sockets");
+        let _ = writeln!(out, "chat_bytes_in_total {}", load(&self.bytes_in));
+        metric(&mut out, "chat_bytes_out_total", "counter", "Bytes written to client sockets");
+        let _ = writeln!(out, "chat_bytes_out_total {}", load(&self.bytes_out));
+        metric(&mut out, "chat_decode_errors_total", "counter", "Frames from clients that could not be decoded");
+        let _ = writeln!(out, "chat_decode_errors_total {}", load(&self.decode_errors));
+        
+        let histogram = &self.broadcast_latency;
+        metric(&mut out, "chat_broadcast_latency_seconds", "histogram", "Time to queue a broadcast for every recipient");
+        let mut cumulative = 0;
+        for (le, bucket) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+            cumulative += load(bucket);
+            let _ = writeln!(out, "chat_broadcast_latency_seconds_bucket{{le=\"{}\"}} {}", le, cumulative);
+        }
+        cumulative += load(&histogram.buckets[LATENCY_BUCKETS.len()]);
+        let _ = writeln!(out, "chat_broadcast_latency_seconds_bucket{{le=\"+Inf\"}} {}", cumulative);
+        let _ = writeln!(out, "chat_broadcast_latency_seconds_sum {}", load(&histogram.sum_micros) as f64 / 1e6);
+        let _ = writeln!(out, "chat_broadcast_latency_seconds_count {}", load(&histogram.count));
+        
+        out
+    }
+}
+
+/// Write the HELP and TYPE lines that introduce a metric
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Answer `GET /metrics` on `listener` until a shutdown is requested
+pub fn serve_http(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    shutdown: ShutdownHandle,
+) -> io::Result<thread::JoinHandle<()>> {
+    info!("Metrics available at http://{}/metrics", listener.local_addr()?);
+    listener.set_nonblocking(true)?;
+    
+    Ok(thread::spawn(move || {
+        while !shutdown.is_shutdown() {
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    if let Err(e) = respond(stream, &metrics) {
+                        debug!("Metrics request from {} failed: {}", addr, e);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Err(e) => {
+                    warn!("Metrics connection failed: {}", e);
+                }
+            }
+        }
+    }))
+}
+
+/// Read one HTTP request and reply with the metrics or a 404
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    
+    // Only the request line matters; read until the end of the headers
+    let mut request = Vec::new();
+    let mut read_buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let n = stream.read(&mut read_buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&read_buf[..n]);
+    }
+    
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+    
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
@@ -1,3 +1,18 @@
 pub mod listener;
 pub mod handler;
-pub mod connection_manager;
\ No newline at end of file
+pub mod connection_manager;
+pub mod username;
+pub mod history;
+pub mod auth;
+pub mod rate_limit;
+pub mod metrics;
+pub mod moderation;
+pub mod reactor;
+pub mod transfers;
+pub mod deliveries;
+pub mod mailbox;
+pub mod settings;
+pub mod resume;
+pub mod storage;
+#[cfg(feature = "async")]
+pub mod async_server;
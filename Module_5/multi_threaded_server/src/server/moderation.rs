@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::info;
+use serde::{Serialize, Deserialize};
+
+use crate::common::protocol::{BanTarget, current_timestamp};
+use crate::server::storage::atomic_write;
+
+/// A ban recorded by an operator
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Ban {
+    pub target: BanTarget,
+    /// Unix timestamp (seconds) when the ban lapses; None is forever
+    pub expires_at: Option<u64>,
+    pub reason: String,
+    /// Operator who issued the ban
+    pub by: String,
+}
+
+impl Ban {
+    fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires| now < expires)
+    }
+    
+    fn matches(&self, username: Option<&str>, ip: IpAddr) -> bool {
+        match &self.target {
+            BanTarget::Username(name) => username.is_some_and(|u| u.eq_ignore_ascii_case(name)),
+            BanTarget::Ip(banned) => *banned == ip,
+        }
+    }
+}
+
+/// Operator grants, bans and mutes shared by every connection
+#[derive(Clone)]
+pub struct Moderation {
+    bans: Arc<Mutex<Vec<Ban>>>,
+    /// Where bans are saved so they survive a restart
+    ban_file: Option<PathBuf>,
+    /// Muted usernames (lowercased) and when their mute ends
+    mutes: Arc<Mutex<HashMap<String, Instant>>>,
+    /// Lowercased name of whoever the first-user rule made an operator
+    first_operator: Arc<Mutex<Option<String>>>,
+}
+
+impl Moderation {
+    /// Create moderation state, loading bans from `ban_file` if it exists
+    pub fn open(ban_file: Option<&Path>) -> Result<Self, anyhow::Error> {
+        let bans = match ban_file {
+            Some(path) => load_bans(path)?,
+            None => Vec::new(),
+        };
+        
+        Ok(Moderation {
+            bans: Arc::new(Mutex::new(bans)),
+            ban_file: ban_file.map(Path::to_path_buf),
+            mutes: Arc::new(Mutex::new(HashMap::new())),
+            first_operator: Arc::new(Mutex::new(None)),
+        })
+    }
+    
+    /// Replace the bans in memory with those in the ban file, picking up edits made by hand
+    ///
+    /// The current list is kept if the file cannot be read.
+    pub fn reload(&self) -> Result<(), anyhow::Error> {
+        let Some(path) = &self.ban_file else {
+            return Ok(());
+        };
+        let mut bans = self.bans.lock().unwrap();
+        *bans = load_bans(path)?;
+        Ok(())
+    }
+    
+    /// Decide whether a joining user becomes an operator
+    ///
+    /// Listed operators always do; with `first_user` set, so does whoever
+    /// joins first after the server starts, each time they join.
+    pub fn grant_operator(&self, username: &str, operators: &[String], first_user: bool) -> bool {
+        if operators.iter().any(|op| op.eq_ignore_ascii_case(username)) {
+            return true;
+        }
+        if !first_user {
+            return false;
+        }
+        let mut first = self.first_operator.lock().unwrap();
+        *first.get_or_insert_with(|| username.to_lowercase()) == username.to_lowercase()
+    }
+    
+    /// Record a ban and save the ban list
+    pub fn ban(&self, ban: Ban) -> io::Result<()> {
+        let mut bans = self.bans.lock().unwrap();
+        let now = current_timestamp();
+        bans.retain(|b| b.is_active(now) && b.target != ban.target);
+        bans.push(ban);
+        
+        match &self.ban_file {
+            Some(path) => save_bans(path, &bans),
+            None => Ok(()),
+        }
+    }
+    
+    /// The active ban covering a username or address, if any
+    pub fn find_ban(&self, username: Option<&str>, ip: IpAddr) -> Option<Ban> {
+        let bans = self.bans.lock().unwrap();
+        let now = current_timestamp();
+        bans.iter()
+            .find(|b| b.is_active(now) && b.matches(username, ip))
+            .cloned()
+    }
+    
+    /// Stop a user from chatting for a while
+    pub fn mute(&self, username: &str, duration: Duration) {
+        let mut mutes = self.mutes.lock().unwrap();
+        mutes.insert(username.to_ascii_lowercase(), Instant::now() + duration);
+    }
+    
+    /// How much longer a user stays muted, if at all
+    pub fn muted_for(&self, username: &str) -> Option<Duration> {
+        let mut mutes = self.mutes.lock().unwrap();
+        let key = username.to_ascii_lowercase();
+        let remaining = mutes.get(&key)?.checked_duration_since(Instant::now());
+        if remaining.is_none() {
+            mutes.remove(&key);
+        }
+        remaining
+    }
+}
+
+/// Read the ban list, which is empty if the file does not exist yet
+fn load_bans(path: &Path) -> Result<Vec<Ban>, anyhow::Error> {
+    match fs::read_to_string(path) {
+        Ok(contents) => {
+            let bans: Vec<Ban> = serde_json::from_str(&contents)
+                .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e))?;
+            info!("Loaded {} bans from {}", bans.len(), path.display());
+            Ok(bans)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => anyhow::bail!("Failed to read {}: {}", path.display(), e),
+    }
+}
+
+/// Write the ban list next to its final path, then move it into place
+fn save_bans(path: &Path, bans: &[Ban]) -> io::Result<()> {
+    let contents = serde_json::to_string_pretty(bans)?;
+    atomic_write(path, contents.as_bytes())
+}
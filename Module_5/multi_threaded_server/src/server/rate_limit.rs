@@ -0,0 +1,164 @@
+use std::time::{Duration, Instant};
+
+/// How fast a single client may send, and how long it is muted for flooding
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitConfig {
+    /// Average messages per second allowed
+    pub messages_per_sec: f64,
+    /// Average bytes per second allowed
+    pub bytes_per_sec: f64,
+    /// How much unused allowance a quiet client can save up and spend at once
+    pub burst: Duration,
+    /// How long a client that keeps flooding after a warning is muted
+    pub mute_duration: Duration,
+    /// Time without violations after which earlier ones are forgotten
+    pub cooldown: Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            messages_per_sec: 10.0,
+            bytes_per_sec: 64.0 * 1024.0,
+            burst: Duration::from_secs(2),
+            mute_duration: Duration::from_secs(30),
+            cooldown: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What to do with a message after checking it against the limits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    /// Within the limits; process it
+    Allow,
+    /// First violation: drop it and warn the client
+    Warn,
+    /// Second violation: drop it and mute the client for the given time
+    Mute(Duration),
+    /// The client is muted; drop it silently
+    Muted,
+    /// Violation after a mute: disconnect the client
+    Disconnect,
+}
+
+/// Refills continuously at `rate` per second up to `capacity`
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: Duration, now: Instant) -> Self {
+        let capacity = (rate * burst.as_secs_f64()).max(1.0);
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+    
+    /// Change the rate, keeping what is left of the allowance
+    fn set_rate(&mut self, rate: f64, burst: Duration, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        self.capacity = (rate * burst.as_secs_f64()).max(1.0);
+        self.tokens = self.tokens.min(self.capacity);
+    }
+    
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+    
+    /// Whether `amount` can be taken; anything above capacity needs a full bucket
+    fn has(&self, amount: f64) -> bool {
+        self.tokens >= amount.min(self.capacity)
+    }
+    
+    fn take(&mut self, amount: f64) {
+        self.tokens = (self.tokens - amount).max(0.0);
+    }
+}
+
+/// Token-bucket limiter for one connection, escalating on repeated floods
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    violations: u32,
+    last_violation: Option<Instant>,
+    muted_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let now = Instant::now();
+        RateLimiter {
+            messages: TokenBucket::new(config.messages_per_sec, config.burst, now),
+            bytes: TokenBucket::new(config.bytes_per_sec, config.burst, now),
+            config,
+            violations: 0,
+            last_violation: None,
+            muted_until: None,
+        }
+    }
+    
+    /// Switch to new limits, keeping the allowance already used and any mute
+    pub fn reconfigure(&mut self, config: &RateLimitConfig) {
+        if *config == self.config {
+            return;
+        }
+        let now = Instant::now();
+        self.messages.set_rate(config.messages_per_sec, config.burst, now);
+        self.bytes.set_rate(config.bytes_per_sec, config.burst, now);
+        self.config = config.clone();
+    }
+    
+    /// Account for a received message of `size` bytes and decide its fate
+    pub fn check(&mut self, size: usize) -> Verdict {
+        self.charge(1.0, size)
+    }
+    
+    /// Account for `size` bytes that do not count as a message of their own
+    ///
+    /// Used for replies such as acks, which a client sends for every message
+    /// it receives and so cannot be held to the message rate.
+    pub fn check_bytes(&mut self, size: usize) -> Verdict {
+        self.charge(0.0, size)
+    }
+    
+    fn charge(&mut self, messages: f64, size: usize) -> Verdict {
+        let now = Instant::now();
+        if self.muted_until.is_some_and(|until| now < until) {
+            return Verdict::Muted;
+        }
+        
+        self.messages.refill(now);
+        self.bytes.refill(now);
+        if self.messages.has(messages) && self.bytes.has(size as f64) {
+            self.messages.take(messages);
+            self.bytes.take(size as f64);
+            return Verdict::Allow;
+        }
+        
+        if self.last_violation.is_some_and(|last| now.duration_since(last) > self.config.cooldown) {
+            self.violations = 0;
+        }
+        self.violations += 1;
+        self.last_violation = Some(now);
+        
+        match self.violations {
+            1 => Verdict::Warn,
+            2 => {
+                self.muted_until = Some(now + self.config.mute_duration);
+                Verdict::Mute(self.config.mute_duration)
+            }
+            _ => Verdict::Disconnect,
+        }
+    }
+}
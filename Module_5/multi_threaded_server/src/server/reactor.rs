@@ -0,0 +1,683 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::AsRawFd;
+use std::sync::{Arc, Mutex, TryLockError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use log::{info, warn, error, debug};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use mio::unix::SourceFd;
+
+use crate::common::protocol::Message;
+use crate::common::transport::Transport;
+use crate::server::connection_manager::{Notify, Outbound, OutboundQueue};
+use crate::server::handler::{
+    end_session, on_join_message, process_buffer, start_session, HandlerContext, JoinState,
+    JoinStep, Session,
+};
+use crate::server::listener::ShutdownHandle;
+use crate::server::rate_limit::RateLimiter;
+
+/// Token of the waker registered with every worker's poll
+const WAKER: Token = Token(usize::MAX);
+
+/// How often event loops wake up to check for shutdown
+const TICK: Duration = Duration::from_millis(100);
+
+/// How often workers look for connections that have gone quiet
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Queued frames are moved into a connection's write buffer until it holds this much
+const WRITE_HIGH_WATER: usize = 64 * 1024;
+
+/// Reads done for one connection before the worker moves on to the others
+const READS_PER_EVENT: usize = 16;
+
+/// Unhandled input a connection may pile up before the worker stops reading from it
+const READ_BACKLOG: usize = 256 * 1024;
+
+/// Handler threads started for every event loop thread
+const HANDLERS_PER_WORKER: usize = 4;
+
+/// A connection accepted by the listener thread and handed to a worker
+type Assignment = (TcpStream, SocketAddr, HandlerContext);
+
+/// Handler work sent to the pool, which may block on disk writes or password hashing
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Accept on `sockets` and serve every client on `workers` event loops until shutdown
+///
+/// `admit` decides whether an accepted connection is served at all.
+pub(crate) fn serve(
+    sockets: Vec<(TcpListener, HandlerContext)>,
+    workers: usize,
+    shutdown: &ShutdownHandle,
+    admit: impl Fn(&SocketAddr, &HandlerContext) -> bool,
+) -> Result<(), anyhow::Error> {
+    let manager = sockets[0].1.manager.clone();
+    let shutdown_timeout = sockets[0].1.config.get().shutdown_timeout;
+    
+    let mut poll = Poll::new()?;
+    for (index, (listener, _)) in sockets.iter().enumerate() {
+        poll.registry().register(&mut SourceFd(&listener.as_raw_fd()), Token(index), Interest::READABLE)?;
+    }
+    
+    let (jobs, pending) = unbounded::<Job>();
+    let handlers: Vec<thread::JoinHandle<()>> = (0..workers.max(1) * HANDLERS_PER_WORKER)
+        .map(|id| {
+            let pending = pending.clone();
+            thread::Builder::new()
+                .name(format!("handler-{}", id))
+                .spawn(move || pending.iter().for_each(|job| job()))
+        })
+        .collect::<io::Result<_>>()?;
+    
+    let workers: Vec<WorkerHandle> = (0..workers.max(1))
+        .map(|id| WorkerHandle::spawn(id, shutdown.clone(), shutdown_timeout, jobs.clone()))
+        .collect::<io::Result<_>>()?;
+    info!("Serving clients on {} event loop threads and {} handler threads", workers.len(), handlers.len());
+    
+    let mut events = Events::with_capacity(64);
+    let mut next_worker = 0;
+    while !shutdown.is_shutdown() {
+        if let Err(e) = poll.poll(&mut events, Some(TICK)) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e.into());
+        }
+        
+        for event in events.iter() {
+            let (listener, ctx) = &sockets[event.token().0];
+            // Readiness is edge-triggered, so drain the whole backlog
+            loop {
+                match listener.accept() {
+                    Ok((stream, addr)) => {
+                        if !admit(&addr, ctx) {
+                            continue;
+                        }
+                        workers[next_worker % workers.len()].assign((stream, addr, ctx.clone()));
+                        next_worker += 1;
+                        ctx.manager.metrics().connection_accepted();
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        error!("Connection failed: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    
+    drop(sockets);
+    let reason = shutdown.reason();
+    info!("Shutting down ({} clients connected): {}", manager.client_count(), reason);
+    
+    // Joined clients get the goodbye; workers close them once it is written
+    manager.close_all(&Message::Shutdown { reason });
+    for worker in workers {
+        worker.join();
+    }
+    
+    // Let the handlers finish ending sessions for the connections just closed
+    drop(jobs);
+    for handler in handlers {
+        if handler.join().is_err() {
+            error!("Handler thread panicked");
+        }
+    }
+    Ok(())
+}
+
+/// The listener thread's side of a worker
+struct WorkerHandle {
+    assignments: Sender<Assignment>,
+    waker: Arc<Waker>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl WorkerHandle {
+    /// Start a worker thread with its own poll
+    fn spawn(id: usize, shutdown: ShutdownHandle, shutdown_timeout: Duration, jobs: Sender<Job>) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (assignments, incoming) = unbounded();
+        
+        let worker = Worker {
+            poll,
+            waker: waker.clone(),
+            incoming,
+            ready: Arc::new(Mutex::new(Vec::new())),
+            jobs,
+            connections: HashMap::new(),
+            next_token: 0,
+            shutdown,
+            shutdown_timeout,
+        };
+        let thread = thread::Builder::new()
+            .name(format!("reactor-{}", id))
+            .spawn(move || worker.run())?;
+        
+        Ok(WorkerHandle {
+            assignments,
+            waker,
+            thread,
+        })
+    }
+    
+    /// Hand a new connection to the worker
+    fn assign(&self, assignment: Assignment) {
+        if self.assignments.send(assignment).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
+    
+    /// Wait for the worker to close its connections and exit
+    fn join(self) {
+        let _ = self.waker.wake();
+        if self.thread.join().is_err() {
+            error!("Event loop thread panicked");
+        }
+    }
+}
+
+/// An event loop serving a share of the connections
+struct Worker {
+    poll: Poll,
+    waker: Arc<Waker>,
+    incoming: Receiver<Assignment>,
+    /// Connections with something new in their outbound queues
+    ready: Arc<Mutex<Vec<Token>>>,
+    /// Where handler work for this worker's connections is sent
+    jobs: Sender<Job>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+}
+
+impl Worker {
+    fn run(mut self) {
+        let mut events = Events::with_capacity(1024);
+        let mut last_sweep = Instant::now();
+        let mut stopping_since: Option<Instant> = None;
+        
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, Some(TICK)) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("Event loop failed: {}", e);
+                break;
+            }
+            
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => {
+                        self.take_assignments();
+                        self.flush_ready();
+                    }
+                    token => {
+                        let readable = event.is_readable() || event.is_read_closed() || event.is_error();
+                        self.drive(token, readable);
+                    }
+                }
+            }
+            
+            if last_sweep.elapsed() >= SWEEP_INTERVAL {
+                self.close_idle();
+                last_sweep = Instant::now();
+            }
+            
+            if self.shutdown.is_shutdown() {
+                // Clients that never joined get no goodbye, so close them now
+                let since = *stopping_since.get_or_insert_with(|| {
+                    self.close_where(|conn| conn.queue.is_none());
+                    Instant::now()
+                });
+                if self.connections.is_empty() {
+                    break;
+                }
+                if since.elapsed() >= self.shutdown_timeout {
+                    warn!("{} connections did not close before the shutdown deadline", self.connections.len());
+                    break;
+                }
+            }
+        }
+        
+        self.close_where(|_| true);
+    }
+    
+    /// Register connections handed over by the listener thread
+    fn take_assignments(&mut self) {
+        while let Ok((tcp, addr, ctx)) = self.incoming.try_recv() {
+            info!("New connection from: {}", addr);
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            
+            if let Err(e) = tcp.set_nonblocking(true) {
+                error!("Failed to set non-blocking mode for {}: {}", addr, e);
+                continue;
+            }
+            let handler_tcp = match tcp.try_clone() {
+                Ok(handler_tcp) => handler_tcp,
+                Err(e) => {
+                    error!("Failed to clone the stream for {}: {}", addr, e);
+                    continue;
+                }
+            };
+            let interest = Interest::READABLE;
+            if let Err(e) = self.poll.registry().register(&mut SourceFd(&tcp.as_raw_fd()), token, interest) {
+                error!("Failed to register {}: {}", addr, e);
+                continue;
+            }
+            
+            let scheduled = Arc::new(AtomicBool::new(false));
+            let notify: Notify = {
+                let scheduled = scheduled.clone();
+                let ready = self.ready.clone();
+                let waker = self.waker.clone();
+                Arc::new(move || {
+                    // One wakeup per batch of frames is enough
+                    if !scheduled.swap(true, Ordering::AcqRel) {
+                        ready.lock().unwrap().push(token);
+                        let _ = waker.wake();
+                    }
+                })
+            };
+            
+            let shared = Arc::new(Shared {
+                incoming: Mutex::new(Vec::new()),
+                busy: AtomicBool::new(false),
+                closed: AtomicBool::new(false),
+                handling: Mutex::new(Handling {
+                    tcp: handler_tcp,
+                    addr,
+                    ctx: ctx.clone(),
+                    notify: notify.clone(),
+                    read_buffer: Vec::new(),
+                    replies: Vec::new(),
+                    phase: Phase::Joining(JoinState::default()),
+                    closing: false,
+                    failed: false,
+                }),
+            });
+            self.connections.insert(token, Connection {
+                tcp,
+                addr,
+                ctx,
+                shared,
+                write_buffer: Vec::new(),
+                queue: None,
+                scheduled,
+                notify,
+                interest,
+                closing: false,
+                queue_closed: false,
+                read_pending: false,
+                last_read: Instant::now(),
+            });
+        }
+    }
+    
+    /// Write out whatever was queued for connections that were notified
+    fn flush_ready(&mut self) {
+        let tokens = std::mem::take(&mut *self.ready.lock().unwrap());
+        for token in tokens {
+            if let Some(conn) = self.connections.get(&token) {
+                conn.scheduled.store(false, Ordering::Release);
+                let readable = conn.read_pending;
+                self.drive(token, readable);
+            }
+        }
+    }
+    
+    /// Read from and write to a connection, closing it once it is finished
+    fn drive(&mut self, token: Token, readable: bool) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+        
+        let open = (!readable || conn.on_readable(&self.jobs)) && conn.flush();
+        if !open || conn.update_interest(self.poll.registry(), token).is_err() {
+            self.close(token);
+        }
+    }
+    
+    /// Close connections that have not sent anything for too long
+    fn close_idle(&mut self) {
+        self.close_where(|conn| {
+            let idle = conn.last_read.elapsed() >= conn.ctx.config.get().connection_timeout;
+            if idle {
+                debug!("Connection from {} timed out", conn.addr);
+            }
+            idle
+        });
+    }
+    
+    fn close_where(&mut self, condition: impl Fn(&Connection) -> bool) {
+        let tokens: Vec<Token> = self.connections.iter()
+            .filter(|(_, conn)| condition(conn))
+            .map(|(token, _)| *token)
+            .collect();
+        for token in tokens {
+            self.close(token);
+        }
+    }
+    
+    fn close(&mut self, token: Token) {
+        if let Some(conn) = self.connections.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut SourceFd(&conn.tcp.as_raw_fd()));
+            conn.shared.closed.store(true, Ordering::Release);
+            // Ending a session may write the resume file, and must wait for any handler still at work
+            let shared = conn.shared.clone();
+            let _ = self.jobs.send(Box::new(move || {
+                let handling = shared.handling.lock().unwrap_or_else(|e| e.into_inner());
+                end_session(&handling.addr, &handling.ctx);
+            }));
+            let _ = conn.tcp.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Where a connection is in its lifetime
+enum Phase {
+    /// Waiting for an acceptable Join
+    Joining(JoinState),
+    /// Registered with the connection manager
+    Joined {
+        session: Session,
+        queue: OutboundQueue,
+        limiter: Box<Option<RateLimiter>>,
+    },
+}
+
+/// A connection's state shared between its worker and the handler pool
+struct Shared {
+    /// Bytes read from the socket that no handler has taken yet
+    incoming: Mutex<Vec<u8>>,
+    /// Set while a handler job is working through `incoming`
+    busy: AtomicBool,
+    /// The worker has closed the connection; handlers leave it alone
+    closed: AtomicBool,
+    handling: Mutex<Handling>,
+}
+
+/// What the handlers need to act on a connection's messages
+struct Handling {
+    /// Passed to the connection manager when the client joins
+    tcp: TcpStream,
+    addr: SocketAddr,
+    ctx: HandlerContext,
+    notify: Notify,
+    read_buffer: Vec<u8>,
+    /// Encoded replies to a client that has no outbound queue yet
+    replies: Vec<u8>,
+    phase: Phase,
+    /// Close once everything already queued has been written
+    closing: bool,
+    /// Close right away
+    failed: bool,
+}
+
+/// A non-blocking client connection owned by one worker
+struct Connection {
+    tcp: TcpStream,
+    addr: SocketAddr,
+    ctx: HandlerContext,
+    shared: Arc<Shared>,
+    /// Encoded bytes the socket has not accepted yet
+    write_buffer: Vec<u8>,
+    /// The outbound queue, once a handler has registered the client
+    queue: Option<OutboundQueue>,
+    /// Set while the connection is in the worker's ready list
+    scheduled: Arc<AtomicBool>,
+    /// Passed to the connection manager so queued frames wake the worker
+    notify: Notify,
+    /// What the poll is currently watching for
+    interest: Interest,
+    /// Close once everything already queued has been written
+    closing: bool,
+    /// The queue handed over its Close marker; nothing after it is sent
+    queue_closed: bool,
+    /// The socket may hold input that was left for a later turn
+    read_pending: bool,
+    last_read: Instant,
+}
+
+impl Connection {
+    /// Read a bounded amount of input and pass it to the handlers
+    ///
+    /// Returns false if the connection should be closed right away.
+    fn on_readable(&mut self, jobs: &Sender<Job>) -> bool {
+        // Leave the rest in the socket until the handlers catch up
+        if self.shared.incoming.lock().unwrap().len() >= READ_BACKLOG {
+            self.read_pending = true;
+            self.dispatch(jobs);
+            return true;
+        }
+        
+        let mut chunk = [0u8; 4096];
+        for _ in 0..READS_PER_EVENT {
+            match (&self.tcp).read(&mut chunk) {
+                Ok(0) => {
+                    debug!("Connection closed by client: {}", self.addr);
+                    return false;
+                }
+                Ok(n) => {
+                    self.ctx.manager.metrics().bytes_in(n);
+                    self.last_read = Instant::now();
+                    // Anything sent after we decided to close is ignored
+                    if !self.closing {
+                        self.shared.incoming.lock().unwrap().extend_from_slice(&chunk[..n]);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.read_pending = false;
+                    self.dispatch(jobs);
+                    return true;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    debug!("Error reading from {}: {}", self.addr, e);
+                    return false;
+                }
+            }
+        }
+        
+        // Readiness is edge-triggered, so come back for the rest on a later turn
+        self.read_pending = true;
+        (self.notify)();
+        self.dispatch(jobs);
+        true
+    }
+    
+    /// Start a handler job for the unhandled input unless one is already running
+    fn dispatch(&self, jobs: &Sender<Job>) {
+        if self.shared.incoming.lock().unwrap().is_empty() || self.shared.busy.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let shared = self.shared.clone();
+        let notify = self.notify.clone();
+        let _ = jobs.send(Box::new(move || {
+            shared.handle();
+            // The worker picks up the replies, the queue and the verdict
+            notify();
+        }));
+    }
+    
+    /// Take what the handlers left for the worker, unless one is still at it
+    ///
+    /// Returns false if the connection should be closed right away.
+    fn collect(&mut self) -> bool {
+        let mut handling = match self.shared.handling.try_lock() {
+            Ok(handling) => handling,
+            Err(TryLockError::WouldBlock) => return true,
+            Err(TryLockError::Poisoned(_)) => return false,
+        };
+        self.write_buffer.append(&mut handling.replies);
+        if let (None, Phase::Joined { queue, .. }) = (&self.queue, &handling.phase) {
+            self.queue = Some(queue.clone());
+        }
+        self.closing |= handling.closing;
+        !handling.failed
+    }
+    
+    /// Move queued frames to the socket until it would block
+    ///
+    /// Returns false once the connection is finished and should be closed.
+    fn flush(&mut self) -> bool {
+        if !self.collect() {
+            return false;
+        }
+        loop {
+            if let Some(queue) = &self.queue {
+                while !self.queue_closed && self.write_buffer.len() < WRITE_HIGH_WATER {
+                    match queue.pop() {
+                        Some(Outbound::Frame(frame)) => self.write_buffer.extend_from_slice(&frame),
+                        Some(Outbound::Close) => {
+                            self.queue_closed = true;
+                            self.closing = true;
+                        }
+                        None => break,
+                    }
+                }
+            }
+            
+            if self.write_buffer.is_empty() {
+                return !self.closing;
+            }
+            
+            match (&self.tcp).write(&self.write_buffer) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    self.write_buffer.drain(..n);
+                    self.ctx.manager.metrics().bytes_out(n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    warn!("Failed to send to {}: {}", self.addr, e);
+                    return false;
+                }
+            }
+        }
+    }
+    
+    /// Watch for writability only while there is output the socket refused
+    fn update_interest(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        let interest = if self.write_buffer.is_empty() {
+            Interest::READABLE
+        } else {
+            Interest::READABLE | Interest::WRITABLE
+        };
+        if interest != self.interest {
+            registry.reregister(&mut SourceFd(&self.tcp.as_raw_fd()), token, interest)?;
+            self.interest = interest;
+        }
+        Ok(())
+    }
+}
+
+impl Shared {
+    /// Handle input until none is left, on a handler thread
+    fn handle(&self) {
+        loop {
+            let bytes = std::mem::take(&mut *self.incoming.lock().unwrap());
+            if bytes.is_empty() {
+                self.busy.store(false, Ordering::Release);
+                // Input read after the check above would otherwise wait for more
+                if self.incoming.lock().unwrap().is_empty() || self.busy.swap(true, Ordering::AcqRel) {
+                    return;
+                }
+                continue;
+            }
+            
+            let Ok(mut handling) = self.handling.lock() else {
+                return;
+            };
+            if self.closed.load(Ordering::Acquire) {
+                return;
+            }
+            if !handling.closing && !handling.failed {
+                handling.read_buffer.extend_from_slice(&bytes);
+                if !handling.process() {
+                    handling.failed = true;
+                }
+            }
+        }
+    }
+}
+
+impl Handling {
+    /// Handle every complete message in the read buffer
+    ///
+    /// Returns false if the connection should be closed right away.
+    fn process(&mut self) -> bool {
+        let Handling { tcp, addr, ctx, notify, read_buffer, replies, phase, closing, .. } = self;
+        
+        while let Phase::Joining(state) = phase {
+            let msg = match ctx.wire_format.codec().decode(read_buffer) {
+                Ok(Some(msg)) => msg,
+                Ok(None) => return true,
+                Err(e) => {
+                    ctx.manager.metrics().decode_error();
+                    error!("Failed to get join message from {}: {}", addr, e);
+                    return false;
+                }
+            };
+            
+            let mut queue = None;
+            let register = |username: &str, version| {
+                let stream = Transport::plain(tcp.try_clone()?);
+                queue = ctx.manager.add_polled_client(*addr, username.to_string(), stream, ctx.wire_format, version, notify.clone())?;
+                Ok(queue.is_some())
+            };
+            match on_join_message(msg, addr, ctx, state, register) {
+                Ok(JoinStep::Reply(reply)) => write_direct(replies, ctx, &reply),
+                Ok(JoinStep::Joined(session)) => {
+                    let Some(queue) = queue else {
+                        return false;
+                    };
+                    start_session(addr, ctx, &session);
+                    *phase = Phase::Joined {
+                        session,
+                        queue,
+                        limiter: Box::new(None),
+                    };
+                }
+                Ok(JoinStep::Reject(reply, e)) => {
+                    error!("Failed to get join message from {}: {}", addr, e);
+                    write_direct(replies, ctx, &reply);
+                    *closing = true;
+                    return true;
+                }
+                Err(e) => {
+                    error!("Failed to register {}: {}", addr, e);
+                    return false;
+                }
+            }
+        }
+        
+        if let Phase::Joined { session, limiter, .. } = phase {
+            if !process_buffer(read_buffer, addr, ctx, session, limiter) {
+                // Unregister now, but still deliver what the handler queued
+                end_session(addr, ctx);
+                *closing = true;
+            }
+        }
+        true
+    }
+}
+
+/// Buffer a reply to a client that has no outbound queue yet
+fn write_direct(write_buffer: &mut Vec<u8>, ctx: &HandlerContext, message: &Message) {
+    if let Ok(bytes) = ctx.wire_format.codec().encode(message) {
+        write_buffer.extend_from_slice(&bytes);
+        ctx.manager.metrics().message_out(message, 1);
+    }
+}
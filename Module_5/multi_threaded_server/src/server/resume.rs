@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::{info, error};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::common::protocol::{current_timestamp, error_codes};
+use crate::server::storage::atomic_write;
+
+/// Why a session could not be resumed
+#[derive(Debug, Error, PartialEq)]
+pub enum ResumeError {
+    #[error("Session expired; join again")]
+    Expired,
+    
+    #[error("{0} is still connected")]
+    StillConnected(String),
+}
+
+impl ResumeError {
+    /// Error code reported to the client
+    pub fn code(&self) -> u16 {
+        match self {
+            ResumeError::Expired => error_codes::UNAUTHORIZED,
+            ResumeError::StillConnected(_) => error_codes::USERNAME_TAKEN,
+        }
+    }
+}
+
+/// A session that can be picked up again with its token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    username: String,
+    /// Rooms the user was in when the connection ended
+    rooms: Vec<String>,
+    /// Unix timestamp (seconds) of when the connection ended; None while connected
+    suspended_at: Option<u64>,
+    /// Last message ID handed out when the session started or was suspended
+    #[serde(default)]
+    last_id: u64,
+}
+
+/// What a resumed session gets back
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suspended {
+    pub username: String,
+    pub rooms: Vec<String>,
+}
+
+/// Session tokens handed to clients, so a dropped connection can be resumed
+///
+/// Only a hash of each token is kept, so the session file does not hold
+/// anything a reader could log in with.
+#[derive(Clone)]
+pub struct ResumeTokens {
+    /// Sessions by hex-encoded token hash
+    entries: Arc<Mutex<BTreeMap<String, Entry>>>,
+    /// Where sessions are saved so they survive a restart
+    file: Option<PathBuf>,
+    /// How long after a connection ends its session can be resumed
+    window: Duration,
+}
+
+impl ResumeTokens {
+    /// Create the token store, loading it from `file` if it exists
+    pub fn open(file: Option<&Path>, window: Duration) -> Result<Self, anyhow::Error> {
+        let mut entries: BTreeMap<String, Entry> = match file {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(contents) => serde_json::from_str(&contents)
+                    .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e))?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => anyhow::bail!("Failed to read {}: {}", path.display(), e),
+            },
+            None => BTreeMap::new(),
+        };
+        
+        // Nobody is connected yet, whatever the file says after a crash
+        let now = current_timestamp();
+        for entry in entries.values_mut() {
+            entry.suspended_at.get_or_insert(now);
+        }
+        if let Some(path) = file {
+            info!("Loaded {} resumable sessions from {}", entries.len(), path.display());
+        }
+        
+        Ok(ResumeTokens {
+            entries: Arc::new(Mutex::new(entries)),
+            file: file.map(Path::to_path_buf),
+            window,
+        })
+    }
+    
+    /// Start a session for a user who just joined, returning its token
+    ///
+    /// Any earlier token for the same user stops working. `last_id` is the
+    /// last message ID handed out so far.
+    pub fn issue(&self, username: &str, last_id: u64) -> String {
+        let token = hex::encode(rand::random::<[u8; 32]>());
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| !entry.username.eq_ignore_ascii_case(username));
+        entries.insert(hash(&token), Entry {
+            username: username.to_string(),
+            rooms: Vec::new(),
+            suspended_at: None,
+            last_id,
+        });
+        self.save(&entries);
+        token
+    }
+    
+    /// Remember the rooms of a user whose connection ended, starting the resume window
+    pub fn suspend(&self, username: &str, rooms: Vec<String>, last_id: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        let now = current_timestamp();
+        entries.retain(|_, entry| !self.expired(entry, now));
+        let Some(entry) = entries.values_mut().find(|entry| entry.username.eq_ignore_ascii_case(username)) else {
+            return;
+        };
+        entry.rooms = rooms;
+        entry.suspended_at = Some(now);
+        entry.last_id = last_id;
+        self.save(&entries);
+    }
+    
+    /// Drop a user's session, for a client that left on purpose
+    pub fn forget(&self, username: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|_, entry| !entry.username.eq_ignore_ascii_case(username));
+        if entries.len() != before {
+            self.save(&entries);
+        }
+    }
+    
+    /// The session a token belongs to, if it can be resumed now
+    ///
+    /// The token keeps working until the resumed session is issued a new one.
+    pub fn lookup(&self, token: &str) -> Result<Suspended, ResumeError> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&hash(token)).ok_or(ResumeError::Expired)?;
+        if entry.suspended_at.is_none() {
+            return Err(ResumeError::StillConnected(entry.username.clone()));
+        }
+        if self.expired(entry, current_timestamp()) {
+            return Err(ResumeError::Expired);
+        }
+        Ok(Suspended {
+            username: entry.username.clone(),
+            rooms: entry.rooms.clone(),
+        })
+    }
+    
+    /// The highest message ID any session was told about, so IDs can keep increasing after a restart
+    pub fn last_id(&self) -> u64 {
+        let entries = self.entries.lock().unwrap();
+        entries.values().map(|entry| entry.last_id).max().unwrap_or(0)
+    }
+    
+    fn expired(&self, entry: &Entry, now: u64) -> bool {
+        entry.suspended_at.is_some_and(|at| now >= at.saturating_add(self.window.as_secs()))
+    }
+    
+    /// Write the sessions to the session file; they stay in memory if it fails
+    fn save(&self, entries: &BTreeMap<String, Entry>) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        let result = serde_json::to_string(entries)
+            .map_err(io::Error::from)
+            .and_then(|contents| atomic_write(path, contents.as_bytes()));
+        if let Err(e) = result {
+            error!("Failed to save sessions to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Hex-encoded SHA-256 of a token
+fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
@@ -0,0 +1,225 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::common::codec::WireFormat;
+use crate::common::tls::ServerTlsConfig;
+use crate::server::auth::AuthConfig;
+use crate::server::listener::{IoModel, ListenerConfig, ServerConfig};
+use crate::server::rate_limit::RateLimitConfig;
+
+/// Server settings gathered from flags, the environment and a config file
+///
+/// Flags win over `CHAT_*` environment variables, which win over the TOML
+/// file named by `--config`; anything left unset keeps its
+/// `ServerConfig::default()` value. File keys are the flag names with
+/// underscores, e.g. `max_connections = 500`.
+#[derive(Debug, Clone, Default, Parser, Deserialize)]
+#[command(name = "multi_threaded_server", version, about = "Multi-threaded chat server", long_about = None)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    /// TOML file to read settings from
+    #[arg(short, long, env = "CHAT_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+    
+    /// Address to accept clients on [default: 127.0.0.1:8080]
+    #[arg(env = "CHAT_BIND")]
+    pub bind: Option<String>,
+    /// Second address speaking JSON lines, for scripts and netcat
+    #[arg(long, env = "CHAT_JSON_BIND")]
+    pub json_bind: Option<String>,
+    /// Address for browser clients speaking WebSocket
+    #[arg(long, env = "CHAT_WS_BIND")]
+    pub ws_bind: Option<String>,
+    /// Address for the Prometheus /metrics endpoint
+    #[arg(long, env = "CHAT_METRICS_BIND")]
+    pub metrics_bind: Option<String>,
+    /// Serve clients from this many event loops instead of a thread each
+    #[arg(long, env = "CHAT_REACTOR_WORKERS")]
+    pub reactor_workers: Option<usize>,
+    
+    /// Clients connected at once before new ones are turned away
+    #[arg(long, env = "CHAT_MAX_CONNECTIONS")]
+    pub max_connections: Option<usize>,
+    /// Seconds a client may stay silent before it is disconnected
+    #[arg(long, env = "CHAT_CONNECTION_TIMEOUT_SECS")]
+    pub connection_timeout_secs: Option<u64>,
+    /// Seconds to wait for clients to finish during shutdown
+    #[arg(long, env = "CHAT_SHUTDOWN_TIMEOUT_SECS")]
+    pub shutdown_timeout_secs: Option<u64>,
+    /// Whether flood protection is on [default: true]
+    #[arg(long, env = "CHAT_RATE_LIMIT", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub rate_limit: Option<bool>,
+    /// Average messages per second a client may send
+    #[arg(long, env = "CHAT_MESSAGES_PER_SEC")]
+    pub messages_per_sec: Option<f64>,
+    /// Average bytes per second a client may send
+    #[arg(long, env = "CHAT_BYTES_PER_SEC")]
+    pub bytes_per_sec: Option<f64>,
+    /// Largest file, in bytes, relayed between clients
+    #[arg(long, env = "CHAT_MAX_FILE_SIZE")]
+    pub max_file_size: Option<u64>,
+    
+    /// Shared token every client must present when joining
+    #[arg(long, env = "CHAT_AUTH_TOKEN", hide_env_values = true, conflicts_with = "credentials_file")]
+    pub auth_token: Option<String>,
+    /// File of users and salted password hashes
+    #[arg(long, env = "CHAT_CREDENTIALS_FILE")]
+    pub credentials_file: Option<PathBuf>,
+    /// Failed authentication attempts before the connection is closed
+    #[arg(long, env = "CHAT_MAX_AUTH_ATTEMPTS")]
+    pub max_auth_attempts: Option<u32>,
+    /// Certificate chain (PEM) for TLS; needs --tls-key
+    #[arg(long, env = "CHAT_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    /// Private key (PEM) for TLS; needs --tls-cert
+    #[arg(long, env = "CHAT_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+    /// CA (PEM) that client certificates must be signed by
+    #[arg(long, env = "CHAT_TLS_CLIENT_CA")]
+    pub tls_client_ca: Option<PathBuf>,
+    
+    /// Comma-separated usernames with operator rights
+    #[arg(long, env = "CHAT_OPERATORS", value_delimiter = ',')]
+    pub operators: Option<Vec<String>>,
+    /// Also make the first client to join an operator
+    #[arg(long, env = "CHAT_FIRST_USER_IS_OPERATOR", num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub first_user_is_operator: Option<bool>,
+    /// File the ban list is kept in
+    #[arg(long, env = "CHAT_BAN_FILE")]
+    pub ban_file: Option<PathBuf>,
+    
+    /// Directory for the on-disk history log
+    #[arg(long, env = "CHAT_HISTORY_DIR")]
+    pub history_dir: Option<PathBuf>,
+    /// Recent messages kept per room and replayed to new members
+    #[arg(long, env = "CHAT_HISTORY_RETENTION")]
+    pub history_retention: Option<usize>,
+    /// File private messages for offline users are kept in
+    #[arg(long, env = "CHAT_MAILBOX_FILE")]
+    pub mailbox_file: Option<PathBuf>,
+    /// Messages kept for each offline user
+    #[arg(long, env = "CHAT_MAILBOX_CAPACITY")]
+    pub mailbox_capacity: Option<usize>,
+    /// Seconds a message waits for an offline user before it is dropped
+    #[arg(long, env = "CHAT_MAILBOX_EXPIRY_SECS")]
+    pub mailbox_expiry_secs: Option<u64>,
+    /// File resumable sessions are kept in
+    #[arg(long, env = "CHAT_SESSION_FILE")]
+    pub session_file: Option<PathBuf>,
+    /// Seconds after a dropped connection during which it can be resumed
+    #[arg(long, env = "CHAT_RESUME_WINDOW_SECS")]
+    pub resume_window_secs: Option<u64>,
+}
+
+impl ServerSettings {
+    /// Read settings from a TOML file
+    pub fn from_file(path: &Path) -> Result<Self, anyhow::Error> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        toml::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e))
+    }
+    
+    /// Fill in settings left unset here from `lower`
+    pub fn or(self, lower: ServerSettings) -> ServerSettings {
+        macro_rules! overlay {
+            ($($field:ident),* $(,)?) => {
+                ServerSettings { $($field: self.$field.or(lower.$field)),* }
+            };
+        }
+        overlay!(
+            config, bind, json_bind, ws_bind, metrics_bind, reactor_workers,
+            max_connections, connection_timeout_secs, shutdown_timeout_secs,
+            rate_limit, messages_per_sec, bytes_per_sec, max_file_size,
+            auth_token, credentials_file, max_auth_attempts, tls_cert, tls_key, tls_client_ca,
+            operators, first_user_is_operator, ban_file,
+            history_dir, history_retention, mailbox_file, mailbox_capacity, mailbox_expiry_secs,
+            session_file, resume_window_secs,
+        )
+    }
+    
+    /// Build the server configuration, reading the config file if one was given
+    ///
+    /// The file is read every time, so calling this again picks up edits to it.
+    pub fn resolve(&self) -> Result<ServerConfig, anyhow::Error> {
+        let settings = match &self.config {
+            Some(path) => self.clone().or(Self::from_file(path)?),
+            None => self.clone(),
+        };
+        settings.into_config()
+    }
+    
+    /// Turn these settings into a configuration, using defaults for anything unset
+    pub fn into_config(self) -> Result<ServerConfig, anyhow::Error> {
+        let defaults = ServerConfig::default();
+        let seconds = |secs: Option<u64>, default: Duration| secs.map_or(default, Duration::from_secs);
+        
+        let auth = match (self.auth_token, self.credentials_file) {
+            (Some(_), Some(_)) => anyhow::bail!("auth_token and credentials_file cannot both be set"),
+            (Some(token), None) => AuthConfig::Token(token),
+            (None, Some(path)) => AuthConfig::CredentialsFile(path),
+            (None, None) => defaults.auth.clone(),
+        };
+        let tls = match (self.tls_cert, self.tls_key) {
+            (Some(cert_path), Some(key_path)) => Some(ServerTlsConfig {
+                cert_path,
+                key_path,
+                client_ca_path: self.tls_client_ca,
+            }),
+            (None, None) => None,
+            _ => anyhow::bail!("tls_cert and tls_key must be set together"),
+        };
+        let rate_limit = match self.rate_limit {
+            Some(false) => None,
+            _ => {
+                let limits = RateLimitConfig::default();
+                Some(RateLimitConfig {
+                    messages_per_sec: self.messages_per_sec.unwrap_or(limits.messages_per_sec),
+                    bytes_per_sec: self.bytes_per_sec.unwrap_or(limits.bytes_per_sec),
+                    ..limits
+                })
+            }
+        };
+        
+        Ok(ServerConfig {
+            bind_addr: self.bind.unwrap_or(defaults.bind_addr),
+            extra_listeners: self.json_bind
+                .map(|bind_addr| vec![ListenerConfig {
+                    bind_addr,
+                    wire_format: WireFormat::JsonLines,
+                }])
+                .unwrap_or(defaults.extra_listeners),
+            websocket_addr: self.ws_bind.or(defaults.websocket_addr),
+            metrics_addr: self.metrics_bind.or(defaults.metrics_addr),
+            io_model: match self.reactor_workers {
+                Some(workers) => IoModel::Reactor { workers },
+                None => defaults.io_model,
+            },
+            max_connections: self.max_connections.unwrap_or(defaults.max_connections),
+            connection_timeout: seconds(self.connection_timeout_secs, defaults.connection_timeout),
+            shutdown_timeout: seconds(self.shutdown_timeout_secs, defaults.shutdown_timeout),
+            rate_limit,
+            max_file_size: self.max_file_size.unwrap_or(defaults.max_file_size),
+            auth,
+            max_auth_attempts: self.max_auth_attempts.unwrap_or(defaults.max_auth_attempts),
+            tls,
+            operators: self.operators
+                .map(|ops| ops.into_iter().map(|op| op.trim().to_string()).filter(|op| !op.is_empty()).collect())
+                .unwrap_or(defaults.operators),
+            first_user_is_operator: self.first_user_is_operator.unwrap_or(defaults.first_user_is_operator),
+            ban_file: self.ban_file.or(defaults.ban_file),
+            history_dir: self.history_dir.or(defaults.history_dir),
+            history_retention: self.history_retention.unwrap_or(defaults.history_retention),
+            mailbox_file: self.mailbox_file.or(defaults.mailbox_file),
+            mailbox_capacity: self.mailbox_capacity.unwrap_or(defaults.mailbox_capacity),
+            mailbox_expiry: seconds(self.mailbox_expiry_secs, defaults.mailbox_expiry),
+            session_file: self.session_file.or(defaults.session_file),
+            resume_window: seconds(self.resume_window_secs, defaults.resume_window),
+            ..defaults
+        })
+    }
+}
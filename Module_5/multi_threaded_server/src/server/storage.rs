@@ -0,0 +1,18 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Replace the file at `path` with `contents` so readers never see it half written
+///
+/// The contents go to `<file name>.tmp` beside it first and are then renamed
+/// over it, so a crash leaves either the old file or the new one.
+pub fn atomic_write(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let Some(name) = path.file_name() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file", path.display())));
+    };
+    let mut temp_name = name.to_os_string();
+    temp_name.push(".tmp");
+    let temp = path.with_file_name(temp_name);
+    fs::write(&temp, contents)?;
+    fs::rename(&temp, path)
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use thiserror::Error;
+
+use crate::common::protocol::{error_codes, FILE_CHUNK_SIZE};
+
+/// Why a file transfer message was refused
+#[derive(Debug, Error, PartialEq)]
+pub enum TransferError {
+    #[error("No transfer with ID {0}")]
+    Unknown(u64),
+    
+    #[error("Transfer ID {0} is already in use")]
+    Duplicate(u64),
+    
+    #[error("Only the {0} of a transfer can do that")]
+    WrongParty(&'static str),
+    
+    #[error("Files larger than {0} bytes cannot be sent")]
+    TooLarge(u64),
+    
+    #[error("Transfer has not been accepted yet")]
+    NotAccepted,
+    
+    #[error("Expected data at offset {expected}, got offset {got}")]
+    OutOfSequence { expected: u64, got: u64 },
+    
+    #[error("Chunk is over {FILE_CHUNK_SIZE} bytes or runs past the end of the file")]
+    BadChunk,
+}
+
+impl TransferError {
+    /// Error code reported to the client
+    pub fn code(&self) -> u16 {
+        match self {
+            TransferError::Unknown(_) => error_codes::NOT_FOUND,
+            TransferError::Duplicate(_) => error_codes::TRANSFER_EXISTS,
+            TransferError::WrongParty(_) => error_codes::FORBIDDEN,
+            TransferError::TooLarge(_) => error_codes::FILE_TOO_LARGE,
+            _ => error_codes::BAD_REQUEST,
+        }
+    }
+}
+
+/// A file the server is relaying from one client to another
+struct Transfer {
+    sender: SocketAddr,
+    recipient: SocketAddr,
+    size: u64,
+    /// Offset the next chunk must start at; None until the recipient accepts
+    next_offset: Option<u64>,
+}
+
+/// File transfers in progress, keyed by the ID the sender chose
+///
+/// The server only checks that chunks arrive in order and within the offered
+/// size; checksums are verified by the recipient.
+#[derive(Clone)]
+pub struct Transfers {
+    active: Arc<Mutex<HashMap<u64, Transfer>>>,
+    max_size: Arc<AtomicU64>,
+}
+
+impl Transfers {
+    /// Track transfers of files up to `max_size` bytes
+    pub fn new(max_size: u64) -> Self {
+        Transfers {
+            active: Arc::new(Mutex::new(HashMap::new())),
+            max_size: Arc::new(AtomicU64::new(max_size)),
+        }
+    }
+    
+    /// Change the size limit for transfers offered from now on
+    pub fn set_max_size(&self, max_size: u64) {
+        self.max_size.store(max_size, Ordering::Relaxed);
+    }
+    
+    /// Start relaying a file `sender` offered to `recipient`
+    pub fn offer(&self, id: u64, sender: SocketAddr, recipient: SocketAddr, size: u64) -> Result<(), TransferError> {
+        let max_size = self.max_size.load(Ordering::Relaxed);
+        if size > max_size {
+            return Err(TransferError::TooLarge(max_size));
+        }
+        let mut active = self.active.lock().unwrap();
+        if active.contains_key(&id) {
+            return Err(TransferError::Duplicate(id));
+        }
+        active.insert(id, Transfer {
+            sender,
+            recipient,
+            size,
+            next_offset: None,
+        });
+        Ok(())
+    }
+    
+    /// Record the recipient asking for data from `offset`, returning the sender to tell
+    ///
+    /// The first accept fixes where the sender starts; later ones are acknowledgements.
+    pub fn accept(&self, id: u64, from: &SocketAddr, offset: u64) -> Result<SocketAddr, TransferError> {
+        let mut active = self.active.lock().unwrap();
+        let transfer = active.get_mut(&id).ok_or(TransferError::Unknown(id))?;
+        if transfer.recipient != *from {
+            return Err(TransferError::WrongParty("recipient"));
+        }
+        if offset > transfer.size {
+            return Err(TransferError::OutOfSequence { expected: transfer.size, got: offset });
+        }
+        if transfer.next_offset.is_none() {
+            transfer.next_offset = Some(offset);
+        }
+        Ok(transfer.sender)
+    }
+    
+    /// Whether `from` is the sender of an accepted transfer and `offset` is where its next chunk starts
+    pub fn expects_chunk(&self, id: u64, from: &SocketAddr, offset: u64) -> bool {
+        let active = self.active.lock().unwrap();
+        active.get(&id).is_some_and(|transfer| transfer.sender == *from && transfer.next_offset == Some(offset))
+    }
+    
+    /// Check a chunk from the sender, returning the recipient to relay it to
+    pub fn chunk(&self, id: u64, from: &SocketAddr, offset: u64, len: usize) -> Result<SocketAddr, TransferError> {
+        let mut active = self.active.lock().unwrap();
+        let transfer = active.get_mut(&id).ok_or(TransferError::Unknown(id))?;
+        if transfer.sender != *from {
+            return Err(TransferError::WrongParty("sender"));
+        }
+        let expected = transfer.next_offset.ok_or(TransferError::NotAccepted)?;
+        if offset != expected {
+            return Err(TransferError::OutOfSequence { expected, got: offset });
+        }
+        if len > FILE_CHUNK_SIZE || offset + len as u64 > transfer.size {
+            return Err(TransferError::BadChunk);
+        }
+        transfer.next_offset = Some(offset + len as u64);
+        Ok(transfer.recipient)
+    }
+    
+    /// Finish a transfer whose every byte was relayed, returning the recipient
+    pub fn complete(&self, id: u64, from: &SocketAddr) -> Result<SocketAddr, TransferError> {
+        let mut active = self.active.lock().unwrap();
+        let transfer = active.get(&id).ok_or(TransferError::Unknown(id))?;
+        if transfer.sender != *from {
+            return Err(TransferError::WrongParty("sender"));
+        }
+        let sent = transfer.next_offset.ok_or(TransferError::NotAccepted)?;
+        if sent != transfer.size {
+            return Err(TransferError::OutOfSequence { expected: sent, got: transfer.size });
+        }
+        let recipient = transfer.recipient;
+        active.remove(&id);
+        Ok(recipient)
+    }
+    
+    /// End a transfer at either party's request, returning the other party
+    pub fn abort(&self, id: u64, from: &SocketAddr) -> Result<SocketAddr, TransferError> {
+        let mut active = self.active.lock().unwrap();
+        let transfer = active.get(&id).ok_or(TransferError::Unknown(id))?;
+        let other = if transfer.sender == *from {
+            transfer.recipient
+        } else if transfer.recipient == *from {
+            transfer.sender
+        } else {
+            return Err(TransferError::WrongParty("sender or recipient"));
+        };
+        active.remove(&id);
+        Ok(other)
+    }
+    
+    /// Drop every transfer `addr` takes part in, returning each ID and the other party
+    pub fn abandon(&self, addr: &SocketAddr) -> Vec<(u64, SocketAddr)> {
+        let mut abandoned = Vec::new();
+        self.active.lock().unwrap().retain(|id, transfer| {
+            let other = if transfer.sender == *addr {
+                transfer.recipient
+            } else if transfer.recipient == *addr {
+                transfer.sender
+            } else {
+                return true;
+            };
+            abandoned.push((*id, other));
+            false
+        });
+        abandoned
+    }
+}
@@ -0,0 +1,85 @@
+use thiserror::Error;
+
+use crate::common::protocol::error_codes;
+
+/// Rules a username must satisfy before a client may join
+#[derive(Debug, Clone)]
+pub struct UsernameRules {
+    /// Minimum length in characters
+    pub min_len: usize,
+    /// Maximum length in characters
+    pub max_len: usize,
+    /// Characters allowed in addition to ASCII letters and digits
+    pub extra_chars: String,
+    /// Names nobody may use, compared case-insensitively
+    pub reserved: Vec<String>,
+}
+
+impl Default for UsernameRules {
+    fn default() -> Self {
+        UsernameRules {
+            min_len: 1,
+            max_len: 32,
+            extra_chars: "_-.".to_string(),
+            reserved: vec!["server".to_string(), "admin".to_string()],
+        }
+    }
+}
+
+/// Reasons a username can be refused
+#[derive(Error, Debug, PartialEq)]
+pub enum UsernameError {
+    #[error("Username must be at least {0} characters")]
+    TooShort(usize),
+    
+    #[error("Username must be at most {0} characters")]
+    TooLong(usize),
+    
+    #[error("Username may not contain {0:?}")]
+    InvalidChar(char),
+    
+    #[error("Username {0} is reserved")]
+    Reserved(String),
+    
+    #[error("Username {0} is already taken")]
+    Taken(String),
+}
+
+impl UsernameError {
+    /// Error code sent back to the client in `Message::Error`
+    pub fn code(&self) -> u16 {
+        match self {
+            UsernameError::TooShort(_)
+            | UsernameError::TooLong(_)
+            | UsernameError::InvalidChar(_) => error_codes::INVALID_USERNAME,
+            UsernameError::Reserved(_) => error_codes::RESERVED_USERNAME,
+            UsernameError::Taken(_) => error_codes::USERNAME_TAKEN,
+        }
+    }
+}
+
+impl UsernameRules {
+    /// Check a username against length, character set and reserved names
+    pub fn validate(&self, username: &str) -> Result<(), UsernameError> {
+        let len = username.chars().count();
+        if len < self.min_len {
+            return Err(UsernameError::TooShort(self.min_len));
+        }
+        if len > self.max_len {
+            return Err(UsernameError::TooLong(self.max_len));
+        }
+        
+        if let Some(c) = username
+            .chars()
+            .find(|c| !c.is_ascii_alphanumeric() && !self.extra_chars.contains(*c))
+        {
+            return Err(UsernameError::InvalidChar(c));
+        }
+        
+        if self.reserved.iter().any(|r| r.eq_ignore_ascii_case(username)) {
+            return Err(UsernameError::Reserved(username.to_string()));
+        }
+        
+        Ok(())
+    }
+}
@@ -1,52 +1,2227 @@
+use std::cell::Cell;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::thread;
 use std::time::Duration;
-use serial_test::serial;
+use clap::Parser;
 
-use multi_threaded_server::common::protocol::Message;
-use multi_threaded_server::client::client::{Client, ClientConfig};
-use multi_threaded_server::server::listener::{Server, ServerConfig};
+use multi_threaded_server::common::protocol::{
+    BanTarget, DeliveryStatus, Message, FramedMessage, RoomInfo, error_codes, current_timestamp, FILE_CHUNK_SIZE,
+    PROTOCOL_VERSION, DEFAULT_ROOM,
+};
+use multi_threaded_server::client::client::{Client, ClientConfig, Interface};
+use multi_threaded_server::client::outbox::{Outbox, MAX_UNCONFIRMED};
+use multi_threaded_server::client::reconnect::{Backoff, ReconnectConfig};
+use multi_threaded_server::client::script::{self, exit_codes};
+use multi_threaded_server::client::session::{ChatSession, Event};
+use multi_threaded_server::client::settings::ClientSettings;
+use multi_threaded_server::client::transfers::FileTransfers;
+use multi_threaded_server::client::tui::{InputLine, Roster};
+use multi_threaded_server::common::codec::WireFormat;
+use multi_threaded_server::server::auth::{AuthConfig, CredentialsFile};
+use multi_threaded_server::server::connection_manager::SlowConsumerPolicy;
+use multi_threaded_server::server::listener::{IoModel, ListenerConfig, ReloadHandle, Server, ServerConfig, ShutdownHandle};
+use multi_threaded_server::server::moderation::Ban;
+use multi_threaded_server::server::rate_limit::RateLimitConfig;
+use multi_threaded_server::server::settings::ServerSettings;
+use tungstenite::{Message as WsMessage, WebSocket};
+#[cfg(feature = "async")]
+use multi_threaded_server::server::async_server::AsyncServer;
+
+/// Server implementation a test runs against
+#[derive(Clone, Copy)]
+enum Core {
+    Blocking,
+    #[cfg(feature = "async")]
+    Async,
+}
+
+thread_local! {
+    /// Every test runs on its own thread, so this picks the server per test
+    static CORE: Cell<Core> = const { Cell::new(Core::Blocking) };
+}
+
+/// A server bound to its sockets and serving on a background thread
+struct RunningServer {
+    addrs: Vec<SocketAddr>,
+    websocket_addr: Option<SocketAddr>,
+    metrics_addr: Option<SocketAddr>,
+    shutdown: ShutdownHandle,
+    reload: ReloadHandle,
+    handle: thread::JoinHandle<Result<(), anyhow::Error>>,
+}
+
+/// Bind `config` with the current test's server implementation and serve it
+fn spawn_server(config: ServerConfig) -> RunningServer {
+    match CORE.with(Cell::get) {
+        Core::Blocking => {
+            let server = Server::bind(config).unwrap();
+            RunningServer {
+                addrs: server.local_addrs().unwrap(),
+                websocket_addr: server.websocket_addr().unwrap(),
+                metrics_addr: server.metrics_addr().unwrap(),
+                shutdown: server.shutdown_handle(),
+                reload: server.reload_handle(),
+                handle: server.spawn(),
+            }
+        }
+        #[cfg(feature = "async")]
+        Core::Async => {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let server = runtime.block_on(AsyncServer::bind(config)).unwrap();
+            RunningServer {
+                addrs: server.local_addrs().unwrap(),
+                websocket_addr: None,
+                metrics_addr: server.metrics_addr().unwrap(),
+                shutdown: server.shutdown_handle(),
+                reload: server.reload_handle(),
+                handle: thread::spawn(move || runtime.block_on(server.serve())),
+            }
+        }
+    }
+}
+
+/// Bind a server on an ephemeral port and serve it on a background thread
+fn start_server(
+    config: ServerConfig,
+) -> (SocketAddr, ShutdownHandle, thread::JoinHandle<Result<(), anyhow::Error>>) {
+    let server = spawn_server(ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(), // Let OS assign port
+        ..config
+    });
+    (server.addrs[0], server.shutdown, server.handle)
+}
+
+/// Open a raw connection to the server
+fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream
+}
+
+/// Send a single framed message over a raw stream
+fn send(stream: &mut impl Write, message: &Message) {
+    let bytes = FramedMessage::encode(message).unwrap();
+    stream.write_all(&bytes).unwrap();
+}
+
+/// Read the next framed message, or None once the server closes the stream
+fn recv(stream: &mut impl Read, buffer: &mut Vec<u8>) -> Option<Message> {
+    let mut read_buf = [0u8; 1024];
+    loop {
+        if let Some(message) = FramedMessage::decode(buffer).unwrap() {
+            return Some(message);
+        }
+        match stream.read(&mut read_buf) {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => buffer.extend_from_slice(&read_buf[..n]),
+        }
+    }
+}
+
+/// Read messages until one matches, skipping presence notices and the like
+fn recv_matching(
+    stream: &mut impl Read,
+    buffer: &mut Vec<u8>,
+    matches: impl Fn(&Message) -> bool,
+) -> Message {
+    loop {
+        match recv(stream, buffer) {
+            Some(message) if matches(&message) => return message,
+            Some(_) => {}
+            None => panic!("Connection closed while waiting for a message"),
+        }
+    }
+}
+
+/// Wait for a session event, skipping any that do not match
+fn next_event(session: &ChatSession, matches: impl Fn(&Event) -> bool) -> Event {
+    loop {
+        match session.events().recv_timeout(Duration::from_secs(5)) {
+            Ok(event) if matches(&event) => return event,
+            Ok(_) => {}
+            Err(e) => panic!("No matching event: {}", e),
+        }
+    }
+}
 
 #[test]
-#[serial]
 fn test_client_server_communication() {
     // Start server in background thread
-    let server_config = ServerConfig {
-        bind_addr: "127.0.0.1:0".to_string(), // Let OS assign port
+    let (addr, shutdown, server_handle) = start_server(ServerConfig {
         max_connections: 10,
         connection_timeout: Duration::from_secs(5),
-    };
-    
-    let server = Server::new(server_config);
-    let server_handle = thread::spawn(move || {
-        server.run().unwrap();
+        ..ServerConfig::default()
     });
     
-    // Give server time to start
-    thread::sleep(Duration::from_millis(100));
-    
     // Connect client
     let client_config = ClientConfig {
-        server_addr: "127.0.0.1:8080".to_string(),
+        server_addr: addr.to_string(),
         username: "test_user".to_string(),
         heartbeat_interval: Duration::from_secs(1),
+        ..ClientConfig::default()
     };
     
-    let mut client = Client::connect(client_config).unwrap();
+    let client = Client::connect(client_config).unwrap();
     
     // Run client briefly
     thread::sleep(Duration::from_secs(2));
     
     // Clean shutdown
     drop(client);
-    drop(server_handle);
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_graceful_shutdown_notifies_clients() {
+    let (addr, shutdown, server_handle) = start_server(ServerConfig {
+        shutdown_timeout: Duration::from_secs(2),
+        ..ServerConfig::default()
+    });
+    
+    let mut stream = connect(addr);
+    let mut buffer = Vec::new();
+    send(&mut stream, &Message::join("alice".to_string(), None));
+    assert!(matches!(recv(&mut stream, &mut buffer), Some(Message::Welcome { .. })));
+    
+    shutdown.shutdown("maintenance");
+    
+    // The client gets a goodbye carrying the reason, then a clean EOF
+    assert_eq!(
+        recv(&mut stream, &mut buffer),
+        Some(Message::Shutdown { reason: "maintenance".to_string() })
+    );
+    assert_eq!(recv(&mut stream, &mut buffer), None);
+    
+    // serve() returns once the handlers have finished
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_servers_bind_distinct_ephemeral_ports() {
+    let (addr_a, shutdown_a, handle_a) = start_server(ServerConfig::default());
+    let (addr_b, shutdown_b, handle_b) = start_server(ServerConfig::default());
+    assert_ne!(addr_a.port(), 0);
+    assert_ne!(addr_a, addr_b);
+    
+    // Both servers accept clients independently
+    for addr in [addr_a, addr_b] {
+        let mut stream = connect(addr);
+        let mut buffer = Vec::new();
+        send(&mut stream, &Message::join("alice".to_string(), None));
+        match recv(&mut stream, &mut buffer) {
+            Some(Message::Welcome { connected_clients, .. }) => {
+                assert_eq!(connected_clients, vec!["alice".to_string()]);
+            }
+            other => panic!("Expected Welcome, got {:?}", other),
+        }
+    }
+    
+    shutdown_a.shutdown("test finished");
+    shutdown_b.shutdown("test finished");
+    handle_a.join().unwrap().unwrap();
+    handle_b.join().unwrap().unwrap();
+}
+
+/// Connect and join with the given username, consuming the Welcome
+fn join(addr: SocketAddr, username: &str) -> (TcpStream, Vec<u8>) {
+    let mut stream = connect(addr);
+    let mut buffer = Vec::new();
+    send(&mut stream, &Message::join(username.to_string(), None));
+    match recv(&mut stream, &mut buffer) {
+        Some(Message::Welcome { .. }) => (stream, buffer),
+        other => panic!("Expected Welcome, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_slow_consumer_does_not_stall_broadcasts() {
+    let (addr, shutdown, server_handle) = start_server(ServerConfig {
+        outbound_queue_size: 8,
+        slow_consumer_policy: SlowConsumerPolicy::Disconnect,
+        ..ServerConfig::default()
+    });
+    
+    // "stalled" joins but never reads from its socket
+    let (_stalled, _) = join(addr, "stalled");
+    let (mut reader, mut reader_buf) = join(addr, "reader");
+    let (mut sender, _) = join(addr, "sender");
+    
+    // Keep chatting until the stalled client's queue overflows; the reading
+    // client must see every message and finally the stalled client leaving
+    let payload = "x".repeat(60 * 1024);
+    let mut stalled_left = false;
+    for _ in 0..1000 {
+        send(&mut sender, &Message::chat("sender".to_string(), payload.clone()));
+        loop {
+            match recv(&mut reader, &mut reader_buf) {
+                Some(Message::Broadcast { from, .. }) if from == "sender" => break,
+                Some(Message::UserLeft { username, .. }) if username == "stalled" => stalled_left = true,
+                Some(_) => {}
+                None => panic!("Reader was disconnected"),
+            }
+        }
+        if stalled_left {
+            break;
+        }
+    }
+    assert!(stalled_left, "Stalled client was never disconnected");
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_kicked_slow_consumer_is_closed_despite_dropped_frames() {
+    // Under Block, the kick's own Error times out waiting for room
+    for policy in [SlowConsumerPolicy::DropOldest, SlowConsumerPolicy::Block(Duration::from_millis(20))] {
+        let (addr, shutdown, server_handle) = start_server(ServerConfig {
+            outbound_queue_size: 4,
+            slow_consumer_policy: policy,
+            operators: vec!["op".to_string()],
+            ..ServerConfig::default()
+        });
+        let (mut op, mut op_buf) = join(addr, "op");
+        let (mut stalled, _) = join(addr, "stalled");
+        
+        // Once the server answers a ping, it has queued everything sent before it
+        let chat_then_ping = |op: &mut TcpStream, op_buf: &mut Vec<u8>, count: usize| {
+            let payload = "x".repeat(900 * 1024);
+            for _ in 0..count {
+                send(op, &Message::chat("op".to_string(), payload.clone()));
+            }
+            send(op, &Message::Ping);
+            recv_matching(op, op_buf, |m| *m == Message::Pong);
+        };
+        
+        // Fill the socket and the queue, kick, then keep chatting so frames
+        // queued after the close marker push older ones out
+        chat_then_ping(&mut op, &mut op_buf, 100);
+        thread::sleep(Duration::from_millis(200));
+        send(&mut op, &Message::Kick { username: "stalled".to_string(), reason: "slow".to_string() });
+        chat_then_ping(&mut op, &mut op_buf, 5);
+        
+        // Reading what is left must end in EOF rather than a connection left open
+        let mut read_buf = vec![0u8; 64 * 1024];
+        loop {
+            match stalled.read(&mut read_buf) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => panic!("Kicked client was never closed under {:?}: {}", policy, e),
+            }
+        }
+        
+        shutdown.shutdown("test finished");
+        server_handle.join().unwrap().unwrap();
+    }
+}
+
+#[test]
+fn test_join_rejects_duplicate_and_invalid_usernames() {
+    let (addr, shutdown, server_handle) = start_server(ServerConfig::default());
+    let (_alice, _) = join(addr, "alice");
+    
+    let mut stream = connect(addr);
+    let mut buffer = Vec::new();
+    let rejected = [
+        ("ALICE", error_codes::USERNAME_TAKEN),
+        ("", error_codes::INVALID_USERNAME),
+        ("bad name", error_codes::INVALID_USERNAME),
+        (&"x".repeat(10 * 1024), error_codes::INVALID_USERNAME),
+        ("Server", error_codes::RESERVED_USERNAME),
+    ];
+    for (username, expected) in rejected {
+        send(&mut stream, &Message::join(username.to_string(), None));
+        match recv(&mut stream, &mut buffer) {
+            Some(Message::Error { code, .. }) => assert_eq!(code, expected, "{:?}", username),
+            other => panic!("Expected Error for {:?}, got {:?}", username, other),
+        }
+    }
+    
+    // The connection stays open so the client can retry with another name
+    send(&mut stream, &Message::join("bob".to_string(), None));
+    match recv(&mut stream, &mut buffer) {
+        Some(Message::Welcome { mut connected_clients, .. }) => {
+            connected_clients.sort();
+            assert_eq!(connected_clients, vec!["alice".to_string(), "bob".to_string()]);
+        }
+        other => panic!("Expected Welcome, got {:?}", other),
+    }
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_rooms_scope_chat_and_presence() {
+    let (addr, shutdown, server_handle) = start_server(ServerConfig::default());
+    let (mut alice, mut alice_buf) = join(addr, "alice");
+    let (mut bob, mut bob_buf) = join(addr, "bob");
+    let (mut carol, mut carol_buf) = join(addr, "carol");
+    
+    send(&mut alice, &Message::CreateRoom { room: "#dev".to_string() });
+    let joined = recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::RoomJoined { .. }));
+    assert_eq!(joined, Message::RoomJoined { room: "#dev".to_string(), members: vec!["alice".to_string()] });
+    
+    // Creating it twice is a conflict
+    send(&mut bob, &Message::CreateRoom { room: "#dev".to_string() });
+    let error = recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::Error { .. }));
+    assert!(matches!(error, Message::Error { code: error_codes::ROOM_EXISTS, .. }));
+    
+    send(&mut bob, &Message::JoinRoom { room: "#dev".to_string() });
+    recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::RoomJoined { .. }));
+    let notice = recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::UserJoined { room, .. } if room == "#dev"));
+    assert_eq!(notice, Message::UserJoined { username: "bob".to_string(), room: "#dev".to_string() });
+    
+    // Chat in #dev reaches bob but not carol, who only sees #general
+    send(&mut alice, &Message::room_chat("alice".to_string(), "#dev".to_string(), "dev only".to_string()));
+    send(&mut alice, &Message::chat("alice".to_string(), "hello all".to_string()));
+    match recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::Broadcast { .. })) {
+        Message::Broadcast { room, content, .. } => {
+            assert_eq!((room.as_str(), content.as_str()), ("#dev", "dev only"));
+        }
+        _ => unreachable!(),
+    }
+    match recv_matching(&mut carol, &mut carol_buf, |m| matches!(m, Message::Broadcast { .. })) {
+        Message::Broadcast { room, content, .. } => {
+            assert_eq!((room.as_str(), content.as_str()), ("#general", "hello all"));
+        }
+        _ => unreachable!(),
+    }
+    
+    // Non-members cannot talk in a room
+    send(&mut carol, &Message::room_chat("carol".to_string(), "#dev".to_string(), "hi".to_string()));
+    let error = recv_matching(&mut carol, &mut carol_buf, |m| matches!(m, Message::Error { .. }));
+    assert!(matches!(error, Message::Error { code: error_codes::NOT_IN_ROOM, .. }));
+    
+    send(&mut carol, &Message::ListRooms);
+    let list = recv_matching(&mut carol, &mut carol_buf, |m| matches!(m, Message::RoomList { .. }));
+    assert_eq!(list, Message::RoomList {
+        rooms: vec![
+            RoomInfo { name: "#dev".to_string(), members: 2 },
+            RoomInfo { name: "#general".to_string(), members: 3 },
+        ],
+    });
+    
+    send(&mut bob, &Message::PartRoom { room: "#dev".to_string() });
+    recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::RoomLeft { .. }));
+    let notice = recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::UserLeft { .. }));
+    assert_eq!(notice, Message::UserLeft { username: "bob".to_string(), room: "#dev".to_string() });
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_list_users_reports_metadata() {
+    let (addr, shutdown, server_handle) = start_server(ServerConfig::default());
+    let (mut alice, mut alice_buf) = join(addr, "alice");
+    let (_bob, _) = join(addr, "bob");
+    
+    send(&mut alice, &Message::ListUsers);
+    let users = match recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::UserList { .. })) {
+        Message::UserList { users } => users,
+        _ => unreachable!(),
+    };
+    
+    let names: Vec<&str> = users.iter().map(|u| u.username.as_str()).collect();
+    assert_eq!(names, vec!["alice", "bob"]);
+    let now = current_timestamp();
+    for user in &users {
+        assert!(user.connected_at <= now && now - user.connected_at < 5, "{:?}", user);
+        assert!(user.idle_secs < 5, "{:?}", user);
+    }
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_history_is_replayed_and_survives_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = || ServerConfig {
+        history_retention: 2,
+        history_dir: Some(dir.path().to_path_buf()),
+        // Small enough that every message rotates the log
+        history_max_file_size: 16,
+        ..ServerConfig::default()
+    };
+    
+    let (addr, shutdown, server_handle) = start_server(config());
+    let (mut alice, _) = join(addr, "alice");
+    let (mut bob, mut bob_buf) = join(addr, "bob");
+    for text in ["one", "two", "three"] {
+        send(&mut alice, &Message::chat("alice".to_string(), text.to_string()));
+        recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::Broadcast { .. }));
+    }
+    
+    let expect_history = |stream: &mut TcpStream, buffer: &mut Vec<u8>| {
+        match recv_matching(stream, buffer, |m| matches!(m, Message::History { .. })) {
+            Message::History { room, messages } => {
+                assert_eq!(room, "#general");
+                let contents: Vec<String> = messages.into_iter()
+                    .map(|m| match m {
+                        Message::Broadcast { content, .. } => content,
+                        other => panic!("Unexpected history entry {:?}", other),
+                    })
+                    .collect();
+                assert_eq!(contents, vec!["two".to_string(), "three".to_string()]);
+            }
+            _ => unreachable!(),
+        }
+    };
+    
+    // A late joiner gets the retained messages right after Welcome
+    let (mut carol, mut carol_buf) = join(addr, "carol");
+    expect_history(&mut carol, &mut carol_buf);
+    
+    shutdown.shutdown("restart");
+    server_handle.join().unwrap().unwrap();
+    
+    // A new server over the same directory replays the logged messages
+    let (addr, shutdown, server_handle) = start_server(config());
+    let (mut dave, mut dave_buf) = join(addr, "dave");
+    expect_history(&mut dave, &mut dave_buf);
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_token_auth_closes_after_repeated_failures() {
+    let (addr, shutdown, server_handle) = start_server(ServerConfig {
+        auth: AuthConfig::Token("s3cret".to_string()),
+        max_auth_attempts: 2,
+        ..ServerConfig::default()
+    });
+    
+    // The right token gets in
+    let mut stream = connect(addr);
+    let mut buffer = Vec::new();
+    send(&mut stream, &Message::join("alice".to_string(), Some("s3cret".to_string())));
+    assert!(matches!(recv(&mut stream, &mut buffer), Some(Message::Welcome { .. })));
+    
+    // Missing and wrong tokens are refused, then the connection is closed
+    let mut stream = connect(addr);
+    let mut buffer = Vec::new();
+    for credentials in [None, Some("guess".to_string())] {
+        send(&mut stream, &Message::join("mallory".to_string(), credentials));
+        match recv(&mut stream, &mut buffer) {
+            Some(Message::Error { code, .. }) => assert_eq!(code, error_codes::UNAUTHORIZED),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+    assert_eq!(recv(&mut stream, &mut buffer), None);
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_credentials_file_auth() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("users");
+    let contents = format!(
+        "# chat users\n{}\n{}\n",
+        CredentialsFile::entry("alice", "wonderland"),
+        CredentialsFile::entry("bob", "builder"),
+    );
+    std::fs::write(&path, contents).unwrap();
+    
+    let (addr, shutdown, server_handle) = start_server(ServerConfig {
+        auth: AuthConfig::CredentialsFile(path),
+        ..ServerConfig::default()
+    });
+    
+    let mut stream = connect(addr);
+    let mut buffer = Vec::new();
+    let attempts = [
+        ("alice", "builder", false),
+        ("carol", "wonderland", false),
+        ("alice", "wonderland", true),
+    ];
+    for (username, password, accepted) in attempts {
+        send(&mut stream, &Message::join(username.to_string(), Some(password.to_string())));
+        match recv(&mut stream, &mut buffer) {
+            Some(Message::Welcome { .. }) if accepted => {}
+            Some(Message::Error { code: error_codes::UNAUTHORIZED, .. }) if !accepted => {}
+            other => panic!("Unexpected reply for {}: {:?}", username, other),
+        }
+    }
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_hello_negotiates_capabilities() {
+    let (addr, shutdown, server_handle) = start_server(ServerConfig::default());
+    
+    // Something to replay, sent by a client that skips the Hello
+    let (mut alice, mut alice_buf) = join(addr, "alice");
+    send(&mut alice, &Message::chat("alice".to_string(), "before".to_string()));
+    // The reply to a later request shows the chat has been handled
+    send(&mut alice, &Message::ListUsers);
+    recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::UserList { .. }));
+    
+    // Unknown capabilities are dropped from the reply
+    let mut bob = connect(addr);
+    let mut bob_buf = Vec::new();
+    send(&mut bob, &Message::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: vec!["compression".to_string(), "rooms".to_string()],
+    });
+    assert_eq!(
+        recv(&mut bob, &mut bob_buf),
+        Some(Message::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: vec!["rooms".to_string()],
+        })
+    );
+    send(&mut bob, &Message::join("bob".to_string(), None));
+    recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::Welcome { .. }));
+    
+    // Bob left out history, so the next thing he sees is live chat
+    send(&mut alice, &Message::chat("alice".to_string(), "after".to_string()));
+    match recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::History { .. } | Message::Broadcast { .. })) {
+        Message::Broadcast { content, .. } => assert_eq!(content, "after"),
+        other => panic!("Expected Broadcast, got {:?}", other),
+    }
+    
+    // A newer client is answered with the version the server speaks
+    let mut carol = connect(addr);
+    let mut carol_buf = Vec::new();
+    send(&mut carol, &Message::Hello {
+        version: PROTOCOL_VERSION + 1,
+        capabilities: vec![],
+    });
+    assert_eq!(
+        recv(&mut carol, &mut carol_buf),
+        Some(Message::Hello { version: PROTOCOL_VERSION, capabilities: vec![] })
+    );
+    
+    // A version older than any the server speaks is refused and the connection closed
+    let mut dave = connect(addr);
+    let mut dave_buf = Vec::new();
+    send(&mut dave, &Message::Hello { version: 0, capabilities: vec![] });
+    match recv(&mut dave, &mut dave_buf) {
+        Some(Message::Error { code, .. }) => assert_eq!(code, error_codes::UPGRADE_REQUIRED),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert_eq!(recv(&mut dave, &mut dave_buf), None);
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+/// Read one length-prefixed frame without decoding it
+fn recv_frame(stream: &mut impl Read) -> Vec<u8> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).unwrap();
+    let mut frame = vec![0u8; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut frame).unwrap();
+    frame
+}
+
+#[test]
+fn test_version_1_clients_get_frames_without_ids() {
+    use bincode::Options;
+    
+    // Bincode writes a variant as its u32 index followed by its fields
+    const BROADCAST: u32 = 3;
+    const HISTORY: u32 = 21;
+    type V1Broadcast = (u32, String, String, String, u64);
+    let exact = || bincode::DefaultOptions::new().with_fixint_encoding().reject_trailing_bytes();
+    
+    let (addr, shutdown, server_handle) = start_server(ServerConfig::default());
+    let (mut alice, mut alice_buf) = join_with_hello(addr, "alice");
+    send(&mut alice, &Message::chat("alice".to_string(), "before".to_string()));
+    send(&mut alice, &Message::ListUsers);
+    recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::UserList { .. }));
+    
+    // Both a version 1 Hello and no Hello at all get the version 1 layout
+    let mut bob = connect(addr);
+    send(&mut bob, &Message::Hello { version: 1, capabilities: vec!["history".to_string()] });
+    assert_eq!(
+        Message::from_bytes(&recv_frame(&mut bob)).unwrap(),
+        Message::Hello { version: 1, capabilities: vec!["history".to_string()] }
+    );
+    let mut carol = connect(addr);
+    for (stream, name) in [(&mut bob, "bob"), (&mut carol, "carol")] {
+        send(stream, &Message::join(name.to_string(), None));
+        let history = loop {
+            let frame = recv_frame(stream);
+            if frame[..4] == HISTORY.to_le_bytes() {
+                break frame;
+            }
+        };
+        let (_, room, messages): (u32, String, Vec<V1Broadcast>) = exact().deserialize(&history).unwrap();
+        assert_eq!(room, DEFAULT_ROOM);
+        assert_eq!(messages.len(), 1);
+        assert_eq!((messages[0].0, messages[0].3.as_str()), (BROADCAST, "before"));
+    }
+    
+    let (mut dave, mut dave_buf) = join_with_hello(addr, "dave");
+    recv_matching(&mut dave, &mut dave_buf, |m| matches!(m, Message::History { .. }));
+    send(&mut alice, &Message::chat("alice".to_string(), "after".to_string()));
+    for stream in [&mut bob, &mut carol] {
+        let broadcast = loop {
+            let frame = recv_frame(stream);
+            if frame[..4] == BROADCAST.to_le_bytes() {
+                break frame;
+            }
+        };
+        let (_, from, _, content, _): V1Broadcast = exact().deserialize(&broadcast).unwrap();
+        assert_eq!((from.as_str(), content.as_str()), ("alice", "after"));
+    }
+    
+    // Clients speaking the current version still see the IDs
+    match recv_matching(&mut dave, &mut dave_buf, |m| matches!(m, Message::Broadcast { .. })) {
+        Message::Broadcast { content, id, .. } => {
+            assert_eq!(content, "after");
+            assert_ne!(id, 0);
+        }
+        other => panic!("Expected Broadcast, got {:?}", other),
+    }
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_frames_from_before_message_ids_decode() {
+    // Bincode writes a variant as its index followed by its fields, so these
+    // tuples are Chat, Broadcast and Private as version 1 laid them out
+    let chat = bincode::serialize(&(0u32, "alice", "#general", "hi", 7u64)).unwrap();
+    assert_eq!(
+        Message::from_bytes(&chat).unwrap(),
+        Message::Chat {
+            sender: "alice".to_string(),
+            room: "#general".to_string(),
+            content: "hi".to_string(),
+            timestamp: 7,
+            client_id: 0,
+        }
+    );
+    
+    let broadcast = bincode::serialize(&(3u32, "alice", "#general", "hi", 7u64)).unwrap();
+    match Message::from_bytes(&broadcast).unwrap() {
+        Message::Broadcast { content, id, .. } => {
+            assert_eq!(content, "hi");
+            assert_eq!(id, 0);
+        }
+        other => panic!("Expected Broadcast, got {:?}", other),
+    }
+    
+    let private = bincode::serialize(&(4u32, "alice", "bob", "psst", 7u64)).unwrap();
+    match Message::from_bytes(&private).unwrap() {
+        Message::Private { to, content, id, client_id, .. } => {
+            assert_eq!((to.as_str(), content.as_str()), ("bob", "psst"));
+            assert_eq!((id, client_id), (0, 0));
+        }
+        other => panic!("Expected Private, got {:?}", other),
+    }
+    
+    // The current layout still round-trips with its IDs in place
+    let current = Message::Private {
+        from: "alice".to_string(),
+        to: "bob".to_string(),
+        content: "psst".to_string(),
+        timestamp: 7,
+        id: 0,
+        client_id: 12,
+    };
+    assert_eq!(Message::from_bytes(&current.to_bytes().unwrap()).unwrap(), current);
+    
+    // Only a frame ending where a layout ends reads as 0; anything else is an error
+    let mut truncated = broadcast.clone();
+    truncated.extend_from_slice(&[1, 0, 0, 0]);
+    assert!(Message::from_bytes(&truncated).is_err());
+    let mut line = br##"{"Broadcast":{"from":"alice","room":"#general","content":"hi","timestamp":7,"id":"abc"}}"##.to_vec();
+    line.push(b'\n');
+    assert!(WireFormat::JsonLines.codec().decode(&mut line).is_err());
+}
+
+#[test]
+fn test_json_lines_listener_talks_to_bincode_clients() {
+    let server = spawn_server(ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(),
+        extra_listeners: vec![ListenerConfig {
+            bind_addr: "127.0.0.1:0".to_string(),
+            wire_format: WireFormat::JsonLines,
+        }],
+        ..ServerConfig::default()
+    });
+    let addrs = server.addrs;
+    let shutdown = server.shutdown;
+    let server_handle = server.handle;
+    
+    let codec = WireFormat::JsonLines.codec();
+    let mut json = connect(addrs[1]);
+    let mut json_buf = Vec::new();
+    let mut recv_json = |stream: &mut TcpStream, matches: fn(&Message) -> bool| loop {
+        if let Some(message) = codec.decode(&mut json_buf).unwrap() {
+            if matches(&message) {
+                return message;
+            }
+            continue;
+        }
+        let mut read_buf = [0u8; 1024];
+        let n = stream.read(&mut read_buf).unwrap();
+        assert_ne!(n, 0, "Connection closed while waiting for a message");
+        json_buf.extend_from_slice(&read_buf[..n]);
+    };
+    
+    // Typed by hand, the way a script or netcat user would send it
+    json.write_all(b"{\"Join\":{\"username\":\"qa\"}}\r\n").unwrap();
+    recv_json(&mut json, |m| matches!(m, Message::Welcome { .. }));
+    
+    // Chat crosses between the two wire formats in both directions
+    let (mut alice, mut alice_buf) = join(addrs[0], "alice");
+    send(&mut alice, &Message::chat("alice".to_string(), "to json".to_string()));
+    match recv_json(&mut json, |m| matches!(m, Message::Broadcast { .. })) {
+        Message::Broadcast { content, .. } => assert_eq!(content, "to json"),
+        other => panic!("Expected Broadcast, got {:?}", other),
+    }
+    json.write_all(&codec.encode(&Message::chat("qa".to_string(), "to bincode".to_string())).unwrap()).unwrap();
+    match recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::Broadcast { .. })) {
+        Message::Broadcast { from, content, .. } => {
+            assert_eq!(from, "qa");
+            assert_eq!(content, "to bincode");
+        }
+        other => panic!("Expected Broadcast, got {:?}", other),
+    }
+    
+    // A malformed line is reported and the connection stays usable
+    json.write_all(b"not json\n\"Ping\"\n").unwrap();
+    match recv_json(&mut json, |m| matches!(m, Message::Error { .. })) {
+        Message::Error { code, .. } => assert_eq!(code, error_codes::BAD_REQUEST),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    recv_json(&mut json, |m| *m == Message::Pong);
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_rate_limit_warns_mutes_then_disconnects() {
+    let (addr, shutdown, server_handle) = start_server(ServerConfig {
+        rate_limit: Some(RateLimitConfig {
+            messages_per_sec: 1.0,
+            burst: Duration::from_secs(2),
+            mute_duration: Duration::from_millis(300),
+            ..RateLimitConfig::default()
+        }),
+        ..ServerConfig::default()
+    });
+    let (mut flooder, mut flooder_buf) = join(addr, "flooder");
+    let (mut bob, mut bob_buf) = join(addr, "bob");
+    let chat = |n: u64| Message::Chat {
+        sender: "flooder".to_string(),
+        room: DEFAULT_ROOM.to_string(),
+        content: format!("spam {}", n),
+        timestamp: 0,
+        client_id: n,
+    };
+    let expect_429 = |stream: &mut TcpStream, buffer: &mut Vec<u8>| {
+        match recv_matching(stream, buffer, |m| matches!(m, Message::Error { .. })) {
+            Message::Error { code, .. } => assert_eq!(code, error_codes::RATE_LIMITED),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    };
+    
+    // The burst allowance gets through, the next message earns a warning,
+    // the one after that a mute, and anything sent while muted is dropped
+    for n in 1..=5 {
+        send(&mut flooder, &chat(n));
+    }
+    expect_429(&mut flooder, &mut flooder_buf);
+    expect_429(&mut flooder, &mut flooder_buf);
+    assert_eq!(recv_receipt(&mut flooder, &mut flooder_buf, 5).1, DeliveryStatus::Failed);
+    
+    // Flooding again once the mute is over ends the connection
+    thread::sleep(Duration::from_millis(400));
+    send(&mut flooder, &chat(6));
+    send(&mut flooder, &chat(7));
+    expect_429(&mut flooder, &mut flooder_buf);
+    assert_eq!(recv_receipt(&mut flooder, &mut flooder_buf, 6).1, DeliveryStatus::Failed);
+    while recv(&mut flooder, &mut flooder_buf).is_some() {}
+    
+    // Bob only ever saw the messages within the limit
+    let (mut carol, _) = join(addr, "carol");
+    send(&mut carol, &Message::chat("carol".to_string(), "done".to_string()));
+    let mut seen = Vec::new();
+    while !seen.contains(&"done".to_string()) {
+        if let Message::Broadcast { content, .. } = recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::Broadcast { .. })) {
+            seen.push(content);
+        }
+    }
+    assert_eq!(seen, vec!["spam 1", "spam 2", "done"]);
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+/// Make a plain HTTP GET request and return the whole response
+fn http_get(addr: SocketAddr, path: &str) -> String {
+    let mut stream = connect(addr);
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn test_metrics_endpoint() {
+    let server = spawn_server(ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(),
+        metrics_addr: Some("127.0.0.1:0".to_string()),
+        ..ServerConfig::default()
+    });
+    let addr = server.addrs[0];
+    let metrics_addr = server.metrics_addr.unwrap();
+    let shutdown = server.shutdown;
+    let server_handle = server.handle;
+    
+    let (mut alice, mut alice_buf) = join(addr, "alice");
+    let (_bob, _) = join(addr, "bob");
+    send(&mut alice, &Message::chat("alice".to_string(), "measured".to_string()));
+    // The reply to a later request shows the chat has been fanned out
+    send(&mut alice, &Message::ListUsers);
+    recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::UserList { .. }));
+    
+    let response = http_get(metrics_addr, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    for line in [
+        "chat_connected_clients 2",
+        "chat_connections_accepted_total 2",
+        "chat_messages_in_total{type=\"chat\"} 1",
+        "chat_messages_in_total{type=\"join\"} 2",
+        "chat_messages_out_total{type=\"broadcast\"} 1",
+        "chat_decode_errors_total 0",
+        "# TYPE chat_broadcast_latency_seconds histogram",
+    ] {
+        assert!(response.lines().any(|l| l == line), "missing {:?} in:\n{}", line, response);
+    }
+    assert!(http_get(metrics_addr, "/").starts_with("HTTP/1.1 404"));
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_operators_kick_mute_and_ban() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = || ServerConfig {
+        operators: vec!["op".to_string()],
+        ban_file: Some(dir.path().join("bans.json")),
+        ..ServerConfig::default()
+    };
+    let expect_error = |stream: &mut TcpStream, buffer: &mut Vec<u8>, expected: u16| {
+        match recv_matching(stream, buffer, |m| matches!(m, Message::Error { .. })) {
+            Message::Error { code, .. } => assert_eq!(code, expected),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    };
+    let expect_closed = |stream: &mut TcpStream, buffer: &mut Vec<u8>| {
+        while recv(stream, buffer).is_some() {}
+    };
+    
+    let (addr, shutdown, server_handle) = start_server(config());
+    let (mut op, _) = join(addr, "op");
+    let (mut alice, mut alice_buf) = join(addr, "alice");
+    let (mut bob, mut bob_buf) = join(addr, "bob");
+    
+    // Only operators may moderate
+    send(&mut alice, &Message::Kick { username: "bob".to_string(), reason: "because".to_string() });
+    expect_error(&mut alice, &mut alice_buf, error_codes::FORBIDDEN);
+    
+    // A muted user cannot chat
+    send(&mut op, &Message::Mute { username: "bob".to_string(), duration_secs: 60 });
+    recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::Notice { .. }));
+    let muted = Outbox::new().track(Message::chat("bob".to_string(), "hello?".to_string()));
+    send(&mut bob, &muted);
+    expect_error(&mut bob, &mut bob_buf, error_codes::FORBIDDEN);
+    assert_eq!(recv_receipt(&mut bob, &mut bob_buf, muted.client_id()), (0, DeliveryStatus::Failed));
+    
+    // A kicked user is told why and disconnected
+    send(&mut op, &Message::Kick { username: "alice".to_string(), reason: "rude".to_string() });
+    expect_error(&mut alice, &mut alice_buf, error_codes::REMOVED);
+    expect_closed(&mut alice, &mut alice_buf);
+    
+    // A banned user is disconnected and cannot join again
+    let ban_bob = Message::Ban {
+        target: BanTarget::Username("bob".to_string()),
+        duration_secs: None,
+        reason: "spam".to_string(),
+    };
+    send(&mut op, &ban_bob);
+    expect_error(&mut bob, &mut bob_buf, error_codes::REMOVED);
+    expect_closed(&mut bob, &mut bob_buf);
+    let expect_banned = |addr: SocketAddr, username: &str| {
+        let mut stream = connect(addr);
+        let mut buffer = Vec::new();
+        send(&mut stream, &Message::join(username.to_string(), None));
+        expect_error(&mut stream, &mut buffer, error_codes::REMOVED);
+        expect_closed(&mut stream, &mut buffer);
+    };
+    expect_banned(addr, "bob");
+    
+    shutdown.shutdown("restart");
+    server_handle.join().unwrap().unwrap();
+    
+    // The ban survives a restart, and the first user to join becomes an operator
+    let (addr, shutdown, server_handle) = start_server(ServerConfig {
+        first_user_is_operator: true,
+        ..config()
+    });
+    let (mut carol, mut carol_buf) = join(addr, "carol");
+    expect_banned(addr, "BOB");
+    
+    // Banning an address turns its connections away at accept
+    send(&mut carol, &Message::Ban {
+        target: BanTarget::Ip("127.0.0.1".parse().unwrap()),
+        duration_secs: Some(60),
+        reason: "closing up".to_string(),
+    });
+    expect_error(&mut carol, &mut carol_buf, error_codes::REMOVED);
+    expect_closed(&mut carol, &mut carol_buf);
+    let mut stream = connect(addr);
+    let mut buffer = Vec::new();
+    send(&mut stream, &Message::join("dave".to_string(), None));
+    assert_eq!(recv(&mut stream, &mut buffer), None);
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_reactor_serves_many_clients() {
+    const CLIENTS: usize = 200;
+    let (addr, shutdown, server_handle) = start_server(ServerConfig {
+        io_model: IoModel::Reactor { workers: 2 },
+        max_connections: CLIENTS + 10,
+        shutdown_timeout: Duration::from_secs(2),
+        ..ServerConfig::default()
+    });
+    
+    let mut clients: Vec<(TcpStream, Vec<u8>)> = (0..CLIENTS)
+        .map(|n| join(addr, &format!("user{}", n)))
+        .collect();
+    
+    // Join and chat in a single write: the chat is handled as soon as the join is
+    let mut late = connect(addr);
+    let mut late_buf = Vec::new();
+    let mut pipelined = FramedMessage::encode(&Message::join("late".to_string(), None)).unwrap();
+    pipelined.extend(FramedMessage::encode(&Message::chat("late".to_string(), "hi all".to_string())).unwrap());
+    late.write_all(&pipelined).unwrap();
+    assert!(matches!(recv(&mut late, &mut late_buf), Some(Message::Welcome { .. })));
+    
+    // Every other client sees the broadcast
+    for (stream, buffer) in clients.iter_mut() {
+        match recv_matching(stream, buffer, |m| matches!(m, Message::Broadcast { .. })) {
+            Message::Broadcast { from, content, .. } => assert_eq!((from.as_str(), content.as_str()), ("late", "hi all")),
+            _ => unreachable!(),
+        }
+    }
+    
+    let (stream, buffer) = &mut clients[0];
+    send(stream, &Message::ListUsers);
+    match recv_matching(stream, buffer, |m| matches!(m, Message::UserList { .. })) {
+        Message::UserList { users } => assert_eq!(users.len(), CLIENTS + 1),
+        _ => unreachable!(),
+    }
+    
+    // Shutdown says goodbye to everyone, then closes
+    shutdown.shutdown("maintenance");
+    clients.push((late, late_buf));
+    for (stream, buffer) in clients.iter_mut() {
+        recv_matching(stream, buffer, |m| matches!(m, Message::Shutdown { .. }));
+        while recv(stream, buffer).is_some() {}
+    }
+    server_handle.join().unwrap().unwrap();
+    
+    // Blocking on a slow client's queue would stall everyone on its worker
+    let blocking = Server::bind(ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(),
+        io_model: IoModel::Reactor { workers: 2 },
+        slow_consumer_policy: SlowConsumerPolicy::Block(Duration::from_secs(1)),
+        ..ServerConfig::default()
+    }).unwrap();
+    assert!(blocking.spawn().join().unwrap().is_err());
+}
+
+/// Write a PEM certificate and key for `name`, signed by the test CA
+/// Send a message as a JSON text frame, the way a browser client would
+fn ws_send(socket: &mut WebSocket<TcpStream>, message: &Message) {
+    socket.send(WsMessage::text(serde_json::to_string(message).unwrap())).unwrap();
+}
+
+/// Read WebSocket frames until one carries a matching message
+fn ws_recv_matching(socket: &mut WebSocket<TcpStream>, matches: impl Fn(&Message) -> bool) -> Message {
+    loop {
+        if let WsMessage::Text(text) = socket.read().unwrap() {
+            let message: Message = serde_json::from_str(&text).unwrap();
+            if matches(&message) {
+                return message;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_websocket_clients_share_the_server_with_tcp_clients() {
+    let server = spawn_server(ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(),
+        websocket_addr: Some("127.0.0.1:0".to_string()),
+        ..ServerConfig::default()
+    });
+    let addr = server.addrs[0];
+    let ws_addr = server.websocket_addr.unwrap();
+    
+    let (mut web, _) = tungstenite::client(format!("ws://{}/chat", ws_addr), connect(ws_addr)).unwrap();
+    ws_send(&mut web, &Message::join("web".to_string(), None));
+    ws_recv_matching(&mut web, |m| matches!(m, Message::Welcome { .. }));
+    
+    let (mut tcp, mut tcp_buf) = join(addr, "native");
+    ws_recv_matching(&mut web, |m| matches!(m, Message::UserJoined { username, .. } if username == "native"));
+    
+    // Typed by hand, as browser code would build it
+    web.send(WsMessage::text(
+        r##"{"Chat":{"sender":"web","room":"#general","content":"hello from a browser","timestamp":0}}"##,
+    )).unwrap();
+    match recv_matching(&mut tcp, &mut tcp_buf, |m| matches!(m, Message::Broadcast { .. })) {
+        Message::Broadcast { from, content, .. } => {
+            assert_eq!(from, "web");
+            assert_eq!(content, "hello from a browser");
+        }
+        _ => unreachable!(),
+    }
+    
+    send(&mut tcp, &Message::chat("native".to_string(), "hello from a socket".to_string()));
+    match ws_recv_matching(&mut web, |m| matches!(m, Message::Broadcast { .. })) {
+        Message::Broadcast { from, content, .. } => {
+            assert_eq!(from, "native");
+            assert_eq!(content, "hello from a socket");
+        }
+        _ => unreachable!(),
+    }
+    
+    ws_send(&mut web, &Message::ListUsers);
+    match ws_recv_matching(&mut web, |m| matches!(m, Message::UserList { .. })) {
+        Message::UserList { users } => {
+            let names: Vec<&str> = users.iter().map(|u| u.username.as_str()).collect();
+            assert_eq!(names, vec!["native", "web"]);
+        }
+        _ => unreachable!(),
+    }
+    
+    // Closing the WebSocket leaves the chat
+    web.close(None).unwrap();
+    recv_matching(&mut tcp, &mut tcp_buf, |m| matches!(m, Message::UserLeft { username, .. } if username == "web"));
+    
+    server.shutdown.shutdown("test finished");
+    server.handle.join().unwrap().unwrap();
+}
+
+#[cfg(feature = "tls")]
+fn issue_cert(
+    dir: &std::path::Path,
+    name: &str,
+    ca: &rcgen::Certificate,
+    ca_key: &rcgen::KeyPair,
+) -> (std::path::PathBuf, std::path::PathBuf) {
+    let key = rcgen::KeyPair::generate().unwrap();
+    let params = rcgen::CertificateParams::new(vec!["localhost".to_string(), "127.0.0.1".to_string()]).unwrap();
+    let cert = params.signed_by(&key, ca, ca_key).unwrap();
+    let cert_path = dir.join(format!("{}.crt", name));
+    let key_path = dir.join(format!("{}.key", name));
+    std::fs::write(&cert_path, cert.pem()).unwrap();
+    std::fs::write(&key_path, key.serialize_pem()).unwrap();
+    (cert_path, key_path)
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn test_tls_with_client_certificates() {
+    use multi_threaded_server::common::tls::{ClientTlsConfig, ServerTlsConfig};
+    use multi_threaded_server::common::transport::Transport;
+    
+    // Self-signed CA that issues both the server and the client certificates
+    let dir = tempfile::tempdir().unwrap();
+    let ca_key = rcgen::KeyPair::generate().unwrap();
+    let mut ca_params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    let ca_path = dir.path().join("ca.crt");
+    std::fs::write(&ca_path, ca.pem()).unwrap();
+    let (server_cert, server_key) = issue_cert(dir.path(), "server", &ca, &ca_key);
+    let (client_cert, client_key) = issue_cert(dir.path(), "client", &ca, &ca_key);
+    
+    let server_tls = ServerTlsConfig {
+        cert_path: server_cert,
+        key_path: server_key,
+        client_ca_path: Some(ca_path.clone()),
+    };
+    
+    // WebSocket clients could only connect unencrypted, so that is refused at bind
+    let bound = Server::bind(ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(),
+        websocket_addr: Some("127.0.0.1:0".to_string()),
+        tls: Some(server_tls.clone()),
+        ..ServerConfig::default()
+    });
+    assert!(bound.is_err());
+    
+    let (addr, shutdown, server_handle) = start_server(ServerConfig {
+        tls: Some(server_tls),
+        ..ServerConfig::default()
+    });
+    
+    let client_tls = ClientTlsConfig {
+        ca_path: ca_path.clone(),
+        server_name: None,
+        cert_path: Some(client_cert),
+        key_path: Some(client_key),
+    };
+    let tls_join = |username: &str| {
+        let mut stream = Transport::connect(&addr.to_string(), Some(&client_tls)).unwrap();
+        stream.tcp().set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buffer = Vec::new();
+        send(&mut stream, &Message::join(username.to_string(), None));
+        recv_matching(&mut stream, &mut buffer, |m| matches!(m, Message::Welcome { .. }));
+        (stream, buffer)
+    };
+    
+    // Chat flows both ways over encrypted connections
+    let (mut alice, mut alice_buf) = tls_join("alice");
+    let (mut bob, mut bob_buf) = tls_join("bob");
+    recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::UserJoined { .. }));
+    send(&mut alice, &Message::chat("alice".to_string(), "over tls".to_string()));
+    match recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::Broadcast { .. })) {
+        Message::Broadcast { from, content, .. } => {
+            assert_eq!(from, "alice");
+            assert_eq!(content, "over tls");
+        }
+        other => panic!("Expected Broadcast, got {:?}", other),
+    }
+    
+    // Without a client certificate the server never lets the client join
+    let anonymous = ClientTlsConfig {
+        cert_path: None,
+        key_path: None,
+        ..client_tls.clone()
+    };
+    if let Ok(mut stream) = Transport::connect(&addr.to_string(), Some(&anonymous)) {
+        stream.tcp().set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let bytes = FramedMessage::encode(&Message::join("mallory".to_string(), None)).unwrap();
+        let _ = stream.write_all(&bytes);
+        assert_eq!(recv(&mut stream, &mut Vec::new()), None);
+    }
+    
+    // Plain TCP clients fail the handshake and get at most a TLS alert back
+    let mut plain = connect(addr);
+    let _ = plain.write_all(&FramedMessage::encode(&Message::join("eve".to_string(), None)).unwrap());
+    let mut reply = Vec::new();
+    let _ = plain.read_to_end(&mut reply);
+    assert!(!matches!(FramedMessage::decode(&mut reply), Ok(Some(Message::Welcome { .. }))));
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+/// Connect, agree to every capability this build supports and join
+fn join_with_hello(addr: SocketAddr, username: &str) -> (TcpStream, Vec<u8>) {
+    let mut stream = connect(addr);
+    let mut buffer = Vec::new();
+    send(&mut stream, &Message::hello());
+    send(&mut stream, &Message::join(username.to_string(), None));
+    recv_matching(&mut stream, &mut buffer, |m| matches!(m, Message::Welcome { .. }));
+    (stream, buffer)
+}
+
+/// Wait for an error reply and return its code
+fn recv_error(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> u16 {
+    match recv_matching(stream, buffer, |m| matches!(m, Message::Error { .. })) {
+        Message::Error { code, .. } => code,
+        _ => unreachable!(),
+    }
+}
+
+/// Feed file transfer messages to `transfers` and send its replies until a note starts with `until`
+fn pump_transfers(stream: &mut TcpStream, buffer: &mut Vec<u8>, transfers: &mut FileTransfers, until: &str) -> String {
+    loop {
+        let message = recv_matching(stream, buffer, |m| m.kind().starts_with("file_"));
+        let update = transfers.handle(message);
+        for reply in &update.replies {
+            send(stream, reply);
+        }
+        match update.note {
+            Some(note) if note.starts_with(until) => return note,
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn test_server_relays_file_transfers() {
+    let (addr, shutdown, server_handle) = start_server(ServerConfig {
+        max_file_size: 100_000,
+        ..ServerConfig::default()
+    });
+    let (mut alice, mut alice_buf) = join_with_hello(addr, "alice");
+    let (mut bob, mut bob_buf) = join_with_hello(addr, "bob");
+    // Carol skips the Hello, so she never agreed to file transfers
+    let (mut carol, mut carol_buf) = join(addr, "carol");
+    
+    let offer = |transfer_id: u64, to: &str, size: u64| Message::FileOffer {
+        transfer_id,
+        from: String::new(),
+        to: to.to_string(),
+        name: "log.txt".to_string(),
+        size,
+        sha256: "0".repeat(64),
+    };
+    let data: Vec<u8> = (0..70_000u32).map(|i| i as u8).collect();
+    let chunk = |offset: usize, end: usize| Message::FileChunk {
+        transfer_id: 1,
+        offset: offset as u64,
+        data: data[offset..end].to_vec(),
+    };
+    
+    // Offers need a recipient that can take the file
+    send(&mut alice, &offer(1, "nobody", 10));
+    assert_eq!(recv_error(&mut alice, &mut alice_buf), error_codes::NOT_FOUND);
+    send(&mut alice, &offer(1, "carol", 10));
+    assert_eq!(recv_error(&mut alice, &mut alice_buf), error_codes::BAD_REQUEST);
+    send(&mut alice, &offer(1, "bob", 100_001));
+    assert_eq!(recv_error(&mut alice, &mut alice_buf), error_codes::FILE_TOO_LARGE);
+    send(&mut carol, &Message::FileAbort { transfer_id: 1, reason: "no".to_string() });
+    assert_eq!(recv_error(&mut carol, &mut carol_buf), error_codes::BAD_REQUEST);
+    
+    send(&mut alice, &offer(1, "bob", data.len() as u64));
+    match recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::FileOffer { .. })) {
+        Message::FileOffer { from, size, .. } => assert_eq!((from.as_str(), size), ("alice", 70_000)),
+        _ => unreachable!(),
+    }
+    send(&mut alice, &offer(1, "bob", 10));
+    assert_eq!(recv_error(&mut alice, &mut alice_buf), error_codes::TRANSFER_EXISTS);
+    
+    // Nothing flows until the recipient accepts, and only from the sender
+    send(&mut alice, &chunk(0, 10));
+    assert_eq!(recv_error(&mut alice, &mut alice_buf), error_codes::BAD_REQUEST);
+    send(&mut bob, &chunk(0, 10));
+    assert_eq!(recv_error(&mut bob, &mut bob_buf), error_codes::FORBIDDEN);
+    
+    // Bob already has the first 1000 bytes, so he resumes from there
+    send(&mut bob, &Message::FileAccept { transfer_id: 1, offset: 1000 });
+    assert_eq!(
+        recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::FileAccept { .. })),
+        Message::FileAccept { transfer_id: 1, offset: 1000 }
+    );
+    send(&mut alice, &chunk(0, 1000));
+    assert_eq!(recv_error(&mut alice, &mut alice_buf), error_codes::BAD_REQUEST);
+    
+    let split = 1000 + FILE_CHUNK_SIZE;
+    send(&mut alice, &chunk(1000, split));
+    send(&mut alice, &chunk(split, data.len()));
+    send(&mut alice, &Message::FileComplete { transfer_id: 1 });
+    assert_eq!(recv_matching(&mut bob, &mut bob_buf, |m| m.kind() == "file_chunk"), chunk(1000, split));
+    assert_eq!(recv_matching(&mut bob, &mut bob_buf, |m| m.kind() == "file_chunk"), chunk(split, data.len()));
+    assert_eq!(
+        recv_matching(&mut bob, &mut bob_buf, |m| m.kind().starts_with("file_")),
+        Message::FileComplete { transfer_id: 1 }
+    );
+    send(&mut alice, &Message::FileComplete { transfer_id: 1 });
+    assert_eq!(recv_error(&mut alice, &mut alice_buf), error_codes::NOT_FOUND);
+    
+    // Transfers end when either party goes away
+    send(&mut alice, &offer(2, "bob", 10));
+    recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::FileOffer { .. }));
+    drop(alice);
+    match recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::FileAbort { .. })) {
+        Message::FileAbort { transfer_id, reason } => {
+            assert_eq!(transfer_id, 2);
+            assert!(reason.contains("alice"), "{}", reason);
+        }
+        _ => unreachable!(),
+    }
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_file_transfer_resumes_and_verifies_download() {
+    let (addr, shutdown, server_handle) = start_server(ServerConfig::default());
+    let dir = tempfile::tempdir().unwrap();
+    let downloads = dir.path().join("downloads");
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let path = dir.path().join("report.bin");
+    std::fs::write(&path, &data).unwrap();
+    
+    let (mut alice, mut alice_buf) = join_with_hello(addr, "alice");
+    let (mut bob, mut bob_buf) = join_with_hello(addr, "bob");
+    let mut sender = FileTransfers::new(dir.path().join("unused"));
+    let mut receiver = FileTransfers::new(&downloads);
+    
+    let offer = sender.offer("bob", &path).unwrap();
+    let Message::FileOffer { sha256, .. } = &offer else { panic!("Expected FileOffer") };
+    // An earlier attempt stopped partway, and a file with the same name is in the way
+    std::fs::create_dir_all(&downloads).unwrap();
+    std::fs::write(downloads.join(format!(".{}.part", sha256)), &data[..100_000]).unwrap();
+    std::fs::write(downloads.join("report.bin"), b"older").unwrap();
+    send(&mut alice, &offer);
+    
+    let sending = thread::spawn(move || pump_transfers(&mut alice, &mut alice_buf, &mut sender, "Sent"));
+    let note = pump_transfers(&mut bob, &mut bob_buf, &mut receiver, "alice offers");
+    assert!(note.contains("report.bin (200000 bytes)"), "{}", note);
+    send(&mut bob, &receiver.accept(None).unwrap());
+    let note = pump_transfers(&mut bob, &mut bob_buf, &mut receiver, "Saved");
+    assert!(note.contains("report (1).bin"), "{}", note);
+    assert_eq!(sending.join().unwrap(), "Sent report.bin to bob");
+    
+    assert_eq!(std::fs::read(downloads.join("report (1).bin")).unwrap(), data);
+    assert_eq!(std::fs::read(downloads.join("report.bin")).unwrap(), b"older");
+    assert!(!downloads.join(format!(".{}.part", sha256)).exists());
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+/// Wait for the receipt for a client ID
+fn recv_receipt(stream: &mut TcpStream, buffer: &mut Vec<u8>, client_id: u64) -> (u64, DeliveryStatus) {
+    match recv_matching(stream, buffer, |m| matches!(m, Message::Receipt { client_id: c, .. } if *c == client_id)) {
+        Message::Receipt { id, status, .. } => (id, status),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn test_message_ids_receipts_and_resends() {
+    let (addr, shutdown, server_handle) = start_server(ServerConfig::default());
+    let (mut alice, mut alice_buf) = join_with_hello(addr, "alice");
+    let (mut bob, mut bob_buf) = join_with_hello(addr, "bob");
+    let mut outbox = Outbox::new();
+    
+    // Tracked chat gets an ID, and a resend of it is not broadcast again
+    let chat = outbox.track(Message::chat("alice".to_string(), "first".to_string()));
+    let Message::Chat { client_id, .. } = chat else { panic!("Expected Chat") };
+    assert_eq!(outbox.unconfirmed(), vec![chat.clone()]);
+    send(&mut alice, &chat);
+    let (first_id, status) = recv_receipt(&mut alice, &mut alice_buf, client_id);
+    assert_eq!(status, DeliveryStatus::Sent);
+    assert_eq!(outbox.confirm(client_id, status), None);
+    assert!(outbox.unconfirmed().is_empty());
+    send(&mut alice, &chat);
+    assert_eq!(recv_receipt(&mut alice, &mut alice_buf, client_id), (first_id, DeliveryStatus::Sent));
+    send(&mut alice, &outbox.track(Message::chat("alice".to_string(), "second".to_string())));
+    
+    let broadcasts: Vec<(u64, String)> = (0..2)
+        .map(|_| match recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::Broadcast { .. })) {
+            Message::Broadcast { id, content, .. } => (id, content),
+            _ => unreachable!(),
+        })
+        .collect();
+    assert_eq!(broadcasts[0], (first_id, "first".to_string()));
+    assert_eq!(broadcasts[1].1, "second");
+    assert!(broadcasts[1].0 > first_id);
+    
+    // A private message is delivered once the recipient acknowledges it
+    let private = outbox.track(Message::private("alice".to_string(), "bob".to_string(), "psst".to_string()));
+    let Message::Private { client_id, .. } = private else { panic!("Expected Private") };
+    send(&mut alice, &private);
+    let (private_id, status) = recv_receipt(&mut alice, &mut alice_buf, client_id);
+    assert_eq!(status, DeliveryStatus::Sent);
+    match recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::Private { .. })) {
+        Message::Private { id, .. } => assert_eq!(id, private_id),
+        _ => unreachable!(),
+    }
+    // Only the recipient's ack counts
+    send(&mut alice, &Message::Ack { id: private_id });
+    send(&mut bob, &Message::Ack { id: private_id });
+    assert_eq!(recv_receipt(&mut alice, &mut alice_buf, client_id), (private_id, DeliveryStatus::Delivered));
+    assert_eq!(outbox.confirm(client_id, DeliveryStatus::Sent), None);
+    assert_eq!(outbox.confirm(client_id, DeliveryStatus::Delivered), Some("bob".to_string()));
+    
+    // After reconnecting, a resend is recognised by username and not delivered twice
+    drop(alice);
+    recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::UserLeft { .. }));
+    let (mut alice, mut alice_buf) = join_with_hello(addr, "alice");
+    send(&mut alice, &private);
+    assert_eq!(recv_receipt(&mut alice, &mut alice_buf, client_id), (private_id, DeliveryStatus::Delivered));
+    send(&mut alice, &Message::private("alice".to_string(), "bob".to_string(), "check".to_string()));
+    match recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::Private { .. })) {
+        Message::Private { content, .. } => assert_eq!(content, "check"),
+        _ => unreachable!(),
+    }
+    
+    // Messages nobody can take fail under the ID they were given
+    let lost = outbox.track(Message::private("alice".to_string(), "nobody".to_string(), "hello?".to_string()));
+    let Message::Private { client_id, .. } = lost else { panic!("Expected Private") };
+    send(&mut alice, &lost);
+    assert_eq!(recv_error(&mut alice, &mut alice_buf), error_codes::NOT_FOUND);
+    let (lost_id, status) = recv_receipt(&mut alice, &mut alice_buf, client_id);
+    assert_eq!(status, DeliveryStatus::Failed);
+    assert!(lost_id > private_id);
+    
+    // Messages turned away before getting an ID fail too, so they are not resent forever
+    let elsewhere = outbox.track(Message::Chat {
+        sender: "alice".to_string(),
+        room: "#elsewhere".to_string(),
+        content: "hi".to_string(),
+        timestamp: 0,
+        client_id: 0,
+    });
+    send(&mut alice, &elsewhere);
+    assert_eq!(recv_error(&mut alice, &mut alice_buf), error_codes::NOT_IN_ROOM);
+    let (id, status) = recv_receipt(&mut alice, &mut alice_buf, elsewhere.client_id());
+    assert_eq!((id, status), (0, DeliveryStatus::Failed));
+    outbox.confirm(elsewhere.client_id(), status);
+    assert!(!outbox.unconfirmed().contains(&elsewhere));
+    
+    // Without receipts the outbox only keeps the newest messages
+    let mut outbox = Outbox::new();
+    let first = outbox.track(Message::chat("alice".to_string(), "0".to_string()));
+    for n in 1..=MAX_UNCONFIRMED {
+        outbox.track(Message::chat("alice".to_string(), n.to_string()));
+    }
+    let unconfirmed = outbox.unconfirmed();
+    assert_eq!(unconfirmed.len(), MAX_UNCONFIRMED);
+    assert_eq!(unconfirmed[0].client_id(), first.client_id() + 1);
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_private_messages_wait_for_offline_users() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = || ServerConfig {
+        mailbox_file: Some(dir.path().join("mailboxes.json")),
+        mailbox_capacity: 2,
+        ..ServerConfig::default()
+    };
+    let (addr, shutdown, server_handle) = start_server(config());
+    let (mut alice, mut alice_buf) = join(addr, "alice");
+    for name in ["bob", "carol"] {
+        drop(join(addr, name));
+        recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::UserLeft { username, .. } if username == name));
+    }
+    
+    // Known users get their messages kept, up to the mailbox capacity
+    let mut outbox = Outbox::new();
+    let mut private = |to: &str, content: &str| {
+        let message = outbox.track(Message::private("alice".to_string(), to.to_string(), content.to_string()));
+        let Message::Private { client_id, .. } = message else { panic!("Expected Private") };
+        (message, client_id)
+    };
+    for content in ["one", "two"] {
+        let (message, client_id) = private("Bob", content);
+        send(&mut alice, &message);
+        assert_eq!(recv_receipt(&mut alice, &mut alice_buf, client_id).1, DeliveryStatus::Queued);
+    }
+    let (message, client_id) = private("bob", "three");
+    send(&mut alice, &message);
+    assert_eq!(recv_error(&mut alice, &mut alice_buf), error_codes::MAILBOX_FULL);
+    let (id, status) = recv_receipt(&mut alice, &mut alice_buf, client_id);
+    assert_eq!(status, DeliveryStatus::Failed);
+    assert_ne!(id, 0);
+    let (message, _) = private("carol", "see you");
+    send(&mut alice, &message);
+    let (message, _) = private("nobody", "hello?");
+    send(&mut alice, &message);
+    assert_eq!(recv_error(&mut alice, &mut alice_buf), error_codes::NOT_FOUND);
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+    
+    // The messages survive a restart and arrive in order right after the Welcome
+    let (addr, shutdown, server_handle) = start_server(config());
+    let (mut bob, mut bob_buf) = join_with_hello(addr, "bob");
+    for content in ["one", "two"] {
+        match recv_matching(&mut bob, &mut bob_buf, |m| !matches!(m, Message::SessionToken { .. })) {
+            Message::Private { from, content: received, id, .. } => {
+                assert_eq!((from.as_str(), received.as_str()), ("alice", content));
+                assert_ne!(id, 0);
+            }
+            other => panic!("Expected Private, got {:?}", other),
+        }
+    }
+    drop(bob);
+    
+    // A mailbox is emptied once delivered
+    let (mut bob, mut bob_buf) = join(addr, "bob");
+    send(&mut bob, &Message::ListUsers);
+    match recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::UserList { .. } | Message::Private { .. })) {
+        Message::UserList { .. } => {}
+        other => panic!("Expected UserList, got {:?}", other),
+    }
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+    
+    // Messages that waited too long are dropped
+    let (addr, shutdown, server_handle) = start_server(ServerConfig {
+        mailbox_expiry: Duration::ZERO,
+        ..config()
+    });
+    let (mut carol, mut carol_buf) = join(addr, "carol");
+    send(&mut carol, &Message::ListUsers);
+    match recv_matching(&mut carol, &mut carol_buf, |m| matches!(m, Message::UserList { .. } | Message::Private { .. })) {
+        Message::UserList { .. } => {}
+        other => panic!("Expected UserList, got {:?}", other),
+    }
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_queued_messages_arrive_before_live_ones() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    
+    // Saving the session file widens the window between joining and getting the mail
+    let dir = tempfile::tempdir().unwrap();
+    let (addr, shutdown, server_handle) = start_server(ServerConfig {
+        session_file: Some(dir.path().join("sessions.json")),
+        ..ServerConfig::default()
+    });
+    let (mut alice, mut alice_buf) = join_with_hello(addr, "alice");
+    drop(join(addr, "bob"));
+    recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::UserLeft { .. }));
+    for content in ["one", "two"] {
+        let private = Outbox::new().track(Message::private("alice".to_string(), "bob".to_string(), content.to_string()));
+        send(&mut alice, &private);
+        assert_eq!(recv_receipt(&mut alice, &mut alice_buf, private.client_id()).1, DeliveryStatus::Queued);
+    }
+    
+    // Alice keeps talking to bob while he joins
+    let done = Arc::new(AtomicBool::new(false));
+    let sender = {
+        let done = done.clone();
+        thread::spawn(move || {
+            while !done.load(Ordering::Relaxed) {
+                send(&mut alice, &Message::private("alice".to_string(), "bob".to_string(), "live".to_string()));
+                thread::sleep(Duration::from_micros(100));
+            }
+        })
+    };
+    let (mut bob, mut bob_buf) = join_with_hello(addr, "bob");
+    let received: Vec<String> = (0..3)
+        .map(|_| match recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::Private { .. })) {
+            Message::Private { content, .. } => content,
+            _ => unreachable!(),
+        })
+        .collect();
+    done.store(true, Ordering::Relaxed);
+    sender.join().unwrap();
+    assert_eq!(received, vec!["one", "two", "live"]);
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_resume_restores_rooms_and_missed_messages() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = || ServerConfig {
+        history_dir: Some(dir.path().join("history")),
+        session_file: Some(dir.path().join("sessions.json")),
+        first_user_is_operator: true,
+        ..ServerConfig::default()
+    };
+    let recv_token = |stream: &mut TcpStream, buffer: &mut Vec<u8>| {
+        match recv_matching(stream, buffer, |m| matches!(m, Message::SessionToken { .. })) {
+            Message::SessionToken { token } => token,
+            _ => unreachable!(),
+        }
+    };
+    let recv_broadcast_id = |stream: &mut TcpStream, buffer: &mut Vec<u8>| {
+        match recv_matching(stream, buffer, |m| matches!(m, Message::Broadcast { .. })) {
+            Message::Broadcast { id, .. } => id,
+            _ => unreachable!(),
+        }
+    };
+    
+    let (addr, shutdown, server_handle) = start_server(config());
+    let (mut alice, mut alice_buf) = join_with_hello(addr, "alice");
+    let token = recv_token(&mut alice, &mut alice_buf);
+    send(&mut alice, &Message::CreateRoom { room: "#ops".to_string() });
+    recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::RoomJoined { .. }));
+    let (mut bob, _) = join(addr, "bob");
+    send(&mut bob, &Message::chat("bob".to_string(), "before".to_string()));
+    let last_id = recv_broadcast_id(&mut alice, &mut alice_buf);
+    
+    // The session outlives a restart, and bob talks while alice is away
+    shutdown.shutdown("restart");
+    server_handle.join().unwrap().unwrap();
+    let (addr, shutdown, server_handle) = start_server(config());
+    let (mut bob, _) = join(addr, "bob");
+    send(&mut bob, &Message::chat("bob".to_string(), "after".to_string()));
+    
+    let mut alice = connect(addr);
+    let mut alice_buf = Vec::new();
+    send(&mut alice, &Message::hello());
+    send(&mut alice, &Message::Resume { token: token.clone(), last_id });
+    recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::Welcome { .. }));
+    let new_token = recv_token(&mut alice, &mut alice_buf);
+    assert_ne!(new_token, token);
+    let mut rooms = Vec::new();
+    let mut missed = Vec::new();
+    while rooms.len() < 2 || missed.is_empty() {
+        match recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::RoomJoined { .. } | Message::History { .. })) {
+            Message::RoomJoined { room, .. } => rooms.push(room),
+            Message::History { room, messages } => {
+                assert_eq!(room, "#general");
+                missed = messages;
+            }
+            _ => unreachable!(),
+        }
+    }
+    rooms.sort();
+    assert_eq!(rooms, vec!["#general".to_string(), "#ops".to_string()]);
+    assert!(matches!(&missed[..], [Message::Broadcast { content, .. }] if content == "after"));
+    
+    // Operator rights are not carried over a restart: bob was back first
+    send(&mut alice, &Message::Kick { username: "bob".to_string(), reason: "test".to_string() });
+    assert_eq!(recv_error(&mut alice, &mut alice_buf), error_codes::FORBIDDEN);
+    
+    // Old tokens stop working, and a live session cannot be taken over
+    let try_resume = |token: &str| {
+        let mut stream = connect(addr);
+        let mut buffer = Vec::new();
+        send(&mut stream, &Message::Resume { token: token.to_string(), last_id: 0 });
+        recv_error(&mut stream, &mut buffer)
+    };
+    assert_eq!(try_resume(&token), error_codes::UNAUTHORIZED);
+    assert_eq!(try_resume(&new_token), error_codes::USERNAME_TAKEN);
+    
+    // Leaving on purpose ends the session for good
+    send(&mut alice, &Message::Leave { username: "alice".to_string() });
+    while recv(&mut alice, &mut alice_buf).is_some() {}
+    assert_eq!(try_resume(&new_token), error_codes::UNAUTHORIZED);
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_resumed_sessions_get_operator_rights_from_current_config() {
+    let config = || ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(),
+        operators: vec!["alice".to_string()],
+        first_user_is_operator: true,
+        ..ServerConfig::default()
+    };
+    let server = spawn_server(config());
+    let addr = server.addrs[0];
+    let join_for_token = |username: &str| {
+        let (mut stream, mut buffer) = join_with_hello(addr, username);
+        match recv_matching(&mut stream, &mut buffer, |m| matches!(m, Message::SessionToken { .. })) {
+            Message::SessionToken { token } => (stream, token),
+            _ => unreachable!(),
+        }
+    };
+    let resume = |token: String| {
+        let mut stream = connect(addr);
+        let mut buffer = Vec::new();
+        send(&mut stream, &Message::hello());
+        send(&mut stream, &Message::Resume { token, last_id: 0 });
+        recv_matching(&mut stream, &mut buffer, |m| matches!(m, Message::Welcome { .. }));
+        (stream, buffer)
+    };
+    
+    // Carol is the first user and alice a listed operator; both connections drop
+    let (carol, carol_token) = join_for_token("carol");
+    let (alice, alice_token) = join_for_token("alice");
+    let (mut bob, mut bob_buf) = join(addr, "bob");
+    drop((carol, alice));
+    for _ in 0..2 {
+        recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::UserLeft { .. }));
+    }
+    server.reload.reload(ServerConfig { operators: Vec::new(), ..config() }).unwrap();
+    let mute_bob = Message::Mute { username: "bob".to_string(), duration_secs: 60 };
+    
+    // Taken off the list, alice does not get her rights back by resuming
+    let (mut alice, mut alice_buf) = resume(alice_token);
+    send(&mut alice, &mute_bob);
+    assert_eq!(recv_error(&mut alice, &mut alice_buf), error_codes::FORBIDDEN);
+    
+    // The first user is still the first user
+    let (mut carol, _) = resume(carol_token);
+    send(&mut carol, &mute_bob);
+    recv_matching(&mut bob, &mut bob_buf, |m| matches!(m, Message::Notice { .. }));
+    
+    server.shutdown.shutdown("test finished");
+    server.handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_message_ids_keep_increasing_without_history() {
+    let dir = tempfile::tempdir().unwrap();
+    let restart = |config: ServerConfig, shutdown: ShutdownHandle, handle: thread::JoinHandle<anyhow::Result<()>>| {
+        shutdown.shutdown("restart");
+        handle.join().unwrap().unwrap();
+        start_server(config)
+    };
+    
+    // A session file alone is enough for a resumed client to see what it missed
+    let config = || ServerConfig {
+        session_file: Some(dir.path().join("sessions.json")),
+        ..ServerConfig::default()
+    };
+    let (addr, shutdown, server_handle) = start_server(config());
+    let (mut alice, mut alice_buf) = join_with_hello(addr, "alice");
+    let token = match recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::SessionToken { .. })) {
+        Message::SessionToken { token } => token,
+        _ => unreachable!(),
+    };
+    let (mut bob, _) = join(addr, "bob");
+    let mut last_id = 0;
+    for n in 0..3 {
+        send(&mut bob, &Message::chat("bob".to_string(), n.to_string()));
+        if let Message::Broadcast { id, .. } = recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::Broadcast { .. })) {
+            last_id = id;
+        }
+    }
+    drop(alice);
+    recv_matching(&mut bob, &mut Vec::new(), |m| matches!(m, Message::UserLeft { .. }));
+    
+    let (addr, shutdown, server_handle) = restart(config(), shutdown, server_handle);
+    let (mut bob, _) = join(addr, "bob");
+    send(&mut bob, &Message::chat("bob".to_string(), "after".to_string()));
+    let mut alice = connect(addr);
+    let mut alice_buf = Vec::new();
+    send(&mut alice, &Message::hello());
+    send(&mut alice, &Message::Resume { token, last_id });
+    match recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::History { .. })) {
+        Message::History { messages, .. } => match &messages[..] {
+            [Message::Broadcast { content, id, .. }] => {
+                assert_eq!(content, "after");
+                assert!(*id > last_id);
+            }
+            other => panic!("Expected the missed message, got {:?}", other),
+        },
+        _ => unreachable!(),
+    }
+    
+    // So is a mailbox file, whose waiting messages keep their IDs
+    let config = || ServerConfig {
+        mailbox_file: Some(dir.path().join("mailboxes.json")),
+        ..ServerConfig::default()
+    };
+    let (addr, shutdown, server_handle) = restart(config(), shutdown, server_handle);
+    drop(join(addr, "carol"));
+    let (mut alice, mut alice_buf) = join_with_hello(addr, "alice");
+    let mut outbox = Outbox::new();
+    for n in 0..3 {
+        let private = outbox.track(Message::private("alice".to_string(), "carol".to_string(), n.to_string()));
+        send(&mut alice, &private);
+        last_id = recv_receipt(&mut alice, &mut alice_buf, private.client_id()).0;
+    }
+    
+    let (addr, shutdown, server_handle) = restart(config(), shutdown, server_handle);
+    let (mut alice, mut alice_buf) = join_with_hello(addr, "alice");
+    let chat = outbox.track(Message::chat("alice".to_string(), "after".to_string()));
+    send(&mut alice, &chat);
+    assert!(recv_receipt(&mut alice, &mut alice_buf, chat.client_id()).0 > last_id);
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_backoff_doubles_with_jitter_and_gives_up() {
+    let mut backoff = Backoff::new(ReconnectConfig {
+        initial_delay: Duration::from_millis(100),
+        max_delay: Duration::from_millis(350),
+        max_attempts: Some(4),
+    });
+    for ceiling in [100, 200, 350, 350] {
+        let delay = backoff.next_delay().unwrap();
+        assert!(delay >= Duration::from_millis(ceiling / 2) && delay <= Duration::from_millis(ceiling));
+    }
+    assert_eq!(backoff.attempt(), 4);
+    assert_eq!(backoff.next_delay(), None);
+    
+    backoff.reset();
+    assert!(backoff.next_delay().unwrap() <= Duration::from_millis(100));
+}
+
+#[test]
+fn test_chat_session_reports_events() {
+    let (addr, shutdown, server_handle) = start_server(ServerConfig::default());
+    let config = |username: &str| ClientConfig {
+        server_addr: addr.to_string(),
+        username: username.to_string(),
+        reconnect: None,
+        ..ClientConfig::default()
+    };
+    
+    let alice = ChatSession::connect(config("alice")).unwrap();
+    assert!(matches!(alice.events().recv().unwrap(), Event::Welcome { .. }));
+    let bob = ChatSession::connect(config("bob")).unwrap();
+    assert!(ChatSession::connect(config("Alice")).is_err());
+    next_event(&alice, |e| matches!(e, Event::UserJoined { username, .. } if username == "bob"));
+    
+    let sent = alice.send_chat("hello").unwrap();
+    let Event::Chat(message) = next_event(&bob, |e| matches!(e, Event::Chat(_))) else { unreachable!() };
+    assert_eq!((message.room.as_str(), message.from.as_str()), (DEFAULT_ROOM, "alice"));
+    assert_eq!(message.content, "hello");
+    assert_eq!(
+        next_event(&alice, |e| matches!(e, Event::Receipt { .. })),
+        Event::Receipt { client_id: sent, status: DeliveryStatus::Sent, to: None }
+    );
+    
+    // Bob's session acknowledges the private message without being asked
+    let sent = alice.send_private("bob", "psst").unwrap();
+    assert!(matches!(
+        next_event(&bob, |e| matches!(e, Event::Private { .. })),
+        Event::Private { from, content, .. } if from == "alice" && content == "psst"
+    ));
+    next_event(&alice, |e| *e == Event::Receipt {
+        client_id: sent,
+        status: DeliveryStatus::Delivered,
+        to: Some("bob".to_string()),
+    });
+    
+    // Each Pong says which ping it answers, even with several in flight
+    let first = alice.ping().unwrap();
+    let second = alice.ping().unwrap();
+    next_event(&alice, |e| matches!(e, Event::Pong { ping, .. } if *ping == first));
+    next_event(&alice, |e| matches!(e, Event::Pong { ping, .. } if *ping == second));
+    
+    bob.create_room("#ops").unwrap();
+    next_event(&bob, |e| matches!(e, Event::RoomJoined { room, .. } if room == "#ops"));
+    assert_eq!(bob.current_room().as_deref(), Some("#ops"));
+    
+    bob.close();
+    next_event(&alice, |e| matches!(e, Event::UserLeft { username, .. } if username == "bob"));
+    
+    // Without reconnecting, the session ends with the connection
+    shutdown.shutdown("test finished");
+    next_event(&alice, |e| matches!(e, Event::Shutdown { reason } if reason == "test finished"));
+    next_event(&alice, |e| *e == Event::Closed);
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_chat_session_reconnects_unless_removed() {
+    // A stand-in server that welcomes each connection, sends it one frame and
+    // then closes it, or leaves it open for the client to end
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let error = |code: u16| FramedMessage::encode(&Message::Error { code, message: "no".to_string() }).unwrap();
+    let malformed = vec![0, 0, 0, 4, 0xff, 0xff, 0xff, 0xff];
+    let server = thread::spawn(move || {
+        let mut open = Vec::new();
+        for (last, close) in [(error(error_codes::FORBIDDEN), true), (malformed, false), (error(error_codes::REMOVED), true)] {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut buffer = Vec::new();
+            recv_matching(&mut stream, &mut buffer, |m| matches!(m, Message::Join { .. }));
+            send(&mut stream, &Message::Welcome {
+                message: "Welcome".to_string(),
+                connected_clients: vec!["alice".to_string()],
+            });
+            stream.write_all(&last).unwrap();
+            if !close {
+                open.push(stream);
+            }
+        }
+    });
+    
+    let alice = ChatSession::connect(ClientConfig {
+        server_addr: addr.to_string(),
+        username: "alice".to_string(),
+        reconnect: Some(ReconnectConfig {
+            initial_delay: Duration::from_millis(50),
+            max_delay: Duration::from_millis(100),
+            max_attempts: Some(5),
+        }),
+        ..ClientConfig::default()
+    }).unwrap();
+    next_event(&alice, |e| matches!(e, Event::Welcome { .. }));
+    
+    // Being refused something is not being turned away
+    next_event(&alice, |e| matches!(e, Event::Error { code: error_codes::FORBIDDEN, .. }));
+    next_event(&alice, |e| matches!(e, Event::Welcome { .. }));
+    
+    // A frame that cannot be decoded is reported, and the session starts over
+    next_event(&alice, |e| matches!(e, Event::Error { code: error_codes::BAD_REQUEST, .. }));
+    next_event(&alice, |e| matches!(e, Event::Welcome { .. }));
+    
+    // Once kicked, there is no point trying again
+    next_event(&alice, |e| matches!(e, Event::Error { code: error_codes::REMOVED, .. }));
+    assert_eq!(next_event(&alice, |e| matches!(e, Event::Reconnecting { .. } | Event::Closed)), Event::Closed);
+    server.join().unwrap();
+}
+
+#[test]
+fn test_input_line_edits_and_recalls_history() {
+    let mut input = InputLine::new();
+    "helo".chars().for_each(|c| input.insert(c));
+    input.left();
+    input.insert('l');
+    input.end();
+    input.insert('!');
+    assert_eq!((input.text().as_str(), input.cursor()), ("hello!", 6));
+    input.home();
+    input.delete();
+    input.right();
+    input.backspace();
+    assert_eq!(input.submit(), "llo!");
+    
+    "/users".chars().for_each(|c| input.insert(c));
+    input.submit();
+    input.submit();
+    "draft".chars().for_each(|c| input.insert(c));
+    
+    // Up walks back through history, Down returns to what was being typed
+    input.previous();
+    assert_eq!(input.text(), "/users");
+    input.previous();
+    input.previous();
+    assert_eq!(input.text(), "llo!");
+    input.next();
+    input.next();
+    assert_eq!(input.text(), "draft");
+    input.clear_to_start();
+    assert_eq!(input.text(), "");
+}
+
+#[test]
+fn test_roster_follows_presence_events() {
+    let mut roster = Roster::new();
+    roster.apply(&Event::Welcome { message: String::new(), users: vec!["bob".into(), "alice".into()] });
+    roster.apply(&Event::UserJoined { username: "carol".into(), room: DEFAULT_ROOM.into() });
+    roster.apply(&Event::RoomJoined { room: "#ops".into(), members: vec!["alice".into(), "dave".into()] });
+    roster.apply(&Event::UserLeft { username: "bob".into(), room: DEFAULT_ROOM.into() });
+    assert_eq!(roster.members(DEFAULT_ROOM), ["alice", "carol"]);
+    assert_eq!(roster.members("#ops"), ["alice", "dave"]);
+    
+    roster.apply(&Event::RoomLeft { room: "#ops".into(), current: Some(DEFAULT_ROOM.into()) });
+    assert!(roster.members("#ops").is_empty());
+}
+
+#[test]
+fn test_script_runs_commands_and_checks_expectations() {
+    let (addr, shutdown, server_handle) = start_server(ServerConfig::default());
+    let dir = tempfile::tempdir().unwrap();
+    let run_script = |script: &str| {
+        let path = dir.path().join("test.chat");
+        std::fs::write(&path, script).unwrap();
+        Client::connect(ClientConfig {
+            server_addr: addr.to_string(),
+            username: "alice".to_string(),
+            reconnect: None,
+            interface: Interface::Script(path),
+            ..ClientConfig::default()
+        }).and_then(Client::run)
+    };
+    
+    // Bob answers alice's chat and private message
+    let bob = ChatSession::connect(ClientConfig {
+        server_addr: addr.to_string(),
+        username: "bob".to_string(),
+        reconnect: None,
+        ..ClientConfig::default()
+    }).unwrap();
+    let bob_thread = thread::spawn(move || {
+        next_event(&bob, |e| matches!(e, Event::Chat(m) if m.content == "hello bob"));
+        bob.send_chat("hi alice, welcome").unwrap();
+        next_event(&bob, |e| matches!(e, Event::Private { content, .. } if content == "psst"));
+        bob.send_private("alice", "got it").unwrap();
+        next_event(&bob, |e| matches!(e, Event::UserLeft { username, .. } if username == "alice"));
+    });
+    run_script(concat!(
+        "# Talk to bob\n",
+        "timeout 5s\n",
+        "say hello bob\n",
+        "expect broadcast from bob in #general containing hi alice\n",
+        "/msg bob psst\n",
+        "expect private from bob containing got it\n",
+        "/quit\n",
+        "expect notice containing never reached\n",
+    )).unwrap();
+    bob_thread.join().unwrap();
+    
+    // An expectation nobody meets fails the run once its timeout passes
+    let error = run_script("timeout 200ms\nwait 50ms\nexpect broadcast from carol\n").unwrap_err();
+    assert_eq!(error.to_string(), "Line 3: timed out waiting for `broadcast from carol`");
+    assert_eq!(script::exit_code(&error), exit_codes::UNMET);
+    
+    // Mistakes are caught before anything runs
+    let error = run_script("say hello\nexpect broadcast frm bob\n").unwrap_err();
+    assert!(error.to_string().starts_with("Line 2: Unexpected \"frm\""));
+    assert_eq!(script::exit_code(&error), exit_codes::USAGE);
+    assert_eq!(script::exit_code(&run_script("wait soon\n").unwrap_err()), exit_codes::USAGE);
+    
+    // Including mistyped directives and commands, which are not sent as chat
+    let error = run_script("say hello\nexpct broadcast from bob\n").unwrap_err();
+    assert!(error.to_string().starts_with("Line 2: Unknown directive \"expct\""), "{}", error);
+    assert_eq!(script::exit_code(&error), exit_codes::USAGE);
+    let error = run_script("/mgs bob hi\n").unwrap_err();
+    assert!(error.to_string().starts_with("Line 1: Unknown command /mgs"), "{}", error);
+    assert_eq!(script::exit_code(&error), exit_codes::USAGE);
+    
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_pipe_mode_prints_events_as_json() {
+    let (addr, shutdown, server_handle) = start_server(ServerConfig::default());
+    let pipe = |input: &str| {
+        let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_client"))
+            .args(["--pipe", "--reconnect=false", &addr.to_string(), "carol"])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+        let output = child.wait_with_output().unwrap();
+        let events: Vec<serde_json::Value> = String::from_utf8(output.stdout).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        (output.status.code(), events)
+    };
+    
+    // Replies to the last lines are printed before the client leaves at end of input
+    let (code, events) = pipe("/create #ci\n/users\n");
+    assert_eq!(code, Some(exit_codes::SUCCESS));
+    assert!(events[0]["Welcome"]["users"].as_array().is_some());
+    assert!(events.iter().any(|e| e["RoomJoined"]["room"] == "#ci"));
+    assert_eq!(events.last().unwrap()["Users"][0]["username"], "carol");
+    
+    let (code, _) = pipe("");
+    assert_eq!(code, Some(exit_codes::SUCCESS));
+    
+    // With no server to talk to, the run fails with its own exit code
+    shutdown.shutdown("test finished");
+    server_handle.join().unwrap().unwrap();
+    let (code, events) = pipe("");
+    assert_eq!((code, events.len()), (Some(exit_codes::DISCONNECTED), 0));
+}
+
+#[test]
+fn test_settings_prefer_flags_then_environment_then_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("server.toml");
+    std::fs::write(&file, concat!(
+        "bind = \"127.0.0.1:9000\"\n",
+        "max_connections = 5\n",
+        "mailbox_capacity = 3\n",
+        "operators = [\"root\"]\n",
+    )).unwrap();
+    std::env::set_var("CHAT_MAILBOX_CAPACITY", "4");
+    
+    let settings = ServerSettings::try_parse_from([
+        "server",
+        "--config", file.to_str().unwrap(),
+        "--max-connections", "6",
+    ]).unwrap();
+    let config = settings.resolve().unwrap();
+    assert_eq!(config.bind_addr, "127.0.0.1:9000");
+    assert_eq!(config.max_connections, 6);
+    assert_eq!(config.mailbox_capacity, 4);
+    assert_eq!(config.operators, vec!["root".to_string()]);
+    assert_eq!(config.history_retention, ServerConfig::default().history_retention);
+    
+    let config = ServerSettings::try_parse_from(["server", "--mailbox-capacity", "7"]).unwrap().resolve().unwrap();
+    assert_eq!(config.mailbox_capacity, 7);
+    std::env::remove_var("CHAT_MAILBOX_CAPACITY");
+    
+    // Switches work bare or with a value, without swallowing the next argument
+    let config = ServerSettings::try_parse_from(["server", "--first-user-is-operator", "--rate-limit=false"])
+        .unwrap()
+        .resolve()
+        .unwrap();
+    assert!(config.first_user_is_operator);
+    assert_eq!(config.rate_limit, None);
+    let settings = ClientSettings::try_parse_from(["client", "--json", "--reconnect=false", "127.0.0.1:9000"]).unwrap();
+    assert_eq!((settings.json, settings.reconnect), (Some(true), Some(false)));
+    assert_eq!(settings.server.as_deref(), Some("127.0.0.1:9000"));
+    
+    // Typos in the file are reported rather than silently ignored
+    std::fs::write(&file, "max_conections = 5\n").unwrap();
+    let settings = ServerSettings::try_parse_from(["server", "--config", file.to_str().unwrap()]).unwrap();
+    assert!(settings.resolve().is_err());
+}
+
+#[test]
+fn test_reload_applies_limits_and_bans() {
+    let dir = tempfile::tempdir().unwrap();
+    let ban_file = dir.path().join("bans.json");
+    let config = || ServerConfig {
+        bind_addr: "127.0.0.1:0".to_string(),
+        ban_file: Some(ban_file.clone()),
+        ..ServerConfig::default()
+    };
+    let server = spawn_server(config());
+    let addr = server.addrs[0];
+    let (mut alice, mut alice_buf) = join(addr, "alice");
+    
+    // A ban added to the file by hand and a lower connection limit
+    let ban = Ban {
+        target: BanTarget::Username("mallory".to_string()),
+        expires_at: None,
+        reason: "spam".to_string(),
+        by: "admin".to_string(),
+    };
+    std::fs::write(&ban_file, serde_json::to_string(&vec![ban]).unwrap()).unwrap();
+    server.reload.reload(ServerConfig {
+        max_connections: 2,
+        rate_limit: Some(RateLimitConfig {
+            bytes_per_sec: 1024.0,
+            burst: Duration::from_secs(1),
+            ..RateLimitConfig::default()
+        }),
+        ..config()
+    }).unwrap();
+    
+    // The new rate limit covers clients already connected, and chunks for
+    // a transfer that does not exist count against it
+    let bogus_chunk = Message::FileChunk { transfer_id: 99, offset: 0, data: vec![0; 4096] };
+    send(&mut alice, &bogus_chunk);
+    send(&mut alice, &bogus_chunk);
+    match recv_matching(&mut alice, &mut alice_buf, |m| matches!(m, Message::Error { code: error_codes::RATE_LIMITED, .. })) {
+        Message::Error { message, .. } => assert!(message.contains("too fast"), "{}", message),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    
+    let mut mallory = connect(addr);
+    let mut mallory_buf = Vec::new();
+    send(&mut mallory, &Message::join("mallory".to_string(), None));
+    match recv_matching(&mut mallory, &mut mallory_buf, |m| matches!(m, Message::Error { .. })) {
+        Message::Error { code, .. } => assert_eq!(code, error_codes::REMOVED),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    
+    let (_bob, _) = join(addr, "bob");
+    let mut carol = connect(addr);
+    let mut carol_buf = Vec::new();
+    send(&mut carol, &Message::join("carol".to_string(), None));
+    assert_eq!(recv(&mut carol, &mut carol_buf), None);
+    
+    server.shutdown.shutdown("test finished");
+    server.handle.join().unwrap().unwrap();
 }
 
 #[test]
 fn test_message_serialization() {
     let original = Message::Chat {
         sender: "alice".to_string(),
+        room: "#general".to_string(),
         content: "Hello, world!".to_string(),
         timestamp: 1234567890,
+        client_id: 42,
     };
     
     let bytes = original.to_bytes().unwrap();
@@ -54,3 +2229,158 @@ fn test_message_serialization() {
     
     assert_eq!(original, decoded);
 }
+
+/// The scenarios above again, served by AsyncServer
+#[cfg(feature = "async")]
+mod async_server {
+    use super::*;
+    use multi_threaded_server::client::async_client::AsyncClient;
+    
+    macro_rules! on_async_server {
+        ($($test:ident),* $(,)?) => {$(
+            #[test]
+            fn $test() {
+                CORE.with(|core| core.set(Core::Async));
+                super::$test();
+            }
+        )*};
+    }
+    
+    on_async_server!(
+        test_client_server_communication,
+        test_graceful_shutdown_notifies_clients,
+        test_servers_bind_distinct_ephemeral_ports,
+        test_slow_consumer_does_not_stall_broadcasts,
+        test_kicked_slow_consumer_is_closed_despite_dropped_frames,
+        test_join_rejects_duplicate_and_invalid_usernames,
+        test_rooms_scope_chat_and_presence,
+        test_list_users_reports_metadata,
+        test_history_is_replayed_and_survives_restart,
+        test_token_auth_closes_after_repeated_failures,
+        test_credentials_file_auth,
+        test_hello_negotiates_capabilities,
+        test_version_1_clients_get_frames_without_ids,
+        test_json_lines_listener_talks_to_bincode_clients,
+        test_rate_limit_warns_mutes_then_disconnects,
+        test_metrics_endpoint,
+        test_operators_kick_mute_and_ban,
+        test_server_relays_file_transfers,
+        test_file_transfer_resumes_and_verifies_download,
+        test_message_ids_receipts_and_resends,
+        test_private_messages_wait_for_offline_users,
+        test_queued_messages_arrive_before_live_ones,
+        test_reload_applies_limits_and_bans,
+        test_resume_restores_rooms_and_missed_messages,
+        test_resumed_sessions_get_operator_rights_from_current_config,
+        test_message_ids_keep_increasing_without_history,
+        test_chat_session_reports_events,
+        test_script_runs_commands_and_checks_expectations,
+        test_pipe_mode_prints_events_as_json,
+    );
+    
+    #[test]
+    fn test_async_server_handles_messages_off_the_runtime() {
+        // One runtime thread, so a handler waiting on it would stall every client
+        let (bound_tx, bound) = std::sync::mpsc::channel();
+        let serving = thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            runtime.block_on(async {
+                let server = AsyncServer::bind(ServerConfig {
+                    bind_addr: "127.0.0.1:0".to_string(),
+                    outbound_queue_size: 2,
+                    slow_consumer_policy: SlowConsumerPolicy::Block(Duration::from_secs(2)),
+                    shutdown_timeout: Duration::from_secs(1),
+                    ..ServerConfig::default()
+                }).await.unwrap();
+                bound_tx.send((server.local_addr().unwrap(), server.shutdown_handle())).unwrap();
+                server.serve().await
+            })
+        });
+        let (addr, shutdown) = bound.recv().unwrap();
+        let (_stalled, _) = join(addr, "stalled");
+        let (mut watcher, mut watcher_buf) = join(addr, "watcher");
+        let (mut sender, _) = join(addr, "sender");
+        
+        // Flood until the stalled client's socket and queue are full, so the
+        // sender's handler waits for room on every message
+        thread::spawn(move || {
+            let frame = FramedMessage::encode(&Message::chat("sender".to_string(), "x".repeat(60 * 1024))).unwrap();
+            while sender.write_all(&frame).is_ok() {}
+        });
+        watcher.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        while recv(&mut watcher, &mut watcher_buf).is_some() {}
+        
+        // Other clients are still answered straight away
+        let started = std::time::Instant::now();
+        watcher.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        send(&mut watcher, &Message::Ping);
+        recv_matching(&mut watcher, &mut watcher_buf, |m| *m == Message::Pong);
+        assert!(started.elapsed() < Duration::from_secs(1), "Pong took {:?}", started.elapsed());
+        
+        shutdown.shutdown("test finished");
+        serving.join().unwrap().unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_async_client_and_shutdown_future() {
+        // Tokio does the scheduling, so asking for the reactor is a mistake
+        let reactor = AsyncServer::bind(ServerConfig {
+            bind_addr: "127.0.0.1:0".to_string(),
+            io_model: IoModel::Reactor { workers: 2 },
+            ..ServerConfig::default()
+        }).await;
+        assert!(reactor.is_err());
+        
+        let server = AsyncServer::bind(ServerConfig {
+            bind_addr: "127.0.0.1:0".to_string(),
+            ..ServerConfig::default()
+        }).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(server.serve_with_shutdown(async {
+            let _ = stopped.await;
+        }));
+        
+        let config = |username: &str| ClientConfig {
+            server_addr: addr.to_string(),
+            username: username.to_string(),
+            ..ClientConfig::default()
+        };
+        let mut alice = AsyncClient::connect(config("alice")).await.unwrap();
+        let mut bob = AsyncClient::connect(config("bob")).await.unwrap();
+        assert!(!bob.capabilities().is_empty());
+        assert!(matches!(bob.recv().await.unwrap(), Some(Message::Welcome { .. })));
+        
+        // A taken name is refused at connect time
+        assert!(AsyncClient::connect(config("Alice")).await.is_err());
+        
+        alice.send(Message::chat("alice".to_string(), "hello from a task".to_string())).await.unwrap();
+        loop {
+            match bob.recv().await.unwrap() {
+                Some(Message::Broadcast { from, content, .. }) => {
+                    assert_eq!(from, "alice");
+                    assert_eq!(content, "hello from a task");
+                    break;
+                }
+                Some(_) => {}
+                None => panic!("Connection closed while waiting for a message"),
+            }
+        }
+        alice.leave().await.unwrap();
+        
+        // Completing the shutdown future says goodbye to everyone still connected
+        stop.send(()).unwrap();
+        loop {
+            match bob.recv().await.unwrap() {
+                Some(Message::Shutdown { reason }) => {
+                    assert_eq!(reason, "Server is shutting down");
+                    break;
+                }
+                Some(_) => {}
+                None => panic!("Connection closed before the shutdown notice"),
+            }
+        }
+        assert_eq!(bob.recv().await.unwrap(), None);
+        serving.await.unwrap().unwrap();
+    }
+}